@@ -0,0 +1,133 @@
+use std::{
+    future::ready,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use futures_util::future::BoxFuture;
+use uuid::Uuid;
+
+use super::AuthCredentials;
+
+/// Source of the bearer token sent with the requests of a client.
+///
+/// The client asks for a token before every request, so providers backed by a
+/// refresh flow, a watched token file or a secret manager should not fetch one
+/// each time, see [`CachedAuthProvider`]. [`AuthCredentials`] is the provider of a
+/// static token.
+pub trait AuthProvider: Send + Sync {
+    /// The current token, without the `Bearer` scheme
+    fn token(&self) -> BoxFuture<'_, Result<String>>;
+
+    /// Id of the authenticated user, cached responses are kept per user. None if
+    /// the provider does not know it, its responses are then never cached
+    fn user_id(&self) -> Option<Uuid> {
+        None
+    }
+
+    /// Called when the server rejected a request with 401 Unauthorized, a cached
+    /// token should be fetched again by the next call to `token`
+    fn invalidate(&self) {}
+}
+
+impl AuthProvider for AuthCredentials {
+    fn token(&self) -> BoxFuture<'_, Result<String>> {
+        Box::pin(ready(Ok(self.jwt_token.clone())))
+    }
+
+    fn user_id(&self) -> Option<Uuid> {
+        Some(self.user_id)
+    }
+}
+
+/// Keeps the token of another provider for `ttl`, and until the server rejects it.
+///
+/// Requests racing past an expired token may each fetch a new one, the last one
+/// fetched is kept.
+pub struct CachedAuthProvider<P> {
+    provider: P,
+    ttl: Duration,
+    cached: Mutex<Option<(String, Instant)>>,
+}
+
+impl<P: AuthProvider> CachedAuthProvider<P> {
+    pub fn new(provider: P, ttl: Duration) -> Self {
+        Self {
+            provider,
+            ttl,
+            cached: Mutex::new(None),
+        }
+    }
+}
+
+impl<P: AuthProvider> AuthProvider for CachedAuthProvider<P> {
+    fn token(&self) -> BoxFuture<'_, Result<String>> {
+        Box::pin(async move {
+            let cached = self.cached.lock().unwrap().clone();
+            if let Some((token, fetched_at)) = cached
+                && fetched_at.elapsed() < self.ttl
+            {
+                return Ok(token);
+            }
+            let token = self.provider.token().await?;
+            *self.cached.lock().unwrap() = Some((token.clone(), Instant::now()));
+            Ok(token)
+        })
+    }
+
+    fn user_id(&self) -> Option<Uuid> {
+        self.provider.user_id()
+    }
+
+    fn invalidate(&self) {
+        self.cached.lock().unwrap().take();
+        self.provider.invalidate();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    // hands out "token-1", "token-2", ... one per call
+    #[derive(Default)]
+    struct CountingProvider {
+        calls: AtomicUsize,
+    }
+
+    impl AuthProvider for CountingProvider {
+        fn token(&self) -> BoxFuture<'_, Result<String>> {
+            let call = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+            Box::pin(ready(Ok(format!("token-{}", call))))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_static_credentials() {
+        let user_id = Uuid::new_v4();
+        let credentials = AuthCredentials::new(user_id, "jwt".to_string());
+        assert_eq!(credentials.token().await.unwrap(), "jwt");
+        assert_eq!(AuthProvider::user_id(&credentials), Some(user_id));
+    }
+
+    #[tokio::test]
+    async fn test_cached_auth_provider() {
+        let provider =
+            CachedAuthProvider::new(CountingProvider::default(), Duration::from_secs(60));
+        assert_eq!(provider.token().await.unwrap(), "token-1");
+        assert_eq!(provider.token().await.unwrap(), "token-1");
+        assert_eq!(provider.user_id(), None);
+
+        // a rejected token is fetched again
+        provider.invalidate();
+        assert_eq!(provider.token().await.unwrap(), "token-2");
+
+        // as is an expired one
+        let provider = CachedAuthProvider::new(CountingProvider::default(), Duration::ZERO);
+        assert_eq!(provider.token().await.unwrap(), "token-1");
+        assert_eq!(provider.token().await.unwrap(), "token-2");
+    }
+}
@@ -0,0 +1,259 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use reqwest::header::{
+    CACHE_CONTROL, ETAG, HeaderMap, HeaderValue, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Settings of the in-memory cache of GET responses
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ResponseCacheConfig {
+    /// Cached responses younger than this are returned without a request, older
+    /// ones are revalidated with `If-None-Match` / `If-Modified-Since`. Zero
+    /// revalidates every time
+    pub max_age: Duration,
+    /// Most responses kept, the oldest one is evicted to store another
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+}
+
+fn default_max_entries() -> usize {
+    256
+}
+
+impl ResponseCacheConfig {
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            max_age,
+            max_entries: default_max_entries(),
+        }
+    }
+}
+
+/// Responses are cached per user, scoped clients share the cache
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub user_id: Option<Uuid>,
+    /// Full URL, query included
+    pub url: String,
+}
+
+#[derive(Debug)]
+struct CachedResponse {
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+    body: Bytes,
+    stored_at: Instant,
+}
+
+#[derive(Debug)]
+pub enum CacheLookup {
+    /// Younger than max age, use it as is
+    Fresh(Bytes),
+    /// Older than max age, send these conditional headers
+    Stale(HeaderMap),
+    Miss,
+}
+
+/// In-memory cache of response bodies with their `ETag` / `Last-Modified`
+/// validators, see [`ResponseCacheConfig`]
+#[derive(Debug)]
+pub struct ResponseCache {
+    config: ResponseCacheConfig,
+    entries: Mutex<HashMap<CacheKey, CachedResponse>>,
+}
+
+impl ResponseCache {
+    pub fn new(config: ResponseCacheConfig) -> Self {
+        Self {
+            config,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn lookup(&self, key: &CacheKey) -> CacheLookup {
+        let entries = self.entries.lock().unwrap();
+        let Some(entry) = entries.get(key) else {
+            return CacheLookup::Miss;
+        };
+        if entry.stored_at.elapsed() < self.config.max_age {
+            return CacheLookup::Fresh(entry.body.clone());
+        }
+        let mut headers = HeaderMap::new();
+        if let Some(etag) = &entry.etag {
+            headers.insert(IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = &entry.last_modified {
+            headers.insert(IF_MODIFIED_SINCE, last_modified.clone());
+        }
+        CacheLookup::Stale(headers)
+    }
+
+    /// Keep the body of a successful response. Responses marked `no-store` are not
+    /// cached
+    pub fn store(&self, key: CacheKey, headers: &HeaderMap, body: Bytes) {
+        let no_store = headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| value.to_ascii_lowercase().contains("no-store"));
+        let mut entries = self.entries.lock().unwrap();
+        if no_store || self.config.max_entries == 0 {
+            entries.remove(&key);
+            return;
+        }
+        if entries.len() >= self.config.max_entries
+            && !entries.contains_key(&key)
+            && let Some(oldest) = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.stored_at)
+                .map(|(key, _)| key.clone())
+        {
+            entries.remove(&oldest);
+        }
+        entries.insert(
+            key,
+            CachedResponse {
+                etag: headers.get(ETAG).cloned(),
+                last_modified: headers.get(LAST_MODIFIED).cloned(),
+                body,
+                stored_at: Instant::now(),
+            },
+        );
+    }
+
+    /// The server answered 304 Not Modified with `headers`: the cached body is
+    /// fresh again. None if the entry was invalidated in the meantime
+    pub fn revalidated(&self, key: &CacheKey, headers: &HeaderMap) -> Option<Bytes> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(key)?;
+        if let Some(etag) = headers.get(ETAG) {
+            entry.etag = Some(etag.clone());
+        }
+        if let Some(last_modified) = headers.get(LAST_MODIFIED) {
+            entry.last_modified = Some(last_modified.clone());
+        }
+        entry.stored_at = Instant::now();
+        Some(entry.body.clone())
+    }
+
+    /// Drop the responses of `url` (with any query) for every user
+    pub fn invalidate(&self, url: &str) {
+        self.entries.lock().unwrap().retain(|key, _| {
+            !key.url
+                .strip_prefix(url)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('?'))
+        });
+    }
+
+    /// Drop every response cached for `user_id`
+    pub fn invalidate_user(&self, user_id: Option<Uuid>) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|key, _| key.user_id != user_id);
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(user_id: Option<Uuid>, url: &str) -> CacheKey {
+        CacheKey {
+            user_id,
+            url: url.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_response_cache() {
+        let cache = ResponseCache::new(ResponseCacheConfig {
+            max_age: Duration::ZERO,
+            max_entries: 2,
+        });
+        let user = Some(Uuid::new_v4());
+        let list = key(user, "http://cherry/api/v1/contract/list?status=online");
+        assert!(matches!(cache.lookup(&list), CacheLookup::Miss));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(ETAG, HeaderValue::from_static("\"v1\""));
+        cache.store(list.clone(), &headers, Bytes::from_static(b"[]"));
+        match cache.lookup(&list) {
+            CacheLookup::Stale(conditional) => {
+                assert_eq!(conditional.get(IF_NONE_MATCH).unwrap(), "\"v1\"");
+                assert!(conditional.get(IF_MODIFIED_SINCE).is_none());
+            }
+            other => panic!("unexpected lookup: {:?}", other),
+        }
+        assert_eq!(
+            cache.revalidated(&list, &HeaderMap::new()).unwrap(),
+            Bytes::from_static(b"[]")
+        );
+
+        // the oldest entry is evicted
+        cache.store(key(user, "http://cherry/a"), &headers, Bytes::new());
+        cache.store(key(user, "http://cherry/b"), &headers, Bytes::new());
+        assert_eq!(cache.len(), 2);
+        assert!(matches!(cache.lookup(&list), CacheLookup::Miss));
+        assert!(cache.revalidated(&list, &HeaderMap::new()).is_none());
+
+        // no-store responses are not kept
+        let mut no_store = HeaderMap::new();
+        no_store.insert(CACHE_CONTROL, HeaderValue::from_static("private, no-store"));
+        cache.store(key(user, "http://cherry/a"), &no_store, Bytes::new());
+        assert_eq!(cache.len(), 1);
+
+        cache.invalidate("http://cherry/b");
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_response_cache_invalidate() {
+        let cache = ResponseCache::new(ResponseCacheConfig::new(Duration::from_secs(60)));
+        let (alice, bob) = (Some(Uuid::new_v4()), Some(Uuid::new_v4()));
+        let headers = HeaderMap::new();
+        for user_id in [alice, bob] {
+            cache.store(key(user_id, "http://cherry/list"), &headers, Bytes::new());
+            cache.store(
+                key(user_id, "http://cherry/list?page=2"),
+                &headers,
+                Bytes::new(),
+            );
+            cache.store(
+                key(user_id, "http://cherry/list_all"),
+                &headers,
+                Bytes::new(),
+            );
+        }
+        assert!(matches!(
+            cache.lookup(&key(alice, "http://cherry/list")),
+            CacheLookup::Fresh(_)
+        ));
+
+        cache.invalidate("http://cherry/list");
+        assert_eq!(cache.len(), 2);
+        cache.invalidate_user(alice);
+        assert_eq!(cache.len(), 1);
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+}
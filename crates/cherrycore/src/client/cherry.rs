@@ -1,20 +1,33 @@
+use std::collections::HashMap;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::{Context, Result};
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt, TryStreamExt};
 use reqwest::{
     Client, ClientBuilder,
     header::{AUTHORIZATION, CONTENT_TYPE, HeaderMap, HeaderValue},
 };
 use serde::{Deserialize, Serialize};
 use streamstore::StreamId;
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
 use crate::types::{
-    CheckAclRequest, CheckAclResponse, Contact, Conversation, CreateConversationRequest, CreateConversationResponse, ListConversationsResponse, ListStreamRequest, ListStreamResponse, LoginRequest, LoginResponse, ResponseError, User
+    CheckAclRequest, CheckAclResponse, Contact, ContactFilter, Conversation, ConversationMeta, GetPresenceRequest, GetPresenceResponse, GetUsersRequest, GetUsersResponse, ConversationMembersRequest, ConversationRequest, CreateConversationRequest, CreateConversationResponse, CreateConversationResult, CreateConversationsRequest, CreateConversationsResponse, ListConversationsResponse, ListStreamRequest, ListStreamResponse, LoginKind, LoginRequest, LoginResponse, MarkReadRequest, MessageSearchQuery, PresenceStatus, ReadState, SetPresenceRequest, ResponseError, SearchHit, SearchResults, Stream as StreamInfo, StreamExportRequest, TypingRequest, UpdateConversationMetaRequest, User, IDEMPOTENCY_KEY_HEADER
 };
 
-use super::{ClientConfig, AuthCredentials};
+use super::{ClientConfig, AuthCredentials, AuthProvider, CherryError, MetricsRecorder, RateLimitConfig, ResponseCacheConfig, ServerClock, cache::{CacheKey, CacheLookup, ResponseCache}, error::map_access_error, paginate::{Page, Paginator}, rate_limit::RateLimiter, redact::{redact_body, redact_headers}};
+
+// Timeout of presence and typing requests, a late signal is worth nothing
+const SIGNAL_TIMEOUT: Duration = Duration::from_secs(5);
+// Bytes of an undecodable response body kept in CherryError::Deserialize
+const BODY_SNIPPET_LEN: usize = 512;
+// Endpoints that work without credentials, requests to any other endpoint fail
+// with CherryError::NotAuthenticated before they are sent if the client has none.
+// Credentials, when set, are still sent to these
+const OPTIONAL_AUTH_ENDPOINTS: &[&str] = &["/auth/login", "/health"];
 
 /// Professional Cherry client implementation
 #[derive(Clone)]
@@ -24,9 +37,12 @@ pub struct CherryClient {
 
 #[derive(Clone)]
 pub struct CherryClientInner {
-    config: ClientConfig,
+    config: Arc<ClientConfig>,
     client: Client,
-    auth: Option<AuthCredentials>,
+    auth: Option<Arc<dyn AuthProvider>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    clock: Arc<ServerClock>,
+    cache: Option<Arc<ResponseCache>>,
 }
 
 impl std::ops::Deref for CherryClient {
@@ -37,6 +53,20 @@ impl std::ops::Deref for CherryClient {
     }
 }
 
+/// A [`CherryClient`] scoped to one user's credentials, see [`CherryClient::as_user`]
+#[derive(Clone)]
+pub struct ScopedClient {
+    client: CherryClient,
+}
+
+impl std::ops::Deref for ScopedClient {
+    type Target = CherryClient;
+
+    fn deref(&self) -> &Self::Target {
+        &self.client
+    }
+}
+
 impl CherryClient {
     /// Create a new client with default configuration
     pub fn new() -> Result<Self> {
@@ -45,20 +75,34 @@ impl CherryClient {
 
     /// Create a new client with custom configuration
     pub fn new_with_config(config: ClientConfig) -> Result<Self> {
-        let client = ClientBuilder::new()
-            .timeout(config.timeout)
+        let builder = config
+            .apply_timeouts(ClientBuilder::new())
             .pool_idle_timeout(config.pool_idle_timeout)
             .pool_max_idle_per_host(config.max_idle_per_host)
             .user_agent(config.user_agent.clone())
-            .no_proxy()
+            .gzip(config.accept_compression)
+            .brotli(config.accept_compression)
+            .deflate(config.accept_compression)
+            .no_proxy();
+        let client = config
+            .tls
+            .apply(builder)?
             .build()
             .context("Failed to create HTTP client")?;
 
+        let rate_limiter = config
+            .rate_limit
+            .map(|rate_limit| RateLimiter::new(rate_limit).map(Arc::new))
+            .transpose()?;
+        let cache = config.response_cache.map(|cache| Arc::new(ResponseCache::new(cache)));
         Ok(Self {
             inner: Arc::new(CherryClientInner {
-                config,
+                config: Arc::new(config),
                 client,
                 auth: None,
+                rate_limiter,
+                clock: Arc::new(ServerClock::new()),
+                cache,
             }),
         })
     }
@@ -73,18 +117,54 @@ impl CherryClient {
 
     /// Set authentication credentials
     pub fn with_auth(self, auth: impl Into<AuthCredentials>) -> Self {
+        self.with_credentials(Arc::new(auth.into()))
+    }
+
+    /// Authenticate requests with the tokens of `provider`, asked for a token before
+    /// every request, e.g. to follow a refresh flow or rotated secrets
+    pub fn with_auth_provider(self, provider: Arc<dyn AuthProvider>) -> Self {
+        self.with_credentials(provider)
+    }
+
+    /// A view of this client that sends its requests as another user.
+    ///
+    /// The view shares the connection pool, configuration and rate limiter of this client,
+    /// only the credentials are replaced, so switching between accounts is cheap.
+    pub fn as_user(&self, auth: &AuthCredentials) -> ScopedClient {
+        ScopedClient {
+            client: self.with_credentials(Arc::new(auth.clone())),
+        }
+    }
+
+    fn with_credentials(&self, auth: Arc<dyn AuthProvider>) -> Self {
+        // reqwest::Client is reference counted, cloning it keeps the same pool
         let inner = CherryClientInner {
-            auth: Some(auth.into()),
+            auth: Some(auth),
             client: self.inner.client.clone(),
             config: self.inner.config.clone(),
+            rate_limiter: self.inner.rate_limiter.clone(),
+            clock: self.inner.clock.clone(),
+            cache: self.inner.cache.clone(),
         };
         Self {
             inner: Arc::new(inner),
         }
     }
 
-    /// Create authenticated headers
-    fn create_headers(&self) -> Result<HeaderMap> {
+    /// The underlying HTTP client, sharing this client's connection pool and settings
+    pub fn http_client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Build the full URL for an API path such as `/users/{id}`, relative to
+    /// `ClientConfig::api_prefix`
+    pub fn build_url(&self, path: &str) -> String {
+        format!("{}{}{}", self.config.base_url, self.config.api_prefix, path)
+    }
+
+    /// Headers (content type and authorization) used by every request of this client,
+    /// the authorization token is asked from the client's auth provider
+    pub async fn auth_headers(&self) -> Result<HeaderMap> {
         let mut headers = HeaderMap::new();
 
         // Set content type
@@ -92,7 +172,8 @@ impl CherryClient {
 
         // Set authorization if available
         if let Some(auth) = &self.auth {
-            let auth_value = HeaderValue::from_str(&format!("Bearer {}", auth.jwt_token))
+            let token = auth.token().await.context("Failed to get auth token")?;
+            let auth_value = HeaderValue::from_str(&format!("Bearer {}", token))
                 .context("Invalid JWT token format")?;
             headers.insert(AUTHORIZATION, auth_value);
         }
@@ -100,95 +181,389 @@ impl CherryClient {
         Ok(headers)
     }
 
+    /// Headers of a request to `endpoint`, failing with `CherryError::NotAuthenticated`
+    /// if it requires credentials and the client has none
+    pub(super) async fn endpoint_headers(&self, endpoint: &'static str) -> Result<HeaderMap> {
+        if self.auth.is_none() && !OPTIONAL_AUTH_ENDPOINTS.contains(&endpoint) {
+            return Err(CherryError::NotAuthenticated {
+                endpoint: endpoint.to_string(),
+            }
+            .into());
+        }
+        self.auth_headers().await
+    }
+
+    /// Send a request, pacing it with the rate limiter if one is configured and
+    /// reporting it to the metrics recorder under the `endpoint` path template
+    async fn send(
+        &self,
+        endpoint: &'static str,
+        req: reqwest::RequestBuilder,
+    ) -> reqwest::Result<reqwest::Response> {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire().await;
+        }
+        let recorder = &self.config.metrics_recorder;
+        recorder.on_request_start(endpoint);
+        let begin = std::time::Instant::now();
+        let result = req.send().await;
+        recorder.on_request_end(
+            endpoint,
+            result.as_ref().ok().map(|response| response.status()),
+            begin.elapsed(),
+        );
+        let response = result?;
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED
+            && let Some(auth) = &self.auth
+        {
+            auth.invalidate();
+        }
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.update_from_headers(response.headers());
+        }
+        self.clock.update_from_headers(response.headers(), begin.elapsed());
+        Ok(response)
+    }
+
     /// Make an authenticated request
-    async fn request<T, Q>(&self, method: reqwest::Method, endpoint: &str, query: Option<&Q>) -> Result<T>
+    async fn request<T, Q>(&self, method: reqwest::Method, endpoint: &'static str, query: Option<&Q>) -> Result<T>
     where
         T: for<'de> Deserialize<'de>,
         Q: Serialize,
     {
-        let url = format!("{}{}", self.config.base_url, endpoint);
-        let headers = self.create_headers()?;
+        self.request_path(method, endpoint, endpoint, query).await
+    }
 
-        log::info!("request: url={}, headers={:?}", url, headers);
+    /// Make an authenticated request to `path`, an instance of the `endpoint` path template
+    async fn request_path<T, Q>(
+        &self,
+        method: reqwest::Method,
+        endpoint: &'static str,
+        path: &str,
+        query: Option<&Q>,
+    ) -> Result<T>
+    where
+        T: for<'de> Deserialize<'de>,
+        Q: Serialize,
+    {
+        let url = self.build_url(path);
+        let headers = self.endpoint_headers(endpoint).await?;
+
+        log::debug!(
+            "request: url={}, headers={:?}",
+            url,
+            redact_headers(&headers, &self.config.sensitive_headers)
+        );
 
+        let cache = self
+            .cache
+            .as_ref()
+            .filter(|_| method == reqwest::Method::GET)
+            .zip(self.cache_user());
         let req = self.client.request(method, &url).headers(headers);
         let req = if let Some(q) = query {
             req.query(&q)
         } else {
             req
         };
-        let response = req
-            .send()
+        let Some((cache, user_id)) = cache else {
+            let response = self
+                .send(endpoint, req)
+                .await
+                .context("Request failed")?;
+            return decode_json::<T>(endpoint, error_for_status(response).await?)
+                .await
+                .context("Failed to deserialize response");
+        };
+
+        let request = req.build().context("Invalid request")?;
+        let key = CacheKey {
+            user_id,
+            url: request.url().to_string(),
+        };
+        let body = self.cached_request(cache, endpoint, &key, request).await?;
+        decode_body::<T>(endpoint, reqwest::StatusCode::OK, &body)
+            .context("Failed to deserialize response")
+    }
+
+    /// Send a GET request through the response cache: a fresh cached body is
+    /// returned without a request, a stale one is revalidated and returned again
+    /// on 304 Not Modified
+    async fn cached_request(
+        &self,
+        cache: &ResponseCache,
+        endpoint: &'static str,
+        key: &CacheKey,
+        request: reqwest::Request,
+    ) -> Result<Bytes> {
+        let mut conditional = request.try_clone().context("Invalid request")?;
+        match cache.lookup(key) {
+            CacheLookup::Fresh(body) => return Ok(body),
+            CacheLookup::Stale(headers) => conditional.headers_mut().extend(headers),
+            CacheLookup::Miss => {}
+        }
+        let response = self
+            .send(endpoint, reqwest::RequestBuilder::from_parts(self.client.clone(), conditional))
             .await
             .context("Request failed")?;
-
-        if !response.status().is_success() {
-            let status = response.status();
-            let error_text = response
-                .text()
+        let response = if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            if let Some(body) = cache.revalidated(key, response.headers()) {
+                return Ok(body);
+            }
+            // invalidated while the request was in flight, fetch it again
+            self.send(endpoint, reqwest::RequestBuilder::from_parts(self.client.clone(), request))
                 .await
-                .unwrap_or_else(|_| "Unknown error".to_string());
-            return Err(anyhow::anyhow!("HTTP {}: {}", status, error_text));
+                .context("Request failed")?
+        } else {
+            response
+        };
+
+        let response = error_for_status(response).await?;
+        let headers = response.headers().clone();
+        let body = response.bytes().await.context("Failed to read response body")?;
+        cache.store(key.clone(), &headers, body.clone());
+        Ok(body)
+    }
+
+    /// Drop the cached responses of the API path `path` (e.g. `/contract/list`),
+    /// whatever their query, so the next request fetches them again
+    pub fn invalidate_cache(&self, path: &str) {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(&self.build_url(path));
         }
+    }
 
-        response
-            .json::<T>()
-            .await
-            .context("Failed to deserialize response")
+    /// Drop every cached response, of all users sharing the cache
+    pub fn clear_cache(&self) {
+        if let Some(cache) = &self.cache {
+            cache.clear();
+        }
+    }
+
+    // Responses cached for this client's user may be outdated by a write it made
+    fn invalidate_own_cache(&self) {
+        if let Some((cache, user_id)) = self.cache.as_ref().zip(self.cache_user()) {
+            cache.invalidate_user(user_id);
+        }
+    }
+
+    // The user the responses of this client are cached for, None if they must not
+    // be cached: a provider that does not know its user could be any account, and
+    // would share its entries with every other such provider on the cache
+    fn cache_user(&self) -> Option<Option<Uuid>> {
+        match &self.auth {
+            Some(auth) => auth.user_id().map(Some),
+            None => Some(None),
+        }
     }
 
     /// Make a POST request with JSON body
     async fn request_with_body<T, U>(
         &self,
         method: reqwest::Method,
-        endpoint: &str,
+        endpoint: &'static str,
+        body: &T,
+    ) -> Result<U>
+    where
+        T: Serialize,
+        U: for<'de> Deserialize<'de>,
+    {
+        let headers = self.endpoint_headers(endpoint).await?;
+        self.request_with_headers(method, endpoint, body, headers).await
+    }
+
+    /// Make a request with JSON body and the given headers
+    async fn request_with_headers<T, U>(
+        &self,
+        method: reqwest::Method,
+        endpoint: &'static str,
         body: &T,
+        headers: HeaderMap,
     ) -> Result<U>
     where
         T: Serialize,
         U: for<'de> Deserialize<'de>,
     {
-        let url = format!("{}{}", self.config.base_url, endpoint);
-        let headers = self.create_headers()?;
+        let url = self.build_url(endpoint);
 
-        log::info!("request: url={}, headers={:?}", url, headers);
+        log::debug!(
+            "request: url={}, headers={:?}",
+            url,
+            redact_headers(&headers, &self.config.sensitive_headers)
+        );
 
+        let req = self.client.request(method, &url).headers(headers).json(body);
         let response = self
-            .client
-            .request(method, &url)
-            .headers(headers)
-            .json(body)
-            .send()
+            .send(endpoint, req)
             .await
             .context("Request failed")?;
 
         if !response.status().is_success() {
             let status = response.status();
-            let error_text = response
+            let message = response
                 .text()
                 .await
                 .unwrap_or_else(|_| "Unknown error".to_string());
-            return Err(anyhow::anyhow!("HTTP {}: {}", status, error_text));
+            return Err(CherryError::Http { status, message }.into());
         }
 
-        response
-            .json::<U>()
+        self.invalidate_own_cache();
+        decode_json::<U>(endpoint, response)
             .await
             .context("Failed to deserialize response")
     }
 
+    /// Make a request with JSON body whose response has no content
+    async fn request_no_content<T>(&self, method: reqwest::Method, endpoint: &'static str, body: &T) -> Result<()>
+    where
+        T: Serialize,
+    {
+        let url = self.build_url(endpoint);
+        let headers = self.endpoint_headers(endpoint).await?;
+
+        log::debug!(
+            "request: url={}, headers={:?}",
+            url,
+            redact_headers(&headers, &self.config.sensitive_headers)
+        );
+
+        let req = self.client.request(method, &url).headers(headers).json(body);
+        let response = self
+            .send(endpoint, req)
+            .await
+            .context("Request failed")?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let message = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Unknown error".to_string());
+            return Err(CherryError::Http { status, message }.into());
+        }
+        self.invalidate_own_cache();
+        Ok(())
+    }
+
+    /// Send a presence or typing signal with JSON body.
+    ///
+    /// Signals are frequent and short-lived, so the request gives up after
+    /// `SIGNAL_TIMEOUT` (or the client timeout if shorter) and is never retried.
+    async fn signal_request<T>(&self, endpoint: &'static str, body: &T) -> Result<reqwest::Response>
+    where
+        T: Serialize,
+    {
+        let url = self.build_url(endpoint);
+        let headers = self.endpoint_headers(endpoint).await?;
+
+        let req = self
+            .client
+            .post(&url)
+            .headers(headers)
+            .timeout(SIGNAL_TIMEOUT.min(self.config.timeout))
+            .json(body);
+        let response = self.send(endpoint, req).await.context("Request failed")?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let message = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Unknown error".to_string());
+            return Err(CherryError::Http { status, message }.into());
+        }
+        Ok(response)
+    }
+
+    /// Prime the connection pool and check the credentials before user traffic.
+    ///
+    /// Sends a GET to `/health` with the client's authorization header, so
+    /// the TLS handshake happens now and a pooled connection is kept for the next
+    /// request. A rejected token surfaces as `CherryError::Http` with 401/403.
+    pub async fn warmup(&self) -> Result<()> {
+        const ENDPOINT: &str = "/health";
+        let url = self.build_url(ENDPOINT);
+        let headers = self.endpoint_headers(ENDPOINT).await?;
+
+        let req = self.client.get(&url).headers(headers);
+        let response = self
+            .send(ENDPOINT, req)
+            .await
+            .context("Warmup request failed")?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let message = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Unknown error".to_string());
+            return Err(CherryError::Http { status, message }.into());
+        }
+        // read the body to the end so the connection goes back to the pool
+        response.bytes().await.context("Failed to read warmup response")?;
+        Ok(())
+    }
+
+    /// The server's current time, read from the `Date` header of a request to
+    /// `/health`. Also refreshes the clock offset used by `now`.
+    pub async fn server_time(&self) -> Result<SystemTime> {
+        const ENDPOINT: &str = "/health";
+        let url = self.build_url(ENDPOINT);
+        let begin = Instant::now();
+        let response = self
+            .send(ENDPOINT, self.client.get(&url))
+            .await
+            .context("Server time request failed")?;
+        let server_time = self
+            .clock
+            .update_from_headers(response.headers(), begin.elapsed())
+            .context("Response has no valid Date header")?;
+        // read the body to the end so the connection goes back to the pool
+        let _ = response.bytes().await;
+        Ok(server_time)
+    }
+
+    /// Estimated server time minus local time, None until a response carried a
+    /// `Date` header. Every response refreshes it
+    pub fn clock_offset(&self) -> Option<chrono::TimeDelta> {
+        self.clock.offset()
+    }
+
+    /// Timestamp for locally created data (e.g. messages), on the server clock when
+    /// `ClientConfig::clock_sync_interval` is set so skewed clients still order
+    /// correctly.
+    ///
+    /// A missing or outdated offset is refreshed with `server_time` first; when the
+    /// server cannot be reached the last estimate, or the local clock, is used.
+    pub async fn now(&self) -> SystemTime {
+        let Some(interval) = self.config.clock_sync_interval else {
+            return SystemTime::now();
+        };
+        if self.clock.is_stale(interval)
+            && let Err(e) = self.server_time().await
+        {
+            log::warn!("Failed to sync the server clock: {:?}", e);
+        }
+        self.clock.now()
+    }
+
     /// Login and get authentication credentials
     pub async fn login(&self, email: &str, password: &str) -> Result<LoginResponse> {
-        let login_request = LoginRequest {
-            email: Some(email.to_string()),
-            password: Some(password.to_string()),
-            type_: "email".to_string(),
-        };
+        self.login_with(LoginKind::Email {
+            email: email.to_string(),
+            password: password.to_string(),
+        })
+        .await
+    }
+
+    /// Login with email, phone or OAuth credentials
+    pub async fn login_with(&self, credentials: LoginKind) -> Result<LoginResponse> {
+        let login_request = LoginRequest::from(credentials);
 
         let login_response = self
             .request_with_body::<LoginRequest, LoginResponse>(
                 reqwest::Method::POST,
-                "/api/v1/auth/login",
+                "/auth/login",
                 &login_request,
             )
             .await?;
@@ -196,91 +571,578 @@ impl CherryClient {
         Ok(login_response)
     }
 
+    /// Login and return a client authenticated as the logged-in user.
+    ///
+    /// The returned client shares this client's connection pool; `self` is left
+    /// unauthenticated. Credentials are kept immutable rather than set in place
+    /// behind a lock, so a client never changes identity under requests already
+    /// in flight — the cost is that holders of the old client must switch to
+    /// the returned one.
+    pub async fn login_and_authenticate(&self, email: &str, password: &str) -> Result<CherryClient> {
+        let response = self.login(email, password).await?;
+        Ok(self.clone().with_auth(AuthCredentials::new(
+            response.user_info.user_id,
+            response.jwt_token,
+        )))
+    }
+
     /// Get all contacts for the authenticated user
     pub async fn get_contacts(&self) -> Result<Vec<Contact>> {
-        self.request::<Vec<Contact>, ()>(reqwest::Method::GET, "/api/v1/contract/list", None)
-            .await
+        self.get_contacts_query(ContactFilter::default()).await
+    }
+
+    /// Get the contacts matching `filter`, filtered server-side
+    pub async fn get_contacts_query(&self, filter: ContactFilter) -> Result<Vec<Contact>> {
+        self.request::<Vec<Contact>, ContactFilter>(
+            reqwest::Method::GET,
+            "/contract/list",
+            Some(&filter),
+        )
+        .await
     }
 
     /// Get user by ID
     pub async fn get_user(&self, user_id: Uuid) -> Result<User> {
-        self.request::<User, ()>(reqwest::Method::GET, &format!("/api/v1/users/{}", user_id), None)
+        self.request_path::<User, ()>(
+            reqwest::Method::GET,
+            "/users/{user_id}",
+            &format!("/users/{}", user_id),
+            None,
+        )
+        .await
+    }
+
+    /// Get several users with one request, ids of users that don't exist are
+    /// left out of the result
+    pub async fn get_users(&self, user_ids: &[Uuid]) -> Result<HashMap<Uuid, User>> {
+        if user_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let request = GetUsersRequest {
+            user_ids: user_ids.to_vec(),
+        };
+        let response = self
+            .request_with_body::<GetUsersRequest, GetUsersResponse>(
+                reqwest::Method::POST,
+                "/users/batch",
+                &request,
+            )
+            .await?;
+        Ok(response.users)
+    }
+
+    /// Set the presence of the authenticated user
+    pub async fn set_presence(&self, status: PresenceStatus) -> Result<()> {
+        let request = SetPresenceRequest { status };
+        let response = self
+            .signal_request("/presence/set", &request)
+            .await?;
+        response.bytes().await.context("Failed to read presence response")?;
+        Ok(())
+    }
+
+    /// Get the presence of several users, users without a known presence are
+    /// left out of the result
+    pub async fn get_presence(&self, user_ids: &[Uuid]) -> Result<HashMap<Uuid, PresenceStatus>> {
+        if user_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let request = GetPresenceRequest {
+            user_ids: user_ids.to_vec(),
+        };
+        let response = self
+            .signal_request("/presence/query", &request)
+            .await?;
+        let response = decode_json::<GetPresenceResponse>("/presence/query", response)
             .await
+            .context("Failed to deserialize response")?;
+        Ok(response.presence)
+    }
+
+    /// Tell the other members of a conversation that the authenticated user is
+    /// typing. The indicator expires on the server, so send it again while the
+    /// user keeps typing
+    pub async fn send_typing(&self, conversation_id: Uuid) -> Result<()> {
+        let request = TypingRequest { conversation_id };
+        let response = self
+            .signal_request("/conversations/typing", &request)
+            .await
+            .map_err(map_access_error)?;
+        response.bytes().await.context("Failed to read typing response")?;
+        Ok(())
     }
 
     pub async fn check_acl(&self, user_id: Uuid, stream_id: Option<StreamId>, conversation_id: Option<Uuid>) -> Result<bool> {
         let request = CheckAclRequest { user_id, stream_id, conversation_id };
-        let response = self.request::<CheckAclResponse, CheckAclRequest>(reqwest::Method::GET, "/api/v1/acl/check", Some(&request)).await?;
+        let response = self.request::<CheckAclResponse, CheckAclRequest>(reqwest::Method::GET, "/acl/check", Some(&request)).await?;
         Ok(response.allowed)
     }
 
     /// Create a new conversation
     pub async fn create_conversation(&self, conversation_type: String, members: &[Uuid]) -> Result<Conversation> {
+        self.create_conversation_with_key(conversation_type, members, Uuid::new_v4())
+            .await
+    }
+
+    /// Create a new conversation, deduped by the server on `idempotency_key`.
+    ///
+    /// Retrying with the same key returns the conversation created by the first
+    /// successful attempt instead of creating a duplicate.
+    pub async fn create_conversation_with_key(
+        &self,
+        conversation_type: String,
+        members: &[Uuid],
+        idempotency_key: Uuid,
+    ) -> Result<Conversation> {
+        self.send_create_conversation(conversation_type, members, None, idempotency_key)
+            .await
+    }
+
+    /// Create a new conversation with its title, avatar and topic set, e.g. a group chat
+    pub async fn create_conversation_with_meta(
+        &self,
+        conversation_type: String,
+        members: &[Uuid],
+        meta: ConversationMeta,
+    ) -> Result<Conversation> {
+        let meta = serde_json::to_value(&meta).context("Failed to serialize conversation meta")?;
+        self.send_create_conversation(conversation_type, members, Some(meta), Uuid::new_v4())
+            .await
+    }
+
+    async fn send_create_conversation(
+        &self,
+        conversation_type: String,
+        members: &[Uuid],
+        meta: Option<serde_json::Value>,
+        idempotency_key: Uuid,
+    ) -> Result<Conversation> {
         let request = CreateConversationRequest {
             conversation_type,
             members: members.to_vec(),
-            meta: None,
+            meta,
+            idempotency_key: Some(idempotency_key),
         };
-        let response = self.request_with_body::<CreateConversationRequest, CreateConversationResponse>(reqwest::Method::POST, "/api/v1/conversations/create", &request).await?;
-        Ok(Conversation {
-            conversation_id: response.conversation_id,
-            conversation_type: response.conversation_type,
-            members: response.members.iter().map(|m| m.to_string()).collect::<Vec<String>>().into(),
-            meta: response.meta,
-            stream_id: response.stream_id,
-            created_at: response.created_at,
-            updated_at: response.created_at,
+        let mut headers = self.endpoint_headers("/conversations/create").await?;
+        headers.insert(
+            IDEMPOTENCY_KEY_HEADER,
+            HeaderValue::from_str(&idempotency_key.to_string())?,
+        );
+        let response = self.request_with_headers::<CreateConversationRequest, CreateConversationResponse>(reqwest::Method::POST, "/conversations/create", &request, headers).await?;
+        Ok(created_conversation(response))
+    }
+
+    /// Create several conversations with one request, e.g. the default channels
+    /// of a new workspace.
+    ///
+    /// The results follow the order of `specs`. A conversation the server failed
+    /// to create is an error at its position, typically a `CherryError::Http`, and
+    /// does not fail the others; the outer error is for the request as a whole.
+    /// Specs without an `idempotency_key` get a new one, set the keys to retry a
+    /// failed batch without creating duplicates.
+    pub async fn create_conversations(
+        &self,
+        specs: &[CreateConversationRequest],
+    ) -> Result<Vec<Result<Conversation>>> {
+        if specs.is_empty() {
+            return Ok(Vec::new());
+        }
+        let request = batch_create_request(specs);
+        let response = self
+            .request_with_body::<CreateConversationsRequest, CreateConversationsResponse>(
+                reqwest::Method::POST,
+                "/conversations/create_batch",
+                &request,
+            )
+            .await?;
+        if response.results.len() != specs.len() {
+            return Err(anyhow::anyhow!(
+                "Expected {} results from /conversations/create_batch, got {}",
+                specs.len(),
+                response.results.len()
+            ));
+        }
+        Ok(response
+            .results
+            .into_iter()
+            .map(|result| match result {
+                CreateConversationResult::Created(response) => Ok(created_conversation(response)),
+                CreateConversationResult::Failed { status, message } => {
+                    let status = reqwest::StatusCode::from_u16(status)
+                        .unwrap_or(reqwest::StatusCode::INTERNAL_SERVER_ERROR);
+                    Err(map_access_error(CherryError::Http { status, message }.into()))
+                }
+            })
+            .collect())
+    }
+
+    /// Add members to a conversation, returning the updated conversation
+    pub async fn add_members(&self, conversation_id: Uuid, members: &[Uuid]) -> Result<Conversation> {
+        let request = ConversationMembersRequest {
+            conversation_id,
+            members: members.to_vec(),
+        };
+        self.request_with_body::<ConversationMembersRequest, Conversation>(
+            reqwest::Method::POST,
+            "/conversations/members/add",
+            &request,
+        )
+        .await
+    }
+
+    /// Replace the meta of a conversation, returning the updated conversation.
+    ///
+    /// Read-modify-write through `Conversation::typed_meta` keeps the fields this
+    /// client does not know about.
+    pub async fn update_conversation_meta(&self, conversation_id: Uuid, meta: ConversationMeta) -> Result<Conversation> {
+        let request = UpdateConversationMetaRequest { conversation_id, meta };
+        self.request_with_body::<UpdateConversationMetaRequest, Conversation>(
+            reqwest::Method::POST,
+            "/conversations/meta/update",
+            &request,
+        )
+        .await
+        .map_err(map_access_error)
+    }
+
+    /// Remove members from a conversation, returning the updated conversation.
+    ///
+    /// The server answers 409 Conflict when this would leave the conversation
+    /// without members, which is returned as `CherryError::LastMemberRemoval`.
+    pub async fn remove_members(&self, conversation_id: Uuid, members: &[Uuid]) -> Result<Conversation> {
+        let request = ConversationMembersRequest {
+            conversation_id,
+            members: members.to_vec(),
+        };
+        self.request_with_body::<ConversationMembersRequest, Conversation>(
+            reqwest::Method::POST,
+            "/conversations/members/remove",
+            &request,
+        )
+        .await
+        .map_err(|e| match e.downcast::<CherryError>() {
+            Ok(CherryError::Http { status, message }) if status == reqwest::StatusCode::CONFLICT => {
+                CherryError::LastMemberRemoval {
+                    conversation_id,
+                    message,
+                }
+                .into()
+            }
+            Ok(e) => e.into(),
+            Err(e) => e,
         })
     }
 
+    /// Delete a conversation for all of its members.
+    ///
+    /// Only the owner can delete a conversation; anyone else gets
+    /// `CherryError::Forbidden`. An unknown conversation is `CherryError::NotFound`.
+    pub async fn delete_conversation(&self, conversation_id: Uuid) -> Result<()> {
+        let request = ConversationRequest { conversation_id };
+        self.request_no_content(reqwest::Method::POST, "/conversations/delete", &request)
+            .await
+            .map_err(map_access_error)
+    }
+
+    /// Leave a conversation as the authenticated user, the conversation stays
+    /// available to the remaining members.
+    ///
+    /// Returns `CherryError::Forbidden` when the user is not a member, and
+    /// `CherryError::NotFound` for an unknown conversation.
+    pub async fn leave_conversation(&self, conversation_id: Uuid) -> Result<()> {
+        let request = ConversationRequest { conversation_id };
+        self.request_no_content(reqwest::Method::POST, "/conversations/leave", &request)
+            .await
+            .map_err(map_access_error)
+    }
+
+    /// Mark the messages of a conversation up to `up_to_offset` in its stream as
+    /// read by the authenticated user.
+    ///
+    /// The read cursor only moves forward; the server answers 409 Conflict for an
+    /// offset before the current cursor, returned as `CherryError::ReadCursorConflict`.
+    pub async fn mark_read(&self, conversation_id: Uuid, up_to_offset: u64) -> Result<()> {
+        let request = MarkReadRequest {
+            conversation_id,
+            up_to_offset,
+        };
+        self.request_no_content(reqwest::Method::POST, "/conversations/read", &request)
+            .await
+            .map_err(|e| match e.downcast::<CherryError>() {
+                Ok(CherryError::Http { status, message }) if status == reqwest::StatusCode::CONFLICT => {
+                    CherryError::ReadCursorConflict {
+                        conversation_id,
+                        message,
+                    }
+                    .into()
+                }
+                Ok(e) => map_access_error(e.into()),
+                Err(e) => e,
+            })
+    }
+
+    /// Get the read position of every member of a conversation
+    pub async fn get_read_state(&self, conversation_id: Uuid) -> Result<ReadState> {
+        let request = ConversationRequest { conversation_id };
+        self.request::<ReadState, ConversationRequest>(
+            reqwest::Method::GET,
+            "/conversations/read_state",
+            Some(&request),
+        )
+        .await
+        .map_err(map_access_error)
+    }
+
     /// Get all conversations for the authenticated user
     pub async fn get_conversations(&self) -> Result<Vec<Conversation>> {
         let response = self
             .request::<ListConversationsResponse, ()>(
                 reqwest::Method::GET,
-                "/api/v1/conversations/list",
+                "/conversations/list",
                 None,
             )
             .await?;
         Ok(response.conversations)
     }
 
+    /// The conversations of the user one by one, see `Paginator`. The listing is not
+    /// paginated by the server yet, it is fetched as a single page
+    pub fn paginate_conversations(&self) -> Paginator<Conversation> {
+        let client = self.clone();
+        Paginator::new(move |_cursor| {
+            let client = client.clone();
+            Box::pin(async move { client.get_conversations().await.map(Page::last) })
+        })
+    }
+
+    /// The contacts matching `filter` one by one, fetched as a single page like
+    /// `paginate_conversations`
+    pub fn paginate_contacts(&self, filter: ContactFilter) -> Paginator<Contact> {
+        let client = self.clone();
+        Paginator::new(move |_cursor| {
+            let (client, filter) = (client.clone(), filter.clone());
+            Box::pin(async move { client.get_contacts_query(filter).await.map(Page::last) })
+        })
+    }
+
+    /// The streams of `user_id` one by one, fetched as a single page like
+    /// `paginate_conversations`
+    pub fn paginate_streams(&self, user_id: Uuid) -> Paginator<StreamInfo> {
+        let client = self.clone();
+        Paginator::new(move |_cursor| {
+            let client = client.clone();
+            Box::pin(async move {
+                let response = client.get_streams(user_id).await?;
+                Ok(Page::last(response.streams))
+            })
+        })
+    }
+
+    /// The hits of `query` one by one, following `next_cursor` from page to page.
+    /// Unlike `search_messages` the coverage of the pages is not reported
+    pub fn paginate_search(&self, query: MessageSearchQuery) -> Paginator<SearchHit> {
+        let client = self.clone();
+        Paginator::new(move |cursor| {
+            let client = client.clone();
+            let query = MessageSearchQuery {
+                cursor: cursor.or_else(|| query.cursor.clone()),
+                ..query.clone()
+            };
+            Box::pin(async move {
+                let results = client.search_messages(query).await?;
+                Ok(Page {
+                    items: results.hits,
+                    next_cursor: results.next_cursor,
+                })
+            })
+        })
+    }
+
+    /// Search the messages of every conversation of the user, or of the
+    /// conversation, sender and time range `query` is narrowed to.
+    ///
+    /// A query without match returns empty results, not an error. Check
+    /// `SearchResults::is_partial`: while the server's index catches up, recent
+    /// messages may be missing from the hits. Pages are fetched by passing
+    /// `query.next_page(&results)` back in.
+    pub async fn search_messages(&self, query: MessageSearchQuery) -> Result<SearchResults> {
+        self.request::<SearchResults, MessageSearchQuery>(
+            reqwest::Method::GET,
+            "/messages/search",
+            Some(&query),
+        )
+        .await
+        .map_err(map_access_error)
+    }
+
+    /// Download the whole message stream of a conversation.
+    ///
+    /// The stream's bytes are yielded in chunks as they arrive, nothing is buffered,
+    /// so exports of any size run in constant memory. A caller who is not a member of
+    /// the conversation gets `CherryError::Forbidden`. Dropping the returned stream
+    /// aborts the download and releases its connection, see
+    /// `export_conversation_with_cancel` to cancel it from elsewhere.
+    pub fn export_conversation(&self, conversation_id: Uuid) -> impl Stream<Item = Result<Bytes>> + Send + 'static {
+        self.export_conversation_from(conversation_id, 0)
+    }
+
+    /// Same as `export_conversation`, starting at stream offset `from_offset`.
+    ///
+    /// The client's request timeout covers the whole download; an interrupted
+    /// export resumes from the offset of the bytes already received.
+    pub fn export_conversation_from(
+        &self,
+        conversation_id: Uuid,
+        from_offset: u64,
+    ) -> impl Stream<Item = Result<Bytes>> + Send + 'static {
+        let client = self.clone();
+        futures_util::stream::once(async move { client.open_export(conversation_id, from_offset).await })
+            .try_flatten()
+    }
+
+    /// Same as `export_conversation_from`, ending the stream as soon as `cancel` is
+    /// cancelled, e.g. when the user navigates away.
+    ///
+    /// The request in flight is aborted and its connection released right away,
+    /// without waiting for the next chunk. A cancelled export just ends, without an
+    /// error; callers that need to tell it from a complete one check `cancel`.
+    pub fn export_conversation_with_cancel(
+        &self,
+        conversation_id: Uuid,
+        from_offset: u64,
+        cancel: CancellationToken,
+    ) -> impl Stream<Item = Result<Bytes>> + Send + 'static {
+        let export = Box::pin(self.export_conversation_from(conversation_id, from_offset));
+        // the export is dropped (closing its connection) as soon as the token fires,
+        // not only when the returned stream is
+        futures_util::stream::unfold(Some((export, cancel)), |state| async move {
+            let (mut export, cancel) = state?;
+            tokio::select! {
+                biased;
+                _ = cancel.cancelled() => None,
+                item = export.next() => item.map(|item| (item, Some((export, cancel)))),
+            }
+        })
+    }
+
+    async fn open_export(
+        self,
+        conversation_id: Uuid,
+        from_offset: u64,
+    ) -> Result<impl Stream<Item = Result<Bytes>> + Send + 'static> {
+        let stream_id = self
+            .get_conversations()
+            .await?
+            .into_iter()
+            .find(|conversation| conversation.conversation_id == conversation_id)
+            .map(|conversation| conversation.stream_id)
+            .ok_or_else(|| CherryError::Forbidden {
+                message: format!("not a member of conversation {}", conversation_id),
+            })?;
+
+        let request = StreamExportRequest {
+            stream_id,
+            offset: from_offset,
+        };
+        const ENDPOINT: &str = "/stream/export";
+        let url = self.build_url(ENDPOINT);
+        let headers = self.endpoint_headers(ENDPOINT).await?;
+
+        let req = self.client.get(&url).headers(headers).query(&request);
+        let response = self
+            .send(ENDPOINT, req)
+            .await
+            .context("Export request failed")?;
+
+        if !response.status().is_success() {
+            let status = response.status();
+            let message = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Unknown error".to_string());
+            return Err(map_access_error(CherryError::Http { status, message }.into()));
+        }
+
+        Ok(response
+            .bytes_stream()
+            .map_err(|e| anyhow::Error::new(e).context("Failed to read export stream")))
+    }
+
     /// Get all streams for a user
     pub async fn get_streams(&self, user_id: Uuid) -> Result<ListStreamResponse> {
         let request = ListStreamRequest { user_id };
 
-        let url = format!("{}/api/v1/streams/list", self.config.base_url);
-        let headers = self.create_headers()?;
+        const ENDPOINT: &str = "/streams/list";
+        let url = self.build_url(ENDPOINT);
+        let headers = self.endpoint_headers(ENDPOINT).await?;
 
+        let req = self.client.get(&url).headers(headers).query(&request);
         let response = self
-            .client
-            .get(&url)
-            .headers(headers)
-            .query(&request)
-            .send()
+            .send(ENDPOINT, req)
             .await
             .context("Failed to get streams")?;
 
         if !response.status().is_success() {
             let status = response.status();
-            let error_text = response
+            let message = response
                 .text()
                 .await
                 .unwrap_or_else(|_| "Unknown error".to_string());
-            return Err(anyhow::anyhow!("HTTP {}: {}", status, error_text));
+            return Err(CherryError::Http { status, message }.into());
         }
 
-        response
-            .json::<ListStreamResponse>()
+        decode_json::<ListStreamResponse>(ENDPOINT, response)
             .await
             .context("Failed to deserialize streams response")
     }
 }
 
+/// The response if its status is a success, `CherryError::Http` with its body otherwise
+async fn error_for_status(response: reqwest::Response) -> Result<reqwest::Response> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+    let status = response.status();
+    let message = response
+        .text()
+        .await
+        .unwrap_or_else(|_| "Unknown error".to_string());
+    Err(CherryError::Http { status, message }.into())
+}
+
+/// Deserialize a successful response of `endpoint` into `T`. When the body does
+/// not match, the error is `CherryError::Deserialize` carrying a redacted snippet
+/// of the body and, if it is JSON, the raw value.
+async fn decode_json<T>(endpoint: &str, response: reqwest::Response) -> Result<T>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    let status = response.status();
+    let body = response.bytes().await.context("Failed to read response body")?;
+    decode_body(endpoint, status, &body)
+}
+
+/// Same as `decode_json` for a body already read, e.g. from the response cache
+fn decode_body<T>(endpoint: &str, status: reqwest::StatusCode, body: &[u8]) -> Result<T>
+where
+    T: for<'de> Deserialize<'de>,
+{
+    serde_json::from_slice::<T>(body).map_err(|e| {
+        CherryError::Deserialize {
+            endpoint: endpoint.to_string(),
+            status,
+            message: e.to_string(),
+            body_snippet: redact_body(body, BODY_SNIPPET_LEN),
+            raw: serde_json::from_slice(body).ok(),
+        }
+        .into()
+    })
+}
+
 /// Builder pattern for creating CherryClient instances
 pub struct CherryClientBuilder {
     config: ClientConfig,
-    auth: Option<AuthCredentials>,
+    auth: Option<Arc<dyn AuthProvider>>,
 }
 
 impl CherryClientBuilder {
@@ -301,8 +1163,28 @@ impl CherryClientBuilder {
         self
     }
 
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.connect_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.config.read_timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_api_prefix(mut self, api_prefix: String) -> Self {
+        self.config.api_prefix = api_prefix;
+        self
+    }
+
     pub fn with_auth(mut self, auth: AuthCredentials) -> Self {
-        self.auth = Some(auth);
+        self.auth = Some(Arc::new(auth));
+        self
+    }
+
+    pub fn with_auth_provider(mut self, provider: Arc<dyn AuthProvider>) -> Self {
+        self.auth = Some(provider);
         self
     }
 
@@ -311,10 +1193,25 @@ impl CherryClientBuilder {
         self
     }
 
+    pub fn with_rate_limit(mut self, rate_limit: RateLimitConfig) -> Self {
+        self.config.rate_limit = Some(rate_limit);
+        self
+    }
+
+    pub fn with_metrics_recorder(mut self, recorder: Arc<dyn MetricsRecorder>) -> Self {
+        self.config.metrics_recorder = recorder;
+        self
+    }
+
+    pub fn with_response_cache(mut self, cache: ResponseCacheConfig) -> Self {
+        self.config.response_cache = Some(cache);
+        self
+    }
+
     pub fn build(self) -> Result<CherryClient> {
         let mut client = CherryClient::new_with_config(self.config)?;
         if let Some(auth) = self.auth {
-            client = client.with_auth(auth);
+            client = client.with_auth_provider(auth);
         }
         Ok(client)
     }
@@ -325,3 +1222,723 @@ impl Default for CherryClientBuilder {
         Self::new()
     }
 }
+
+fn created_conversation(response: CreateConversationResponse) -> Conversation {
+    Conversation {
+        conversation_id: response.conversation_id,
+        conversation_type: response.conversation_type,
+        members: response.members.iter().map(|m| m.to_string()).collect::<Vec<String>>().into(),
+        meta: response.meta,
+        stream_id: response.stream_id,
+        created_at: response.created_at,
+        updated_at: response.created_at,
+    }
+}
+
+// The batch of `specs`, every spec with an idempotency key
+fn batch_create_request(specs: &[CreateConversationRequest]) -> CreateConversationsRequest {
+    let conversations = specs
+        .iter()
+        .map(|spec| CreateConversationRequest {
+            idempotency_key: Some(spec.idempotency_key.unwrap_or_else(Uuid::new_v4)),
+            ..spec.clone()
+        })
+        .collect();
+    CreateConversationsRequest { conversations }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use flate2::{Compression, write::GzEncoder};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    // Clock of the mock server, ahead of the local one
+    const SERVER_CLOCK_AHEAD: Duration = Duration::from_secs(600);
+
+    // Minimal HTTP server answering `routes` (path prefix -> body), gzipping the body
+    // when the request accepts it. Returns the base url and the Accept-Encoding
+    // header of every request seen.
+    async fn start_server(routes: Vec<(&'static str, Vec<u8>)>) -> (String, Arc<Mutex<Vec<Option<String>>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_by_server = seen.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = socket.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8_lossy(&request).to_string();
+                let path = request.split(' ').nth(1).unwrap_or_default().to_string();
+                let accept_encoding = request
+                    .lines()
+                    .filter_map(|line| line.split_once(':'))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("accept-encoding"))
+                    .map(|(_, value)| value.trim().to_string());
+                seen_by_server.lock().unwrap().push(accept_encoding.clone());
+
+                let body = routes
+                    .iter()
+                    .find(|(prefix, _)| path.starts_with(prefix))
+                    .map(|(_, body)| body.clone())
+                    .unwrap_or_default();
+                let gzip = accept_encoding.is_some_and(|value| value.contains("gzip"));
+                let (body, encoding) = if gzip {
+                    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                    encoder.write_all(&body).unwrap();
+                    (encoder.finish().unwrap(), "Content-Encoding: gzip\r\n")
+                } else {
+                    (body, "")
+                };
+                let date = chrono::DateTime::<chrono::Utc>::from(SystemTime::now() + SERVER_CLOCK_AHEAD)
+                    .format("%a, %d %b %Y %H:%M:%S GMT");
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nDate: {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    date,
+                    encoding,
+                    body.len()
+                );
+                socket.write_all(head.as_bytes()).await.unwrap();
+                socket.write_all(&body).await.unwrap();
+                socket.shutdown().await.unwrap();
+            }
+        });
+        (base_url, seen)
+    }
+
+    fn conversations_body(conversation_id: Uuid) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "conversations": [{
+                "conversation_id": conversation_id,
+                "conversation_type": "group",
+                "members": [],
+                "meta": {"note": "x".repeat(4096)},
+                "stream_id": 7,
+                "created_at": "2025-06-01T10:00:00Z",
+                "updated_at": "2025-06-01T10:00:00Z",
+            }]
+        }))
+        .unwrap()
+    }
+
+    fn test_auth() -> AuthCredentials {
+        AuthCredentials::new(Uuid::new_v4(), "test-token".to_string())
+    }
+
+    fn new_client(base_url: String, accept_compression: bool) -> CherryClient {
+        CherryClient::new_with_config(ClientConfig {
+            base_url,
+            accept_compression,
+            ..ClientConfig::default_cherry()
+        })
+        .unwrap()
+        .with_auth(test_auth())
+    }
+
+    #[tokio::test]
+    async fn test_gzip_response() {
+        let conversation_id = Uuid::new_v4();
+        let (base_url, seen) =
+            start_server(vec![("/api/v1/conversations/list", conversations_body(conversation_id))]).await;
+
+        let conversations = new_client(base_url, true).get_conversations().await.unwrap();
+        assert_eq!(conversations.len(), 1);
+        assert_eq!(conversations[0].conversation_id, conversation_id);
+        assert_eq!(conversations[0].stream_id, 7);
+
+        let seen = seen.lock().unwrap();
+        let accept_encoding = seen[0].as_deref().unwrap();
+        assert!(accept_encoding.contains("gzip"), "{}", accept_encoding);
+        assert!(accept_encoding.contains("br"), "{}", accept_encoding);
+    }
+
+    #[tokio::test]
+    async fn test_gzip_export_stream() {
+        let conversation_id = Uuid::new_v4();
+        let export: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+        let (base_url, seen) = start_server(vec![
+            ("/api/v1/conversations/list", conversations_body(conversation_id)),
+            ("/api/v1/stream/export", export.clone()),
+        ])
+        .await;
+
+        let chunks: Vec<Bytes> = new_client(base_url, true)
+            .export_conversation(conversation_id)
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(chunks.concat(), export);
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 2);
+        assert!(seen.iter().all(|value| value.as_deref().is_some_and(|v| v.contains("gzip"))));
+    }
+
+    #[tokio::test]
+    async fn test_export_cancel() {
+        let conversation_id = Uuid::new_v4();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let (closed_sender, mut closed) = tokio::sync::mpsc::channel(1);
+        // answers the conversation list, then sends one chunk of the export and
+        // holds the connection open until the client goes away
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 4096];
+                let n = socket.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).to_string();
+                if request.contains("/api/v1/conversations/list") {
+                    let body = conversations_body(conversation_id);
+                    let head = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                        body.len()
+                    );
+                    socket.write_all(head.as_bytes()).await.unwrap();
+                    socket.write_all(&body).await.unwrap();
+                    continue;
+                }
+                socket
+                    .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n")
+                    .await
+                    .unwrap();
+                while let Ok(n) = socket.read(&mut buf).await {
+                    if n == 0 {
+                        break;
+                    }
+                }
+                closed_sender.send(()).await.unwrap();
+            }
+        });
+
+        let cancel = CancellationToken::new();
+        let mut export = Box::pin(new_client(base_url, true).export_conversation_with_cancel(
+            conversation_id,
+            0,
+            cancel.clone(),
+        ));
+        assert_eq!(export.next().await.unwrap().unwrap(), Bytes::from_static(b"hello"));
+
+        cancel.cancel();
+        assert!(export.next().await.is_none());
+        tokio::time::timeout(Duration::from_secs(5), closed.recv())
+            .await
+            .expect("export connection still open after cancel")
+            .unwrap();
+    }
+
+    #[derive(Debug, Default)]
+    struct TestRecorder {
+        started: Mutex<Vec<&'static str>>,
+        finished: Mutex<Vec<(&'static str, Option<reqwest::StatusCode>)>>,
+    }
+
+    impl MetricsRecorder for TestRecorder {
+        fn on_request_start(&self, endpoint: &'static str) {
+            self.started.lock().unwrap().push(endpoint);
+        }
+
+        fn on_request_end(&self, endpoint: &'static str, status: Option<reqwest::StatusCode>, _duration: Duration) {
+            self.finished.lock().unwrap().push((endpoint, status));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metrics_recorder() {
+        let conversation_id = Uuid::new_v4();
+        let user = serde_json::json!({
+            "id": 1,
+            "username": "alice",
+            "display_name": "Alice",
+            "avatar_path": null,
+            "status": "online",
+        });
+        let (base_url, _) = start_server(vec![
+            ("/api/v1/conversations/list", conversations_body(conversation_id)),
+            ("/api/v1/users/", serde_json::to_vec(&user).unwrap()),
+        ])
+        .await;
+
+        let recorder = Arc::new(TestRecorder::default());
+        let client = CherryClientBuilder::new()
+            .with_base_url(base_url)
+            .with_metrics_recorder(recorder.clone())
+            .with_auth(test_auth())
+            .build()
+            .unwrap();
+        client.get_conversations().await.unwrap();
+        assert_eq!(client.get_user(Uuid::new_v4()).await.unwrap().username, "alice");
+
+        // endpoints are path templates, the user id is not part of the label
+        let endpoints = vec!["/conversations/list", "/users/{user_id}"];
+        assert_eq!(*recorder.started.lock().unwrap(), endpoints);
+        assert_eq!(
+            *recorder.finished.lock().unwrap(),
+            endpoints
+                .into_iter()
+                .map(|endpoint| (endpoint, Some(reqwest::StatusCode::OK)))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_messages() {
+        let (conversation_id, sender_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let body = serde_json::json!({
+            "hits": [{
+                "conversation_id": conversation_id,
+                "stream_id": 7,
+                "offset": 4096,
+                "sender_id": sender_id,
+                "timestamp": "2025-06-01T10:00:00Z",
+                "snippet": "see you at <em>lunch</em>",
+            }],
+            "next_cursor": "page-2",
+            "coverage": {"status": "partial", "indexed_until": "2025-06-01T12:00:00Z"},
+        });
+        let (base_url, _) = start_server(vec![
+            (
+                "/api/v1/messages/search?text=lunch&cursor=page-2",
+                b"{}".to_vec(),
+            ),
+            ("/api/v1/messages/search", body.to_string().into_bytes()),
+        ])
+        .await;
+        let client = new_client(base_url, false);
+
+        let query = MessageSearchQuery {
+            sender_id: Some(sender_id),
+            ..MessageSearchQuery::new("lunch")
+        };
+        let results = client.search_messages(query.clone()).await.unwrap();
+        assert!(results.is_partial());
+        assert_eq!(results.hits.len(), 1);
+        assert_eq!(results.hits[0].conversation_id, conversation_id);
+        assert_eq!(
+            (results.hits[0].stream_id, results.hits[0].offset),
+            (7, 4096)
+        );
+
+        let next = query.next_page(&results).unwrap();
+        assert_eq!(next.cursor.as_deref(), Some("page-2"));
+        let results = client
+            .search_messages(MessageSearchQuery {
+                sender_id: None,
+                ..next
+            })
+            .await
+            .unwrap();
+        assert!(results.is_empty());
+        assert!(!results.is_partial());
+        assert!(query.next_page(&results).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_paginate() {
+        let hit = |offset: u64| {
+            serde_json::json!({
+                "conversation_id": Uuid::nil(),
+                "stream_id": 7,
+                "offset": offset,
+                "sender_id": Uuid::nil(),
+                "timestamp": "2025-06-01T10:00:00Z",
+            })
+        };
+        let first = serde_json::json!({"hits": [hit(1), hit(2)], "next_cursor": "page-2"});
+        let second = serde_json::json!({"hits": [hit(3)]});
+        let conversation_id = Uuid::new_v4();
+        let (base_url, seen) = start_server(vec![
+            (
+                "/api/v1/messages/search?text=lunch&cursor=page-2",
+                second.to_string().into_bytes(),
+            ),
+            ("/api/v1/messages/search", first.to_string().into_bytes()),
+            ("/api/v1/conversations/list", conversations_body(conversation_id)),
+        ])
+        .await;
+        let client = new_client(base_url, false);
+
+        let offsets = client
+            .paginate_search(MessageSearchQuery::new("lunch"))
+            .map_ok(|hit| hit.offset)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(offsets, vec![1, 2, 3]);
+        assert_eq!(seen.lock().unwrap().len(), 2);
+
+        let mut conversations = client.paginate_conversations();
+        while let Some(conversation) = conversations.next().await {
+            assert_eq!(conversation.unwrap().conversation_id, conversation_id);
+        }
+        assert_eq!(seen.lock().unwrap().len(), 3);
+    }
+
+    struct SealedVault;
+
+    impl AuthProvider for SealedVault {
+        fn token(&self) -> futures_util::future::BoxFuture<'_, Result<String>> {
+            Box::pin(async { Err(anyhow::anyhow!("vault is sealed")) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auth_provider_error() {
+        let (base_url, seen) =
+            start_server(vec![("/api/v1/conversations/list", conversations_body(Uuid::new_v4()))]).await;
+        let client = new_client(base_url, false).with_auth_provider(Arc::new(SealedVault));
+
+        let err = client.get_conversations().await.unwrap_err();
+        assert!(format!("{:#}", err).contains("vault is sealed"), "{:#}", err);
+        // nothing is sent without a token
+        assert!(seen.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_api_prefix() {
+        let conversation_id = Uuid::new_v4();
+        let (base_url, seen) =
+            start_server(vec![("/gateway/api/v2/conversations/list", conversations_body(conversation_id))]).await;
+        let client = CherryClientBuilder::new()
+            .with_base_url(base_url.clone())
+            .with_api_prefix("/gateway/api/v2".to_string())
+            .with_auth(test_auth())
+            .build()
+            .unwrap();
+        assert_eq!(
+            client.build_url("/users/{user_id}"),
+            format!("{}/gateway/api/v2/users/{{user_id}}", base_url)
+        );
+
+        let conversations = client.get_conversations().await.unwrap();
+        assert_eq!(conversations[0].conversation_id, conversation_id);
+        assert_eq!(seen.lock().unwrap().len(), 1);
+        assert_eq!(
+            new_client(base_url.clone(), true).build_url("/health"),
+            format!("{}/api/v1/health", base_url)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_not_authenticated() {
+        let (base_url, seen) = start_server(vec![
+            ("/api/v1/conversations/list", conversations_body(Uuid::new_v4())),
+            ("/api/v1/health", Vec::new()),
+        ])
+        .await;
+        let client = CherryClient::new_with_base_url(base_url).unwrap();
+
+        let err = client.get_conversations().await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<CherryError>(),
+            Some(CherryError::NotAuthenticated { endpoint }) if endpoint == "/conversations/list"
+        ));
+        let err = client.create_conversation("direct".to_string(), &[Uuid::new_v4()]).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<CherryError>().unwrap().status(), reqwest::StatusCode::UNAUTHORIZED);
+        assert!(seen.lock().unwrap().is_empty());
+
+        // endpoints with optional auth are sent without credentials
+        client.warmup().await.unwrap();
+        assert_eq!(seen.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_create_conversations() {
+        let (created_id, member) = (Uuid::new_v4(), Uuid::new_v4());
+        let body = serde_json::to_vec(&serde_json::json!({
+            "results": [
+                {"created": {
+                    "conversation_id": created_id,
+                    "conversation_type": "group",
+                    "members": [member],
+                    "meta": {"name": "general"},
+                    "stream_id": 9,
+                    "created_at": "2025-06-01T10:00:00Z",
+                    "is_new": true,
+                }},
+                {"failed": {"status": 403, "message": "not a workspace admin"}},
+            ]
+        }))
+        .unwrap();
+        let (base_url, seen) = start_server(vec![("/api/v1/conversations/create_batch", body)]).await;
+        let client = new_client(base_url, true);
+        let spec = |name: &str| CreateConversationRequest {
+            conversation_type: "group".to_string(),
+            members: vec![member],
+            meta: Some(serde_json::json!({ "name": name })),
+            idempotency_key: None,
+        };
+
+        let results = client.create_conversations(&[spec("general"), spec("random")]).await.unwrap();
+        assert_eq!(results.len(), 2);
+        let created = results[0].as_ref().unwrap();
+        assert_eq!(created.conversation_id, created_id);
+        assert_eq!(created.stream_id, 9);
+        let err = results[1].as_ref().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<CherryError>(),
+            Some(CherryError::Forbidden { message }) if message == "not a workspace admin"
+        ));
+
+        // a batch answered with the wrong number of results fails as a whole
+        assert!(client.create_conversations(&[spec("general")]).await.is_err());
+        assert!(client.create_conversations(&[]).await.unwrap().is_empty());
+        assert_eq!(seen.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_batch_create_request_keys() {
+        let key = Uuid::new_v4();
+        let spec = |idempotency_key| CreateConversationRequest {
+            conversation_type: "group".to_string(),
+            members: Vec::new(),
+            meta: None,
+            idempotency_key,
+        };
+        let request = batch_create_request(&[spec(Some(key)), spec(None), spec(None)]);
+        let keys = request
+            .conversations
+            .iter()
+            .map(|spec| spec.idempotency_key.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(keys[0], key);
+        assert_ne!(keys[1], keys[2]);
+    }
+
+    #[tokio::test]
+    async fn test_accept_compression_disabled() {
+        let conversation_id = Uuid::new_v4();
+        let (base_url, seen) =
+            start_server(vec![("/api/v1/conversations/list", conversations_body(conversation_id))]).await;
+
+        let conversations = new_client(base_url, false).get_conversations().await.unwrap();
+        assert_eq!(conversations[0].conversation_id, conversation_id);
+        assert_eq!(*seen.lock().unwrap(), vec![None]);
+    }
+
+    #[tokio::test]
+    async fn test_server_time() {
+        let (base_url, _) = start_server(vec![("/api/v1/health", Vec::new())]).await;
+        let ahead = |time: SystemTime| time.duration_since(SystemTime::now()).unwrap();
+        // Date has a resolution of one second
+        let near_server_clock =
+            |time: Duration| time.abs_diff(SERVER_CLOCK_AHEAD) < Duration::from_secs(2);
+
+        // without clock sync, now is the local clock
+        let client = new_client(base_url.clone(), true);
+        assert!(client.clock_offset().is_none());
+        assert!(client.now().await <= SystemTime::now());
+
+        let server_time = client.server_time().await.unwrap();
+        assert!(near_server_clock(ahead(server_time)));
+        let offset = client.clock_offset().unwrap().to_std().unwrap();
+        assert!(near_server_clock(offset));
+
+        // with clock sync, the first now syncs the clock
+        let client = CherryClient::new_with_config(ClientConfig {
+            base_url,
+            clock_sync_interval: Some(Duration::from_secs(60)),
+            ..ClientConfig::default_cherry()
+        })
+        .unwrap();
+        assert!(near_server_clock(ahead(client.now().await)));
+        assert!(client.clock_offset().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_response_cache_etag() {
+        let conversation_id = Uuid::new_v4();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        // status of every response sent
+        let statuses = Arc::new(Mutex::new(Vec::new()));
+        let statuses_by_server = statuses.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 4096];
+                let n = socket.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+                let head = if request.contains("if-none-match: \"v1\"") {
+                    statuses_by_server.lock().unwrap().push(304);
+                    "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nConnection: close\r\n\r\n".to_string()
+                } else {
+                    statuses_by_server.lock().unwrap().push(200);
+                    let body = conversations_body(conversation_id);
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nETag: \"v1\"\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        String::from_utf8(body).unwrap()
+                    )
+                };
+                socket.write_all(head.as_bytes()).await.unwrap();
+                socket.shutdown().await.unwrap();
+            }
+        });
+
+        // without max age every request is revalidated
+        let client = CherryClientBuilder::new()
+            .with_base_url(base_url.clone())
+            .with_response_cache(ResponseCacheConfig::new(Duration::ZERO))
+            .with_auth(test_auth())
+            .build()
+            .unwrap();
+        for _ in 0..3 {
+            let conversations = client.get_conversations().await.unwrap();
+            assert_eq!(conversations[0].conversation_id, conversation_id);
+        }
+        assert_eq!(*statuses.lock().unwrap(), vec![200, 304, 304]);
+
+        client.invalidate_cache("/conversations/list");
+        client.get_conversations().await.unwrap();
+        assert_eq!(statuses.lock().unwrap().last(), Some(&200));
+
+        // within max age the cached body is returned without a request
+        statuses.lock().unwrap().clear();
+        let client = CherryClientBuilder::new()
+            .with_base_url(base_url)
+            .with_response_cache(ResponseCacheConfig::new(Duration::from_secs(60)))
+            .with_auth(test_auth())
+            .build()
+            .unwrap();
+        client.get_conversations().await.unwrap();
+        client.get_conversations().await.unwrap();
+        assert_eq!(*statuses.lock().unwrap(), vec![200]);
+        client.clear_cache();
+        client.get_conversations().await.unwrap();
+        assert_eq!(*statuses.lock().unwrap(), vec![200, 200]);
+    }
+
+    // a provider that does not know its user
+    struct StaticToken(&'static str);
+
+    impl AuthProvider for StaticToken {
+        fn token(&self) -> futures_util::future::BoxFuture<'_, Result<String>> {
+            Box::pin(async move { Ok(self.0.to_string()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_response_cache_without_user_id() {
+        let (alice_conversation, bob_conversation) = (Uuid::new_v4(), Uuid::new_v4());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let requests_by_server = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 4096];
+                let n = socket.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+                requests_by_server.fetch_add(1, Ordering::SeqCst);
+                let conversation_id = if request.contains("bearer alice") {
+                    alice_conversation
+                } else {
+                    bob_conversation
+                };
+                let body = conversations_body(conversation_id);
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                socket.write_all(head.as_bytes()).await.unwrap();
+                socket.write_all(&body).await.unwrap();
+                socket.shutdown().await.unwrap();
+            }
+        });
+
+        let client = CherryClientBuilder::new()
+            .with_base_url(base_url)
+            .with_response_cache(ResponseCacheConfig::new(Duration::from_secs(60)))
+            .build()
+            .unwrap();
+        let alice = client.clone().with_auth_provider(Arc::new(StaticToken("alice")));
+        let bob = client.clone().with_auth_provider(Arc::new(StaticToken("bob")));
+
+        for _ in 0..2 {
+            let conversations = alice.get_conversations().await.unwrap();
+            assert_eq!(conversations[0].conversation_id, alice_conversation);
+            let conversations = bob.get_conversations().await.unwrap();
+            assert_eq!(conversations[0].conversation_id, bob_conversation);
+        }
+        // nothing was cached for either of them
+        assert_eq!(requests.load(Ordering::SeqCst), 4);
+        assert!(client.cache.as_ref().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_read_timeout() {
+        // accepts the request and never answers it
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                sockets.push(socket);
+            }
+        });
+
+        let client = CherryClientBuilder::new()
+            .with_base_url(base_url)
+            .with_timeout(Duration::from_secs(30))
+            .with_connect_timeout(Duration::from_secs(1))
+            .with_read_timeout(Duration::from_millis(200))
+            .with_auth(test_auth())
+            .build()
+            .unwrap();
+        let begin = Instant::now();
+        let err = client.get_conversations().await.unwrap_err();
+        assert!(begin.elapsed() < Duration::from_secs(5), "{:?}", begin.elapsed());
+        let timed_out = err
+            .chain()
+            .filter_map(|e| e.downcast_ref::<reqwest::Error>())
+            .any(|e| e.is_timeout());
+        assert!(timed_out, "{:#}", err);
+    }
+
+    #[tokio::test]
+    async fn test_deserialize_error_body_snippet() {
+        let body = serde_json::json!({
+            "items": [{"id": 1}],
+            "next_page": null,
+            "access_token": "secret-token",
+        });
+        let (base_url, _) =
+            start_server(vec![("/api/v1/conversations/list", body.to_string().into_bytes())]).await;
+
+        let err = new_client(base_url, false).get_conversations().await.unwrap_err();
+        match err.downcast_ref::<CherryError>() {
+            Some(CherryError::Deserialize {
+                endpoint,
+                status,
+                body_snippet,
+                raw,
+                ..
+            }) => {
+                assert_eq!(endpoint, "/conversations/list");
+                assert_eq!(*status, reqwest::StatusCode::OK);
+                assert!(body_snippet.contains(r#""items":[{"id":1}]"#), "{}", body_snippet);
+                assert!(!body_snippet.contains("secret-token"), "{}", body_snippet);
+                assert_eq!(raw.as_ref().unwrap()["items"][0]["id"], 1);
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+        assert!(format!("{:#}", err).contains("next_page"), "{:#}", err);
+    }
+}
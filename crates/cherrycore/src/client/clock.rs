@@ -0,0 +1,113 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use reqwest::header::{DATE, HeaderMap};
+
+/// Estimate of the server clock, taken from the `Date` header of responses.
+///
+/// `Date` has a resolution of one second, so the estimated offset is only accurate
+/// to about a second, enough to order messages of clients whose clocks drifted
+/// by minutes.
+#[derive(Debug, Default)]
+pub struct ServerClock {
+    sample: Mutex<Option<ClockSample>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ClockSample {
+    // server time minus local time
+    offset: TimeDelta,
+    taken_at: Instant,
+}
+
+impl ServerClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Update the estimate from a response received `elapsed` after its request
+    /// was sent. Returns the server time of the response, None without a valid
+    /// `Date` header.
+    pub fn update_from_headers(
+        &self,
+        headers: &HeaderMap,
+        elapsed: Duration,
+    ) -> Option<SystemTime> {
+        let date = headers
+            .get(DATE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| DateTime::parse_from_rfc2822(value).ok())?
+            .with_timezone(&Utc);
+        // the server stamped the response about halfway through the round trip
+        let local = DateTime::<Utc>::from(SystemTime::now())
+            - TimeDelta::from_std(elapsed / 2).unwrap_or_default();
+        *self.sample.lock().unwrap() = Some(ClockSample {
+            offset: date - local,
+            taken_at: Instant::now(),
+        });
+        Some(date.into())
+    }
+
+    /// Server time minus local time, None until a response carried a `Date` header
+    pub fn offset(&self) -> Option<TimeDelta> {
+        self.sample.lock().unwrap().map(|sample| sample.offset)
+    }
+
+    /// Whether there is no estimate or it is older than `max_age`
+    pub fn is_stale(&self, max_age: Duration) -> bool {
+        self.sample
+            .lock()
+            .unwrap()
+            .is_none_or(|sample| sample.taken_at.elapsed() > max_age)
+    }
+
+    /// Local time corrected by the estimated offset, the local time without one
+    pub fn now(&self) -> SystemTime {
+        let now = SystemTime::now();
+        match self.offset() {
+            Some(offset) => (DateTime::<Utc>::from(now) + offset).into(),
+            None => now,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date_headers(time: SystemTime) -> HeaderMap {
+        let date = DateTime::<Utc>::from(time).format("%a, %d %b %Y %H:%M:%S GMT");
+        let mut headers = HeaderMap::new();
+        headers.insert(DATE, date.to_string().parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_server_clock_offset() {
+        let clock = ServerClock::new();
+        assert!(clock.offset().is_none());
+        assert!(clock.is_stale(Duration::from_secs(60)));
+        assert!(
+            clock
+                .update_from_headers(&HeaderMap::new(), Duration::ZERO)
+                .is_none()
+        );
+
+        // a server 10 minutes ahead
+        let server_now = SystemTime::now() + Duration::from_secs(600);
+        let server_time = clock
+            .update_from_headers(&date_headers(server_now), Duration::from_millis(100))
+            .unwrap();
+        assert!(server_now.duration_since(server_time).unwrap() < Duration::from_secs(1));
+
+        let offset = clock.offset().unwrap();
+        assert!((offset - TimeDelta::seconds(600)).abs() <= TimeDelta::seconds(1));
+        assert!(!clock.is_stale(Duration::from_secs(60)));
+
+        let ahead = clock.now().duration_since(SystemTime::now()).unwrap();
+        assert!(ahead > Duration::from_secs(598) && ahead < Duration::from_secs(601));
+    }
+}
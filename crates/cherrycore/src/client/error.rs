@@ -0,0 +1,114 @@
+use std::fmt::Display;
+
+use reqwest::StatusCode;
+use uuid::Uuid;
+
+/// Typed errors returned by the clients, wrapped in `anyhow::Error`.
+/// Callers can recover them with `error.downcast_ref::<CherryError>()`.
+#[derive(Debug)]
+pub enum CherryError {
+    /// The server answered with a non-success status
+    Http { status: StatusCode, message: String },
+    /// The caller is not allowed to perform the operation, e.g. a non-owner deleting a conversation
+    Forbidden { message: String },
+    /// The requested resource does not exist, or is not visible to the caller
+    NotFound { message: String },
+    /// A successful response of `endpoint` did not match the expected type.
+    /// `body_snippet` is the start of the body with credentials redacted, for
+    /// diagnostics. `raw` holds the body as parsed JSON if it is JSON at all, so
+    /// callers can still pick out the fields they need.
+    Deserialize {
+        endpoint: String,
+        status: StatusCode,
+        message: String,
+        body_snippet: String,
+        raw: Option<serde_json::Value>,
+    },
+    /// The server refused to remove the last members of a conversation
+    LastMemberRemoval {
+        conversation_id: Uuid,
+        message: String,
+    },
+    /// The read cursor of a conversation would move backward, e.g. another
+    /// device already marked later messages as read
+    ReadCursorConflict {
+        conversation_id: Uuid,
+        message: String,
+    },
+    /// `endpoint` requires credentials and the client has none, see
+    /// `CherryClient::with_auth`. The request was not sent
+    NotAuthenticated { endpoint: String },
+}
+
+impl CherryError {
+    /// The HTTP status the server answered with
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Http { status, .. } => *status,
+            Self::Forbidden { .. } => StatusCode::FORBIDDEN,
+            Self::NotFound { .. } => StatusCode::NOT_FOUND,
+            Self::Deserialize { status, .. } => *status,
+            Self::LastMemberRemoval { .. } => StatusCode::CONFLICT,
+            Self::ReadCursorConflict { .. } => StatusCode::CONFLICT,
+            Self::NotAuthenticated { .. } => StatusCode::UNAUTHORIZED,
+        }
+    }
+}
+
+impl Display for CherryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Http { status, message } => write!(f, "HTTP {}: {}", status, message),
+            Self::Forbidden { message } => write!(f, "Forbidden: {}", message),
+            Self::NotFound { message } => write!(f, "Not found: {}", message),
+            Self::Deserialize {
+                endpoint,
+                status,
+                message,
+                body_snippet,
+                ..
+            } => write!(
+                f,
+                "Unexpected response from {} (HTTP {}): {}, body: {}",
+                endpoint, status, message, body_snippet
+            ),
+            Self::LastMemberRemoval {
+                conversation_id,
+                message,
+            } => write!(
+                f,
+                "Cannot remove the last members of conversation {}: {}",
+                conversation_id, message
+            ),
+            Self::ReadCursorConflict {
+                conversation_id,
+                message,
+            } => write!(
+                f,
+                "Read cursor of conversation {} cannot move backward: {}",
+                conversation_id, message
+            ),
+            Self::NotAuthenticated { endpoint } => write!(
+                f,
+                "Not authenticated: {} requires credentials, see CherryClient::with_auth",
+                endpoint
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CherryError {}
+
+/// Turn 403/404 `Http` errors into `Forbidden`/`NotFound`, other errors are kept as is
+pub(crate) fn map_access_error(error: anyhow::Error) -> anyhow::Error {
+    match error.downcast::<CherryError>() {
+        Ok(CherryError::Http { status, message }) if status == StatusCode::FORBIDDEN => {
+            CherryError::Forbidden { message }.into()
+        }
+        Ok(CherryError::Http { status, message }) if status == StatusCode::NOT_FOUND => {
+            CherryError::NotFound { message }.into()
+        }
+        Ok(e) => e.into(),
+        Err(e) => e,
+    }
+}
@@ -136,8 +136,8 @@ impl FileClient {
         A: Into<AuthCredentials>,
     {
         let config = config.into();
-        let client = ClientBuilder::new()
-            .timeout(config.timeout)
+        let client = config
+            .apply_timeouts(ClientBuilder::new())
             .pool_idle_timeout(config.pool_idle_timeout)
             .pool_max_idle_per_host(config.max_idle_per_host)
             .user_agent(config.user_agent.clone())
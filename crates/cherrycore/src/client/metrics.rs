@@ -0,0 +1,62 @@
+use std::{fmt, sync::Arc, time::Duration};
+
+use reqwest::StatusCode;
+
+/// Hooks called around every request the client sends, to bridge request counts,
+/// latencies and error rates to a metrics system.
+///
+/// `endpoint` is the path template of the call without the API prefix, e.g.
+/// `/users/{user_id}`, never the interpolated URL, so it can be used as a metric
+/// label directly.
+pub trait MetricsRecorder: fmt::Debug + Send + Sync {
+    /// A request is about to be sent, after any rate limiting delay
+    fn on_request_start(&self, _endpoint: &'static str) {}
+
+    /// A request finished, `status` is None if no response was received
+    /// (connection error, timeout)
+    fn on_request_end(
+        &self,
+        endpoint: &'static str,
+        status: Option<StatusCode>,
+        duration: Duration,
+    );
+}
+
+/// Recorder that does nothing, the default
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopRecorder;
+
+impl MetricsRecorder for NoopRecorder {
+    fn on_request_end(
+        &self,
+        _endpoint: &'static str,
+        _status: Option<StatusCode>,
+        _duration: Duration,
+    ) {
+    }
+}
+
+/// Recorder that logs every finished request, failures as warnings
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LogRecorder;
+
+impl MetricsRecorder for LogRecorder {
+    fn on_request_end(
+        &self,
+        endpoint: &'static str,
+        status: Option<StatusCode>,
+        duration: Duration,
+    ) {
+        match status {
+            Some(status) if status.is_success() => {
+                log::info!("request {}: {} in {:?}", endpoint, status, duration)
+            }
+            Some(status) => log::warn!("request {}: {} in {:?}", endpoint, status, duration),
+            None => log::warn!("request {}: no response after {:?}", endpoint, duration),
+        }
+    }
+}
+
+pub(crate) fn default_recorder() -> Arc<dyn MetricsRecorder> {
+    Arc::new(NoopRecorder)
+}
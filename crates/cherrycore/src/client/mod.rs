@@ -1,12 +1,32 @@
+pub mod auth;
+pub mod cache;
 pub mod cherry;
+pub mod clock;
+pub mod error;
 pub mod stream;
 pub mod file;
+pub mod metrics;
+pub mod paginate;
+pub mod rate_limit;
+pub mod redact;
+pub mod tls;
+pub mod ws;
 
+use std::sync::Arc;
 use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
+pub use auth::{AuthProvider, CachedAuthProvider};
+pub use cache::ResponseCacheConfig;
+pub use clock::ServerClock;
+pub use error::CherryError;
+pub use metrics::{LogRecorder, MetricsRecorder, NoopRecorder};
+pub use paginate::{Page, Paginator};
+pub use rate_limit::RateLimitConfig;
+pub use tls::{ClientIdentity, TlsConfig};
+
 /// Authentication credentials
 #[derive(Debug, Clone)]
 pub struct AuthCredentials {
@@ -35,14 +55,62 @@ impl From<(&Uuid, &String)> for AuthCredentials {
 pub struct ClientConfig {
     /// Base URL of the Cherry server
     pub base_url: String,
-    /// Default timeout for requests
+    /// Overall cap on a request, from connecting until the response body is read.
+    /// It bounds the other timeouts: whichever expires first fails the request
     pub timeout: Duration,
+    /// Cap on establishing a connection (DNS, TCP and TLS), to fail fast on dead
+    /// hosts. None leaves the connect phase bounded by `timeout` only
+    #[serde(default)]
+    pub connect_timeout: Option<Duration>,
+    /// Cap on each read of a response, reset by every successful read, so a stalled
+    /// connection fails while a large body that keeps arriving (e.g. an export) does
+    /// not. None leaves reads bounded by `timeout` only
+    #[serde(default)]
+    pub read_timeout: Option<Duration>,
     /// Maximum idle connections per host
     pub max_idle_per_host: usize,
     /// Connection pool timeout
     pub pool_idle_timeout: Duration,
     /// User agent string
     pub user_agent: String,
+    /// Client-side request throttling, disabled when None
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Extra header names whose values are redacted from request logs,
+    /// on top of `Authorization`, `Proxy-Authorization` and `Cookie`
+    #[serde(default)]
+    pub sensitive_headers: Vec<String>,
+    /// Extra root certificates, client identity for mutual TLS
+    #[serde(default)]
+    pub tls: TlsConfig,
+    /// Ask for gzip, brotli or deflate compressed responses and decompress them,
+    /// streamed responses (e.g. conversation exports) included
+    #[serde(default = "default_accept_compression")]
+    pub accept_compression: bool,
+    /// Receives the endpoint, status and latency of every request
+    #[serde(skip, default = "metrics::default_recorder")]
+    pub metrics_recorder: Arc<dyn MetricsRecorder>,
+    /// Anchor `CherryClient::now` to the server clock, re-syncing it once the
+    /// estimate is older than this. None keeps the local clock
+    #[serde(default)]
+    pub clock_sync_interval: Option<Duration>,
+    /// Cache GET responses in memory, revalidating them with their `ETag` /
+    /// `Last-Modified`. None (the default) disables caching
+    #[serde(default)]
+    pub response_cache: Option<ResponseCacheConfig>,
+    /// Path between `base_url` and the endpoints of `CherryClient`, e.g. `/api/v2`
+    /// for a newer server or `/chat/api/v1` behind a prefixed gateway. Metrics and
+    /// errors name endpoints without it
+    #[serde(default = "default_api_prefix")]
+    pub api_prefix: String,
+}
+
+fn default_accept_compression() -> bool {
+    true
+}
+
+fn default_api_prefix() -> String {
+    "/api/v1".to_string()
 }
 
 impl Default for ClientConfig {
@@ -67,13 +135,36 @@ impl From<&String> for ClientConfig {
 }
 
 impl ClientConfig {
+    /// Apply the total, connect and read timeouts to the builder
+    pub fn apply_timeouts(&self, builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        let builder = builder.timeout(self.timeout);
+        let builder = match self.connect_timeout {
+            Some(timeout) => builder.connect_timeout(timeout),
+            None => builder,
+        };
+        match self.read_timeout {
+            Some(timeout) => builder.read_timeout(timeout),
+            None => builder,
+        }
+    }
+
     pub fn default_cherry() -> Self {
         Self {
             max_idle_per_host: 10,
             base_url: "http://localhost:8180".to_string(),
             timeout: Duration::from_secs(30),
+            connect_timeout: None,
+            read_timeout: None,
             pool_idle_timeout: Duration::from_secs(90),
             user_agent: "CherryClient/1.0".to_string(),
+            rate_limit: None,
+            sensitive_headers: Vec::new(),
+            tls: TlsConfig::default(),
+            accept_compression: true,
+            metrics_recorder: metrics::default_recorder(),
+            clock_sync_interval: None,
+            response_cache: None,
+            api_prefix: default_api_prefix(),
         }
     }
 
@@ -82,8 +173,18 @@ impl ClientConfig {
             max_idle_per_host: 10,
             base_url: "http://localhost:8080".to_string(),
             timeout: Duration::from_secs(30),
+            connect_timeout: None,
+            read_timeout: None,
             pool_idle_timeout: Duration::from_secs(90),
             user_agent: "StreamClient/1.0".to_string(),
+            rate_limit: None,
+            sensitive_headers: Vec::new(),
+            tls: TlsConfig::default(),
+            accept_compression: true,
+            metrics_recorder: metrics::default_recorder(),
+            clock_sync_interval: None,
+            response_cache: None,
+            api_prefix: default_api_prefix(),
         }
     }
 
@@ -92,8 +193,18 @@ impl ClientConfig {
             max_idle_per_host: 10,
             base_url: "http://localhost:8280".to_string(),
             timeout: Duration::from_secs(30),
+            connect_timeout: None,
+            read_timeout: None,
             pool_idle_timeout: Duration::from_secs(90),
             user_agent: "FileClient/1.0".to_string(),
+            rate_limit: None,
+            sensitive_headers: Vec::new(),
+            tls: TlsConfig::default(),
+            accept_compression: true,
+            metrics_recorder: metrics::default_recorder(),
+            clock_sync_interval: None,
+            response_cache: None,
+            api_prefix: default_api_prefix(),
         }
     }
 }
@@ -0,0 +1,156 @@
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::Result;
+use futures_util::{Stream, future::BoxFuture};
+
+/// One page of a listing and the cursor of the page after it
+#[derive(Debug, Clone)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// None on the last page
+    pub next_cursor: Option<String>,
+}
+
+impl<T> Page<T> {
+    /// The only page of a listing that is not paginated
+    pub fn last(items: Vec<T>) -> Self {
+        Self {
+            items,
+            next_cursor: None,
+        }
+    }
+}
+
+type FetchPage<T> = Box<dyn FnMut(Option<String>) -> BoxFuture<'static, Result<Page<T>>> + Send>;
+
+enum PageState<T> {
+    /// Fetch the page of this cursor next, None for the first page
+    Next(Option<String>),
+    Fetching(BoxFuture<'static, Result<Page<T>>>),
+    Done,
+}
+
+/// Stream of the items of a paginated listing, e.g. `CherryClient::paginate_conversations`.
+///
+/// Pages are fetched one at a time as the items of the previous one are consumed,
+/// by calling the fetch function with the cursor of the page (None for the first
+/// one) until a page has no `next_cursor`. A failed fetch is yielded as an error
+/// and ends the stream.
+pub struct Paginator<T> {
+    fetch: FetchPage<T>,
+    items: VecDeque<T>,
+    state: PageState<T>,
+}
+
+impl<T> Paginator<T> {
+    pub fn new(
+        fetch: impl FnMut(Option<String>) -> BoxFuture<'static, Result<Page<T>>> + Send + 'static,
+    ) -> Self {
+        Self {
+            fetch: Box::new(fetch),
+            items: VecDeque::new(),
+            state: PageState::Next(None),
+        }
+    }
+}
+
+// the items are never pinned, only moved out of the queue
+impl<T> Unpin for Paginator<T> {}
+
+impl<T> Stream for Paginator<T> {
+    type Item = Result<T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(item) = this.items.pop_front() {
+                return Poll::Ready(Some(Ok(item)));
+            }
+            match &mut this.state {
+                PageState::Done => return Poll::Ready(None),
+                PageState::Next(cursor) => {
+                    let page = (this.fetch)(cursor.take());
+                    this.state = PageState::Fetching(page);
+                }
+                PageState::Fetching(page) => match page.as_mut().poll(cx) {
+                    Poll::Pending => return Poll::Pending,
+                    Poll::Ready(Err(e)) => {
+                        this.state = PageState::Done;
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                    Poll::Ready(Ok(page)) => {
+                        this.items.extend(page.items);
+                        this.state = match page.next_cursor {
+                            Some(cursor) => PageState::Next(Some(cursor)),
+                            None => PageState::Done,
+                        };
+                    }
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use futures_util::{StreamExt, TryStreamExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_paginator() {
+        let cursors = Arc::new(Mutex::new(Vec::new()));
+        let seen = cursors.clone();
+        let paginator = Paginator::new(move |cursor: Option<String>| {
+            seen.lock().unwrap().push(cursor.clone());
+            Box::pin(async move {
+                Ok(match cursor.as_deref() {
+                    None => Page {
+                        items: vec![1, 2],
+                        next_cursor: Some("b".to_string()),
+                    },
+                    // empty pages are skipped
+                    Some("b") => Page {
+                        items: vec![],
+                        next_cursor: Some("c".to_string()),
+                    },
+                    _ => Page::last(vec![3]),
+                })
+            })
+        });
+
+        assert_eq!(
+            paginator.try_collect::<Vec<_>>().await.unwrap(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(
+            *cursors.lock().unwrap(),
+            vec![None, Some("b".to_string()), Some("c".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_paginator_error() {
+        let mut paginator = Paginator::new(|cursor: Option<String>| {
+            Box::pin(async move {
+                match cursor {
+                    None => Ok(Page {
+                        items: vec!["first"],
+                        next_cursor: Some("next".to_string()),
+                    }),
+                    Some(_) => Err(anyhow::anyhow!("page failed")),
+                }
+            })
+        });
+
+        assert_eq!(paginator.next().await.unwrap().unwrap(), "first");
+        assert!(paginator.next().await.unwrap().is_err());
+        assert!(paginator.next().await.is_none());
+    }
+}
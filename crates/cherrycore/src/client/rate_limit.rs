@@ -0,0 +1,242 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use reqwest::header::HeaderMap;
+use serde::{Deserialize, Serialize};
+
+const RATE_LIMIT_REMAINING: &str = "X-RateLimit-Remaining";
+const RATE_LIMIT_RESET: &str = "X-RateLimit-Reset";
+
+/// Client-side rate limit settings
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    /// Sustained number of requests per second
+    pub requests_per_second: f64,
+    /// Maximum number of requests that can be sent in a burst
+    pub burst: u32,
+}
+
+impl RateLimitConfig {
+    /// Check that the limit lets requests through: `requests_per_second` must be
+    /// positive and finite and `burst` at least 1
+    pub fn validate(&self) -> Result<()> {
+        if !self.requests_per_second.is_finite() || self.requests_per_second <= 0.0 {
+            return Err(anyhow::anyhow!(
+                "Invalid rate limit: requests_per_second must be positive, got {}",
+                self.requests_per_second
+            ));
+        }
+        if self.burst == 0 {
+            return Err(anyhow::anyhow!(
+                "Invalid rate limit: burst must be at least 1"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Token bucket that paces requests to stay under the server's rate limits
+#[derive(Debug)]
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+    // set when the server reports no remaining requests
+    paused_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    /// Fails if `config` is invalid, see [`RateLimitConfig::validate`]
+    pub fn new(config: RateLimitConfig) -> Result<Self> {
+        config.validate()?;
+        Ok(Self {
+            config,
+            state: Mutex::new(BucketState {
+                tokens: config.burst as f64,
+                last_refill: Instant::now(),
+                paused_until: None,
+            }),
+        })
+    }
+
+    /// Wait until a request can be sent and take a token for it
+    pub async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                let now = Instant::now();
+                self.refill(&mut state, now);
+
+                match state.paused_until {
+                    Some(until) if until > now => until - now,
+                    _ if state.tokens >= 1.0 => {
+                        state.tokens -= 1.0;
+                        return;
+                    }
+                    _ => Duration::from_secs_f64(
+                        (1.0 - state.tokens) / self.config.requests_per_second,
+                    ),
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Adapt the bucket to the `X-RateLimit-Remaining`/`X-RateLimit-Reset` response headers
+    pub fn update_from_headers(&self, headers: &HeaderMap) {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse::<u64>().ok())
+        };
+        let Some(remaining) = header(RATE_LIMIT_REMAINING) else {
+            return;
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        self.refill(&mut state, now);
+        state.tokens = state.tokens.min(remaining as f64);
+        if remaining == 0 {
+            let reset = header(RATE_LIMIT_RESET)
+                .map(reset_delay)
+                .unwrap_or(Duration::from_secs(1));
+            state.paused_until = Some(now + reset);
+        }
+    }
+
+    fn refill(&self, state: &mut BucketState, now: Instant) {
+        let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.config.requests_per_second)
+            .min(self.config.burst as f64);
+        state.last_refill = now;
+        if state.paused_until.is_some_and(|until| until <= now) {
+            state.paused_until = None;
+        }
+    }
+}
+
+// X-RateLimit-Reset is either a number of seconds or a unix timestamp
+fn reset_delay(reset: u64) -> Duration {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    if reset > now {
+        Duration::from_secs(reset - now)
+    } else if reset > 1_000_000_000 {
+        Duration::ZERO
+    } else {
+        Duration::from_secs(reset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    fn limiter(requests_per_second: f64, burst: u32) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            requests_per_second,
+            burst,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        for (requests_per_second, burst) in [
+            (0.0, 1),
+            (-1.0, 1),
+            (f64::NAN, 1),
+            (f64::INFINITY, 1),
+            (10.0, 0),
+        ] {
+            let config = RateLimitConfig {
+                requests_per_second,
+                burst,
+            };
+            assert!(RateLimiter::new(config).is_err(), "{:?}", config);
+        }
+    }
+
+    #[test]
+    fn test_refill() {
+        let limiter = limiter(10.0, 5);
+        let mut state = limiter.state.lock().unwrap();
+        let start = state.last_refill;
+        state.tokens = 0.0;
+
+        limiter.refill(&mut state, start + Duration::from_millis(250));
+        assert!((state.tokens - 2.5).abs() < 1e-9, "{}", state.tokens);
+        // never more than the burst
+        limiter.refill(&mut state, start + Duration::from_secs(60));
+        assert_eq!(state.tokens, 5.0);
+    }
+
+    #[tokio::test]
+    async fn test_acquire() {
+        let limiter = limiter(200.0, 2);
+        let start = Instant::now();
+        // the burst goes through right away, the next request waits for a token
+        limiter.acquire().await;
+        limiter.acquire().await;
+        limiter.acquire().await;
+        assert!(start.elapsed() >= Duration::from_millis(4));
+    }
+
+    #[test]
+    fn test_pause_from_headers() {
+        let limiter = limiter(100.0, 10);
+        let mut headers = HeaderMap::new();
+
+        // no remaining header, nothing changes
+        headers.insert(RATE_LIMIT_RESET, HeaderValue::from_static("2"));
+        limiter.update_from_headers(&headers);
+        assert!(limiter.state.lock().unwrap().paused_until.is_none());
+
+        headers.insert(RATE_LIMIT_REMAINING, HeaderValue::from_static("3"));
+        limiter.update_from_headers(&headers);
+        {
+            let state = limiter.state.lock().unwrap();
+            assert!(state.tokens <= 3.0);
+            assert!(state.paused_until.is_none());
+        }
+
+        headers.insert(RATE_LIMIT_REMAINING, HeaderValue::from_static("0"));
+        let now = Instant::now();
+        limiter.update_from_headers(&headers);
+        let paused_until = limiter.state.lock().unwrap().paused_until.unwrap();
+        assert!(paused_until >= now + Duration::from_secs(2));
+        assert!(paused_until <= Instant::now() + Duration::from_secs(2));
+
+        // the pause is lifted once it is over
+        let mut state = limiter.state.lock().unwrap();
+        limiter.refill(&mut state, paused_until);
+        assert!(state.paused_until.is_none());
+    }
+
+    #[test]
+    fn test_reset_delay() {
+        assert_eq!(reset_delay(30), Duration::from_secs(30));
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let delay = reset_delay(now + 10);
+        assert!(delay <= Duration::from_secs(10) && delay >= Duration::from_secs(9));
+        // a timestamp in the past
+        assert_eq!(reset_delay(now - 10), Duration::ZERO);
+    }
+}
@@ -8,6 +8,7 @@ use async_tungstenite::tungstenite::{Message, client::IntoClientRequest};
 use futures_util::StreamExt;
 use streamstore::StreamId;
 use tokio::select;
+use tokio_util::sync::CancellationToken;
 
 #[derive(Clone)]
 pub struct StreamClient {
@@ -97,11 +98,24 @@ impl StreamClient {
         Ok(response)
     }
 
+    /// Open a WebSocket reading streams, requests are sent on the returned sender
+    /// and responses arrive on the receiver. Dropping the receiver closes the connection.
     pub async fn open_stream(
         &self,
     ) -> Result<(
         tokio::sync::mpsc::Sender<StreamReadRequest>,
         tokio::sync::mpsc::Receiver<StreamReadResponse>,
+    )> {
+        self.open_stream_with_cancel(CancellationToken::new()).await
+    }
+
+    /// Same as `open_stream`, also closing the connection once `cancel` is cancelled
+    pub async fn open_stream_with_cancel(
+        &self,
+        cancel: CancellationToken,
+    ) -> Result<(
+        tokio::sync::mpsc::Sender<StreamReadRequest>,
+        tokio::sync::mpsc::Receiver<StreamReadResponse>,
     )> {
         // replace http with ws
         let url = format!(
@@ -113,7 +127,7 @@ impl StreamClient {
         let mut request = url.into_client_request().unwrap();
         if let Some(auth) = &self.auth {
             let auth_header = format!("Bearer {}", auth.jwt_token);
-            log::info!("Setting Authorization header: {}", auth_header);
+            log::info!("Setting Authorization header for user {}", auth.user_id);
             request
                 .headers_mut()
                 .insert("Authorization", auth_header.parse().unwrap());
@@ -130,7 +144,24 @@ impl StreamClient {
             log::info!("WebSocket message handler started");
             loop {
                 select! {
-                    Some(msg) = ws_stream.next() => {
+                    _ = cancel.cancelled() => {
+                        log::info!("stream read cancelled, closing WebSocket");
+                        let _ = ws_stream.close(None).await;
+                        break;
+                    }
+
+                    _ = tx.closed() => {
+                        log::info!("stream read receiver dropped, closing WebSocket");
+                        let _ = ws_stream.close(None).await;
+                        break;
+                    }
+
+                    msg = ws_stream.next() => {
+                        // dropping tx tells the receiver the connection is gone
+                        let Some(msg) = msg else {
+                            log::info!("WebSocket closed");
+                            break;
+                        };
                         match msg {
                             Ok(Message::Text(text)) => {
                                 let msg: StreamReadResponse = serde_json::from_str(&text).unwrap();
@@ -147,6 +178,7 @@ impl StreamClient {
                             }
                             Ok(Message::Close(close)) => {
                                 log::info!("close: {:?}", close);
+                                break;
                             }
                             Ok(Message::Binary(binary)) => {
                                 log::info!("binary: {:?}", binary);
@@ -176,6 +208,165 @@ impl StreamClient {
 
         Ok((req_tx, msg_rx))
     }
+
+    /// Follow a stream from `from_offset`, yielding its data as it is appended.
+    ///
+    /// A dropped connection is reopened after a delay growing with `backoff`,
+    /// resuming right after the last offset yielded, and a `TailEvent::Reconnecting`
+    /// is yielded first so the UI can show it. Delivery is at-least-once: a chunk
+    /// may be yielded again after a reconnect, consumers dedupe on its offset.
+    /// Only a rejected handshake (e.g. an expired token) ends the stream, with an
+    /// error; dropping it closes the connection.
+    pub fn tail_stream(
+        &self,
+        stream_id: StreamId,
+        from_offset: u64,
+        backoff: ReconnectBackoff,
+    ) -> impl futures_util::Stream<Item = Result<TailEvent>> + Send + 'static {
+        let tail = Tail {
+            client: self.clone(),
+            stream_id,
+            next_offset: from_offset,
+            backoff,
+            attempt: 0,
+            delay: None,
+            responses: None,
+            done: false,
+        };
+        futures_util::stream::unfold(tail, |mut tail| async move {
+            let event = tail.next_event().await?;
+            Some((event, tail))
+        })
+    }
+}
+
+/// Delay before `StreamClient::tail_stream` reconnects, doubling on every failed
+/// attempt from `initial` up to `max`
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectBackoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for ReconnectBackoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ReconnectBackoff {
+    // delay before the given attempt, the first one is attempt 1
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = 1u32 << attempt.saturating_sub(1).min(31);
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// Item of `StreamClient::tail_stream`
+#[derive(Debug)]
+pub enum TailEvent {
+    /// Stream data starting at `offset`
+    Data(StreamReadResponse),
+    /// The connection was lost, it is reopened from `from_offset` after `delay`
+    Reconnecting {
+        attempt: u32,
+        from_offset: u64,
+        delay: Duration,
+    },
+}
+
+struct Tail {
+    client: StreamClient,
+    stream_id: StreamId,
+    // offset following the last data yielded
+    next_offset: u64,
+    backoff: ReconnectBackoff,
+    // failed attempts since data was last received
+    attempt: u32,
+    delay: Option<Duration>,
+    responses: Option<tokio::sync::mpsc::Receiver<StreamReadResponse>>,
+    done: bool,
+}
+
+impl Tail {
+    async fn next_event(&mut self) -> Option<Result<TailEvent>> {
+        if self.done {
+            return None;
+        }
+        loop {
+            if let Some(responses) = &mut self.responses {
+                match responses.recv().await {
+                    Some(response) if response.stream_id != self.stream_id => continue,
+                    Some(response) => {
+                        self.attempt = 0;
+                        let end = response.offset + response.data.len() as u64;
+                        self.next_offset = self.next_offset.max(end);
+                        return Some(Ok(TailEvent::Data(response)));
+                    }
+                    None => {
+                        log::warn!(
+                            "stream {} tail connection lost at offset {}",
+                            self.stream_id,
+                            self.next_offset
+                        );
+                        self.responses = None;
+                        return Some(Ok(self.reconnecting()));
+                    }
+                }
+            }
+
+            if let Some(delay) = self.delay.take() {
+                tokio::time::sleep(delay).await;
+            }
+            match self.connect().await {
+                Ok(responses) => self.responses = Some(responses),
+                Err(e) if is_handshake_rejected(&e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+                Err(e) => {
+                    log::warn!("stream {} tail connect error: {:?}", self.stream_id, e);
+                    return Some(Ok(self.reconnecting()));
+                }
+            }
+        }
+    }
+
+    fn reconnecting(&mut self) -> TailEvent {
+        self.attempt += 1;
+        let delay = self.backoff.delay(self.attempt);
+        self.delay = Some(delay);
+        TailEvent::Reconnecting {
+            attempt: self.attempt,
+            from_offset: self.next_offset,
+            delay,
+        }
+    }
+
+    async fn connect(&self) -> Result<tokio::sync::mpsc::Receiver<StreamReadResponse>> {
+        // the connection stays open until its receiver is dropped
+        let (requests, responses) = self.client.open_stream().await?;
+        requests
+            .send(StreamReadRequest {
+                stream_id: self.stream_id,
+                offset: self.next_offset,
+            })
+            .await
+            .map_err(|_| anyhow::anyhow!("WebSocket closed before the read request was sent"))?;
+        Ok(responses)
+    }
+}
+
+// A handshake answered with a client error will not succeed by retrying
+fn is_handshake_rejected(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<async_tungstenite::tungstenite::Error>(),
+        Some(async_tungstenite::tungstenite::Error::Http(response))
+            if response.status().is_client_error()
+    )
 }
 
 pub struct StreamRecordDecoder {
@@ -266,3 +457,99 @@ impl StreamRecordDecoderMachine {
         decoder.decode_all()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // WebSocket server answering the read request of each connection with one
+    // chunk, the first connection is closed right after
+    async fn start_server(
+        chunks: Vec<&'static [u8]>,
+    ) -> (
+        String,
+        tokio::sync::mpsc::UnboundedReceiver<StreamReadRequest>,
+    ) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let (requests_tx, requests_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            for (i, chunk) in chunks.into_iter().enumerate() {
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut ws = async_tungstenite::tokio::accept_async(socket)
+                    .await
+                    .unwrap();
+                let Some(Ok(Message::Text(text))) = ws.next().await else {
+                    panic!("expected a read request");
+                };
+                let request: StreamReadRequest = serde_json::from_str(&text).unwrap();
+                let response = StreamReadResponse {
+                    stream_id: request.stream_id,
+                    offset: request.offset,
+                    data: chunk.to_vec(),
+                };
+                requests_tx.send(request).unwrap();
+                let text = serde_json::to_string(&response).unwrap();
+                ws.send(Message::Text(text.into())).await.unwrap();
+                if i == 0 {
+                    ws.close(None).await.unwrap();
+                } else {
+                    // keep the last connection open
+                    tokio::spawn(async move { while ws.next().await.is_some() {} });
+                }
+            }
+        });
+        (base_url, requests_rx)
+    }
+
+    #[tokio::test]
+    async fn test_tail_stream_reconnect() {
+        let (base_url, mut requests) = start_server(vec![b"hello", b" world"]).await;
+        let client = StreamClient::new(
+            &base_url,
+            AuthCredentials::new(uuid::Uuid::new_v4(), "jwt".into()),
+        );
+        let backoff = ReconnectBackoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(100),
+        };
+        let mut tail = Box::pin(client.tail_stream(1, 0, backoff));
+
+        let Some(Ok(TailEvent::Data(data))) = tail.next().await else {
+            panic!("expected data");
+        };
+        assert_eq!((data.offset, data.data.as_slice()), (0, &b"hello"[..]));
+        assert!(matches!(
+            tail.next().await,
+            Some(Ok(TailEvent::Reconnecting {
+                attempt: 1,
+                from_offset: 5,
+                ..
+            }))
+        ));
+        let Some(Ok(TailEvent::Data(data))) = tail.next().await else {
+            panic!("expected data");
+        };
+        assert_eq!((data.offset, data.data.as_slice()), (5, &b" world"[..]));
+
+        let offsets: Vec<_> = [requests.recv().await, requests.recv().await]
+            .into_iter()
+            .map(|request| request.unwrap().offset)
+            .collect();
+        assert_eq!(offsets, vec![0, 5]);
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let backoff = ReconnectBackoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+        };
+        let delays: Vec<_> = (1..=6).map(|attempt| backoff.delay(attempt)).collect();
+        assert_eq!(
+            delays,
+            [100, 200, 400, 800, 1000, 1000].map(Duration::from_millis)
+        );
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,71 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use reqwest::{Certificate, ClientBuilder, Identity};
+use serde::{Deserialize, Serialize};
+
+/// TLS settings of the HTTP client
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// PEM files of extra root certificates to trust, e.g. an internal CA
+    #[serde(default)]
+    pub root_certificates: Vec<PathBuf>,
+    /// Client certificate presented for mutual TLS
+    #[serde(default)]
+    pub client_identity: Option<ClientIdentity>,
+    /// Accept any server certificate, only meant for test environments
+    #[serde(default)]
+    pub danger_accept_invalid_certs: bool,
+}
+
+/// Client certificate and its private key, both PEM encoded
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientIdentity {
+    /// Certificate chain, leaf first
+    pub certificate: PathBuf,
+    /// PKCS#8 private key of the leaf certificate
+    pub private_key: PathBuf,
+}
+
+impl TlsConfig {
+    /// Load the configured certificates and apply them to the builder
+    pub fn apply(&self, mut builder: ClientBuilder) -> Result<ClientBuilder> {
+        for path in &self.root_certificates {
+            let pem = std::fs::read(path)
+                .with_context(|| format!("Failed to read root certificate {}", path.display()))?;
+            for cert in Certificate::from_pem_bundle(&pem)
+                .with_context(|| format!("Invalid root certificate {}", path.display()))?
+            {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+
+        if let Some(identity) = &self.client_identity {
+            builder = builder.identity(identity.load()?);
+        }
+
+        if self.danger_accept_invalid_certs {
+            log::warn!("TLS certificate verification is disabled");
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        Ok(builder)
+    }
+}
+
+impl ClientIdentity {
+    fn load(&self) -> Result<Identity> {
+        let certificate = std::fs::read(&self.certificate).with_context(|| {
+            format!(
+                "Failed to read client certificate {}",
+                self.certificate.display()
+            )
+        })?;
+        let private_key = std::fs::read(&self.private_key).with_context(|| {
+            format!(
+                "Failed to read client private key {}",
+                self.private_key.display()
+            )
+        })?;
+        Identity::from_pkcs8_pem(&certificate, &private_key).context("Invalid client identity")
+    }
+}
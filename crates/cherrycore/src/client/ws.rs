@@ -0,0 +1,308 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use async_tungstenite::tungstenite::{Message, client::IntoClientRequest};
+use futures_util::StreamExt;
+use reqwest::header::AUTHORIZATION;
+use tokio::{select, sync::mpsc};
+
+use crate::types::{Message as CherryMessage, WsClientFrame, WsServerEvent};
+
+use super::cherry::CherryClient;
+
+/// Interval between keepalive pings, the connection is considered dead when
+/// nothing was received for two intervals
+const WS_PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Bidirectional conversation channel over a single WebSocket connection.
+///
+/// Server events are read through the `Stream` implementation. When the
+/// connection is lost the stream yields an error and then ends; call
+/// [`CherryWs::reconnect`] to establish a new connection.
+pub struct CherryWs {
+    client: CherryClient,
+    frame_tx: mpsc::Sender<WsClientFrame>,
+    event_rx: mpsc::Receiver<Result<WsServerEvent>>,
+}
+
+impl CherryClient {
+    /// Open a WebSocket connection for conversations, authenticated with the client's JWT
+    pub async fn connect_ws(&self) -> Result<CherryWs> {
+        let (frame_tx, event_rx) = open_ws(self).await?;
+        Ok(CherryWs {
+            client: self.clone(),
+            frame_tx,
+            event_rx,
+        })
+    }
+}
+
+impl CherryWs {
+    /// Send a message to its conversation
+    pub async fn send_message(&self, message: CherryMessage) -> Result<()> {
+        self.frame_tx
+            .send(WsClientFrame::SendMessage { message })
+            .await
+            .map_err(|_| anyhow::anyhow!("WebSocket is disconnected"))
+    }
+
+    /// Whether the underlying connection is still alive
+    pub fn is_connected(&self) -> bool {
+        !self.frame_tx.is_closed()
+    }
+
+    /// Replace the connection with a new one, events not yet read from the old one are dropped
+    pub async fn reconnect(&mut self) -> Result<()> {
+        let (frame_tx, event_rx) = open_ws(&self.client).await?;
+        self.frame_tx = frame_tx;
+        self.event_rx = event_rx;
+        Ok(())
+    }
+}
+
+impl futures_util::Stream for CherryWs {
+    type Item = Result<WsServerEvent>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.event_rx.poll_recv(cx)
+    }
+}
+
+async fn open_ws(
+    client: &CherryClient,
+) -> Result<(
+    mpsc::Sender<WsClientFrame>,
+    mpsc::Receiver<Result<WsServerEvent>>,
+)> {
+    // replace http with ws
+    let url = client
+        .build_url("/conversations/ws")
+        .replacen("http", "ws", 1);
+
+    let mut request = url.as_str().into_client_request()?;
+    // fails with CherryError::NotAuthenticated before connecting if the client has
+    // no credentials
+    let headers = client.endpoint_headers("/conversations/ws").await?;
+    if let Some(auth_header) = headers.get(AUTHORIZATION) {
+        request
+            .headers_mut()
+            .insert(AUTHORIZATION, auth_header.clone());
+    }
+
+    let (mut ws_stream, _) = async_tungstenite::tokio::connect_async(request).await?;
+    log::info!("WebSocket connection established: {}", url);
+
+    let (frame_tx, mut frame_rx) = mpsc::channel::<WsClientFrame>(100);
+    let (event_tx, event_rx) = mpsc::channel(100);
+    tokio::spawn(async move {
+        let mut ping = tokio::time::interval(WS_PING_INTERVAL);
+        let mut last_seen = Instant::now();
+        let reason = loop {
+            select! {
+                msg = ws_stream.next() => {
+                    last_seen = Instant::now();
+                    match msg {
+                        Some(Ok(Message::Text(text))) => {
+                            let event = serde_json::from_str::<WsServerEvent>(&text)
+                                .map_err(|e| anyhow::anyhow!("Invalid WebSocket event: {}", e));
+                            if event_tx.send(event).await.is_err() {
+                                break None;
+                            }
+                        }
+                        // pongs are answered by tungstenite itself
+                        Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => {}
+                        Some(Ok(Message::Close(close))) => {
+                            break Some(anyhow::anyhow!("WebSocket closed by server: {:?}", close));
+                        }
+                        Some(Ok(_)) => {}
+                        Some(Err(e)) => break Some(anyhow::anyhow!("WebSocket error: {}", e)),
+                        None => break Some(anyhow::anyhow!("WebSocket closed")),
+                    }
+                }
+
+                frame = frame_rx.recv() => {
+                    // the CherryWs was dropped or reconnected
+                    let Some(frame) = frame else {
+                        let _ = ws_stream.close(None).await;
+                        break None;
+                    };
+                    let text = match serde_json::to_string(&frame) {
+                        Ok(text) => text,
+                        Err(e) => {
+                            log::error!("Failed to serialize WebSocket frame: {}", e);
+                            continue;
+                        }
+                    };
+                    if let Err(e) = ws_stream.send(Message::Text(text.into())).await {
+                        break Some(anyhow::anyhow!("WebSocket send error: {}", e));
+                    }
+                }
+
+                _ = ping.tick() => {
+                    if last_seen.elapsed() > WS_PING_INTERVAL * 2 {
+                        break Some(anyhow::anyhow!("WebSocket keepalive timed out"));
+                    }
+                    if let Err(e) = ws_stream.send(Message::Ping(Vec::new().into())).await {
+                        break Some(anyhow::anyhow!("WebSocket ping error: {}", e));
+                    }
+                }
+            }
+        };
+
+        if let Some(reason) = reason {
+            log::warn!("{}", reason);
+            let _ = event_tx.send(Err(reason)).await;
+        }
+    });
+
+    Ok((frame_tx, event_rx))
+}
+
+#[cfg(test)]
+mod tests {
+    use async_tungstenite::tungstenite::handshake::server::{Request, Response};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::client::{AuthCredentials, CherryError, ClientConfig};
+
+    fn new_message(id: i64) -> CherryMessage {
+        CherryMessage {
+            id,
+            user_id: Uuid::new_v4(),
+            content: serde_json::json!({ "text": "hi" }),
+            conversation_id: Uuid::new_v4(),
+            timestamp: chrono::Utc::now(),
+            reply_to: None,
+            type_: "text".to_string(),
+        }
+    }
+
+    // WebSocket server pushing a message event with the connection number on every
+    // connection, then answering the first frame and sending an undecodable event.
+    // The connection is dropped without a close frame right after. Returns the base
+    // url and the Authorization header of every connection
+    async fn start_server() -> (String, tokio::sync::mpsc::UnboundedReceiver<Option<String>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let (auth_tx, auth_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            for connection in 0.. {
+                let (socket, _) = listener.accept().await.unwrap();
+                let auth_tx = auth_tx.clone();
+                // the handshake callback type is tungstenite's
+                #[allow(clippy::result_large_err)]
+                let callback = move |request: &Request, response: Response| {
+                    let auth = request
+                        .headers()
+                        .get(AUTHORIZATION)
+                        .map(|value| value.to_str().unwrap().to_string());
+                    auth_tx.send(auth).unwrap();
+                    Ok(response)
+                };
+                let mut ws = async_tungstenite::tokio::accept_hdr_async(socket, callback)
+                    .await
+                    .unwrap();
+
+                let event = WsServerEvent::Message {
+                    stream_id: 7,
+                    offset: connection,
+                    message: new_message(1),
+                };
+                let text = serde_json::to_string(&event).unwrap();
+                ws.send(Message::Text(text.into())).await.unwrap();
+
+                // skip the client's keepalive pings
+                let text = loop {
+                    match ws.next().await {
+                        Some(Ok(Message::Text(text))) => break text,
+                        Some(Ok(_)) => {}
+                        other => panic!("expected a client frame, got {:?}", other),
+                    }
+                };
+                let WsClientFrame::SendMessage { message } = serde_json::from_str(&text).unwrap();
+                let event = WsServerEvent::Error {
+                    message: format!("received {}", message.id),
+                };
+                let text = serde_json::to_string(&event).unwrap();
+                ws.send(Message::Text(text.into())).await.unwrap();
+                ws.send(Message::Text("not an event".into())).await.unwrap();
+                drop(ws);
+            }
+        });
+        (base_url, auth_rx)
+    }
+
+    async fn next_event(ws: &mut CherryWs) -> Option<Result<WsServerEvent>> {
+        tokio::time::timeout(Duration::from_secs(5), ws.next())
+            .await
+            .expect("timed out waiting for a WebSocket event")
+    }
+
+    #[tokio::test]
+    async fn test_ws_events_and_reconnect() {
+        let (base_url, mut auth) = start_server().await;
+        let client = CherryClient::new_with_config(ClientConfig {
+            base_url,
+            ..ClientConfig::default_cherry()
+        })
+        .unwrap()
+        .with_auth(AuthCredentials::new(Uuid::new_v4(), "jwt".into()));
+
+        let mut ws = client.connect_ws().await.unwrap();
+        assert_eq!(auth.recv().await.unwrap().as_deref(), Some("Bearer jwt"));
+        let Some(Ok(WsServerEvent::Message {
+            stream_id, offset, ..
+        })) = next_event(&mut ws).await
+        else {
+            panic!("expected a message event");
+        };
+        assert_eq!((stream_id, offset), (7, 0));
+
+        ws.send_message(new_message(42)).await.unwrap();
+        let Some(Ok(WsServerEvent::Error { message })) = next_event(&mut ws).await else {
+            panic!("expected an error event");
+        };
+        assert_eq!(message, "received 42");
+
+        // an undecodable event is yielded as an error, the connection stays up
+        let err = next_event(&mut ws).await.unwrap().unwrap_err();
+        assert!(
+            err.to_string().contains("Invalid WebSocket event"),
+            "{}",
+            err
+        );
+
+        // the dropped connection is reported once, then the stream ends
+        assert!(next_event(&mut ws).await.unwrap().is_err());
+        assert!(next_event(&mut ws).await.is_none());
+        assert!(!ws.is_connected());
+        assert!(ws.send_message(new_message(43)).await.is_err());
+
+        ws.reconnect().await.unwrap();
+        assert!(ws.is_connected());
+        assert_eq!(auth.recv().await.unwrap().as_deref(), Some("Bearer jwt"));
+        let Some(Ok(WsServerEvent::Message { offset, .. })) = next_event(&mut ws).await else {
+            panic!("expected a message event after reconnecting");
+        };
+        assert_eq!(offset, 1);
+    }
+
+    #[tokio::test]
+    async fn test_ws_not_authenticated() {
+        let (base_url, mut auth) = start_server().await;
+        let client = CherryClient::new_with_base_url(base_url).unwrap();
+
+        let err = client.connect_ws().await.err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<CherryError>(),
+            Some(CherryError::NotAuthenticated { endpoint }) if endpoint == "/conversations/ws"
+        ));
+        // no connection was attempted
+        assert!(auth.try_recv().is_err());
+    }
+}
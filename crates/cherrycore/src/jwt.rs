@@ -102,7 +102,6 @@ where
             .extract::<TypedHeader<Authorization<Bearer>>>()
             .await
             .map_err(|_| AuthError::InvalidToken)?;
-        log::info!("from_request_parts: bearer={}", bearer.token());
         // Decode the user data
         let token_data =
             decode::<JwtClaims>(bearer.token(), &KEYS.decoding, &Validation::default())
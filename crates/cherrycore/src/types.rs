@@ -22,9 +22,57 @@ use crate::jwt::AuthError;
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LoginRequest {
     #[serde(rename = "type")]
-    pub type_: String, // email, github_oauth
+    pub type_: String, // email, phone, github_oauth
     pub email: Option<String>,
     pub password: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phone: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+/// Credentials of a login, each kind sets the matching `LoginRequest` type and fields
+#[derive(Debug, Clone)]
+pub enum LoginKind {
+    Email { email: String, password: String },
+    Phone { phone: String, password: String },
+    /// Token issued by an OAuth provider such as `github`
+    OAuth { provider: String, token: String },
+}
+
+impl From<LoginKind> for LoginRequest {
+    fn from(kind: LoginKind) -> Self {
+        let empty = LoginRequest {
+            type_: String::new(),
+            email: None,
+            password: None,
+            phone: None,
+            provider: None,
+            token: None,
+        };
+        match kind {
+            LoginKind::Email { email, password } => LoginRequest {
+                type_: "email".to_string(),
+                email: Some(email),
+                password: Some(password),
+                ..empty
+            },
+            LoginKind::Phone { phone, password } => LoginRequest {
+                type_: "phone".to_string(),
+                phone: Some(phone),
+                password: Some(password),
+                ..empty
+            },
+            LoginKind::OAuth { provider, token } => LoginRequest {
+                type_: format!("{}_oauth", provider),
+                provider: Some(provider),
+                token: Some(token),
+                ..empty
+            },
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -66,6 +114,12 @@ pub struct StreamReadRequest {
     pub offset: u64,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StreamExportRequest {
+    pub stream_id: StreamId,
+    pub offset: u64,
+}
+
 #[serde_as]
 #[derive(Serialize, Deserialize)]
 pub struct StreamReadResponse {
@@ -192,6 +246,40 @@ pub struct Conversation {
     pub updated_at: DateTime<chrono::Utc>,
 }
 
+impl Conversation {
+    /// The conversation's `meta` as a `ConversationMeta`, default when it has none
+    pub fn typed_meta(&self) -> Result<ConversationMeta, serde_json::Error> {
+        if self.meta.is_null() {
+            return Ok(ConversationMeta::default());
+        }
+        ConversationMeta::deserialize(&self.meta)
+    }
+}
+
+/// Typed view of the `meta` object of a conversation
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ConversationMeta {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    /// Application defined fields
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    pub custom: serde_json::Map<String, Value>,
+    /// Fields unknown to this version, written back unchanged so an update does
+    /// not drop what newer clients stored
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateConversationMetaRequest {
+    pub conversation_id: Uuid,
+    pub meta: ConversationMeta,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ListConversationsResponse {
     pub conversations: Vec<Conversation>,
@@ -215,13 +303,18 @@ pub struct UpdateStreamOffsetResponse {
     pub success: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateConversationRequest {
     pub conversation_type: String, // "direct" or "group"
     pub members: Vec<Uuid>,        // 会话成员的用户ID列表
     pub meta: Option<Value>,       // 可选的会话元数据，如群组名称等
+    /// Key the server dedupes retried creations on, also sent as the `Idempotency-Key` header
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<Uuid>,
 }
 
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreateConversationResponse {
     pub conversation_id: Uuid,
@@ -233,6 +326,57 @@ pub struct CreateConversationResponse {
     pub is_new: bool, // 是否是新创建的会话（用于1对1重复检测）
 }
 
+/// Several conversations created with one request, each deduped by the server
+/// on its own `idempotency_key`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateConversationsRequest {
+    pub conversations: Vec<CreateConversationRequest>,
+}
+
+/// One result per requested conversation, in the order of the request
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateConversationsResponse {
+    pub results: Vec<CreateConversationResult>,
+}
+
+/// Outcome of one conversation of a batch, a failed one does not fail the others
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CreateConversationResult {
+    Created(CreateConversationResponse),
+    Failed { status: u16, message: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConversationMembersRequest {
+    pub conversation_id: Uuid,
+    pub members: Vec<Uuid>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConversationRequest {
+    pub conversation_id: Uuid,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MarkReadRequest {
+    pub conversation_id: Uuid,
+    pub up_to_offset: u64, // 已读到的消息流偏移
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MemberReadState {
+    pub user_id: Uuid,
+    pub read_offset: u64,
+    pub updated_at: Option<DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReadState {
+    pub conversation_id: Uuid,
+    pub members: Vec<MemberReadState>,
+}
+
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct User {
     pub id: i32,
@@ -242,6 +386,47 @@ pub struct User {
     pub status: String, // online, offline, busy, away
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetUsersRequest {
+    pub user_ids: Vec<Uuid>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetUsersResponse {
+    // keyed by the requested id, ids of missing users are left out
+    pub users: HashMap<Uuid, User>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceStatus {
+    Online,
+    Away,
+    Busy,
+    Offline,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetPresenceRequest {
+    pub status: PresenceStatus,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetPresenceRequest {
+    pub user_ids: Vec<Uuid>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetPresenceResponse {
+    // users without a known presence are left out
+    pub presence: HashMap<Uuid, PresenceStatus>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TypingRequest {
+    pub conversation_id: Uuid,
+}
+
 #[derive(Debug, sqlx::FromRow, Serialize, Deserialize)]
 pub struct Contact {
     pub contact_id: Uuid,
@@ -258,6 +443,122 @@ pub struct Contact {
     pub mute_settings: Value,
 }
 
+/// Server-side filter for the contact list.
+///
+/// All fields are optional; unset fields are omitted from the query string.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ContactFilter {
+    /// Substring match against the contact's remark name / username
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Substring match against the contact's email
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    /// Only return contacts updated at or after this time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub updated_since: Option<DateTime<chrono::Utc>>,
+}
+
+/// Query of a message search across the conversations of the user.
+///
+/// Unset filters are omitted from the query string. `cursor` is the
+/// `next_cursor` of the previous page, see [`MessageSearchQuery::next_page`].
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct MessageSearchQuery {
+    /// Full-text match against the message content
+    pub text: String,
+    /// Only search this conversation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub conversation_id: Option<Uuid>,
+    /// Only return messages sent by this user
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sender_id: Option<Uuid>,
+    /// Only return messages sent at or after this time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<DateTime<chrono::Utc>>,
+    /// Only return messages sent before this time
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub until: Option<DateTime<chrono::Utc>>,
+    /// Most hits per page, the server default if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+}
+
+impl MessageSearchQuery {
+    pub fn new(text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            ..Self::default()
+        }
+    }
+
+    /// The same query for the page after `results`, None on the last page
+    pub fn next_page(&self, results: &SearchResults) -> Option<Self> {
+        let cursor = results.next_cursor.clone()?;
+        Some(Self {
+            cursor: Some(cursor),
+            ..self.clone()
+        })
+    }
+}
+
+/// A matching message, `stream_id` and `offset` locate it in the conversation's
+/// stream so it can be read (or scrolled to) directly
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchHit {
+    pub conversation_id: Uuid,
+    pub stream_id: StreamId,
+    pub offset: u64,
+    pub sender_id: Uuid,
+    pub timestamp: DateTime<chrono::Utc>,
+    /// Excerpt of the message around the match
+    #[serde(default)]
+    pub snippet: String,
+}
+
+/// How much of the user's messages the search index covered
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum SearchCoverage {
+    #[default]
+    Complete,
+    /// The index is still catching up, messages after `indexed_until` (or of
+    /// some conversations if unknown) may be missing from the hits
+    Partial {
+        #[serde(default)]
+        indexed_until: Option<DateTime<chrono::Utc>>,
+    },
+}
+
+/// A page of search hits, best match first.
+///
+/// No match is not an error: `hits` is then empty, see [`SearchResults::is_empty`].
+/// Hits of a [`SearchCoverage::Partial`] search are correct but may be incomplete.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct SearchResults {
+    #[serde(default)]
+    pub hits: Vec<SearchHit>,
+    /// Cursor of the next page, None on the last page
+    #[serde(default)]
+    pub next_cursor: Option<String>,
+    #[serde(default)]
+    pub coverage: SearchCoverage,
+}
+
+impl SearchResults {
+    /// Whether nothing matched the query
+    pub fn is_empty(&self) -> bool {
+        self.hits.is_empty()
+    }
+
+    /// Whether hits may be missing because the index is not up to date
+    pub fn is_partial(&self) -> bool {
+        matches!(self.coverage, SearchCoverage::Partial { .. })
+    }
+}
+
 pub enum StreamType {
     Message,
     File,
@@ -354,6 +655,31 @@ pub struct Message {
     pub type_: String
 }
 
+/// Frames sent by the client over the conversation WebSocket
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WsClientFrame {
+    SendMessage { message: Message },
+}
+
+/// Events pushed by the server over the conversation WebSocket
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WsServerEvent {
+    Message {
+        stream_id: StreamId,
+        offset: u64,
+        message: Message,
+    },
+    Event {
+        stream_id: StreamId,
+        event: StreamEvent,
+    },
+    Error {
+        message: String,
+    },
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ReactionContent {
     pub emoji: String,
@@ -596,3 +922,149 @@ pub struct ImageUploadCompleteResponse {
     pub thumbnail_url: String,
     pub message_id: Option<i64>, // 如果直接发送消息，返回消息ID
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // cherryserver serializes these same types, so the wire format is snake_case.
+    // The payloads below are responses captured from the server
+    const LOGIN_RESPONSE: &str = r#"{
+        "user_info": {
+            "user_id": "6f1c2a3e-8a5b-4c1d-9e2f-0a1b2c3d4e5f",
+            "username": "alice",
+            "email": "alice@example.com",
+            "avatar_url": null,
+            "status": "online",
+            "profile": {},
+            "app_config": {},
+            "stream_meta": {"notification_stream_id": 7}
+        },
+        "jwt_token": "eyJhbGciOiJIUzI1NiJ9.e30.c2ln"
+    }"#;
+
+    const LIST_CONVERSATIONS_RESPONSE: &str = r#"{
+        "conversations": [{
+            "conversation_id": "0b9f6e2a-1c3d-4e5f-8a7b-9c0d1e2f3a4b",
+            "conversation_type": "group",
+            "members": ["6f1c2a3e-8a5b-4c1d-9e2f-0a1b2c3d4e5f"],
+            "meta": {"name": "team"},
+            "stream_id": 42,
+            "created_at": "2025-06-01T12:00:00Z",
+            "updated_at": "2025-06-02T08:30:00Z"
+        }]
+    }"#;
+
+    const CREATE_CONVERSATION_RESPONSE: &str = r#"{
+        "conversation_id": "0b9f6e2a-1c3d-4e5f-8a7b-9c0d1e2f3a4b",
+        "conversation_type": "direct",
+        "members": [
+            "6f1c2a3e-8a5b-4c1d-9e2f-0a1b2c3d4e5f",
+            "1a2b3c4d-5e6f-4a8b-9c0d-e1f2a3b4c5d6"
+        ],
+        "meta": {},
+        "stream_id": 43,
+        "created_at": "2025-06-01T12:00:00Z",
+        "is_new": true
+    }"#;
+
+    #[test]
+    fn test_login_response_wire_format() {
+        let response = serde_json::from_str::<LoginResponse>(LOGIN_RESPONSE).unwrap();
+        assert_eq!(response.user_info.username, "alice");
+        assert_eq!(response.user_info.email, "alice@example.com");
+        assert_eq!(response.user_info.stream_meta["notification_stream_id"], 7);
+        assert_eq!(response.jwt_token, "eyJhbGciOiJIUzI1NiJ9.e30.c2ln");
+    }
+
+    #[test]
+    fn test_conversation_wire_format() {
+        let response =
+            serde_json::from_str::<ListConversationsResponse>(LIST_CONVERSATIONS_RESPONSE).unwrap();
+        let conversation = &response.conversations[0];
+        assert_eq!(conversation.conversation_type, "group");
+        assert_eq!(conversation.stream_id, 42);
+        assert_eq!(conversation.meta["name"], "team");
+
+        let response =
+            serde_json::from_str::<CreateConversationResponse>(CREATE_CONVERSATION_RESPONSE)
+                .unwrap();
+        assert_eq!(response.conversation_type, "direct");
+        assert_eq!(response.members.len(), 2);
+        assert_eq!(response.stream_id, 43);
+        assert!(response.is_new);
+
+        // round trip keeps the snake_case field names
+        let value = serde_json::to_value(&response).unwrap();
+        assert_eq!(
+            value,
+            serde_json::from_str::<Value>(CREATE_CONVERSATION_RESPONSE).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_conversation_meta() {
+        let response =
+            serde_json::from_str::<ListConversationsResponse>(LIST_CONVERSATIONS_RESPONSE).unwrap();
+        let mut meta = response.conversations[0].typed_meta().unwrap();
+        assert_eq!(meta.title, None);
+        assert_eq!(meta.extra["name"], "team");
+
+        meta.title = Some("Team".to_string());
+        meta.avatar_url = Some("https://example.com/team.png".to_string());
+        meta.custom.insert("color".to_string(), "red".into());
+        let value = serde_json::to_value(&meta).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "title": "Team",
+                "avatar_url": "https://example.com/team.png",
+                "custom": {"color": "red"},
+                "name": "team"
+            })
+        );
+        assert_eq!(
+            serde_json::from_value::<ConversationMeta>(value).unwrap(),
+            meta
+        );
+
+        let mut conversation = response.conversations[0].clone();
+        conversation.meta = Value::Null;
+        assert_eq!(
+            conversation.typed_meta().unwrap(),
+            ConversationMeta::default()
+        );
+    }
+
+    #[test]
+    fn test_request_wire_format() {
+        let request = CreateConversationRequest {
+            conversation_type: "group".to_string(),
+            members: vec![Uuid::nil()],
+            meta: None,
+            idempotency_key: Some(Uuid::nil()),
+        };
+        let value = serde_json::to_value(&request).unwrap();
+        assert_eq!(value["conversation_type"], "group");
+        assert_eq!(value["idempotency_key"], Uuid::nil().to_string());
+        assert!(value.get("conversationType").is_none());
+
+        let request = ConversationMembersRequest {
+            conversation_id: Uuid::nil(),
+            members: vec![],
+        };
+        let value = serde_json::to_value(&request).unwrap();
+        assert_eq!(value["conversation_id"], Uuid::nil().to_string());
+    }
+
+    #[test]
+    fn test_camel_case_payload_is_rejected() {
+        // required fields make a casing mismatch an error instead of silent defaults
+        let camel_case = LIST_CONVERSATIONS_RESPONSE
+            .replace("conversation_id", "conversationId")
+            .replace("conversation_type", "conversationType")
+            .replace("stream_id", "streamId");
+        let err = serde_json::from_str::<ListConversationsResponse>(&camel_case).unwrap_err();
+        assert!(err.to_string().contains("missing field"), "{}", err);
+    }
+}
@@ -74,7 +74,7 @@ fn main() {
         let data = format!("hello world {}\n", i);
         hash.update(data.as_bytes());
         store
-            .append(1, data.into(), make_callback(cond.clone()))
+            .append(1, data, make_callback(cond.clone()))
             .unwrap();
     }
 
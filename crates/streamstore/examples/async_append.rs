@@ -45,7 +45,7 @@ async fn main() {
         let data = format!("hello world {}\n", i);
         //log::info!("Appending entry: {}", data);
         hash.update(data.as_bytes());
-        let result = store.append_async(1, data.into()).await;
+        let result = store.append_async(1, data).await;
         match result {
             Ok(_offset) => {
                 // log::debug!("Append success: {} {}", i, offset);
@@ -0,0 +1,163 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{StreamId, errors, manifest};
+
+pub(crate) const CURSORS_FILE_NAME: &str = "CURSORS";
+const CURSORS_VERSION_V1: u32 = 1;
+
+// Read offsets of stream consumers (see Store::save_cursor), kept in a sidecar
+// file next to the manifest. Like the manifest it is rewritten atomically (temp
+// file + rename) on every save, it is small: one record per consumer and stream.
+//
+// Text layout, one record per line, the consumer id last so it may hold spaces:
+//   version <u32>
+//   cursor <stream id> <offset> <consumer id>
+#[derive(Debug, Default)]
+pub(crate) struct Cursors {
+    path: PathBuf,
+    cursors: HashMap<(String, StreamId), u64>,
+}
+
+impl Cursors {
+    // Load the cursors of `dir`, none if there is no cursor file yet
+    pub(crate) fn load(dir: &Path) -> Result<Cursors> {
+        let path = dir.join(CURSORS_FILE_NAME);
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Cursors {
+                    path,
+                    cursors: HashMap::new(),
+                });
+            }
+            Err(e) => {
+                return Err(errors::new_io_error(e)
+                    .context(format!("Failed to read cursors: {}", path.display())));
+            }
+        };
+        let cursors = Self::decode(&content)
+            .context(format!("Failed to decode cursors: {}", path.display()))?;
+        Ok(Cursors { path, cursors })
+    }
+
+    pub(crate) fn get(&self, consumer_id: &str, stream_id: StreamId) -> Option<u64> {
+        self.cursors
+            .get(&(consumer_id.to_string(), stream_id))
+            .copied()
+    }
+
+    // Record the offset and rewrite the file, the previous offset is kept if the
+    // file cannot be written
+    pub(crate) fn save(
+        &mut self,
+        consumer_id: &str,
+        stream_id: StreamId,
+        offset: u64,
+    ) -> Result<()> {
+        if consumer_id.is_empty() || consumer_id.contains(['\n', '\r']) {
+            return Err(errors::new_invalid_data()
+                .context(format!("Invalid consumer id: {:?}", consumer_id)));
+        }
+        let key = (consumer_id.to_string(), stream_id);
+        let previous = self.cursors.insert(key.clone(), offset);
+        if let Err(e) = self.store() {
+            match previous {
+                Some(previous) => self.cursors.insert(key, previous),
+                None => self.cursors.remove(&key),
+            };
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn store(&self) -> Result<()> {
+        manifest::store_file(&self.path, &self.encode())
+    }
+
+    fn encode(&self) -> String {
+        let mut cursors = self.cursors.iter().collect::<Vec<_>>();
+        cursors.sort();
+        let mut content = format!("version {}\n", CURSORS_VERSION_V1);
+        for ((consumer_id, stream_id), offset) in cursors {
+            content.push_str(&format!(
+                "cursor {} {} {}\n",
+                stream_id, offset, consumer_id
+            ));
+        }
+        content
+    }
+
+    fn decode(content: &str) -> Result<HashMap<(String, StreamId), u64>> {
+        let mut cursors = HashMap::new();
+        let mut version = None;
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            let (key, value) = line.split_once(' ').ok_or_else(errors::new_invalid_data)?;
+            match key {
+                "version" => version = Some(value.parse::<u32>()?),
+                "cursor" => {
+                    let mut fields = value.splitn(3, ' ');
+                    let (Some(stream_id), Some(offset), Some(consumer_id)) =
+                        (fields.next(), fields.next(), fields.next())
+                    else {
+                        return Err(errors::new_invalid_data());
+                    };
+                    cursors.insert(
+                        (consumer_id.to_string(), stream_id.parse::<StreamId>()?),
+                        offset.parse::<u64>()?,
+                    );
+                }
+                _ => log::warn!("Unknown cursor record: {}", line),
+            }
+        }
+        if version != Some(CURSORS_VERSION_V1) {
+            return Err(anyhow::anyhow!(
+                "Unsupported cursors version: {:?}",
+                version
+            ));
+        }
+        Ok(cursors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursors_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("streamstore-cursors-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut cursors = Cursors::load(&dir).unwrap();
+        assert_eq!(cursors.get("indexer", 1), None);
+        cursors.save("indexer", 1, 10).unwrap();
+        cursors.save("indexer", 1, 20).unwrap();
+        cursors.save("search export", 1, 5).unwrap();
+        cursors.save("indexer", 2, 7).unwrap();
+        assert!(cursors.save("", 1, 1).is_err());
+        assert!(cursors.save("bad\nid", 1, 1).is_err());
+        assert!(!dir.join(CURSORS_FILE_NAME).with_extension("tmp").exists());
+
+        let cursors = Cursors::load(&dir).unwrap();
+        assert_eq!(cursors.get("indexer", 1), Some(20));
+        assert_eq!(cursors.get("indexer", 2), Some(7));
+        assert_eq!(cursors.get("search export", 1), Some(5));
+        assert_eq!(cursors.get("search export", 2), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cursors_decode() {
+        assert!(Cursors::decode("cursor 1 2 indexer\n").is_err());
+        assert!(Cursors::decode("version 1\ncursor 1 indexer\n").is_err());
+        let cursors = Cursors::decode("version 1\ncursor 3 40 a b\n").unwrap();
+        assert_eq!(cursors[&("a b".to_string(), 3)], 40);
+    }
+}
@@ -5,8 +5,25 @@ use anyhow::{Error, anyhow};
 use crate::{StreamId, errors};
 use anyhow::{Context, Result};
 
+/// Entry format version 1, the layout of an encoded entry is (little endian):
+///
+/// ```text
+/// +---------+--------+---------------+-----------------+-----------------+
+/// | version | id     | stream_id     | data_len        | data            |
+/// | u8 (1)  | u64    | i64           | u32             | data_len bytes  |
+/// +---------+--------+---------------+-----------------+-----------------+
+/// ```
+///
+/// The leading version byte is part of every format version, decoders read it
+/// first and dispatch on it. New versions must keep that byte and get a new
+/// number instead of changing an existing layout, so logs written by older
+/// versions stay readable.
+pub const ENTRY_VERSION_V1: u8 = 1;
+
 pub type AppendEntryResultFn = Box<dyn Fn(Result<u64>) -> () + Send + Sync>;
-pub type DataType = Vec<u8>;
+// Entry data is reference counted, so data arriving as Bytes (e.g. a request
+// body) reaches the memtable without a copy
+pub type DataType = bytes::Bytes;
 
 pub struct Entry {
     // auto increment id
@@ -27,13 +44,14 @@ impl Encoder for Entry {
         let mut data = Vec::new();
         data.extend_from_slice(&self.version.to_le_bytes());
 
-        if self.version == 1 {
-            data.extend_from_slice(&self.id.to_le_bytes());
-            data.extend_from_slice(&self.stream_id.to_le_bytes());
-            data.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
-            data.extend_from_slice(&self.data);
-        } else {
-            panic!("Unsupported version");
+        match self.version {
+            ENTRY_VERSION_V1 => {
+                data.extend_from_slice(&self.id.to_le_bytes());
+                data.extend_from_slice(&self.stream_id.to_le_bytes());
+                data.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+                data.extend_from_slice(&self.data);
+            }
+            _ => panic!("Unsupported version"),
         }
         data
     }
@@ -60,28 +78,12 @@ impl<'a> Decoder<'a> for File {
                 Err(ref e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break, // End of file
                 Err(e) => return Err(anyhow!(e)),
             }
-            if entry.version == 1 {
-                let mut id_buf = [0u8; 8];
-                self.read_exact(&mut id_buf).context("")?;
-                entry.id = u64::from_le_bytes(id_buf);
-
-                let mut stream_id_buf = [0u8; 8];
-                self.read_exact(&mut stream_id_buf)
-                    .context("Failed to read stream_id")?;
-                entry.stream_id = i64::from_le_bytes(stream_id_buf);
-
-                let mut data_size_buf = [0u8; 4];
-                self.read_exact(&mut data_size_buf)
-                    .context("Failed to read data size")?;
-
-                let data_size = u32::from_le_bytes(data_size_buf);
-
-                entry.data.resize(data_size as usize, 0);
-                self.read_exact(&mut entry.data)
-                    .map_err(errors::new_io_error)?;
-            } else {
-                log::error!("Unsupported version: {}", entry.version);
-                return Err(anyhow!(errors::new_invalid_data()));
+            match entry.version {
+                ENTRY_VERSION_V1 => decode_v1(self, &mut entry)?,
+                version => {
+                    log::error!("Unsupported version: {}", version);
+                    return Err(errors::new_unsupported_entry_version(version));
+                }
             }
             // Call the closure with the decoded entry
             if !closure(entry)? {
@@ -92,16 +94,60 @@ impl<'a> Decoder<'a> for File {
     }
 }
 
+// Read the fields following the version byte of a version 1 entry
+fn decode_v1(reader: &mut impl Read, entry: &mut Entry) -> Result<()> {
+    let mut id_buf = [0u8; 8];
+    reader
+        .read_exact(&mut id_buf)
+        .context("Failed to read id")?;
+    entry.id = u64::from_le_bytes(id_buf);
+
+    let mut stream_id_buf = [0u8; 8];
+    reader
+        .read_exact(&mut stream_id_buf)
+        .context("Failed to read stream_id")?;
+    entry.stream_id = i64::from_le_bytes(stream_id_buf);
+
+    let mut data_size_buf = [0u8; 4];
+    reader
+        .read_exact(&mut data_size_buf)
+        .context("Failed to read data size")?;
+
+    let data_size = u32::from_le_bytes(data_size_buf);
+
+    let mut data = vec![0u8; data_size as usize];
+    reader.read_exact(&mut data).map_err(errors::new_io_error)?;
+    entry.data = data.into();
+    Ok(())
+}
+
 impl Entry {
     pub fn default() -> Self {
         Entry {
             version: 0,
             id: 0,
             stream_id: 0,
-            data: Vec::new(),
+            data: DataType::new(),
             callback: None,
         }
     }
+
+    // A version 1 entry, taking ownership of `data` without copying it when it
+    // is Bytes or a Vec
+    pub fn new(id: u64, stream_id: StreamId, data: impl Into<DataType>) -> Self {
+        Entry {
+            version: ENTRY_VERSION_V1,
+            id,
+            stream_id,
+            data: data.into(),
+            callback: None,
+        }
+    }
+
+    // Same as new, copying `data`
+    pub fn from_slice(id: u64, stream_id: StreamId, data: &[u8]) -> Self {
+        Self::new(id, stream_id, DataType::copy_from_slice(data))
+    }
 }
 
 impl std::fmt::Debug for Entry {
@@ -110,7 +156,7 @@ impl std::fmt::Debug for Entry {
             .field("version", &self.version)
             .field("id", &self.id)
             .field("stream_id", &self.stream_id)
-            .field("data", &self.data)
+            .field("data", &&self.data[..])
             .finish()
     }
 }
@@ -118,6 +164,7 @@ impl std::fmt::Debug for Entry {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::errors::Error as StoreError;
     use std::fs;
     use std::io::Write;
 
@@ -127,7 +174,7 @@ mod tests {
             version: 1,
             id: 1,
             stream_id: 1,
-            data: "hello world".as_bytes().to_vec(),
+            data: "hello world".as_bytes().to_vec().into(),
             callback: None,
         };
 
@@ -150,7 +197,7 @@ mod tests {
             assert_eq!(decoded_entry.version, 1);
             assert_eq!(decoded_entry.id, 1);
             assert_eq!(decoded_entry.stream_id, 1);
-            assert_eq!(decoded_entry.data, b"hello world");
+            assert_eq!(decoded_entry.data, &b"hello world"[..]);
             Ok(true)
         }))
         .expect("Failed to decode entry");
@@ -175,7 +222,7 @@ mod tests {
             version: 1,
             id: 42,
             stream_id: 123,
-            data: vec![1, 2, 3],
+            data: vec![1, 2, 3].into(),
             callback: None,
         };
         let debug_str = format!("{:?}", entry);
@@ -191,7 +238,7 @@ mod tests {
             version: 1,
             id: 100,
             stream_id: 200,
-            data: vec![0x41, 0x42, 0x43], // "ABC"
+            data: vec![0x41, 0x42, 0x43].into(), // "ABC"
             callback: None,
         };
 
@@ -233,7 +280,7 @@ mod tests {
             version: 2, // Unsupported version
             id: 1,
             stream_id: 1,
-            data: vec![1, 2, 3],
+            data: vec![1, 2, 3].into(),
             callback: None,
         };
         entry.encode();
@@ -246,21 +293,21 @@ mod tests {
                 version: 1,
                 id: 1,
                 stream_id: 10,
-                data: "first".as_bytes().to_vec(),
+                data: "first".as_bytes().to_vec().into(),
                 callback: None,
             },
             Entry {
                 version: 1,
                 id: 2,
                 stream_id: 20,
-                data: "second".as_bytes().to_vec(),
+                data: "second".as_bytes().to_vec().into(),
                 callback: None,
             },
             Entry {
                 version: 1,
                 id: 3,
                 stream_id: 30,
-                data: "third".as_bytes().to_vec(),
+                data: "third".as_bytes().to_vec().into(),
                 callback: None,
             },
         ];
@@ -325,14 +372,14 @@ mod tests {
                 version: 1,
                 id: 1,
                 stream_id: 10,
-                data: "first".as_bytes().to_vec(),
+                data: "first".as_bytes().to_vec().into(),
                 callback: None,
             },
             Entry {
                 version: 1,
                 id: 2,
                 stream_id: 20,
-                data: "second".as_bytes().to_vec(),
+                data: "second".as_bytes().to_vec().into(),
                 callback: None,
             },
         ];
@@ -373,7 +420,7 @@ mod tests {
             version: 1,
             id: 999,
             stream_id: 888,
-            data: large_data.clone(),
+            data: large_data.clone().into(),
             callback: None,
         };
 
@@ -399,4 +446,55 @@ mod tests {
         // Clean up
         let _ = fs::remove_file("test_large_entry.bin");
     }
+
+    #[test]
+    fn test_entry_decode_unsupported_version() {
+        let entry = Entry {
+            version: ENTRY_VERSION_V1,
+            id: 1,
+            stream_id: 1,
+            data: b"data".to_vec().into(),
+            callback: None,
+        };
+        let mut encoded = entry.encode();
+        // a second entry from a newer, unknown format version
+        encoded.extend_from_slice(&[7, 1, 2, 3]);
+
+        let mut file = File::create("test_unsupported_version.bin").expect("Failed to create file");
+        file.write_all(&encoded).expect("Failed to write to file");
+        drop(file);
+
+        let mut file = File::open("test_unsupported_version.bin").expect("Failed to open file");
+        let mut count = 0;
+        let err = file
+            .decode(Box::new(|_entry| {
+                count += 1;
+                Ok(true)
+            }))
+            .unwrap_err();
+        assert_eq!(count, 1);
+        assert!(matches!(
+            err.downcast_ref::<StoreError>(),
+            Some(StoreError::UnsupportedEntryVersion { version: 7 })
+        ));
+
+        // Clean up
+        let _ = fs::remove_file("test_unsupported_version.bin");
+    }
+
+    #[test]
+    fn test_entry_new() {
+        let data = bytes::Bytes::from(vec![1u8, 2, 3]);
+        let entry = Entry::new(5, 2, data.clone());
+        assert_eq!(entry.version, ENTRY_VERSION_V1);
+        assert_eq!((entry.id, entry.stream_id), (5, 2));
+        // the entry shares the buffer
+        assert_eq!(entry.data.as_ptr(), data.as_ptr());
+
+        let slice = [4u8, 5];
+        let entry = Entry::from_slice(6, 2, &slice);
+        assert_eq!(entry.data, &slice[..]);
+        assert_ne!(entry.data.as_ptr(), slice.as_ptr());
+        assert_eq!(Entry::new(7, 2, "text").data, &b"text"[..]);
+    }
 }
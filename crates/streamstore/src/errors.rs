@@ -2,7 +2,68 @@ use thiserror::Error;
 
 use crate::StreamId;
 
-#[derive(Debug, Error)]
+// The segment file operation that failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentOp {
+    OpenSegment,
+    MapSegment,
+    WriteSegment,
+    RenameSegment,
+    AdviseSegment,
+}
+
+impl std::fmt::Display for SegmentOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SegmentOp::OpenSegment => write!(f, "open segment"),
+            SegmentOp::MapSegment => write!(f, "map segment"),
+            SegmentOp::WriteSegment => write!(f, "write segment"),
+            SegmentOp::RenameSegment => write!(f, "rename segment"),
+            SegmentOp::AdviseSegment => write!(f, "advise segment"),
+        }
+    }
+}
+
+// The operation on a store metadata file (manifest, cursors) that failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileOp {
+    CreateFile,
+    WriteFile,
+    RenameFile,
+    SyncDirectory,
+}
+
+impl std::fmt::Display for FileOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileOp::CreateFile => write!(f, "create file"),
+            FileOp::WriteFile => write!(f, "write file"),
+            FileOp::RenameFile => write!(f, "rename file"),
+            FileOp::SyncDirectory => write!(f, "sync directory"),
+        }
+    }
+}
+
+// An io::Error reduced to its kind and message, which keeps Error Clone and
+// PartialEq: a flush failure can be handed to every waiter and errors compared
+// in tests
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{message}")]
+pub struct IoErrorInfo {
+    pub kind: std::io::ErrorKind,
+    pub message: String,
+}
+
+impl From<std::io::Error> for IoErrorInfo {
+    fn from(e: std::io::Error) -> Self {
+        IoErrorInfo {
+            kind: e.kind(),
+            message: e.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
 pub enum Error {
     #[error("Stream already exists")]
     AlreadyExists,
@@ -26,25 +87,150 @@ pub enum Error {
     WalChannelSendError,
 
     #[error("IO error")]
-    IoError(std::io::Error),
+    IoError(#[source] IoErrorInfo),
+
+    #[error("JSON error: {0}")]
+    JsonError(String),
+
+    #[error("failed to {op} {}: {source}", path.display())]
+    SegmentIoError {
+        op: SegmentOp,
+        path: std::path::PathBuf,
+        #[source]
+        source: IoErrorInfo,
+    },
+
+    #[error("failed to {op} {}: {source}", path.display())]
+    FileIoError {
+        op: FileOp,
+        path: std::path::PathBuf,
+        #[source]
+        source: IoErrorInfo,
+    },
 
     #[error("Stream {stream_id} offset {offset} is invalid")]
     StreamOffsetInvalid { stream_id: StreamId, offset: u64 },
 
+    #[error("Stream {stream_id} offset {offset} is below the retained base offset {base}")]
+    OffsetBelowRetention {
+        stream_id: StreamId,
+        offset: u64,
+        base: u64,
+    },
+
     #[error("Stream {stream_id} Not Found")]
     StreamNotFound { stream_id: StreamId },
+
+    #[error("unsupported entry format version {version}")]
+    UnsupportedEntryVersion { version: u8 },
+
+    #[error("entry {id} is invalid: {reason}")]
+    InvalidEntry { id: u64, reason: &'static str },
+
+    #[error("segment {} is corrupt: {reason}", path.display())]
+    CorruptSegment {
+        path: std::path::PathBuf,
+        reason: String,
+    },
+
+    #[error("segment {} metadata is corrupt: {reason}", path.display())]
+    CorruptSegmentMetadata {
+        path: std::path::PathBuf,
+        reason: String,
+    },
+
+    #[error("segment {} stream headers are not sorted: stream {stream_id} follows stream {previous}", path.display())]
+    UnsortedStreamHeaders {
+        path: std::path::PathBuf,
+        previous: StreamId,
+        stream_id: StreamId,
+    },
+
+    #[error("memtable is full: {size} bytes, appending {len} more exceeds the limit of {limit}")]
+    MemtableFull { size: u64, len: u64, limit: u64 },
+
+    #[error("segment {} is locked by another process", path.display())]
+    SegmentLocked { path: std::path::PathBuf },
+
+    #[error("failed to flush segment {}: {reason}", path.display())]
+    FlushFailed {
+        path: std::path::PathBuf,
+        reason: String,
+    },
+
+    #[error("stream {stream_id} cannot be compacted: expected offset {expected}, found {offset}")]
+    CompactionOverlap {
+        stream_id: StreamId,
+        expected: u64,
+        offset: u64,
+    },
+
+    #[error("memtable size {recorded} does not match the {counted} bytes of its stream tables")]
+    SizeMismatch { counted: u64, recorded: u64 },
+
+    #[error("memtable is empty, there is nothing to flush")]
+    EmptyMemtable,
+
+    #[error("segment {} stream {stream_id} already has a crc", path.display())]
+    CrcAlreadySet {
+        path: std::path::PathBuf,
+        stream_id: StreamId,
+    },
+
+    #[error("segment {} has no file digest", path.display())]
+    NoFileDigest { path: std::path::PathBuf },
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::IoError(e.into())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::JsonError(e.to_string())
+    }
 }
 
 pub fn new_stream_offset_invalid(stream_id: StreamId, offset: u64) -> anyhow::Error {
     anyhow::anyhow!(Error::StreamOffsetInvalid { stream_id, offset })
 }
 
+pub fn new_offset_below_retention(stream_id: StreamId, offset: u64, base: u64) -> anyhow::Error {
+    anyhow::anyhow!(Error::OffsetBelowRetention {
+        stream_id,
+        offset,
+        base,
+    })
+}
+
 pub fn new_stream_not_found(stream_id: StreamId) -> anyhow::Error {
     anyhow::anyhow!(Error::StreamNotFound { stream_id })
 }
 
 pub fn new_io_error(e: std::io::Error) -> anyhow::Error {
-    anyhow::anyhow!(Error::IoError(e))
+    anyhow::anyhow!(Error::from(e))
+}
+
+pub fn new_segment_io_error(
+    op: SegmentOp,
+    path: &std::path::Path,
+    e: std::io::Error,
+) -> anyhow::Error {
+    anyhow::anyhow!(Error::SegmentIoError {
+        op,
+        path: path.to_path_buf(),
+        source: e.into(),
+    })
+}
+
+pub fn new_file_io_error(op: FileOp, path: &std::path::Path, e: std::io::Error) -> anyhow::Error {
+    anyhow::anyhow!(Error::FileIoError {
+        op,
+        path: path.to_path_buf(),
+        source: e.into(),
+    })
 }
 
 pub fn new_invalid_path(path: std::path::PathBuf) -> anyhow::Error {
@@ -55,10 +241,94 @@ pub fn new_invalid_data() -> anyhow::Error {
     anyhow::anyhow!(Error::InvalidData)
 }
 
+pub fn new_already_exists() -> anyhow::Error {
+    anyhow::anyhow!(Error::AlreadyExists)
+}
+
+pub fn new_unsupported_entry_version(version: u8) -> anyhow::Error {
+    anyhow::anyhow!(Error::UnsupportedEntryVersion { version })
+}
+
+pub fn new_invalid_entry(id: u64, reason: &'static str) -> anyhow::Error {
+    anyhow::anyhow!(Error::InvalidEntry { id, reason })
+}
+
+pub fn new_corrupt_segment(path: &std::path::Path, reason: String) -> anyhow::Error {
+    anyhow::anyhow!(Error::CorruptSegment {
+        path: path.to_path_buf(),
+        reason,
+    })
+}
+
+pub fn new_corrupt_segment_metadata(path: &std::path::Path, reason: String) -> anyhow::Error {
+    anyhow::anyhow!(Error::CorruptSegmentMetadata {
+        path: path.to_path_buf(),
+        reason,
+    })
+}
+
+pub fn new_unsorted_stream_headers(
+    path: &std::path::Path,
+    previous: StreamId,
+    stream_id: StreamId,
+) -> anyhow::Error {
+    anyhow::anyhow!(Error::UnsortedStreamHeaders {
+        path: path.to_path_buf(),
+        previous,
+        stream_id,
+    })
+}
+
+pub fn new_compaction_overlap(stream_id: StreamId, expected: u64, offset: u64) -> anyhow::Error {
+    anyhow::anyhow!(Error::CompactionOverlap {
+        stream_id,
+        expected,
+        offset,
+    })
+}
+
+pub fn new_size_mismatch(counted: u64, recorded: u64) -> anyhow::Error {
+    anyhow::anyhow!(Error::SizeMismatch { counted, recorded })
+}
+
+pub fn new_empty_memtable() -> anyhow::Error {
+    anyhow::anyhow!(Error::EmptyMemtable)
+}
+
+pub fn new_crc_already_set(path: &std::path::Path, stream_id: StreamId) -> anyhow::Error {
+    anyhow::anyhow!(Error::CrcAlreadySet {
+        path: path.to_path_buf(),
+        stream_id,
+    })
+}
+
+pub fn new_no_file_digest(path: &std::path::Path) -> anyhow::Error {
+    anyhow::anyhow!(Error::NoFileDigest {
+        path: path.to_path_buf(),
+    })
+}
+
 pub fn new_store_is_read_only() -> anyhow::Error {
     anyhow::anyhow!(Error::StoreIsReadOnly)
 }
 
+pub fn new_memtable_full(size: u64, len: u64, limit: u64) -> anyhow::Error {
+    anyhow::anyhow!(Error::MemtableFull { size, len, limit })
+}
+
+pub fn new_segment_locked(path: &std::path::Path) -> anyhow::Error {
+    anyhow::anyhow!(Error::SegmentLocked {
+        path: path.to_path_buf(),
+    })
+}
+
+pub fn new_flush_failed(path: &std::path::Path, reason: String) -> anyhow::Error {
+    anyhow::anyhow!(Error::FlushFailed {
+        path: path.to_path_buf(),
+        reason,
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -89,14 +359,46 @@ mod tests {
         assert_eq!(error.to_string(), "channel is closed");
 
         let io_error = std::io::Error::new(std::io::ErrorKind::NotFound, "file not found");
-        let error = Error::IoError(io_error);
+        let error = Error::from(io_error);
         assert_eq!(error.to_string(), "IO error");
 
+        let io_error = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "permission denied");
+        let error = Error::SegmentIoError {
+            op: SegmentOp::MapSegment,
+            path: PathBuf::from("/data/000123.seg"),
+            source: io_error.into(),
+        };
+        assert_eq!(
+            error.to_string(),
+            "failed to map segment /data/000123.seg: permission denied"
+        );
+
+        let io_error = std::io::Error::new(std::io::ErrorKind::StorageFull, "no space left");
+        let error = Error::FileIoError {
+            op: FileOp::WriteFile,
+            path: PathBuf::from("/data/MANIFEST.tmp"),
+            source: io_error.into(),
+        };
+        assert_eq!(
+            error.to_string(),
+            "failed to write file /data/MANIFEST.tmp: no space left"
+        );
+
         let error = Error::StreamOffsetInvalid { stream_id: 123, offset: 456 };
         assert_eq!(error.to_string(), "Stream 123 offset 456 is invalid");
 
         let error = Error::StreamNotFound { stream_id: 789 };
         assert_eq!(error.to_string(), "Stream 789 Not Found");
+
+        let error = Error::CompactionOverlap {
+            stream_id: 1,
+            expected: 5,
+            offset: 3,
+        };
+        assert_eq!(
+            error.to_string(),
+            "stream 1 cannot be compacted: expected offset 5, found 3"
+        );
     }
 
     #[test]
@@ -122,6 +424,74 @@ mod tests {
         assert!(err.to_string().contains("store is read-only"));
     }
 
+    #[test]
+    fn test_error_source() {
+        use std::error::Error as _;
+
+        let io_error = std::io::Error::new(std::io::ErrorKind::NotFound, "file not found");
+        let error = Error::from(io_error);
+        assert_eq!(error.source().unwrap().to_string(), "file not found");
+
+        let io_error = std::io::Error::new(std::io::ErrorKind::NotFound, "file not found");
+        let err = new_segment_io_error(
+            SegmentOp::OpenSegment,
+            std::path::Path::new("/test/1-2.seg"),
+            io_error,
+        );
+        let error = err.downcast_ref::<Error>().unwrap();
+        assert!(matches!(
+            error,
+            Error::SegmentIoError {
+                op: SegmentOp::OpenSegment,
+                ..
+            }
+        ));
+        assert_eq!(error.source().unwrap().to_string(), "file not found");
+    }
+
+    #[test]
+    fn test_error_from() {
+        fn open_missing() -> Result<std::fs::File, Error> {
+            Ok(std::fs::File::open("/nonexistent/dir/1-2.seg")?)
+        }
+        fn parse_invalid() -> Result<serde_json::Value, Error> {
+            Ok(serde_json::from_str("{")?)
+        }
+
+        assert!(
+            matches!(open_missing(), Err(Error::IoError(e)) if e.kind == std::io::ErrorKind::NotFound)
+        );
+        assert!(matches!(parse_invalid(), Err(Error::JsonError(_))));
+    }
+
+    #[test]
+    fn test_error_clone_eq() {
+        let err = new_segment_io_error(
+            SegmentOp::WriteSegment,
+            std::path::Path::new("/test/1-2.seg"),
+            std::io::Error::new(std::io::ErrorKind::StorageFull, "no space left"),
+        );
+        let error = err.downcast_ref::<Error>().unwrap();
+        // e.g. one flush failure delivered to every waiter
+        let waiters = vec![error.clone(); 2];
+        assert!(waiters.iter().all(|waiter| waiter == error));
+        assert_eq!(
+            *error,
+            Error::SegmentIoError {
+                op: SegmentOp::WriteSegment,
+                path: PathBuf::from("/test/1-2.seg"),
+                source: IoErrorInfo {
+                    kind: std::io::ErrorKind::StorageFull,
+                    message: "no space left".to_string(),
+                },
+            }
+        );
+        assert_ne!(
+            Error::StreamNotFound { stream_id: 1 },
+            Error::StreamNotFound { stream_id: 2 }
+        );
+    }
+
     #[test]
     fn test_error_debug() {
         let error = Error::AlreadyExists;
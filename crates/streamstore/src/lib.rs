@@ -1,15 +1,22 @@
+mod cursors;
 pub mod entry;
-mod errors;
+pub mod errors;
 mod futures;
-mod mem_table;
+mod manifest;
+pub mod mem_table;
 mod metrics;
 pub mod options;
 mod reader;
 mod reload;
-mod segments;
+pub mod segments;
 pub mod store;
+mod stream_cache;
 mod table;
-mod wal;
+pub mod wal;
+pub use crate::reader::StreamHandle;
 pub use crate::store::Store;
 
+// Id of a stream, allocated by the server (the BIGSERIAL key of the streams
+// table). It is not derived from a conversation's Uuid: a conversation records
+// the id of its stream, so ids never collide and no hashing takes place
 pub type StreamId = i64;
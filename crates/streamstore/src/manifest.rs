@@ -0,0 +1,292 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{
+    errors::{self, FileOp},
+    store::SegmentArc,
+};
+
+pub(crate) const MANIFEST_FILE_NAME: &str = "MANIFEST";
+const MANIFEST_VERSION_V1: u32 = 1;
+
+// The manifest records the set of live segment files and the last entry id
+// they cover. It is rewritten atomically (temp file + rename) every time the
+// segment list changes, so a crash never leaves a half-written manifest and
+// segments that never made it into the manifest are never loaded.
+//
+// Text layout, one record per line:
+//   version <u32>
+//   last_entry <u64>
+//   next_segment <u64>
+//   segment <file name>
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct Manifest {
+    pub(crate) last_entry: u64,
+    // sequence number the next segment file is named after, 0 in older manifests
+    pub(crate) next_segment: u64,
+    pub(crate) segments: Vec<String>,
+}
+
+// Segment files are named after a monotonic sequence number, zero-padded so
+// that sorting the names sorts them by creation order
+pub(crate) fn segment_file_name(sequence: u64) -> String {
+    format!("{:020}.seg", sequence)
+}
+
+// The sequence number of a segment file name, None for names that are not
+// made by segment_file_name (e.g. the `<first>-<last>.seg` names of older stores)
+pub(crate) fn parse_segment_sequence(file_name: &str) -> Option<u64> {
+    let sequence = file_name.strip_suffix(".seg")?;
+    if sequence.len() != 20 || !sequence.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    sequence.parse().ok()
+}
+
+// The sequence number to name the next segment of `dir` after: past the one
+// recorded in the manifest and past every segment file in the directory, so
+// files written after the last manifest update are never overwritten
+pub(crate) fn next_segment_sequence(dir: &Path) -> Result<u64> {
+    let mut next = Manifest::load(dir)?.map_or(0, |manifest| manifest.next_segment);
+    for entry in std::fs::read_dir(dir).context("Failed to read segment directory")? {
+        let entry = entry.map_err(errors::new_io_error)?;
+        if let Some(sequence) = entry.file_name().to_str().and_then(parse_segment_sequence) {
+            next = next.max(sequence + 1);
+        }
+    }
+    Ok(next)
+}
+
+impl Manifest {
+    pub(crate) fn from_segments(segment_files: &VecDeque<SegmentArc>, next_segment: u64) -> Self {
+        Manifest {
+            next_segment,
+            last_entry: segment_files
+                .iter()
+                .map(|segment| segment.entry_index().1)
+                .max()
+                .unwrap_or(0),
+            segments: segment_files
+                .iter()
+                .filter_map(|segment| {
+                    segment
+                        .filename()
+                        .file_name()
+                        .map(|name| name.to_string_lossy().to_string())
+                })
+                .collect(),
+        }
+    }
+
+    pub(crate) fn path(dir: &Path) -> PathBuf {
+        dir.join(MANIFEST_FILE_NAME)
+    }
+
+    // Load the manifest from `dir`, returns None if there is no manifest yet
+    pub(crate) fn load(dir: &Path) -> Result<Option<Manifest>> {
+        let path = Self::path(dir);
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(errors::new_io_error(e)
+                    .context(format!("Failed to read manifest: {}", path.display())));
+            }
+        };
+        Self::decode(&content)
+            .map(Some)
+            .context(format!("Failed to decode manifest: {}", path.display()))
+    }
+
+    // Atomically replace the manifest in `dir`
+    pub(crate) fn store(&self, dir: &Path) -> Result<()> {
+        store_file(&Self::path(dir), &self.encode())
+    }
+
+    fn encode(&self) -> String {
+        let mut content = format!(
+            "version {}\nlast_entry {}\nnext_segment {}\n",
+            MANIFEST_VERSION_V1, self.last_entry, self.next_segment
+        );
+        for segment in self.segments.iter() {
+            content.push_str(&format!("segment {}\n", segment));
+        }
+        content
+    }
+
+    fn decode(content: &str) -> Result<Manifest> {
+        let mut manifest = Manifest::default();
+        let mut version = None;
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            let (key, value) = line.split_once(' ').ok_or_else(errors::new_invalid_data)?;
+            match key {
+                "version" => version = Some(value.parse::<u32>()?),
+                "last_entry" => manifest.last_entry = value.parse::<u64>()?,
+                "next_segment" => manifest.next_segment = value.parse::<u64>()?,
+                "segment" => manifest.segments.push(value.to_string()),
+                _ => log::warn!("Unknown manifest record: {}", line),
+            }
+        }
+        if version != Some(MANIFEST_VERSION_V1) {
+            return Err(anyhow::anyhow!(
+                "Unsupported manifest version: {:?}",
+                version
+            ));
+        }
+        Ok(manifest)
+    }
+}
+
+// Atomically replace `path` with `content`: the content is written and synced to
+// a temp file next to it, renamed over `path`, and the directory is synced so the
+// rename survives a crash too. Failures carry the file and operation, see
+// Error::FileIoError
+pub(crate) fn store_file(path: &Path, content: &str) -> Result<()> {
+    let temp_path = path.with_extension("tmp");
+
+    let mut file = File::create(&temp_path)
+        .map_err(|e| errors::new_file_io_error(FileOp::CreateFile, &temp_path, e))?;
+    file.write_all(content.as_bytes())
+        .and_then(|_| file.sync_all())
+        .map_err(|e| errors::new_file_io_error(FileOp::WriteFile, &temp_path, e))?;
+    drop(file);
+
+    std::fs::rename(&temp_path, path)
+        .map_err(|e| errors::new_file_io_error(FileOp::RenameFile, path, e))?;
+    sync_dir(
+        path.parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new(".")),
+    )
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)
+        .and_then(|dir_file| dir_file.sync_all())
+        .map_err(|e| errors::new_file_io_error(FileOp::SyncDirectory, dir, e))
+}
+
+// directories cannot be opened as files elsewhere, the rename is all we have
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+// Remove temp files left behind by a crash in the middle of writing a segment
+// or the manifest
+pub(crate) fn cleanup_temp_files(dir: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(dir).context("Failed to read segment directory")? {
+        let entry = entry.map_err(errors::new_io_error)?;
+        let filename = entry.path();
+        if filename.extension().is_some_and(|ext| ext == "tmp") {
+            log::warn!("Removing orphaned temp file: {}", filename.display());
+            std::fs::remove_file(&filename)
+                .context(format!("Failed to remove temp file: {}", filename.display()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("streamstore-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_manifest_store_and_load() {
+        let dir = test_dir("manifest");
+        assert_eq!(Manifest::load(&dir).unwrap(), None);
+
+        let manifest = Manifest {
+            last_entry: 100,
+            next_segment: 2,
+            segments: vec!["1-50.seg".to_string(), "51-100.seg".to_string()],
+        };
+        manifest.store(&dir).unwrap();
+        assert!(!Manifest::path(&dir).with_extension("tmp").exists());
+        assert_eq!(Manifest::load(&dir).unwrap(), Some(manifest));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_manifest_store_error() {
+        let dir = test_dir("manifest-store-error").join("missing");
+        let err = Manifest::default().store(&dir).unwrap_err();
+        match err.downcast_ref::<errors::Error>() {
+            Some(errors::Error::FileIoError { op, path, source }) => {
+                assert_eq!(*op, FileOp::CreateFile);
+                assert_eq!(*path, Manifest::path(&dir).with_extension("tmp"));
+                assert_eq!(source.kind, std::io::ErrorKind::NotFound);
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+
+        std::fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_manifest_decode_without_next_segment() {
+        let manifest = Manifest::decode("version 1\nlast_entry 7\nsegment 1-7.seg\n").unwrap();
+        assert_eq!(manifest.next_segment, 0);
+        assert_eq!(manifest.segments, vec!["1-7.seg".to_string()]);
+    }
+
+    #[test]
+    fn test_next_segment_sequence() {
+        let dir = test_dir("next-segment");
+        assert_eq!(next_segment_sequence(&dir).unwrap(), 0);
+
+        assert_eq!(segment_file_name(3), "00000000000000000003.seg");
+        assert_eq!(parse_segment_sequence(&segment_file_name(3)), Some(3));
+        assert_eq!(parse_segment_sequence("1-2.seg"), None);
+        assert_eq!(parse_segment_sequence("3.seg"), None);
+
+        Manifest {
+            next_segment: 5,
+            ..Default::default()
+        }
+        .store(&dir)
+        .unwrap();
+        std::fs::write(dir.join("1-2.seg"), b"segment").unwrap();
+        assert_eq!(next_segment_sequence(&dir).unwrap(), 5);
+
+        // a file written after the last manifest update
+        std::fs::write(dir.join(segment_file_name(8)), b"segment").unwrap();
+        assert_eq!(next_segment_sequence(&dir).unwrap(), 9);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_manifest_decode_invalid() {
+        assert!(Manifest::decode("last_entry 1\n").is_err());
+        assert!(Manifest::decode("version 1\nlast_entry abc\n").is_err());
+    }
+
+    #[test]
+    fn test_cleanup_temp_files() {
+        let dir = test_dir("cleanup");
+        std::fs::write(dir.join("1-2.tmp"), b"partial").unwrap();
+        std::fs::write(dir.join("1-2.seg"), b"segment").unwrap();
+
+        cleanup_temp_files(&dir).unwrap();
+        assert!(!dir.join("1-2.tmp").exists());
+        assert!(dir.join("1-2.seg").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
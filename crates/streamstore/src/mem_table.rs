@@ -1,31 +1,282 @@
-use crate::{StreamId, entry::Entry, table::StreamTable};
+use crate::{
+    StreamId,
+    entry::Entry,
+    errors, reload,
+    table::{STREAM_DATA_BUFFER_CAP, StreamTable},
+    wal::{Wal, WalInner, WalSyncPolicy},
+};
 use anyhow::Result;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap, hash_map},
     io,
-    sync::{Arc, Mutex, Weak, atomic::AtomicU64},
+    rc::Rc,
+    sync::{Arc, Mutex, MutexGuard, TryLockError, Weak, atomic::AtomicU64},
 };
 
+pub use crate::table::StreamDataPool;
+
 pub type MemTableArc = Arc<MemTable>;
 pub type MemTableWeak = Weak<MemTable>;
-pub(crate) type GetStreamOffset = Box<dyn Fn(StreamId) -> Result<u64, anyhow::Error> + Send + Sync>;
+pub type GetStreamOffset = Box<dyn Fn(StreamId) -> Result<u64, anyhow::Error> + Send + Sync>;
+pub type OnFlush = Box<dyn Fn(&HashMap<StreamId, u64>) + Send + Sync>;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamStat {
+    pub stream_id: StreamId,
+    pub size: u64,
+    pub base_offset: u64,
+    pub end_offset: u64,
+    pub chunk_count: usize,
+}
+
+// Recommended max age of a memtable for time-based rotation (see should_flush): five
+// minutes bounds how long a quiet store keeps data out of segments, while busy
+// stores still rotate on size well before that
+pub const RECOMMENDED_MAX_TABLE_AGE_MS: u64 = 5 * 60 * 1000;
+
+// Default number of stream table shards, appends to streams in different
+// shards do not contend on the same lock
+pub const DEFAULT_STREAM_TABLE_SHARDS: usize = 16;
+
+type StreamTableShard = HashMap<StreamId, StreamTable>;
+
+// Result of MemTable::try_append
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppendOutcome {
+    // the entry was appended, with the stream offset append returns
+    Appended(u64),
+    // the stream table is locked, nothing was appended
+    WouldBlock,
+}
+
 pub struct MemTable {
-    stream_tables: Mutex<HashMap<StreamId, StreamTable>>,
+    // stream tables sharded by stream id, locks are always taken in shard order
+    stream_tables: Box<[Mutex<StreamTableShard>]>,
     first_entry: AtomicU64,
     last_entry: AtomicU64,
     size: AtomicU64,
+    // unix time in milliseconds of the first append since the table was created or reset, 0 if empty
+    first_append_at: AtomicU64,
     get_stream_offset: Mutex<GetStreamOffset>,
+    // next-write offsets seeded by reset_with_offsets, take precedence over get_stream_offset
+    seed_offsets: Mutex<HashMap<StreamId, u64>>,
+    // called with the end offsets of every stream once the table is flushed to a segment
+    on_flush: Mutex<Option<OnFlush>>,
+    // pool the stream tables take their chunk buffers from, if any
+    stream_data_pool: Option<Arc<StreamDataPool>>,
+    // hard cap on the table size, appends past it fail with Error::MemtableFull
+    memory_limit: Option<u64>,
+    // WAL appends are written to before the stream tables, if any
+    wal: Option<Mutex<MemTableWal>>,
+    // capacity of the data chunks of new stream tables
+    stream_chunk_capacity: u64,
+}
+
+struct MemTableWal {
+    wal: Arc<WalInner>,
+    policy: WalSyncPolicy,
+    // appends written since the last sync
+    unsynced: usize,
+}
+
+// All shards of a MemTable locked at once, a consistent view of every stream table
+pub struct StreamTables<'a> {
+    shards: Vec<MutexGuard<'a, StreamTableShard>>,
+}
+
+impl StreamTables<'_> {
+    pub fn get(&self, stream_id: &StreamId) -> Option<&StreamTable> {
+        self.shards[shard_index(*stream_id, self.shards.len())].get(stream_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&StreamId, &StreamTable)> {
+        self.shards.iter().flat_map(|shard| shard.iter())
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| shard.is_empty())
+    }
+}
+
+impl std::ops::Index<&StreamId> for StreamTables<'_> {
+    type Output = StreamTable;
+
+    fn index(&self, stream_id: &StreamId) -> &StreamTable {
+        self.get(stream_id).expect("stream table not found")
+    }
+}
+
+fn shard_index(stream_id: StreamId, shards: usize) -> usize {
+    stream_id.rem_euclid(shards as StreamId) as usize
 }
 
 impl MemTable {
     pub fn new(get_stream_offset: GetStreamOffset) -> Self {
+        Self::with_shards(get_stream_offset, DEFAULT_STREAM_TABLE_SHARDS)
+    }
+
+    pub fn with_shards(get_stream_offset: GetStreamOffset, shards: usize) -> Self {
+        assert!(shards > 0, "MemTable needs at least one shard");
         MemTable {
-            stream_tables: Mutex::new(HashMap::new()),
+            stream_tables: (0..shards).map(|_| Mutex::new(HashMap::new())).collect(),
             first_entry: AtomicU64::new(0),
             last_entry: AtomicU64::new(0),
             size: AtomicU64::new(0),
+            first_append_at: AtomicU64::new(0),
             get_stream_offset: Mutex::new(get_stream_offset),
+            seed_offsets: Mutex::new(HashMap::new()),
+            on_flush: Mutex::new(None),
+            stream_data_pool: None,
+            memory_limit: None,
+            wal: None,
+            stream_chunk_capacity: STREAM_DATA_BUFFER_CAP,
+        }
+    }
+
+    // Take the stream data buffers of new streams from `pool`, they go back to it
+    // when the table is reset or dropped after its flush
+    pub fn with_stream_data_pool(mut self, pool: Arc<StreamDataPool>) -> Self {
+        self.stream_data_pool = Some(pool);
+        self
+    }
+
+    // Give the stream tables data chunks of `capacity` bytes instead of the default
+    // 128KB, so high-volume streams grow in fewer reallocations. A stream holding
+    // only a few bytes still takes one chunk, keep it modest with many streams.
+    // Chunks of another capacity than 128KB are not taken from the stream data pool
+    pub fn with_stream_chunk_capacity(mut self, capacity: u64) -> Self {
+        assert!(capacity > 0, "stream chunk capacity must not be zero");
+        self.stream_chunk_capacity = capacity;
+        self
+    }
+
+    // Fail appends that would take the table past `limit` bytes of data with
+    // Error::MemtableFull, the caller is expected to flush the table and retry.
+    // This is a hard limit, unlike the threshold given to should_flush which only
+    // says when a rotation is due. An entry larger than the limit is still taken
+    // by an empty table, so a flush always makes room
+    pub fn with_memory_limit(mut self, limit: u64) -> Self {
+        self.memory_limit = Some(limit);
+        self
+    }
+
+    // Replay the WAL files of `wal_dir` (created if missing) into a new table and
+    // write every later append to the WAL too, so the table can be rebuilt from
+    // the WAL after a crash without a Store around it. append_batch writes its
+    // batch to the WAL in one write, after validating it and before applying it.
+    // WAL files rotate once they reach `max_wal_size`; without a Store flushing
+    // the table none is removed. Appends return once the entry is as durable as
+    // `policy` asks, see WalSyncPolicy. The WAL takes entries in id order only: an
+    // append (or batch entry) whose id does not follow the previous one fails
+    // with Error::InvalidEntry. An
+    // append failing after its WAL write (e.g. on the stream offset) is still in
+    // the WAL and is replayed by the next recover
+    pub fn recover(wal_dir: &str, max_wal_size: u64, policy: WalSyncPolicy) -> Result<Self> {
+        let (mut tables, files, file) =
+            reload::reload_wals(wal_dir, 0, u64::MAX, &mut HashMap::new())?;
+        // an unbounded table size replays every entry into the one table
+        let table = tables
+            .pop_back()
+            .and_then(Rc::into_inner)
+            .expect("reload_wals returns its last table unshared");
+        let (next, _) = std::sync::mpsc::sync_channel(1);
+        let wal = Wal::new(
+            file,
+            wal_dir.to_string(),
+            max_wal_size,
+            table.get_last_entry(),
+            next,
+            files,
+            Box::new(|_| {}),
+        );
+        Ok(table.with_wal(&wal, policy))
+    }
+
+    // Write every entry to `wal` before appending it, see recover
+    pub(crate) fn with_wal(mut self, wal: &Wal, policy: WalSyncPolicy) -> Self {
+        self.wal = Some(Mutex::new(MemTableWal {
+            wal: wal.clone_inner(),
+            policy,
+            unsynced: 0,
+        }));
+        self
+    }
+
+    // Sync the appends written to the WAL since its last sync, a no-op without a
+    // WAL. With WalSyncPolicy::Batched or None this makes every append so far durable
+    pub fn sync_wal(&self) -> Result<()> {
+        let Some(wal) = &self.wal else {
+            return Ok(());
+        };
+        let mut wal = wal.lock().unwrap();
+        if wal.unsynced > 0 {
+            wal.wal.sync()?;
+            wal.unsynced = 0;
         }
+        Ok(())
+    }
+
+    fn new_stream_table(&self, stream_id: StreamId, offset: u64) -> StreamTable {
+        StreamTable::with_pool(stream_id, offset, self.stream_data_pool.clone())
+            .with_chunk_capacity(self.stream_chunk_capacity)
+    }
+
+    fn shard(&self, stream_id: StreamId) -> MutexGuard<'_, StreamTableShard> {
+        self.stream_tables[shard_index(stream_id, self.stream_tables.len())]
+            .lock()
+            .unwrap()
+    }
+
+    // Set the handler called with the end offset of every stream when the table
+    // has been written to a segment. It runs after the segment file is durable and
+    // before the table can be reset, so the offsets it sees are the flushed ones
+    pub fn set_on_flush(&self, handler: impl Fn(&HashMap<StreamId, u64>) + Send + Sync + 'static) {
+        *self.on_flush.lock().unwrap() = Some(Box::new(handler));
+    }
+
+    // End offset (next write offset) of every stream in the table
+    pub fn end_offsets(&self) -> HashMap<StreamId, u64> {
+        self.stream_stats()
+            .into_iter()
+            .map(|stat| (stat.stream_id, stat.end_offset))
+            .collect()
+    }
+
+    // Report a completed flush to the on_flush handler, if one is set
+    pub(crate) fn notify_flushed(&self) {
+        if let Some(handler) = self.on_flush.lock().unwrap().as_ref() {
+            handler(&self.end_offsets());
+        }
+    }
+
+    // Clear all stream data so the table can be reused after a flush,
+    // the get_stream_offset and on_flush handlers are kept
+    pub fn reset(&self) {
+        let guard = self.get_stream_tables();
+        self.reset_locked(guard);
+    }
+
+    fn reset_locked(&self, mut guard: StreamTables) {
+        guard.shards.iter_mut().for_each(|shard| shard.clear());
+        self.seed_offsets.lock().unwrap().clear();
+        self.size.store(0, std::sync::atomic::Ordering::SeqCst);
+        self.first_entry
+            .store(0, std::sync::atomic::Ordering::SeqCst);
+        self.last_entry
+            .store(0, std::sync::atomic::Ordering::SeqCst);
+        self.first_append_at
+            .store(0, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    // Same as reset, but new streams start at the given end offsets
+    // (e.g. taken from the just-flushed segment) instead of asking get_stream_offset
+    pub fn reset_with_offsets(&self, end_offsets: HashMap<StreamId, u64>) {
+        let guard = self.get_stream_tables();
+        self.reset_locked(guard);
+        *self.seed_offsets.lock().unwrap() = end_offsets;
     }
 
     pub fn get_first_entry(&self) -> u64 {
@@ -40,25 +291,115 @@ impl MemTable {
         self.size.load(std::sync::atomic::Ordering::SeqCst)
     }
 
+    // Check the incrementally maintained size against the sizes of the stream
+    // tables, failing with Error::SizeMismatch if they differ. Appends reserve their
+    // size before writing to a stream table, so only a table no longer appended to
+    // (e.g. one being flushed) is expected to pass
+    pub fn audit(&self) -> Result<()> {
+        let stream_tables = self.get_stream_tables();
+        let counted = stream_tables
+            .iter()
+            .map(|(_, stream_table)| stream_table.size())
+            .sum::<u64>();
+        let recorded = self.get_size();
+        if counted != recorded {
+            return Err(errors::new_size_mismatch(counted, recorded));
+        }
+        Ok(())
+    }
+
+    // Milliseconds the oldest data of the table has been waiting for a flush at unix
+    // time `now` (in milliseconds, see unix_millis), None if nothing was appended yet
+    pub fn oldest_entry_age(&self, now: u64) -> Option<u64> {
+        let first_append_at = self
+            .first_append_at
+            .load(std::sync::atomic::Ordering::SeqCst);
+        (first_append_at != 0).then(|| now.saturating_sub(first_append_at))
+    }
+
+    // Whether the table should be rotated, because it holds more than `max_size` bytes
+    // or, if `max_age` is set, its oldest data is at least `max_age` milliseconds old
+    // (RECOMMENDED_MAX_TABLE_AGE_MS is a good default)
+    pub fn should_flush(&self, max_size: u64, max_age: Option<u64>, now: u64) -> bool {
+        self.get_size() > max_size
+            || max_age
+                .is_some_and(|max_age| self.oldest_entry_age(now).is_some_and(|age| age >= max_age))
+    }
+
     pub fn get_stream_ids(&self) -> Vec<StreamId> {
-        let guard = self.stream_tables.lock().unwrap();
-        guard.keys().cloned().collect()
+        self.stream_tables
+            .iter()
+            .flat_map(|shard| shard.lock().unwrap().keys().cloned().collect::<Vec<_>>())
+            .collect()
+    }
+
+    pub fn stream_count(&self) -> usize {
+        self.stream_tables
+            .iter()
+            .map(|shard| shard.lock().unwrap().len())
+            .sum()
     }
 
-    pub fn get_stream_tables(&self) -> std::sync::MutexGuard<HashMap<StreamId, StreamTable>> {
-        self.stream_tables.lock().unwrap()
+    // Size and chunk statistics of every stream, each shard is locked only
+    // for as long as it takes to read its tables
+    pub fn stream_stats(&self) -> Vec<StreamStat> {
+        let mut stats = Vec::new();
+        for shard in self.stream_tables.iter() {
+            let guard = shard.lock().unwrap();
+            stats.extend(guard.values().map(|stream_table| StreamStat {
+                stream_id: stream_table.stream_id(),
+                size: stream_table.size(),
+                base_offset: stream_table.offset(),
+                end_offset: stream_table.offset() + stream_table.size(),
+                chunk_count: stream_table.chunk_count(),
+            }));
+        }
+        stats
+    }
+
+    // Lock every shard, in shard order
+    pub fn get_stream_tables(&self) -> StreamTables<'_> {
+        StreamTables {
+            shards: self
+                .stream_tables
+                .iter()
+                .map(|shard| shard.lock().unwrap())
+                .collect(),
+        }
+    }
+
+    // Whether the stream has data in this table, locks only the stream's shard
+    pub fn contains_stream(&self, stream_id: StreamId) -> bool {
+        self.shard(stream_id).contains_key(&stream_id)
+    }
+
+    // Stream offset of the first byte this table holds for the stream, data
+    // below it lives in segments
+    pub fn stream_base_offset(&self, stream_id: StreamId) -> Option<u64> {
+        self.shard(stream_id)
+            .get(&stream_id)
+            .map(|stream_table| stream_table.offset())
     }
 
     pub fn get_stream_range(&self, stream_id: StreamId) -> Option<(u64, u64)> {
-        let guard = self.stream_tables.lock().unwrap();
+        let guard = self.shard(stream_id);
         if let Some(stream_table) = guard.get(&stream_id) {
             return stream_table.get_stream_range();
         }
         None
     }
 
+    // Call `f` with the stream's data borrowed from the table, without copying it,
+    // while the stream's shard is locked. None if the stream is not in the table or
+    // its data spans several chunks, read_stream then copies it out instead
+    pub fn with_stream_data<R>(&self, stream_id: StreamId, f: impl FnOnce(&[u8]) -> R) -> Option<R> {
+        let guard = self.shard(stream_id);
+        let data = guard.get(&stream_id)?.contiguous_data()?;
+        Some(f(data))
+    }
+
     pub fn read_stream(&self, stream_id: StreamId, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
-        let guard = self.stream_tables.lock().unwrap();
+        let guard = self.shard(stream_id);
         if let Some(stream_table) = guard.get(&stream_id) {
             return stream_table.read_stream(offset, buf);
         }
@@ -68,8 +409,76 @@ impl MemTable {
         ))
     }
 
-    // return the stream offset
+    // Read several (stream_id, offset, size) ranges as of the same moment: the
+    // shards of the requested streams are locked once, in shard order like
+    // append_batch, for all the reads, so no append lands between two of them.
+    // A read stops at the end of the stream's data in this table. A stream not in
+    // the table is an Error::StreamNotFound at its request's position, the other
+    // requests are still read
+    pub fn read_multi(&self, requests: &[(StreamId, u64, u64)]) -> Vec<Result<Vec<u8>>> {
+        let shard_count = self.stream_tables.len();
+        let guards = requests
+            .iter()
+            .map(|(stream_id, _, _)| shard_index(*stream_id, shard_count))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|index| (index, self.stream_tables[index].lock().unwrap()))
+            .collect::<BTreeMap<_, _>>();
+        requests
+            .iter()
+            .map(|&(stream_id, offset, size)| {
+                let stream_table = guards[&shard_index(stream_id, shard_count)]
+                    .get(&stream_id)
+                    .ok_or_else(|| errors::new_stream_not_found(stream_id))?;
+                let end = stream_table
+                    .get_stream_range()
+                    .map_or(offset, |(_, end)| end);
+                let mut buf = vec![0u8; size.min(end.saturating_sub(offset)) as usize];
+                let read = stream_table
+                    .read_stream(offset, &mut buf)
+                    .map_err(errors::new_io_error)?;
+                buf.truncate(read);
+                Ok(buf)
+            })
+            .collect()
+    }
+
+    // Append the entry, returning the stream offset after its data (the next
+    // write offset of the stream)
     pub fn append(&self, entry: &Entry) -> Result<u64> {
+        self.append_range(entry).map(|(_, end)| end)
+    }
+
+    // Same as append, returning the stream range [begin, end) the entry's data
+    // was written to, begin being the offset to reference the entry by
+    pub fn append_range(&self, entry: &Entry) -> Result<(u64, u64)> {
+        self.check_append(entry);
+        let mut guard = self.shard(entry.stream_id);
+        let end = self.append_to_shard(&mut guard, entry)?;
+        Ok((end - entry.data.len() as u64, end))
+    }
+
+    // Same as append, without waiting for the stream table lock: returns
+    // AppendOutcome::WouldBlock when it is held (e.g. by a flush), in which case
+    // nothing was appended. Callers must handle that case, typically an async
+    // ingest path yields and retries instead of blocking its executor thread.
+    // Only the stream table lock is tried: the append still waits for the short
+    // locks of the seed offsets and the stream offset callback of a new stream
+    // (and whatever the callback itself waits for, e.g. the Store's offsets),
+    // and with a WAL (see recover) for the WAL write and sync
+    pub fn try_append(&self, entry: &Entry) -> Result<AppendOutcome> {
+        self.check_append(entry);
+        let shard = &self.stream_tables[shard_index(entry.stream_id, self.stream_tables.len())];
+        let mut guard = match shard.try_lock() {
+            Ok(guard) => guard,
+            Err(TryLockError::WouldBlock) => return Ok(AppendOutcome::WouldBlock),
+            Err(err @ TryLockError::Poisoned(_)) => panic!("{}", err),
+        };
+        self.append_to_shard(&mut guard, entry)
+            .map(AppendOutcome::Appended)
+    }
+
+    fn check_append(&self, entry: &Entry) {
         assert!(entry.stream_id != 0, "Stream ID cannot be zero");
         assert!(entry.data.len() > 0, "Entry data cannot be empty");
         assert!(entry.id > 0, "Entry ID must be greater than zero");
@@ -77,41 +486,237 @@ impl MemTable {
             entry.id > self.last_entry.load(std::sync::atomic::Ordering::SeqCst),
             "Entry ID must be greater than the last entry ID"
         );
+    }
 
+    fn append_to_shard(&self, guard: &mut StreamTableShard, entry: &Entry) -> Result<u64> {
         let data_len = entry.data.len() as u64;
+        // reserve the size before appending, so appends to different shards can't
+        // pass the memory limit together
+        self.reserve_size(data_len)?;
+        let result = self
+            .write_wal(std::slice::from_ref(entry))
+            .and_then(|()| self.append_reserved(guard, entry));
+        if result.is_err() {
+            self.release_size(data_len);
+        }
+        result
+    }
 
-        let mut guard = self.stream_tables.lock().unwrap();
-
+    fn append_reserved(&self, guard: &mut StreamTableShard, entry: &Entry) -> Result<u64> {
         let res = match guard.get_mut(&entry.stream_id) {
             Some(stream_table) => stream_table,
             None => {
-                let offset = match self.get_stream_offset.lock().unwrap()(entry.stream_id) {
-                    Ok(offset) => offset,
-                    Err(e) => return Err(e),
-                };
-                guard.insert(entry.stream_id, StreamTable::new(entry.stream_id, offset));
+                let offset = self.new_stream_offset(entry.stream_id)?;
+                self.seed_offsets.lock().unwrap().remove(&entry.stream_id);
+                guard.insert(
+                    entry.stream_id,
+                    self.new_stream_table(entry.stream_id, offset),
+                );
                 guard.get_mut(&entry.stream_id).unwrap()
             }
         };
 
         // Append the data to the stream table
-        let offset = res.append(&entry.data)?;
+        let offset = res.append_bytes(&entry.data)?;
+        res.record_entry(entry.id);
 
-        // Update the stream table
+        self.update_entry_range(entry.id, entry.id);
+        Ok(offset)
+    }
+
+    // Write the entries to the WAL in one write, if there is a WAL, and sync them
+    // as its policy asks. Every entry must follow the one before it, the first
+    // one the WAL's last entry
+    fn write_wal(&self, entries: &[Entry]) -> Result<()> {
+        let Some(wal) = &self.wal else {
+            return Ok(());
+        };
+        let mut wal = wal.lock().unwrap();
+        let mut last_entry = wal.wal.last_entry();
+        for entry in entries {
+            if entry.id != last_entry + 1 {
+                return Err(errors::new_invalid_entry(
+                    entry.id,
+                    "does not follow the last entry of the WAL",
+                ));
+            }
+            last_entry = entry.id;
+        }
+        wal.wal.batch_write(entries)?;
+        wal.unsynced += entries.len();
+        let sync = match wal.policy {
+            WalSyncPolicy::EveryWrite => true,
+            WalSyncPolicy::Batched(appends) => wal.unsynced >= appends,
+            WalSyncPolicy::None => false,
+        };
+        if sync {
+            wal.wal.sync()?;
+            wal.unsynced = 0;
+        }
+        Ok(())
+    }
+
+    // Add `len` bytes to the table size, unless that passes the memory limit
+    fn reserve_size(&self, len: u64) -> Result<()> {
+        let Some(limit) = self.memory_limit else {
+            self.size
+                .fetch_add(len, std::sync::atomic::Ordering::SeqCst);
+            return Ok(());
+        };
         self.size
-            .fetch_add(data_len, std::sync::atomic::Ordering::SeqCst);
+            .fetch_update(
+                std::sync::atomic::Ordering::SeqCst,
+                std::sync::atomic::Ordering::SeqCst,
+                |size| (size == 0 || size + len <= limit).then_some(size + len),
+            )
+            .map(|_| ())
+            .map_err(|size| errors::new_memtable_full(size, len, limit))
+    }
 
+    fn release_size(&self, len: u64) {
+        self.size
+            .fetch_sub(len, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    // Append all entries while holding the locks of every shard they touch.
+    // The entries are validated first, and if anything fails none of them is applied
+    pub fn append_batch(&self, entries: &[Entry]) -> Result<()> {
+        let shard_count = self.stream_tables.len();
+        // lock the touched shards in shard order
+        let mut guards = entries
+            .iter()
+            .map(|entry| shard_index(entry.stream_id, shard_count))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|index| (index, self.stream_tables[index].lock().unwrap()))
+            .collect::<BTreeMap<_, _>>();
+        macro_rules! shard_of {
+            ($stream_id:expr) => {
+                guards
+                    .get_mut(&shard_index($stream_id, shard_count))
+                    .unwrap()
+            };
+        }
+
+        let mut last_entry = self.get_last_entry();
+        let mut new_streams = HashMap::new();
+        for entry in entries {
+            if entry.stream_id == 0 {
+                return Err(errors::new_invalid_entry(
+                    entry.id,
+                    "stream id cannot be zero",
+                ));
+            }
+            if entry.data.is_empty() {
+                return Err(errors::new_invalid_entry(entry.id, "data cannot be empty"));
+            }
+            if entry.id <= last_entry {
+                return Err(errors::new_invalid_entry(
+                    entry.id,
+                    "id must be greater than the last entry id",
+                ));
+            }
+            last_entry = entry.id;
+
+            if !shard_of!(entry.stream_id).contains_key(&entry.stream_id)
+                && !new_streams.contains_key(&entry.stream_id)
+            {
+                let offset = self.new_stream_offset(entry.stream_id)?;
+                new_streams.insert(entry.stream_id, offset);
+            }
+        }
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let size = entries
+            .iter()
+            .map(|entry| entry.data.len() as u64)
+            .sum::<u64>();
+        self.reserve_size(size)?;
+        if let Err(e) = self.write_wal(entries) {
+            self.release_size(size);
+            return Err(e);
+        }
+
+        // None for the streams created by this batch
+        let mut checkpoints = HashMap::new();
+        for entry in entries {
+            let stream_table = match shard_of!(entry.stream_id).entry(entry.stream_id) {
+                hash_map::Entry::Occupied(e) => {
+                    checkpoints
+                        .entry(entry.stream_id)
+                        .or_insert_with(|| Some(e.get().checkpoint()));
+                    e.into_mut()
+                }
+                hash_map::Entry::Vacant(e) => {
+                    checkpoints.insert(entry.stream_id, None);
+                    e.insert(self.new_stream_table(entry.stream_id, new_streams[&entry.stream_id]))
+                }
+            };
+
+            if let Err(e) = stream_table.append_bytes(&entry.data) {
+                for (stream_id, checkpoint) in checkpoints {
+                    let shard = shard_of!(stream_id);
+                    match checkpoint {
+                        Some(checkpoint) => shard.get_mut(&stream_id).unwrap().rollback(checkpoint),
+                        None => {
+                            shard.remove(&stream_id);
+                        }
+                    }
+                }
+                self.release_size(size);
+                return Err(e);
+            }
+            stream_table.record_entry(entry.id);
+        }
+
+        let mut seed_offsets = self.seed_offsets.lock().unwrap();
+        for stream_id in new_streams.keys() {
+            seed_offsets.remove(stream_id);
+        }
+
+        self.update_entry_range(entries[0].id, last_entry);
+        Ok(())
+    }
+
+    // Appends to different shards can finish out of order, so only ever
+    // extend the entry range
+    fn update_entry_range(&self, first_entry: u64, last_entry: u64) {
+        // only the first append of the table needs the clock
+        if self.first_append_at.load(std::sync::atomic::Ordering::SeqCst) == 0 {
+            let _ = self.first_append_at.compare_exchange(
+                0,
+                unix_millis(),
+                std::sync::atomic::Ordering::SeqCst,
+                std::sync::atomic::Ordering::SeqCst,
+            );
+        }
         self.last_entry
-            .store(entry.id, std::sync::atomic::Ordering::SeqCst);
+            .fetch_max(last_entry, std::sync::atomic::Ordering::SeqCst);
+        let _ = self.first_entry.fetch_update(
+            std::sync::atomic::Ordering::SeqCst,
+            std::sync::atomic::Ordering::SeqCst,
+            |current| (current == 0 || first_entry < current).then_some(first_entry),
+        );
+    }
 
-        if self.first_entry.load(std::sync::atomic::Ordering::SeqCst) == 0 {
-            self.first_entry
-                .store(entry.id, std::sync::atomic::Ordering::SeqCst);
+    // The offset a stream not in the table yet starts at
+    fn new_stream_offset(&self, stream_id: StreamId) -> Result<u64> {
+        if let Some(offset) = self.seed_offsets.lock().unwrap().get(&stream_id) {
+            return Ok(*offset);
         }
-        Ok(offset)
+        self.get_stream_offset.lock().unwrap()(stream_id)
     }
 }
 
+// Current unix time in milliseconds, the clock of oldest_entry_age and should_flush
+pub fn unix_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
 /// Asserts that the type `T` is `Send` and `Sync`.
 /// This is useful for ensuring that types used in concurrent contexts are safe to share across threads.
 #[allow(unused)]
@@ -147,7 +752,7 @@ mod tests {
             version: 1,
             id: 1,
             stream_id: 100,
-            data: b"test data".to_vec(),
+            data: b"test data".to_vec().into(),
             callback: None,
         };
 
@@ -170,21 +775,21 @@ mod tests {
                 version: 1,
                 id: 1,
                 stream_id: 100,
-                data: b"first".to_vec(),
+                data: b"first".to_vec().into(),
                 callback: None,
             },
             Entry {
                 version: 1,
                 id: 2,
                 stream_id: 100,
-                data: b"second".to_vec(),
+                data: b"second".to_vec().into(),
                 callback: None,
             },
             Entry {
                 version: 1,
                 id: 3,
                 stream_id: 200,
-                data: b"third".to_vec(),
+                data: b"third".to_vec().into(),
                 callback: None,
             },
         ];
@@ -210,6 +815,26 @@ mod tests {
         assert_eq!(stream_ids, vec![100, 200]);
     }
 
+    #[test]
+    fn test_mem_table_append_range() {
+        let mem_table = MemTable::new(Box::new(|_stream_id| Ok(100)));
+        assert_eq!(
+            mem_table.append_range(&new_entry(1, 1, b"first")).unwrap(),
+            (100, 105)
+        );
+        assert_eq!(
+            mem_table.append_range(&new_entry(2, 1, b"second")).unwrap(),
+            (105, 111)
+        );
+        // spanning stream data chunks
+        let large = vec![7u8; 200 << 10];
+        assert_eq!(
+            mem_table.append_range(&new_entry(3, 1, &large)).unwrap(),
+            (111, 111 + large.len() as u64)
+        );
+        assert_eq!(mem_table.append(&new_entry(4, 2, b"x")).unwrap(), 101);
+    }
+
     #[test]
     fn test_mem_table_get_stream_range() {
         let get_stream_offset = Box::new(|_stream_id| Ok(0));
@@ -222,7 +847,7 @@ mod tests {
             version: 1,
             id: 1,
             stream_id: 100,
-            data: b"test data".to_vec(),
+            data: b"test data".to_vec().into(),
             callback: None,
         };
 
@@ -232,6 +857,64 @@ mod tests {
         assert_eq!(range, Some((0, 9)));
     }
 
+    #[test]
+    fn test_mem_table_contains_stream() {
+        let get_stream_offset = Box::new(|stream_id| match stream_id {
+            100 => Ok(1000),
+            _ => Ok(0),
+        });
+        let mem_table = MemTable::new(get_stream_offset);
+        assert!(!mem_table.contains_stream(100));
+        assert_eq!(mem_table.stream_base_offset(100), None);
+
+        mem_table.append(&new_entry(1, 100, b"data1")).unwrap();
+        mem_table.append(&new_entry(2, 100, b"data2")).unwrap();
+        assert!(mem_table.contains_stream(100));
+        assert_eq!(mem_table.stream_base_offset(100), Some(1000));
+        assert!(!mem_table.contains_stream(200));
+
+        // flushed out: the data now lives in a segment
+        mem_table.reset();
+        assert!(!mem_table.contains_stream(100));
+        assert_eq!(mem_table.stream_base_offset(100), None);
+
+        mem_table.reset_with_offsets(HashMap::from([(100, 1010)]));
+        mem_table.append(&new_entry(3, 100, b"data3")).unwrap();
+        assert_eq!(mem_table.stream_base_offset(100), Some(1010));
+    }
+
+    #[test]
+    fn test_mem_table_with_stream_data() {
+        let get_stream_offset = Box::new(|_stream_id| Ok(0));
+        let mem_table = MemTable::new(get_stream_offset);
+        assert_eq!(mem_table.with_stream_data(100, |data| data.len()), None);
+
+        mem_table.append(&new_entry(1, 100, b"hello")).unwrap();
+        mem_table.append(&new_entry(2, 100, b" world")).unwrap();
+        assert_eq!(
+            mem_table.with_stream_data(100, |data| data.to_vec()),
+            Some(b"hello world".to_vec())
+        );
+
+        // a large entry is kept as one shared chunk
+        let large = vec![7u8; 200 * 1024];
+        mem_table.append(&new_entry(3, 300, &large)).unwrap();
+        assert_eq!(
+            mem_table.with_stream_data(300, |data| data.len()),
+            Some(large.len())
+        );
+
+        // small appends past the first 128KB chunk are fragmented, read them with
+        // read_stream
+        for (id, chunk) in (4..).zip(large.chunks(50 * 1024)) {
+            mem_table.append(&new_entry(id, 200, chunk)).unwrap();
+        }
+        assert_eq!(mem_table.with_stream_data(200, |data| data.len()), None);
+        let mut buf = vec![0u8; large.len()];
+        assert_eq!(mem_table.read_stream(200, 0, &mut buf).unwrap(), large.len());
+        assert_eq!(buf, large);
+    }
+
     #[test]
     fn test_mem_table_read_stream() {
         let get_stream_offset = Box::new(|_stream_id| Ok(0));
@@ -241,7 +924,7 @@ mod tests {
             version: 1,
             id: 1,
             stream_id: 100,
-            data: b"hello world".to_vec(),
+            data: b"hello world".to_vec().into(),
             callback: None,
         };
 
@@ -272,6 +955,35 @@ mod tests {
         assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::NotFound);
     }
 
+    #[test]
+    fn test_mem_table_read_multi() {
+        let mem_table = MemTable::with_shards(Box::new(|_stream_id| Ok(0)), 4);
+        for (id, stream_id, data) in [(1, 1, "hello"), (2, 2, "world"), (3, 5, "shared shard")] {
+            mem_table
+                .append(&Entry {
+                    version: 1,
+                    id,
+                    stream_id,
+                    data: data.as_bytes().to_vec().into(),
+                    callback: None,
+                })
+                .unwrap();
+        }
+
+        let results = mem_table.read_multi(&[(1, 0, 5), (9, 0, 5), (5, 7, 100), (2, 1, 3), (1, 10, 5)]);
+        assert_eq!(results.len(), 5);
+        assert_eq!(results[0].as_ref().unwrap(), b"hello");
+        assert!(matches!(
+            results[1].as_ref().unwrap_err().downcast_ref::<errors::Error>(),
+            Some(errors::Error::StreamNotFound { stream_id: 9 })
+        ));
+        // reads stop at the end of the stream
+        assert_eq!(results[2].as_ref().unwrap(), b"shard");
+        assert_eq!(results[3].as_ref().unwrap(), b"orl");
+        assert!(results[4].as_ref().unwrap().is_empty());
+        assert!(mem_table.read_multi(&[]).is_empty());
+    }
+
     #[test]
     fn test_mem_table_with_custom_stream_offset() {
         let get_stream_offset = Box::new(|stream_id| match stream_id {
@@ -285,7 +997,7 @@ mod tests {
             version: 1,
             id: 1,
             stream_id: 100,
-            data: b"data1".to_vec(),
+            data: b"data1".to_vec().into(),
             callback: None,
         };
 
@@ -293,7 +1005,7 @@ mod tests {
             version: 1,
             id: 2,
             stream_id: 200,
-            data: b"data2".to_vec(),
+            data: b"data2".to_vec().into(),
             callback: None,
         };
 
@@ -317,7 +1029,7 @@ mod tests {
             version: 1,
             id: 1,
             stream_id: 0, // Invalid stream ID
-            data: b"test".to_vec(),
+            data: b"test".to_vec().into(),
             callback: None,
         };
 
@@ -334,7 +1046,7 @@ mod tests {
             version: 1,
             id: 1,
             stream_id: 100,
-            data: Vec::new(), // Empty data
+            data: bytes::Bytes::new(), // Empty data
             callback: None,
         };
 
@@ -351,7 +1063,7 @@ mod tests {
             version: 1,
             id: 0, // Invalid entry ID
             stream_id: 100,
-            data: b"test".to_vec(),
+            data: b"test".to_vec().into(),
             callback: None,
         };
 
@@ -368,7 +1080,7 @@ mod tests {
             version: 1,
             id: 2,
             stream_id: 100,
-            data: b"first".to_vec(),
+            data: b"first".to_vec().into(),
             callback: None,
         };
 
@@ -376,7 +1088,7 @@ mod tests {
             version: 1,
             id: 1, // Lower than previous entry ID
             stream_id: 100,
-            data: b"second".to_vec(),
+            data: b"second".to_vec().into(),
             callback: None,
         };
 
@@ -399,7 +1111,7 @@ mod tests {
             version: 1,
             id: 1,
             stream_id: 999,
-            data: b"test".to_vec(),
+            data: b"test".to_vec().into(),
             callback: None,
         };
 
@@ -413,6 +1125,350 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_mem_table_reset() {
+        let get_stream_offset = Box::new(|stream_id| match stream_id {
+            100 => Ok(1000),
+            _ => Ok(0),
+        });
+        let mem_table = MemTable::new(get_stream_offset);
+
+        let entry = Entry {
+            version: 1,
+            id: 10,
+            stream_id: 100,
+            data: b"data1".to_vec().into(),
+            callback: None,
+        };
+        assert_eq!(mem_table.append(&entry).unwrap(), 1005);
+
+        mem_table.reset();
+        assert_eq!(mem_table.get_first_entry(), 0);
+        assert_eq!(mem_table.get_last_entry(), 0);
+        assert_eq!(mem_table.get_size(), 0);
+        assert!(mem_table.get_stream_ids().is_empty());
+        assert_eq!(mem_table.get_stream_range(100), None);
+
+        // the handler is preserved, and entry ids start over
+        let entry = Entry {
+            version: 1,
+            id: 1,
+            stream_id: 100,
+            data: b"data2".to_vec().into(),
+            callback: None,
+        };
+        assert_eq!(mem_table.append(&entry).unwrap(), 1005);
+        assert_eq!(mem_table.get_first_entry(), 1);
+        assert_eq!(mem_table.get_size(), 5);
+    }
+
+    #[test]
+    fn test_mem_table_reset_with_offsets() {
+        let get_stream_offset = Box::new(|_stream_id| Ok(0));
+        let mem_table = MemTable::new(get_stream_offset);
+
+        let entry = Entry {
+            version: 1,
+            id: 1,
+            stream_id: 100,
+            data: b"hello".to_vec().into(),
+            callback: None,
+        };
+        assert_eq!(mem_table.append(&entry).unwrap(), 5);
+
+        mem_table.reset_with_offsets(HashMap::from([(100, 5)]));
+
+        let entry = Entry {
+            version: 1,
+            id: 2,
+            stream_id: 100,
+            data: b"world".to_vec().into(),
+            callback: None,
+        };
+        assert_eq!(mem_table.append(&entry).unwrap(), 10);
+        assert_eq!(mem_table.get_stream_range(100), Some((5, 10)));
+
+        // streams without a seeded offset fall back to the handler
+        let entry = Entry {
+            version: 1,
+            id: 3,
+            stream_id: 200,
+            data: b"other".to_vec().into(),
+            callback: None,
+        };
+        assert_eq!(mem_table.append(&entry).unwrap(), 5);
+    }
+
+    fn new_entry(id: u64, stream_id: StreamId, data: &[u8]) -> Entry {
+        Entry {
+            version: 1,
+            id,
+            stream_id,
+            data: data.to_vec().into(),
+            callback: None,
+        }
+    }
+
+    #[test]
+    fn test_mem_table_stream_stats() {
+        let mem_table = MemTable::new(Box::new(|stream_id| Ok(stream_id as u64 * 10)));
+        assert_eq!(mem_table.stream_count(), 0);
+        assert!(mem_table.stream_stats().is_empty());
+
+        mem_table.append(&new_entry(1, 1, b"abc")).unwrap();
+        mem_table.append(&new_entry(2, 2, b"de")).unwrap();
+        mem_table.append(&new_entry(3, 1, b"f")).unwrap();
+
+        assert_eq!(mem_table.stream_count(), 2);
+        let mut stats = mem_table.stream_stats();
+        stats.sort_by_key(|stat| stat.stream_id);
+        assert_eq!(
+            stats,
+            vec![
+                StreamStat {
+                    stream_id: 1,
+                    size: 4,
+                    base_offset: 10,
+                    end_offset: 14,
+                    chunk_count: 1,
+                },
+                StreamStat {
+                    stream_id: 2,
+                    size: 2,
+                    base_offset: 20,
+                    end_offset: 22,
+                    chunk_count: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mem_table_append_batch() {
+        let mem_table = MemTable::new(Box::new(|stream_id| Ok(stream_id as u64 * 100)));
+        mem_table.append(&new_entry(1, 1, b"a")).unwrap();
+
+        mem_table
+            .append_batch(&[
+                new_entry(2, 1, b"bc"),
+                new_entry(3, 2, b"def"),
+                new_entry(4, 1, b"g"),
+            ])
+            .unwrap();
+
+        assert_eq!(mem_table.get_first_entry(), 1);
+        assert_eq!(mem_table.get_last_entry(), 4);
+        assert_eq!(mem_table.get_size(), 7);
+        assert_eq!(mem_table.get_stream_range(1), Some((100, 104)));
+        assert_eq!(mem_table.get_stream_range(2), Some((200, 203)));
+        assert_eq!(mem_table.get_stream_tables()[&1].entry_range(), (1, 4));
+    }
+
+    #[test]
+    fn test_mem_table_try_append() {
+        let mem_table = MemTable::new(Box::new(|_| Ok(0)));
+        assert_eq!(
+            mem_table.try_append(&new_entry(1, 1, b"abc")).unwrap(),
+            AppendOutcome::Appended(3)
+        );
+
+        // a flush holds every stream table lock
+        let stream_tables = mem_table.get_stream_tables();
+        assert_eq!(
+            mem_table.try_append(&new_entry(2, 1, b"de")).unwrap(),
+            AppendOutcome::WouldBlock
+        );
+        drop(stream_tables);
+        assert_eq!(mem_table.get_last_entry(), 1);
+        assert_eq!(mem_table.get_size(), 3);
+
+        assert_eq!(
+            mem_table.try_append(&new_entry(2, 1, b"de")).unwrap(),
+            AppendOutcome::Appended(5)
+        );
+        assert_eq!(mem_table.get_stream_range(1), Some((0, 5)));
+    }
+
+    #[test]
+    fn test_mem_table_memory_limit() {
+        let mem_table = MemTable::new(Box::new(|_stream_id| Ok(0))).with_memory_limit(10);
+        mem_table.append(&new_entry(1, 1, b"abcdef")).unwrap();
+
+        let err = mem_table.append(&new_entry(2, 2, b"ghijk")).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<errors::Error>(),
+            Some(errors::Error::MemtableFull {
+                size: 6,
+                len: 5,
+                limit: 10
+            })
+        ));
+        let err = mem_table
+            .append_batch(&[new_entry(2, 1, b"gh"), new_entry(3, 2, b"ijk")])
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<errors::Error>(),
+            Some(errors::Error::MemtableFull { .. })
+        ));
+        assert!(mem_table.try_append(&new_entry(2, 1, b"ghijk")).is_err());
+        // nothing was applied by the rejected appends
+        assert_eq!(mem_table.get_size(), 6);
+        assert_eq!(mem_table.get_stream_ids(), vec![1]);
+        assert_eq!(mem_table.get_last_entry(), 1);
+
+        // up to the limit is fine
+        assert_eq!(mem_table.append(&new_entry(2, 1, b"ghij")).unwrap(), 10);
+        assert!(mem_table.append(&new_entry(3, 1, b"k")).is_err());
+        // the soft threshold is independent
+        assert!(mem_table.should_flush(8, None, unix_millis()));
+
+        // once flushed there is room again, even for an entry over the limit
+        mem_table.reset();
+        let large = vec![1u8; 64];
+        assert_eq!(mem_table.append(&new_entry(3, 1, &large)).unwrap(), 64);
+        assert!(mem_table.append(&new_entry(4, 1, b"x")).is_err());
+    }
+
+    #[test]
+    fn test_mem_table_audit() {
+        let mem_table = MemTable::new(Box::new(|_stream_id| Ok(0)));
+        mem_table.audit().unwrap();
+        mem_table.append(&new_entry(1, 1, b"abc")).unwrap();
+        mem_table
+            .append_batch(&[new_entry(2, 2, b"de"), new_entry(3, 17, b"f")])
+            .unwrap();
+        // rejected appends give their reserved size back
+        assert!(
+            mem_table
+                .append_batch(&[new_entry(4, 1, b"g"), new_entry(4, 2, b"h")])
+                .is_err()
+        );
+        mem_table.audit().unwrap();
+
+        mem_table
+            .size
+            .fetch_add(2, std::sync::atomic::Ordering::SeqCst);
+        let err = mem_table.audit().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<errors::Error>(),
+            Some(errors::Error::SizeMismatch {
+                counted: 6,
+                recorded: 8
+            })
+        ));
+
+        mem_table.reset();
+        mem_table.audit().unwrap();
+    }
+
+    #[test]
+    fn test_mem_table_append_batch_invalid_entry() {
+        let mem_table = MemTable::new(Box::new(|_stream_id| Ok(0)));
+        mem_table.append(&new_entry(5, 1, b"a")).unwrap();
+
+        // the second entry is not monotonic, nothing must be applied
+        let err = mem_table
+            .append_batch(&[new_entry(6, 1, b"b"), new_entry(6, 2, b"c")])
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<errors::Error>(),
+            Some(errors::Error::InvalidEntry { id: 6, .. })
+        ));
+        assert!(mem_table.append_batch(&[new_entry(4, 1, b"b")]).is_err());
+        assert!(mem_table.append_batch(&[new_entry(7, 1, b"")]).is_err());
+
+        assert_eq!(mem_table.get_last_entry(), 5);
+        assert_eq!(mem_table.get_size(), 1);
+        assert_eq!(mem_table.get_stream_range(1), Some((0, 1)));
+        assert_eq!(mem_table.get_stream_ids(), vec![1]);
+    }
+
+    #[test]
+    fn test_mem_table_append_batch_stream_offset_error() {
+        let mem_table = MemTable::new(Box::new(|stream_id| match stream_id {
+            3 => Err(anyhow::anyhow!("stream 3 is unavailable")),
+            _ => Ok(0),
+        }));
+        mem_table.reset_with_offsets(HashMap::from([(2, 50)]));
+
+        let result = mem_table.append_batch(&[
+            new_entry(1, 1, b"a"),
+            new_entry(2, 2, b"b"),
+            new_entry(3, 3, b"c"),
+        ]);
+        assert!(result.is_err());
+        assert_eq!(mem_table.get_last_entry(), 0);
+        assert_eq!(mem_table.get_size(), 0);
+        assert!(mem_table.get_stream_ids().is_empty());
+
+        // the seeded offset was not consumed by the failed batch
+        mem_table.append_batch(&[new_entry(1, 2, b"b")]).unwrap();
+        assert_eq!(mem_table.get_stream_range(2), Some((50, 51)));
+    }
+
+    #[test]
+    fn test_mem_table_append_batch_rollback() {
+        let mem_table = MemTable::new(Box::new(|stream_id| match stream_id {
+            3 => Err(anyhow::anyhow!("stream 3 is unavailable")),
+            _ => Ok(0),
+        }))
+        .with_memory_limit(16);
+        mem_table.append(&new_entry(1, 1, b"abc")).unwrap();
+        mem_table.append(&new_entry(2, 2, b"de")).unwrap();
+
+        let check_unchanged = |mem_table: &MemTable| {
+            assert_eq!(mem_table.get_first_entry(), 1);
+            assert_eq!(mem_table.get_last_entry(), 2);
+            assert_eq!(mem_table.get_size(), 5);
+            let mut stream_ids = mem_table.get_stream_ids();
+            stream_ids.sort();
+            assert_eq!(stream_ids, vec![1, 2]);
+            assert_eq!(mem_table.get_stream_range(1), Some((0, 3)));
+            assert_eq!(mem_table.get_stream_range(2), Some((0, 2)));
+            let stream_tables = mem_table.get_stream_tables();
+            assert_eq!(stream_tables[&1].entry_range(), (1, 1));
+            assert_eq!(stream_tables[&2].entry_range(), (2, 2));
+            drop(stream_tables);
+            mem_table.audit().unwrap();
+        };
+
+        // the stream offset of the third entry's new stream fails
+        let err = mem_table
+            .append_batch(&[
+                new_entry(3, 1, b"f"),
+                new_entry(4, 4, b"g"),
+                new_entry(5, 3, b"h"),
+                new_entry(6, 2, b"i"),
+            ])
+            .unwrap_err();
+        assert_eq!(err.to_string(), "stream 3 is unavailable");
+        check_unchanged(&mem_table);
+
+        // the third entry takes the table past its memory limit
+        let err = mem_table
+            .append_batch(&[
+                new_entry(3, 1, b"fghij"),
+                new_entry(4, 4, b"k"),
+                new_entry(5, 2, b"lmnopq"),
+            ])
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<errors::Error>(),
+            Some(errors::Error::MemtableFull { .. })
+        ));
+        check_unchanged(&mem_table);
+
+        // a later batch continues where the table was
+        mem_table
+            .append_batch(&[new_entry(3, 1, b"f"), new_entry(4, 4, b"g")])
+            .unwrap();
+        assert_eq!(mem_table.get_last_entry(), 4);
+        assert_eq!(mem_table.get_size(), 7);
+        assert_eq!(mem_table.get_stream_range(1), Some((0, 4)));
+        assert_eq!(mem_table.get_stream_range(4), Some((0, 1)));
+        assert_eq!(mem_table.get_stream_tables()[&1].entry_range(), (1, 3));
+    }
+
     #[test]
     fn test_mem_table_concurrent_access() {
         use std::sync::{
@@ -437,7 +1493,7 @@ mod tests {
                     version: 1,
                     id: entry_id,
                     stream_id: 100 + (i % 3), // Use different streams to reduce contention
-                    data: format!("data{}", i).into_bytes(),
+                    data: format!("data{}", i).into_bytes().into(),
                     callback: None,
                 };
                 mem_table_clone.append(&entry)
@@ -459,4 +1515,182 @@ mod tests {
         assert_eq!(mem_table.get_last_entry(), 10);
         assert_eq!(mem_table.get_first_entry(), 1);
     }
+
+    #[test]
+    fn test_mem_table_shards() {
+        let mem_table = MemTable::with_shards(Box::new(|_stream_id| Ok(0)), 4);
+        for (id, stream_id) in (1..=8).enumerate() {
+            mem_table
+                .append(&new_entry(id as u64 + 1, stream_id, b"data"))
+                .unwrap();
+        }
+        mem_table
+            .append_batch(&[new_entry(9, 1, b"more"), new_entry(10, 6, b"more")])
+            .unwrap();
+
+        assert_eq!(mem_table.stream_count(), 8);
+        assert_eq!(mem_table.get_first_entry(), 1);
+        assert_eq!(mem_table.get_last_entry(), 10);
+        assert_eq!(mem_table.get_size(), 40);
+
+        let mut stream_ids = mem_table.get_stream_ids();
+        stream_ids.sort();
+        assert_eq!(stream_ids, (1..=8).collect::<Vec<_>>());
+
+        // the snapshot sees every shard
+        let stream_tables = mem_table.get_stream_tables();
+        assert_eq!(stream_tables.len(), 8);
+        assert_eq!(stream_tables[&1].size(), 8);
+        assert_eq!(stream_tables[&6].size(), 8);
+        assert!(stream_tables.get(&9).is_none());
+        assert_eq!(stream_tables.iter().map(|(_, t)| t.size()).sum::<u64>(), 40);
+    }
+
+    #[test]
+    fn test_mem_table_stream_data_pool() {
+        let pool = Arc::new(StreamDataPool::new(16));
+        let mem_table =
+            MemTable::new(Box::new(|_stream_id| Ok(0))).with_stream_data_pool(pool.clone());
+        for id in 1..=100 {
+            mem_table
+                .append(&new_entry(id, id as StreamId % 4 + 1, b"data"))
+                .unwrap();
+        }
+        assert_eq!(pool.stats().allocations, 4);
+
+        // appending after a reset reuses the buffers instead of allocating
+        mem_table.reset();
+        assert_eq!(pool.stats().free, 4);
+        for id in 101..=200 {
+            mem_table
+                .append(&new_entry(id, id as StreamId % 4 + 1, b"data"))
+                .unwrap();
+        }
+        let stats = pool.stats();
+        assert_eq!(stats.allocations, 4);
+        assert_eq!(stats.reuses, 4);
+        assert_eq!(stats.free, 0);
+    }
+
+    #[test]
+    fn test_mem_table_stream_chunk_capacity() {
+        let mem_table =
+            MemTable::new(Box::new(|_stream_id| Ok(0))).with_stream_chunk_capacity(1 << 20);
+        // 300KB, three default chunks
+        let data = vec![1u8; 1024];
+        for id in 1..=300 {
+            mem_table.append(&new_entry(id, 1, &data)).unwrap();
+        }
+        let stats = mem_table.stream_stats();
+        assert_eq!(stats[0].chunk_count, 1);
+        assert_eq!(stats[0].size, 300 << 10);
+    }
+
+    #[test]
+    fn test_mem_table_oldest_entry_age() {
+        let mem_table = MemTable::new(Box::new(|_stream_id| Ok(0)));
+        let now = unix_millis();
+        assert_eq!(mem_table.oldest_entry_age(now), None);
+        assert!(!mem_table.should_flush(100, Some(0), now));
+
+        mem_table.append(&new_entry(1, 1, b"data")).unwrap();
+        let appended_at = unix_millis();
+        // later appends do not move the age
+        mem_table.append(&new_entry(2, 1, b"more")).unwrap();
+        let age = mem_table.oldest_entry_age(appended_at + 60_000).unwrap();
+        assert!((60_000..=60_000 + appended_at - now).contains(&age));
+        // a clock going backwards does not underflow
+        assert_eq!(mem_table.oldest_entry_age(0), Some(0));
+
+        assert!(!mem_table.should_flush(100, None, appended_at + 60_000));
+        assert!(!mem_table.should_flush(100, Some(120_000), appended_at + 60_000));
+        assert!(mem_table.should_flush(100, Some(60_000), appended_at + 60_000));
+        assert!(mem_table.should_flush(4, None, appended_at));
+
+        mem_table.reset();
+        assert_eq!(mem_table.oldest_entry_age(appended_at + 60_000), None);
+    }
+
+    #[test]
+    fn test_mem_table_with_wal() {
+        let dir =
+            std::env::temp_dir().join(format!("streamstore_mem_table_wal_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let wal_path = dir.to_str().unwrap().to_string();
+
+        let mem_table = MemTable::recover(&wal_path, 1 << 20, WalSyncPolicy::Batched(2)).unwrap();
+        assert_eq!(mem_table.get_last_entry(), 0);
+
+        mem_table
+            .append(&Entry::from_slice(1, 100, b"hello"))
+            .unwrap();
+        mem_table
+            .append(&Entry::from_slice(2, 200, b"wal"))
+            .unwrap();
+        mem_table
+            .append(&Entry::from_slice(3, 100, b" world"))
+            .unwrap();
+        mem_table.sync_wal().unwrap();
+
+        // entries out of id order are refused before anything is written
+        let err = mem_table
+            .append(&Entry::from_slice(5, 100, b"gap"))
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<errors::Error>(),
+            Some(errors::Error::InvalidEntry { id: 5, .. })
+        ));
+        assert_eq!(mem_table.get_last_entry(), 3);
+        assert_eq!(mem_table.get_size(), 14);
+        drop(mem_table);
+
+        // the appends are replayed from the WAL alone, and later appends continue it
+        let mem_table = MemTable::recover(&wal_path, 1 << 20, WalSyncPolicy::EveryWrite).unwrap();
+        assert_eq!(mem_table.get_last_entry(), 3);
+        let mut buf = [0u8; 64];
+        let n = mem_table.read_stream(100, 0, &mut buf).unwrap();
+        assert_eq!(&buf[..n], b"hello world");
+        let n = mem_table.read_stream(200, 0, &mut buf).unwrap();
+        assert_eq!(&buf[..n], b"wal");
+        mem_table.append(&Entry::from_slice(4, 200, b"!")).unwrap();
+        drop(mem_table);
+
+        let mem_table = MemTable::recover(&wal_path, 1 << 20, WalSyncPolicy::None).unwrap();
+        assert_eq!(mem_table.get_last_entry(), 4);
+        let n = mem_table.read_stream(200, 0, &mut buf).unwrap();
+        assert_eq!(&buf[..n], b"wal!");
+
+        // batches are written to the WAL too, and appends continue after them
+        mem_table
+            .append_batch(&[
+                Entry::from_slice(5, 100, b"!"),
+                Entry::from_slice(6, 300, b"batch"),
+            ])
+            .unwrap();
+        mem_table.append(&Entry::from_slice(7, 300, b"ed")).unwrap();
+        // a batch with a gap in its ids is refused as a whole
+        let err = mem_table
+            .append_batch(&[
+                Entry::from_slice(8, 100, b"x"),
+                Entry::from_slice(10, 100, b"y"),
+            ])
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<errors::Error>(),
+            Some(errors::Error::InvalidEntry { id: 10, .. })
+        ));
+        assert_eq!(mem_table.get_size(), 23);
+        mem_table.sync_wal().unwrap();
+        drop(mem_table);
+
+        let mem_table = MemTable::recover(&wal_path, 1 << 20, WalSyncPolicy::None).unwrap();
+        assert_eq!(mem_table.get_last_entry(), 7);
+        let n = mem_table.read_stream(100, 0, &mut buf).unwrap();
+        assert_eq!(&buf[..n], b"hello world!");
+        let n = mem_table.read_stream(300, 0, &mut buf).unwrap();
+        assert_eq!(&buf[..n], b"batched");
+        drop(mem_table);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
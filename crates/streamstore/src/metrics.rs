@@ -48,6 +48,24 @@ lazy_static! {
         );
         c
     };
+    pub static ref stream_header_cache_hit_count: Counter = {
+        let c: Counter = Default::default();
+        registry.lock().unwrap().register(
+            "stream_header_cache_hit_count",
+            "Count of stream header cache hits",
+            c.clone(),
+        );
+        c
+    };
+    pub static ref stream_header_cache_miss_count: Counter = {
+        let c: Counter = Default::default();
+        registry.lock().unwrap().register(
+            "stream_header_cache_miss_count",
+            "Count of stream header cache misses",
+            c.clone(),
+        );
+        c
+    };
     pub static ref find_segment_time_seconds: Histogram = {
         let h = Histogram::new(exponential_buckets(0.0000001, 2.0, 25));
         registry.lock().unwrap().register(
@@ -1,4 +1,4 @@
-use crate::Store;
+use crate::{Store, segments::DEFAULT_READ_AHEAD};
 use anyhow::Result;
 
 #[derive(Clone, Debug)]
@@ -11,6 +11,16 @@ pub struct Options {
     pub(crate) segment_merge_count: u64,
     pub(crate) max_segment_merge_level: u32,
     pub(crate) reload_check_crc: bool,
+    // entries of the segment stream header cache, 0 disables it
+    pub(crate) stream_header_cache_capacity: usize,
+    // alignment of stream data in generated segments, 1 packs the data
+    pub(crate) segment_data_alignment: u64,
+    // free memtable stream data buffers kept for reuse, 0 disables the pool
+    pub(crate) stream_data_pool_capacity: usize,
+    // advisory locks on segment files, for stores whose data directory is shared
+    pub(crate) segment_file_lock: bool,
+    // bytes of segment data prefetched ahead of stream readers, 0 disables read-ahead
+    pub(crate) segment_read_ahead: u64,
 }
 
 impl Default for Options {
@@ -24,6 +34,11 @@ impl Default for Options {
             segment_merge_count: 5,
             max_segment_merge_level: 5,
             reload_check_crc: false,
+            stream_header_cache_capacity: 0,
+            segment_data_alignment: 1,
+            stream_data_pool_capacity: 0,
+            segment_file_lock: false,
+            segment_read_ahead: DEFAULT_READ_AHEAD,
         }
     }
 }
@@ -62,6 +77,13 @@ impl Options {
         self
     }
 
+    // Segments are merged up to this level, 0 disables merging (e.g. for a read
+    // replica, see Store::refresh)
+    pub fn max_segment_merge_level(&mut self, max_segment_merge_level: u32) -> &mut Self {
+        self.max_segment_merge_level = max_segment_merge_level;
+        self
+    }
+
     pub fn wal_path(&mut self, wal_path: &str) -> &mut Self {
         self.wal_path = wal_path.to_string();
         self
@@ -82,6 +104,41 @@ impl Options {
         self.max_tables_count = max_tables_count;
         self
     }
+    // Cache up to `capacity` resolved segment stream headers for the read path, 0 disables the cache
+    pub fn stream_header_cache_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.stream_header_cache_capacity = capacity;
+        self
+    }
+    // Start every stream's data in new segments at a multiple of `alignment`
+    // (a power of two, e.g. 4096 for O_DIRECT reads), 1 keeps the data packed
+    pub fn segment_data_alignment(&mut self, alignment: u64) -> &mut Self {
+        assert!(
+            alignment.is_power_of_two(),
+            "alignment must be a power of two"
+        );
+        self.segment_data_alignment = alignment;
+        self
+    }
+    // Keep up to `capacity` free 128KB memtable stream data buffers for reuse. Buffers are
+    // returned when a flushed memtable is released (past max_tables_count). 0 disables the pool
+    pub fn stream_data_pool_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.stream_data_pool_capacity = capacity;
+        self
+    }
+    // Lock segment files against other processes sharing the data directory: segments
+    // being written are locked exclusively, open segments are held with a shared lock,
+    // contention fails with Error::SegmentLocked. Off by default
+    pub fn segment_file_lock(&mut self, lock: bool) -> &mut Self {
+        self.segment_file_lock = lock;
+        self
+    }
+    // Prefetch (MADV_WILLNEED) `read_ahead` bytes of segment data ahead of stream
+    // readers as they advance, smoothing sequential scans of cold segments.
+    // 1 MiB by default, 0 disables read-ahead
+    pub fn segment_read_ahead(&mut self, read_ahead: u64) -> &mut Self {
+        self.segment_read_ahead = read_ahead;
+        self
+    }
     pub fn wal_path_str(&self) -> &str {
         &self.wal_path
     }
@@ -1,9 +1,12 @@
 use std::{io, sync::Arc};
 
+use anyhow::Result;
+
 use crate::{
     StreamId,
     mem_table::MemTableWeak,
     metrics,
+    segments::ReadAhead,
     store::{SegmentWeak, StreamStoreInner},
 };
 
@@ -24,11 +27,13 @@ pub struct StreamReader {
     read_mem_table: Option<MemTableWeak>,
     read_segment: Option<SegmentWeak>,
     read_state: Arc<std::sync::Mutex<StreamReadState>>,
+    read_ahead: ReadAhead,
 }
 
 impl StreamReader {
     pub fn new(inner: Arc<StreamStoreInner>, stream_id: StreamId) -> Self {
         Self {
+            read_ahead: ReadAhead::new(inner.segment_read_ahead()),
             inner,
             stream_id,
             read_mem_table: None,
@@ -59,6 +64,7 @@ impl StreamReader {
     fn reset_read_state(&mut self) {
         self.read_mem_table = None;
         self.read_segment = None;
+        self.read_ahead.reset();
         *self.read_state.lock().unwrap() = StreamReadState::None;
     }
 
@@ -67,7 +73,14 @@ impl StreamReader {
         if let Some(segment) = &self.read_segment {
             if let Some(segment) = segment.upgrade() {
                 metrics::read_segment_hit_count.inc();
-                let (begin, end) = segment.get_stream_range(self.stream_id).unwrap();
+                let stream_header = self
+                    .inner
+                    .find_stream_header(&segment, self.stream_id)
+                    .unwrap();
+                let (begin, end) = (
+                    stream_header.offset,
+                    stream_header.offset + stream_header.size,
+                );
                 assert!(
                     begin <= self.offset() && self.offset() <= end,
                     "Offset {} out of range[{}, {}) for Stream ID {}",
@@ -77,7 +90,10 @@ impl StreamReader {
                     self.stream_id
                 );
 
-                let bytes_read = segment.read_stream(self.stream_id, self.offset(), buf)?;
+                self.read_ahead
+                    .advance(&segment, &stream_header, self.offset());
+                let bytes_read =
+                    segment.read_stream_with_header(&stream_header, self.offset(), buf)?;
                 if bytes_read > 0 {
                     self.offset_inc(bytes_read);
                     read_bytes_all += bytes_read;
@@ -93,8 +109,15 @@ impl StreamReader {
                 Some(segment) => {
                     metrics::read_segment_miss_count.inc();
                     metrics::find_segment_time_seconds.observe(begin_ts.elapsed().as_secs_f64());
-                    let bytes_read = segment.read_stream(
-                        self.stream_id,
+                    // find_segment only returns segments holding the stream
+                    let stream_header = self
+                        .inner
+                        .find_stream_header(&segment, self.stream_id)
+                        .unwrap();
+                    self.read_ahead
+                        .advance(&segment, &stream_header, self.offset());
+                    let bytes_read = segment.read_stream_with_header(
+                        &stream_header,
                         self.offset(),
                         &mut buf[read_bytes_all..],
                     )?;
@@ -367,3 +390,56 @@ impl io::Seek for StreamReader {
         };
     }
 }
+
+// Read-only view of a stream as a seekable file (see Store::open_stream): reads
+// go through the segments, the flushed memtables and the current memtable as the
+// position moves, the stream's length grows with its appends. The position starts
+// at the stream's first offset, which is past 0 once retention dropped data
+pub struct StreamHandle {
+    reader: StreamReader,
+}
+
+impl StreamHandle {
+    pub(crate) fn new(inner: Arc<StreamStoreInner>, stream_id: StreamId) -> Result<Self> {
+        let begin = inner.get_stream_begin(stream_id)?;
+        let reader = StreamReader::new(inner, stream_id);
+        reader.set_offset(begin);
+        Ok(StreamHandle { reader })
+    }
+
+    pub fn stream_id(&self) -> StreamId {
+        self.reader.stream_id()
+    }
+
+    // Logical length of the stream: the offset after its last byte, which is also
+    // the position SeekFrom::End is relative to
+    pub fn len(&self) -> Result<u64> {
+        self.reader.inner.get_stream_end(self.reader.stream_id())
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        let (begin, end) = self
+            .reader
+            .inner
+            .get_stream_range(self.reader.stream_id())?;
+        Ok(begin == end)
+    }
+
+    pub fn position(&self) -> u64 {
+        self.reader.offset()
+    }
+}
+
+impl io::Read for StreamHandle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+// Seeks are checked against the stream's current range, a position before its
+// first offset or past its end fails with InvalidInput and leaves the position as is
+impl io::Seek for StreamHandle {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.reader.seek(pos)
+    }
+}
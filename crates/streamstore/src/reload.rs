@@ -13,11 +13,17 @@ use std::{
 use crate::{
     entry::Decoder,
     errors,
+    manifest::{self, MANIFEST_FILE_NAME, Manifest},
     mem_table::{GetStreamOffset, MemTable},
     segments::Segment, StreamId,
 };
 
-pub fn reload_segments(segment_path: &str, check_crc: bool) -> Result<VecDeque<Arc<Segment>>> {
+// With `lock_files` every segment is held with a shared lock, see Segment::open_locked
+pub fn reload_segments(
+    segment_path: &str,
+    check_crc: bool,
+    lock_files: bool,
+) -> Result<VecDeque<Arc<Segment>>> {
     // Check if the segment path exists
     if !std::path::Path::new(segment_path).exists() {
         // create the segment path if it does not exist
@@ -25,7 +31,90 @@ pub fn reload_segments(segment_path: &str, check_crc: bool) -> Result<VecDeque<A
         log::info!("Segment directory created: {}", segment_path);
     }
 
+    manifest::cleanup_temp_files(std::path::Path::new(segment_path))?;
+
     let mut segment_files = VecDeque::new();
+    for filename in live_segment_files(segment_path)? {
+        let segment = open_segment(&filename, check_crc, lock_files)?;
+        segment_files.push_back(std::sync::Arc::new(segment));
+    }
+
+    segment_files
+        .make_contiguous()
+        .sort_by(|a, b| -> std::cmp::Ordering { a.entry_index().0.cmp(&b.entry_index().0) });
+
+    Ok(segment_files)
+}
+
+// The live segment files of the directory, in entry order. Only the segments
+// listed in the manifest are live, fall back to scanning the directory for
+// stores written before the manifest existed
+pub fn live_segment_files(segment_path: &str) -> Result<Vec<PathBuf>> {
+    let filenames = match Manifest::load(std::path::Path::new(segment_path))? {
+        Some(manifest) => {
+            log::info!(
+                "Loaded manifest with {} segments, last entry {}",
+                manifest.segments.len(),
+                manifest.last_entry
+            );
+            manifest
+                .segments
+                .iter()
+                .map(|name| std::path::Path::new(&segment_path).join(name))
+                .collect::<Vec<_>>()
+        }
+        None => {
+            // order the files by entry range, reading only their metadata
+            let mut filenames = list_segment_files(segment_path)?
+                .into_iter()
+                .map(|filename| {
+                    let metadata = Segment::read_metadata(&filename)?;
+                    log::info!(
+                        "Found segment {:?} entries [{}, {}] with {} streams",
+                        filename,
+                        metadata.first_entry,
+                        metadata.last_entry,
+                        metadata.stream_ids.len()
+                    );
+                    Ok((metadata.first_entry, filename))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            filenames.sort();
+            filenames
+                .into_iter()
+                .map(|(_, filename)| filename)
+                .collect()
+        }
+    };
+    Ok(filenames)
+}
+
+// Open a segment of the store, see reload_segments for `check_crc` and `lock_files`
+pub fn open_segment(filename: &PathBuf, check_crc: bool, lock_files: bool) -> Result<Segment> {
+    let segment = if lock_files {
+        Segment::open_locked(filename)?
+    } else {
+        Segment::open(filename)?
+    };
+
+    if check_crc {
+        // check crc
+        let begin = std::time::Instant::now();
+        segment.check_crc().context(format!(
+            "Failed to check CRC for segment file: {:?}",
+            filename
+        ))?;
+        log::info!(
+            "CRC check passed for segment file: {:?} elapsed seconds {}",
+            filename,
+            begin.elapsed().as_secs()
+        );
+    }
+    Ok(segment)
+}
+
+fn list_segment_files(segment_path: &str) -> Result<Vec<PathBuf>> {
+    let mut filenames = vec![];
     for entry in std::fs::read_dir(&segment_path).context("Failed to read segment directory")? {
         let entry = entry.map_err(errors::new_io_error)?;
         if !entry.file_type().map_err(errors::new_io_error)?.is_file() {
@@ -33,36 +122,17 @@ pub fn reload_segments(segment_path: &str, check_crc: bool) -> Result<VecDeque<A
         }
         //segment path join file name
         let filename = std::path::Path::new(&segment_path).join(entry.file_name());
+        if filename.file_name().is_some_and(|name| name == MANIFEST_FILE_NAME) {
+            continue;
+        }
         // check if file name is valid
         if !filename.extension().map_or(false, |ext| ext == "seg") {
             log::warn!("Invalid segment file name: {:?}", filename);
             continue;
         }
-
-        let segment = Segment::open(&filename)?;
-
-        if check_crc {
-            // check crc
-            let begin = std::time::Instant::now();
-            segment.check_crc().context(format!(
-                "Failed to check CRC for segment file: {:?}",
-                filename
-            ))?;
-            log::info!(
-                "CRC check passed for segment file: {:?} elapsed seconds {}",
-                filename,
-                begin.elapsed().as_secs()
-            );
-        }
-
-        segment_files.push_back(std::sync::Arc::new(segment));
+        filenames.push(filename);
     }
-
-    segment_files
-        .make_contiguous()
-        .sort_by(|a, b| -> std::cmp::Ordering { a.entry_index().0.cmp(&b.entry_index().0) });
-
-    Ok(segment_files)
+    Ok(filenames)
 }
 
 fn list_wal_files(wal_path: &str) -> Result<Vec<(String, u64)>> {
@@ -1,20 +1,63 @@
-use crate::{StreamId, errors, mem_table::MemTable, store::SegmentArc};
+use crate::{
+    StreamId,
+    errors::{self, SegmentOp},
+    mem_table::{MemTable, MemTableArc},
+    store::SegmentArc,
+};
 use anyhow::Result;
 use crc::Crc;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{HashMap, HashSet},
     fmt::Display,
-    fs::File,
-    io::{self, Write},
+    fs::{File, OpenOptions},
+    io::{self, IoSlice, Read, Seek, Write},
     path::{self},
     rc::Rc,
-    sync::atomic,
+    sync::{Arc, atomic},
 };
 
 const SEGMENT_STREAM_HEADER_SIZE: u64 = std::mem::size_of::<SegmentStreamHeader>() as u64;
 const SEGMENT_HEADER_SIZE: u64 = std::mem::size_of::<SegmentHeader>() as u64;
 const SEGMENT_STREAM_HEADER_VERSION_V1: u64 = 1;
+// V2 stream headers record the min/max entry id of each stream
+const SEGMENT_STREAM_HEADER_VERSION_V2: u64 = 2;
 const SEGMENT_HEADER_VERSION_V1: u32 = 1;
+// V2 segments use V2 stream headers
+const SEGMENT_HEADER_VERSION_V2: u32 = 2;
+const SEGMENT_WRITE_CHUNK_SIZE: usize = 64 << 10; // 64KB
+// Segments with more streams than this get a block index over their stream
+// header table, lookups then search the small block index and one block
+#[cfg(not(test))]
+const STREAM_HEADER_BLOCK_THRESHOLD: u64 = 64 << 10;
+#[cfg(not(test))]
+const STREAM_HEADER_BLOCK_LEN: u64 = 256; // 16KB of stream headers
+// small in tests, so segments of a few streams are written with a block index
+#[cfg(test)]
+const STREAM_HEADER_BLOCK_THRESHOLD: u64 = 4;
+#[cfg(test)]
+const STREAM_HEADER_BLOCK_LEN: u64 = 2;
+// IOV_MAX on Linux, larger batches are split into several writev calls
+const WRITE_VECTORED_MAX_SLICES: usize = 1024;
+const SEGMENT_TRAILER_SIZE: u64 = std::mem::size_of::<SegmentTrailer>() as u64;
+const SEGMENT_TRAILER_MAGIC: u64 = 0x5345_474d_5452_4c52; // "SEGMTRLR"
+const SEGMENT_FOOTER_SIZE: u64 = std::mem::size_of::<SegmentFooter>() as u64;
+const SEGMENT_FOOTER_MAGIC: u64 = 0x5345_474d_464f_4f54; // "SEGMFOOT"
+// SegmentHeader::flags bit: the file digest (see SegmentReader::file_digest) is
+// stored in the 8 bytes before the trailer
+const SEGMENT_FLAG_FILE_DIGEST: u64 = 1;
+const SEGMENT_FILE_DIGEST_SIZE: u64 = 8;
+static FILE_DIGEST_CRC: Crc<u64> = Crc::<u64>::new(&crc::CRC_64_REDIS);
+#[cfg(feature = "stream-index")]
+const STREAM_INDEX_INTERVAL: u64 = 64 << 10; // 64KB
+// default read-ahead window of sequential stream readers
+pub const DEFAULT_READ_AHEAD: u64 = 1 << 20; // 1MB
+#[cfg(feature = "stream-index")]
+const STREAM_INDEX_ENTRY_SIZE: u64 = std::mem::size_of::<StreamIndexEntry>() as u64;
+
+// Source of Segment::id, ids are never reused within a process
+static NEXT_SEGMENT_ID: atomic::AtomicU64 = atomic::AtomicU64::new(1);
 
 #[derive(Debug, Clone)]
 #[repr(C)]
@@ -30,21 +73,39 @@ pub struct SegmentStreamHeader {
     pub(crate) size: u64,
     // checksum of the stream
     pub(crate) crc64: u64,
+    // The min entry id that contributed data to the stream
+    pub(crate) first_entry: u64,
+    // The max entry id that contributed data to the stream
+    pub(crate) last_entry: u64,
 }
 
 impl Default for SegmentStreamHeader {
     fn default() -> Self {
         SegmentStreamHeader {
-            version: SEGMENT_STREAM_HEADER_VERSION_V1,
+            version: SEGMENT_STREAM_HEADER_VERSION_V2,
             stream_id: 0,
             offset: 0,
             file_offset: 0,
             size: 0,
             crc64: 0,
+            first_entry: 0,
+            last_entry: 0,
         }
     }
 }
 
+// On-disk layout of the stream headers of V1 segments
+#[derive(Debug, Clone)]
+#[repr(C)]
+struct SegmentStreamHeaderV1 {
+    version: u64,
+    stream_id: StreamId,
+    offset: u64,
+    file_offset: u64,
+    size: u64,
+    crc64: u64,
+}
+
 #[derive(Clone, Debug)]
 #[repr(C)]
 pub struct SegmentHeader {
@@ -54,19 +115,44 @@ pub struct SegmentHeader {
     pub(crate) first_entry: u64,
     pub(crate) stream_headers_offset: u64,
     pub(crate) stream_headers_count: u64,
-    _pading: [u8; 88], // Padding to ensure the size is 128 bytes
+    // alignment of every stream's file_offset, 0 (older files) and 1 mean unaligned
+    pub(crate) data_alignment: u64,
+    // file offset of the stream offset index written after the stream data, 0 if none
+    pub(crate) stream_index_offset: u64,
+    // stream bytes between two entries of the stream offset index
+    pub(crate) stream_index_interval: u64,
+    // stream headers per block of the stream header block index, 0 if there is none
+    pub(crate) stream_header_block_len: u64,
+    // file offset of the block index, the first stream id of every block, it
+    // follows the stream header table
+    pub(crate) stream_header_blocks_offset: u64,
+    // file offset and size of the producer's free-form metadata blob, it follows
+    // the stream header table (and block index). Size 0 means there is none
+    pub(crate) metadata_offset: u64,
+    pub(crate) metadata_size: u64,
+    // SEGMENT_FLAG_* bits, 0 in older files
+    pub(crate) flags: u64,
+    _pading: [u8; 24], // Padding to ensure the size is 128 bytes
 }
 
 impl Default for SegmentHeader {
     fn default() -> Self {
         SegmentHeader {
-            version: SEGMENT_HEADER_VERSION_V1,
+            version: SEGMENT_HEADER_VERSION_V2,
             level: 0,
             last_entry: 0,
             first_entry: 0,
             stream_headers_offset: SEGMENT_HEADER_SIZE,
             stream_headers_count: 0,
-            _pading: [0; 88],
+            data_alignment: 1,
+            stream_index_offset: 0,
+            stream_index_interval: 0,
+            stream_header_block_len: 0,
+            stream_header_blocks_offset: 0,
+            metadata_offset: 0,
+            metadata_size: 0,
+            flags: 0,
+            _pading: [0; 24],
         }
     }
 }
@@ -81,48 +167,910 @@ impl Display for SegmentHeader {
     }
 }
 
+// Fixed trailer at the end of V2 segment files, with a checksum over the
+// segment header and the stream header table
+#[derive(Debug, Clone)]
+#[repr(C)]
+struct SegmentTrailer {
+    magic: u64,
+    metadata_crc64: u64,
+}
+
+impl SegmentTrailer {
+    fn new(segment_header: &[u8], stream_headers: &[u8]) -> Self {
+        let crc64 = Crc::<u64>::new(&crc::CRC_64_REDIS);
+        let mut hash = crc64.digest();
+        hash.update(segment_header);
+        hash.update(stream_headers);
+        SegmentTrailer {
+            magic: SEGMENT_TRAILER_MAGIC,
+            metadata_crc64: hash.finalize(),
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(
+                self as *const SegmentTrailer as *const u8,
+                SEGMENT_TRAILER_SIZE as usize,
+            )
+        }
+    }
+}
+
+// Fixed footer of segments in the footer layout (see SegmentWriter), where the
+// stream data comes first and the stream header table and segment header are
+// appended after it. The magic is last, so the layout is told apart by the final
+// bytes of the file
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct SegmentFooter {
+    // file offset of the segment header, only the footer follows it
+    header_offset: u64,
+    // same checksum as SegmentTrailer::metadata_crc64
+    metadata_crc64: u64,
+    magic: u64,
+}
+
+impl SegmentFooter {
+    // Parse the footer from the end of `tail`, the last bytes of a file of
+    // `file_size` bytes. None for segments in the header layout
+    fn parse(tail: &[u8], file_size: u64) -> std::result::Result<Option<SegmentFooter>, String> {
+        let Some(start) = tail.len().checked_sub(SEGMENT_FOOTER_SIZE as usize) else {
+            return Ok(None);
+        };
+        let footer =
+            unsafe { std::ptr::read_unaligned(tail[start..].as_ptr() as *const SegmentFooter) };
+        if footer.magic != SEGMENT_FOOTER_MAGIC {
+            return Ok(None);
+        }
+        if footer.header_offset % 8 != 0
+            || footer
+                .header_offset
+                .checked_add(SEGMENT_HEADER_SIZE + SEGMENT_FOOTER_SIZE)
+                != Some(file_size)
+        {
+            return Err(format!(
+                "footer header offset {} does not match file size {}",
+                footer.header_offset, file_size
+            ));
+        }
+        Ok(Some(footer))
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(
+                self as *const SegmentFooter as *const u8,
+                SEGMENT_FOOTER_SIZE as usize,
+            )
+        }
+    }
+}
+
+// Entry of the sparse stream offset index: the data of stream offset `offset`
+// starts at `file_offset`. The index region holds the start of every stream's
+// entries (one u64 per stream header, plus the end), followed by the entries
+// of each stream in stream header order, sorted by offset
+#[cfg(feature = "stream-index")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+struct StreamIndexEntry {
+    offset: u64,
+    file_offset: u64,
+}
+
+// Human readable summary of a segment, for debugging and ops tooling
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SegmentDescription {
+    pub filename: String,
+    pub version: u32,
+    pub level: u32,
+    pub first_entry: u64,
+    pub last_entry: u64,
+    pub stream_headers_offset: u64,
+    pub stream_headers_count: u64,
+    #[serde(default)]
+    pub data_alignment: u64,
+    pub streams: Vec<SegmentStreamDescription>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SegmentStreamDescription {
+    pub stream_id: StreamId,
+    pub offset: u64,
+    pub size: u64,
+    pub file_offset: u64,
+    pub first_entry: u64,
+    pub last_entry: u64,
+    pub crc64: u64,
+    pub crc_ok: bool,
+    // hex of the first bytes of the stream data, only set by describe_with_preview
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_preview: Option<String>,
+}
+
+impl SegmentDescription {
+    pub fn to_json_string(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+// How much of a segment's stream data a compaction would drop, see
+// Segment::compaction_stats
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+    // stream data a compaction keeps
+    pub live_bytes: u64,
+    // stream data of tombstoned streams and data below the retention offsets
+    pub dead_bytes: u64,
+    // the part of dead_bytes that belongs to tombstoned streams
+    pub tombstone_bytes: u64,
+    pub stream_count: usize,
+    // streams of the segment that are tombstoned
+    pub tombstone_count: usize,
+}
+
+impl CompactionStats {
+    // Share of the stream data that is dead, 0 for a segment without data. A
+    // compaction policy should prefer the segments with the highest ratio
+    pub fn dead_ratio(&self) -> f64 {
+        let total = self.live_bytes + self.dead_bytes;
+        if total == 0 {
+            return 0.0;
+        }
+        self.dead_bytes as f64 / total as f64
+    }
+}
+
+// Segment header fields and stream ids, read by Segment::read_metadata without
+// mapping the file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentMetadata {
+    pub version: u32,
+    pub level: u32,
+    pub first_entry: u64,
+    pub last_entry: u64,
+    pub stream_headers_offset: u64,
+    pub stream_headers_count: u64,
+    pub data_alignment: u64,
+    pub stream_index_offset: u64,
+    pub stream_index_interval: u64,
+    pub stream_header_block_len: u64,
+    pub stream_header_blocks_offset: u64,
+    pub metadata_offset: u64,
+    pub metadata_size: u64,
+    // ids of the streams in the segment, in stream header order (sorted)
+    pub stream_ids: Vec<StreamId>,
+}
+
 pub struct Segment {
     #[allow(dead_code)]
     pub filename: path::PathBuf,
+    id: u64,
     file: Option<File>,
-    data: Option<memmap2::Mmap>,
+    // the mapping, released on drop before the file is deleted
+    reader: Option<SegmentReader>,
     drop_delete: atomic::AtomicBool,
 }
 
+/// Read handle of a segment, cheap to clone and independent of the Segment's
+/// lifecycle: it keeps the mapping alive, so it can be moved into tasks that
+/// outlive the segment being pruned or merged away.
+///
+/// Segment derefs to its own reader, so every read method is also available
+/// on the segment.
+#[derive(Clone)]
+pub struct SegmentReader {
+    filename: Arc<path::Path>,
+    data: Arc<memmap2::Mmap>,
+    // V1 stream headers converted to the current layout, None for V2 segments
+    legacy_stream_headers: Option<Arc<[SegmentStreamHeader]>>,
+    // footer of segments in the footer layout, None for the header layout
+    footer: Option<SegmentFooter>,
+}
+
 impl Segment {
+    // Open the segment, verifying its metadata (header and stream header table)
+    // before anything is read through it. Debug builds also check that the stream
+    // header table is sorted, see repair_segment for files that are not
     pub fn open(file_name: &path::PathBuf) -> Result<Segment> {
-        let file = File::open(&file_name).map_err(errors::new_io_error)?;
-        let mmap = unsafe { memmap2::Mmap::map(&file) }.map_err(errors::new_io_error)?;
-        let segment = Segment {
+        let segment = Self::open_with(file_name, true)?;
+        if cfg!(debug_assertions) {
+            segment.verify_stream_header_order()?;
+        }
+        Ok(segment)
+    }
+
+    /// Open the segment without verifying its metadata, for forensic recovery of
+    /// files that fail Segment::open.
+    ///
+    /// # Safety
+    /// Corrupt metadata can make any later read go out of the mapped file.
+    pub unsafe fn open_unchecked(file_name: &path::PathBuf) -> Result<Segment> {
+        Self::open_with(file_name, false)
+    }
+
+    /// Map an already open segment file, e.g. one handed out by a descriptor cache.
+    ///
+    /// `name` is kept for diagnostics and is the path removed when drop_delete is set.
+    pub fn from_file(file: File, name: String) -> Result<Segment> {
+        Self::from_file_with(file, path::PathBuf::from(name), true)
+    }
+
+    // Same as open, holding a shared advisory lock on the file for the segment's
+    // lifetime. Fails with Error::SegmentLocked while a writer holds the file, see
+    // generate_segment_with_alignment
+    pub fn open_locked(file_name: &path::PathBuf) -> Result<Segment> {
+        let file = File::open(file_name)
+            .map_err(|e| errors::new_segment_io_error(SegmentOp::OpenSegment, file_name, e))?;
+        lock_file(&file, file_name, false)?;
+        let segment = Self::from_file_with(file, file_name.clone(), true)?;
+        if cfg!(debug_assertions) {
+            segment.verify_stream_header_order()?;
+        }
+        Ok(segment)
+    }
+
+    fn open_with(file_name: &path::PathBuf, verify: bool) -> Result<Segment> {
+        let file = File::open(file_name)
+            .map_err(|e| errors::new_segment_io_error(SegmentOp::OpenSegment, file_name, e))?;
+        Self::from_file_with(file, file_name.clone(), verify)
+    }
+
+    fn from_file_with(file: File, file_name: path::PathBuf, verify: bool) -> Result<Segment> {
+        let mmap = unsafe { memmap2::Mmap::map(&file) }
+            .map_err(|e| errors::new_segment_io_error(SegmentOp::MapSegment, &file_name, e))?;
+        let mut segment = Segment {
             file: Some(file),
-            data: Some(mmap),
-            filename: file_name.clone(),
+            reader: Some(SegmentReader {
+                filename: Arc::from(file_name.as_path()),
+                data: Arc::new(mmap),
+                legacy_stream_headers: None,
+                footer: None,
+            }),
+            filename: file_name,
+            id: NEXT_SEGMENT_ID.fetch_add(1, atomic::Ordering::Relaxed),
             drop_delete: atomic::AtomicBool::new(false),
         };
+        if segment.file_size() < SEGMENT_HEADER_SIZE {
+            return Err(errors::new_corrupt_segment(
+                &segment.filename,
+                format!(
+                    "file size {} is smaller than the segment header",
+                    segment.file_size()
+                ),
+            ));
+        }
+        let footer = SegmentFooter::parse(&segment.data, segment.file_size())
+            .map_err(|reason| errors::new_corrupt_segment_metadata(&segment.filename, reason))?;
+        segment.reader.as_mut().unwrap().footer = footer;
+        if verify {
+            segment.verify_metadata()?;
+        }
+
+        let header = segment.get_segment_header();
+        if header.version == SEGMENT_HEADER_VERSION_V1 {
+            let table_end = header
+                .stream_headers_count
+                .checked_mul(std::mem::size_of::<SegmentStreamHeaderV1>() as u64)
+                .and_then(|size| size.checked_add(header.stream_headers_offset));
+            if table_end.is_none_or(|end| end > segment.file_size()) {
+                return Err(errors::new_corrupt_segment(
+                    &segment.filename,
+                    "stream header table exceeds file size".to_string(),
+                ));
+            }
+            // V1 headers carry no per-stream entry range, use the segment's as an upper bound
+            let headers = unsafe {
+                std::slice::from_raw_parts(
+                    segment.data().add(header.stream_headers_offset as usize)
+                        as *const SegmentStreamHeaderV1,
+                    header.stream_headers_count as usize,
+                )
+            };
+            segment.reader.as_mut().unwrap().legacy_stream_headers = Some(
+                headers
+                    .iter()
+                    .map(|h| SegmentStreamHeader {
+                        version: SEGMENT_STREAM_HEADER_VERSION_V1,
+                        stream_id: h.stream_id,
+                        offset: h.offset,
+                        file_offset: h.file_offset,
+                        size: h.size,
+                        crc64: h.crc64,
+                        first_entry: header.first_entry,
+                        last_entry: header.last_entry,
+                    })
+                    .collect(),
+            );
+        }
+        if verify {
+            segment.verify_stream_bounds()?;
+        }
         Ok(segment)
     }
 
+    // Check the file digest of the segment file at `file_name` (see
+    // SegmentReader::file_digest) by streaming the whole file, without mapping it,
+    // e.g. once a copy from another node is complete. Fails with
+    // Error::NoFileDigest for segments written without a digest
+    pub fn verify_file_digest(file_name: &path::Path) -> Result<bool> {
+        let io_error = |e| errors::new_segment_io_error(SegmentOp::OpenSegment, file_name, e);
+        let mut file = File::open(file_name).map_err(io_error)?;
+        let file_size = file.metadata().map_err(io_error)?.len();
+        let tail_size = SEGMENT_FILE_DIGEST_SIZE + SEGMENT_TRAILER_SIZE;
+        let Some(digest_at) = file_size
+            .checked_sub(tail_size)
+            .filter(|at| *at >= SEGMENT_HEADER_SIZE)
+        else {
+            return Err(errors::new_no_file_digest(file_name));
+        };
+
+        let mut header = [0u8; SEGMENT_HEADER_SIZE as usize];
+        file.read_exact(&mut header).map_err(io_error)?;
+        let header = unsafe { std::ptr::read_unaligned(header.as_ptr() as *const SegmentHeader) };
+        let mut tail = [0u8; (SEGMENT_FILE_DIGEST_SIZE + SEGMENT_TRAILER_SIZE) as usize];
+        file.seek(io::SeekFrom::Start(digest_at))
+            .map_err(io_error)?;
+        file.read_exact(&mut tail).map_err(io_error)?;
+        let (stored, trailer) = tail.split_at(SEGMENT_FILE_DIGEST_SIZE as usize);
+        // footer layout segments have no digest, their header is not at the start
+        let is_footer_layout = SegmentFooter::parse(&tail, file_size).is_ok_and(|f| f.is_some());
+        if is_footer_layout
+            || header.version != SEGMENT_HEADER_VERSION_V2
+            || header.flags & SEGMENT_FLAG_FILE_DIGEST == 0
+            || trailer[..8] != SEGMENT_TRAILER_MAGIC.to_ne_bytes()
+        {
+            return Err(errors::new_no_file_digest(file_name));
+        }
+
+        file.seek(io::SeekFrom::Start(0)).map_err(io_error)?;
+        let mut digest = FILE_DIGEST_CRC.digest();
+        let mut buf = vec![0u8; SEGMENT_WRITE_CHUNK_SIZE];
+        let mut left = digest_at;
+        while left > 0 {
+            let len = left.min(buf.len() as u64) as usize;
+            file.read_exact(&mut buf[..len]).map_err(io_error)?;
+            digest.update(&buf[..len]);
+            left -= len as u64;
+        }
+        digest.update(trailer);
+        Ok(digest.finalize() == u64::from_ne_bytes(stored.try_into().unwrap()))
+    }
+
+    // Read the segment header and stream header table with positioned reads,
+    // without mapping the file, e.g. to scan a directory of large segments.
+    // The metadata is checked the same way Segment::open checks it
+    pub fn read_metadata(file_name: &path::Path) -> Result<SegmentMetadata> {
+        let io_error = |e| errors::new_segment_io_error(SegmentOp::OpenSegment, file_name, e);
+        let corrupt = |reason: String| errors::new_corrupt_segment_metadata(file_name, reason);
+        let mut file = File::open(file_name).map_err(io_error)?;
+        let file_size = file.metadata().map_err(io_error)?.len();
+        if file_size < SEGMENT_HEADER_SIZE {
+            return Err(errors::new_corrupt_segment(
+                file_name,
+                format!("file size {} is smaller than the segment header", file_size),
+            ));
+        }
+
+        let mut read_at = |offset: u64, len: u64| -> Result<Vec<u8>> {
+            let mut buf = vec![0u8; len as usize];
+            file.seek(io::SeekFrom::Start(offset)).map_err(io_error)?;
+            file.read_exact(&mut buf).map_err(io_error)?;
+            Ok(buf)
+        };
+
+        let tail_size = file_size.min(SEGMENT_FOOTER_SIZE);
+        let tail = read_at(file_size - tail_size, tail_size)?;
+        let footer = SegmentFooter::parse(&tail, file_size).map_err(corrupt)?;
+        let header_offset = footer.map_or(0, |footer| footer.header_offset);
+        let header_bytes = read_at(header_offset, SEGMENT_HEADER_SIZE)?;
+        let header =
+            unsafe { std::ptr::read_unaligned(header_bytes.as_ptr() as *const SegmentHeader) };
+        let stream_header_size = match header.version {
+            SEGMENT_HEADER_VERSION_V1 => std::mem::size_of::<SegmentStreamHeaderV1>() as u64,
+            SEGMENT_HEADER_VERSION_V2 => SEGMENT_STREAM_HEADER_SIZE,
+            version => return Err(corrupt(format!("unknown version {}", version))),
+        };
+        let (table_start, table_limit) = match footer {
+            Some(footer) => (0, footer.header_offset),
+            None => (SEGMENT_HEADER_SIZE, file_size),
+        };
+        let table_size = header
+            .stream_headers_count
+            .checked_mul(stream_header_size)
+            .filter(|size| {
+                header.stream_headers_offset >= table_start
+                    && size
+                        .checked_add(header.stream_headers_offset)
+                        .is_some_and(|end| end <= table_limit)
+            })
+            .ok_or_else(|| {
+                corrupt(format!(
+                    "stream header table ({} headers at offset {}) exceeds file size {}",
+                    header.stream_headers_count, header.stream_headers_offset, file_size
+                ))
+            })?;
+        let table = read_at(header.stream_headers_offset, table_size)?;
+
+        let metadata_crc64 = match footer {
+            Some(footer) => Some(footer.metadata_crc64),
+            None if header.version == SEGMENT_HEADER_VERSION_V2 => {
+                let metadata_end = header.stream_headers_offset + table_size;
+                if file_size < metadata_end + SEGMENT_TRAILER_SIZE {
+                    return Err(corrupt("missing trailer".to_string()));
+                }
+                let trailer_bytes =
+                    read_at(file_size - SEGMENT_TRAILER_SIZE, SEGMENT_TRAILER_SIZE)?;
+                let trailer = unsafe {
+                    std::ptr::read_unaligned(trailer_bytes.as_ptr() as *const SegmentTrailer)
+                };
+                if trailer.magic != SEGMENT_TRAILER_MAGIC {
+                    return Err(corrupt("invalid trailer magic".to_string()));
+                }
+                Some(trailer.metadata_crc64)
+            }
+            None => None,
+        };
+        if metadata_crc64
+            .is_some_and(|crc64| crc64 != SegmentTrailer::new(&header_bytes, &table).metadata_crc64)
+        {
+            return Err(corrupt("metadata checksum mismatch".to_string()));
+        }
+
+        // the stream id follows the version in both stream header layouts
+        let stream_ids = table
+            .chunks_exact(stream_header_size as usize)
+            .map(|stream_header| StreamId::from_ne_bytes(stream_header[8..16].try_into().unwrap()))
+            .collect();
+        Ok(SegmentMetadata {
+            version: header.version,
+            level: header.level,
+            first_entry: header.first_entry,
+            last_entry: header.last_entry,
+            stream_headers_offset: header.stream_headers_offset,
+            stream_headers_count: header.stream_headers_count,
+            data_alignment: header.data_alignment.max(1),
+            stream_index_offset: header.stream_index_offset,
+            stream_index_interval: header.stream_index_interval,
+            stream_header_block_len: header.stream_header_block_len,
+            stream_header_blocks_offset: header.stream_header_blocks_offset,
+            metadata_offset: header.metadata_offset,
+            metadata_size: header.metadata_size,
+            stream_ids,
+        })
+    }
+
+    // Ask the OS to read the whole segment into the page cache ahead of use,
+    // a no-op where madvise is not supported
+    pub fn prefetch(&self) -> Result<()> {
+        self.prefetch_range(0, self.file_size() as usize)
+    }
+
+    // Same as prefetch, limited to the segment header and stream header table
+    // (the end of the file for the footer layout)
+    pub fn prefetch_metadata(&self) -> Result<()> {
+        let header = self.get_segment_header();
+        if self.footer.is_some() {
+            let offset = header.stream_headers_offset.min(self.file_size());
+            return self.prefetch_range(offset as usize, (self.file_size() - offset) as usize);
+        }
+        let stream_header_size = match header.version {
+            SEGMENT_HEADER_VERSION_V1 => std::mem::size_of::<SegmentStreamHeaderV1>() as u64,
+            _ => SEGMENT_STREAM_HEADER_SIZE,
+        };
+        let metadata_size =
+            header.stream_headers_offset + header.stream_headers_count * stream_header_size;
+        self.prefetch_range(0, metadata_size as usize)
+    }
+
+    // Prefetch `len` bytes of the stream's data from stream offset `offset`, clamped
+    // to the part of the stream this segment holds
+    pub fn prefetch_stream(
+        &self,
+        stream_header: &SegmentStreamHeader,
+        offset: u64,
+        len: u64,
+    ) -> Result<()> {
+        let (start, end) = self.data_range(stream_header)?;
+        let start = (start + offset.saturating_sub(stream_header.offset) as usize).min(end);
+        let len = (len as usize).min(end - start);
+        if len == 0 {
+            return Ok(());
+        }
+        self.prefetch_range(start, len)
+    }
+
+    #[cfg(unix)]
+    fn prefetch_range(&self, offset: usize, len: usize) -> Result<()> {
+        self.data
+            .advise_range(memmap2::Advice::WillNeed, offset, len)
+            .map_err(|e| errors::new_segment_io_error(SegmentOp::AdviseSegment, &self.filename, e))
+    }
+
+    #[cfg(not(unix))]
+    fn prefetch_range(&self, _offset: usize, _len: usize) -> Result<()> {
+        Ok(())
+    }
+
+    // Check that the stream header table lies within the file (before the segment
+    // header for the footer layout), and for V2 segments that it matches the
+    // checksum in the trailer or footer
+    fn verify_metadata(&self) -> Result<()> {
+        let corrupt = |reason: String| errors::new_corrupt_segment_metadata(&self.filename, reason);
+
+        // the file holds at least a segment header, checked by from_file_with
+        let file_size = self.file_size();
+        let header = self.get_segment_header();
+        let stream_header_size = match header.version {
+            SEGMENT_HEADER_VERSION_V1 => std::mem::size_of::<SegmentStreamHeaderV1>() as u64,
+            SEGMENT_HEADER_VERSION_V2 => SEGMENT_STREAM_HEADER_SIZE,
+            version => return Err(corrupt(format!("unknown version {}", version))),
+        };
+        let (table_start, table_limit) = match self.footer {
+            Some(footer) => (0, footer.header_offset),
+            None => (SEGMENT_HEADER_SIZE, file_size),
+        };
+        let metadata_end = header
+            .stream_headers_count
+            .checked_mul(stream_header_size)
+            .and_then(|size| size.checked_add(header.stream_headers_offset))
+            .filter(|end| header.stream_headers_offset >= table_start && *end <= table_limit)
+            .ok_or_else(|| {
+                corrupt(format!(
+                    "stream header table ({} headers at offset {}) exceeds file size {}",
+                    header.stream_headers_count, header.stream_headers_offset, file_size
+                ))
+            })?;
+
+        let metadata_crc64 = match self.footer {
+            Some(footer) => footer.metadata_crc64,
+            None if header.version == SEGMENT_HEADER_VERSION_V1 => return Ok(()),
+            None => {
+                if file_size < metadata_end + SEGMENT_TRAILER_SIZE {
+                    return Err(corrupt("missing trailer".to_string()));
+                }
+                let trailer_offset = (file_size - SEGMENT_TRAILER_SIZE) as usize;
+                let trailer = unsafe {
+                    std::ptr::read_unaligned(
+                        self.data().add(trailer_offset) as *const SegmentTrailer
+                    )
+                };
+                if trailer.magic != SEGMENT_TRAILER_MAGIC {
+                    return Err(corrupt("invalid trailer magic".to_string()));
+                }
+                trailer.metadata_crc64
+            }
+        };
+        let header_offset = self.header_offset();
+        let expected = SegmentTrailer::new(
+            &self.data[header_offset..header_offset + SEGMENT_HEADER_SIZE as usize],
+            &self.data[header.stream_headers_offset as usize..metadata_end as usize],
+        );
+        if metadata_crc64 != expected.metadata_crc64 {
+            return Err(corrupt("metadata checksum mismatch".to_string()));
+        }
+        Ok(())
+    }
+
+    // Check that the data of every stream lies within the file
+    fn verify_stream_bounds(&self) -> Result<()> {
+        let data_end = match (self.footer, self.get_segment_header().version) {
+            (Some(footer), _) => footer.header_offset,
+            (None, SEGMENT_HEADER_VERSION_V1) => self.file_size(),
+            _ => self.file_size() - SEGMENT_TRAILER_SIZE,
+        };
+        for stream_header in self.stream_headers()? {
+            if stream_header
+                .file_offset
+                .checked_add(stream_header.size)
+                .is_none_or(|end| end > data_end)
+            {
+                return Err(errors::new_corrupt_segment_metadata(
+                    &self.filename,
+                    format!(
+                        "stream {} data exceeds file size {}",
+                        stream_header.stream_id, data_end
+                    ),
+                ));
+            }
+        }
+        let header = self.get_segment_header();
+        if header.metadata_size > 0
+            && header
+                .metadata_offset
+                .checked_add(header.metadata_size)
+                .is_none_or(|end| end > data_end)
+        {
+            return Err(errors::new_corrupt_segment_metadata(
+                &self.filename,
+                format!(
+                    "metadata ({} bytes at offset {}) exceeds file size {}",
+                    header.metadata_size, header.metadata_offset, data_end
+                ),
+            ));
+        }
+        self.verify_stream_header_blocks()
+    }
+
+    // Check that the block index matches the stream header table it indexes
+    fn verify_stream_header_blocks(&self) -> Result<()> {
+        if self.get_segment_header().stream_header_block_len == 0 {
+            return Ok(());
+        }
+        let headers = self.stream_headers()?;
+        let matches = self
+            .stream_header_blocks()
+            .is_some_and(|(blocks, block_len)| {
+                blocks.len() == headers.len().div_ceil(block_len)
+                    && blocks
+                        .iter()
+                        .zip(headers.iter().step_by(block_len))
+                        .all(|(stream_id, header)| *stream_id == header.stream_id)
+            });
+        if !matches {
+            return Err(errors::new_corrupt_segment_metadata(
+                &self.filename,
+                "stream header block index does not match the stream headers".to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    // find_stream_header binary searches the table, which must be strictly
+    // increasing by stream id
+    fn verify_stream_header_order(&self) -> Result<()> {
+        match self
+            .stream_headers()?
+            .windows(2)
+            .find(|pair| pair[0].stream_id >= pair[1].stream_id)
+        {
+            Some(pair) => Err(errors::new_unsorted_stream_headers(
+                &self.filename,
+                pair[0].stream_id,
+                pair[1].stream_id,
+            )),
+            None => Ok(()),
+        }
+    }
+
     pub fn check_crc(&self) -> Result<bool> {
+        Ok(self.find_crc_mismatch()?.is_none())
+    }
+
+    // Verify the stream header order and the crc of every stream, the error names
+    // the first stream that does not match
+    pub fn validate(&self) -> Result<()> {
+        self.verify_stream_header_order()?;
+        if let Some(stream_header) = self.find_crc_mismatch()? {
+            return Err(errors::new_corrupt_segment(
+                &self.filename,
+                format!("stream {} crc mismatch", stream_header.stream_id),
+            ));
+        }
+        Ok(())
+    }
+
+    // The first stream whose data does not match its crc, the crcs are computed once
+    // for check_crc and validate
+    fn find_crc_mismatch(&self) -> Result<Option<&SegmentStreamHeader>> {
         let header = self.get_segment_header();
-        if header.version != SEGMENT_HEADER_VERSION_V1 {
+        if header.version != SEGMENT_HEADER_VERSION_V1
+            && header.version != SEGMENT_HEADER_VERSION_V2
+        {
             return Err(anyhow::anyhow!(
                 "Invalid segment header version: {}",
                 header.version
             ));
         }
 
-        for stream_header in self.get_stream_headers() {
-            let stream_data = self.stream_data(stream_header.stream_id);
-            if let Some(data) = stream_data {
+        Ok(self.first_crc_mismatch(self.stream_headers()?))
+    }
+
+    // Compute the crc of every stream and write it into the stream header table in
+    // place, for segments written without checksums (crc64 0). The file is mapped
+    // read-write only for the update, the metadata checksum of V2 segments (and
+    // their file digest) is rewritten to match and the file is flushed before the
+    // segment is remapped.
+    // Fails with Error::CrcAlreadySet if any stream already has a crc, so a
+    // segment is never processed twice. Returns the number of streams updated
+    pub fn backfill_crcs(&mut self) -> Result<usize> {
+        let map_error = |e| errors::new_segment_io_error(SegmentOp::MapSegment, &self.filename, e);
+        let write_error =
+            |e| errors::new_segment_io_error(SegmentOp::WriteSegment, &self.filename, e);
+        let stream_headers = self.stream_headers()?;
+        if let Some(stream_header) = stream_headers.iter().find(|header| header.crc64 != 0) {
+            return Err(errors::new_crc_already_set(
+                &self.filename,
+                stream_header.stream_id,
+            ));
+        }
+        let crc64 = Crc::<u64>::new(&crc::CRC_64_REDIS);
+        let crcs = stream_headers
+            .iter()
+            .map(|stream_header| Ok(crc64.checksum(self.stream_header_data(stream_header)?)))
+            .collect::<Result<Vec<_>>>()?;
+
+        let header = self.get_segment_header();
+        let (stride, crc_field) = match header.version {
+            SEGMENT_HEADER_VERSION_V1 => (
+                std::mem::size_of::<SegmentStreamHeaderV1>(),
+                std::mem::offset_of!(SegmentStreamHeaderV1, crc64),
+            ),
+            _ => (
+                SEGMENT_STREAM_HEADER_SIZE as usize,
+                std::mem::offset_of!(SegmentStreamHeader, crc64),
+            ),
+        };
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.filename)
+            .map_err(|e| errors::new_segment_io_error(SegmentOp::OpenSegment, &self.filename, e))?;
+        let mut mmap = unsafe { memmap2::MmapMut::map_mut(&file) }.map_err(map_error)?;
+        if mmap.len() as u64 != self.file_size() {
+            return Err(errors::new_corrupt_segment(
+                &self.filename,
+                "file size changed while backfilling crcs".to_string(),
+            ));
+        }
+
+        let table_start = header.stream_headers_offset as usize;
+        for (index, crc) in crcs.iter().enumerate() {
+            let at = table_start + index * stride + crc_field;
+            mmap[at..at + 8].copy_from_slice(&crc.to_ne_bytes());
+        }
+        // the trailer and the footer both keep the metadata checksum after a u64
+        let checksum_at = match self.footer {
+            Some(_) => Some(
+                mmap.len() - SEGMENT_FOOTER_SIZE as usize
+                    + std::mem::offset_of!(SegmentFooter, metadata_crc64),
+            ),
+            None if header.version == SEGMENT_HEADER_VERSION_V1 => None,
+            None => Some(
+                mmap.len() - SEGMENT_TRAILER_SIZE as usize
+                    + std::mem::offset_of!(SegmentTrailer, metadata_crc64),
+            ),
+        };
+        if let Some(at) = checksum_at {
+            let header_offset = self.header_offset();
+            let table_end = table_start + crcs.len() * stride;
+            let trailer = SegmentTrailer::new(
+                &mmap[header_offset..header_offset + SEGMENT_HEADER_SIZE as usize],
+                &mmap[table_start..table_end],
+            );
+            mmap[at..at + 8].copy_from_slice(&trailer.metadata_crc64.to_ne_bytes());
+        }
+        if self.file_digest().is_some() {
+            let at = mmap.len() - (SEGMENT_FILE_DIGEST_SIZE + SEGMENT_TRAILER_SIZE) as usize;
+            let mut digest = FILE_DIGEST_CRC.digest();
+            digest.update(&mmap[..at]);
+            digest.update(&mmap[at + SEGMENT_FILE_DIGEST_SIZE as usize..]);
+            mmap[at..at + 8].copy_from_slice(&digest.finalize().to_ne_bytes());
+        }
+        mmap.flush().map_err(write_error)?;
+        drop(mmap);
+        drop(file);
+
+        // remap read-only, V1 stream headers are converted again with their crcs.
+        // The original file handle is kept, with the advisory lock it may hold
+        let mut reopened = Self::open_with(&self.filename, true)?;
+        std::mem::swap(&mut self.reader, &mut reopened.reader);
+        log::info!(
+            "Backfilled the crcs of {} streams in segment {}",
+            crcs.len(),
+            self.filename.display()
+        );
+        Ok(crcs.len())
+    }
+
+    // Streams are checked in parallel with the parallel-crc feature, the result is
+    // still the first mismatching stream in header order
+    #[cfg(feature = "parallel-crc")]
+    fn first_crc_mismatch<'a>(
+        &self,
+        stream_headers: &'a [SegmentStreamHeader],
+    ) -> Option<&'a SegmentStreamHeader> {
+        use rayon::prelude::*;
+        stream_headers
+            .par_iter()
+            .find_first(|stream_header| !self.stream_crc_ok(stream_header))
+    }
+
+    #[cfg(not(feature = "parallel-crc"))]
+    fn first_crc_mismatch<'a>(
+        &self,
+        stream_headers: &'a [SegmentStreamHeader],
+    ) -> Option<&'a SegmentStreamHeader> {
+        stream_headers
+            .iter()
+            .find(|stream_header| !self.stream_crc_ok(stream_header))
+    }
+
+    fn stream_crc_ok(&self, stream_header: &SegmentStreamHeader) -> bool {
+        match self.stream_header_data(stream_header) {
+            Ok(data) => {
                 let crc64 = Crc::<u64>::new(&crc::CRC_64_REDIS);
                 let mut hash = crc64.digest();
                 hash.update(data);
-                if hash.finalize() != stream_header.crc64 {
-                    return Ok(false);
-                }
+                hash.finalize() == stream_header.crc64
             }
+            // data outside the file can't match its checksum
+            Err(_) => false,
+        }
+    }
+
+    // What compacting the segment would drop, from the stream headers alone.
+    // Segments record no tombstones of their own, `retention` says what is dead
+    // as for maintain_segments: None for a tombstoned stream, else the offset below
+    // which the stream's data is dropped. Fails if the stream header table does
+    // not fit in the file, see stream_headers
+    pub fn compaction_stats(
+        &self,
+        retention: &dyn Fn(StreamId) -> Option<u64>,
+    ) -> Result<CompactionStats> {
+        let mut stats = CompactionStats::default();
+        for header in self.stream_headers()? {
+            stats.stream_count += 1;
+            let dead = match retention(header.stream_id) {
+                None => {
+                    stats.tombstone_count += 1;
+                    stats.tombstone_bytes += header.size;
+                    header.size
+                }
+                Some(retain_from) => retain_from.saturating_sub(header.offset).min(header.size),
+            };
+            stats.dead_bytes += dead;
+            stats.live_bytes += header.size - dead;
+        }
+        Ok(stats)
+    }
+
+    // Describe the segment header and stream headers, without any stream data
+    pub fn describe(&self) -> SegmentDescription {
+        self.describe_with_preview(0)
+    }
+
+    // Same as describe, with a hex preview of at most `preview_len` data bytes per stream
+    pub fn describe_with_preview(&self, preview_len: usize) -> SegmentDescription {
+        let header = self.get_segment_header();
+        let streams = self
+            .get_stream_headers()
+            .iter()
+            .map(|stream_header| SegmentStreamDescription {
+                stream_id: stream_header.stream_id,
+                offset: stream_header.offset,
+                size: stream_header.size,
+                file_offset: stream_header.file_offset,
+                first_entry: stream_header.first_entry,
+                last_entry: stream_header.last_entry,
+                crc64: stream_header.crc64,
+                crc_ok: self.stream_crc_ok(stream_header),
+                data_preview: if preview_len > 0 {
+                    self.stream_header_data(stream_header).ok().map(|data| {
+                        data.iter()
+                            .take(preview_len)
+                            .map(|b| format!("{:02x}", b))
+                            .collect()
+                    })
+                } else {
+                    None
+                },
+            })
+            .collect();
+
+        SegmentDescription {
+            filename: self.filename.display().to_string(),
+            version: header.version,
+            level: header.level,
+            first_entry: header.first_entry,
+            last_entry: header.last_entry,
+            stream_headers_offset: header.stream_headers_offset,
+            stream_headers_count: header.stream_headers_count,
+            data_alignment: self.data_alignment(),
+            streams,
         }
-        Ok(true)
     }
 
     pub fn set_drop_delete(&self, drop_delete: bool) {
@@ -135,6 +1083,11 @@ impl Segment {
         (header.first_entry, header.last_entry)
     }
 
+    // Process-unique id of this opened segment, a merged or reopened file gets a new one
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
     pub fn filename(&self) -> path::PathBuf {
         self.filename.clone()
     }
@@ -143,45 +1096,203 @@ impl Segment {
         self.get_segment_header().level
     }
 
+    // Alignment of the stream data file offsets, 1 for unaligned segments
+    pub fn data_alignment(&self) -> u64 {
+        self.get_segment_header().data_alignment.max(1)
+    }
+
+    /// A read handle sharing this segment's mapping
+    pub fn reader(&self) -> SegmentReader {
+        (**self).clone()
+    }
+}
+
+impl std::ops::Deref for Segment {
+    type Target = SegmentReader;
+
+    fn deref(&self) -> &SegmentReader {
+        self.reader.as_ref().unwrap()
+    }
+}
+
+impl SegmentReader {
+    pub(crate) fn file_size(&self) -> u64 {
+        self.data.len() as u64
+    }
+
     pub fn get_segment_header(&self) -> SegmentHeader {
-        unsafe { &*(self.data() as *const SegmentHeader) }.clone()
+        unsafe { &*(self.data().add(self.header_offset()) as *const SegmentHeader) }.clone()
+    }
+
+    // Digest of the whole file, a crc64 of every byte of it except the 8 bytes of
+    // the digest itself (stored right before the trailer), to compare copies of
+    // the segment on other nodes in one value, see Segment::verify_file_digest.
+    // None for segments written without one: older files and the footer layout
+    pub fn file_digest(&self) -> Option<u64> {
+        let header = self.get_segment_header();
+        if self.footer.is_some()
+            || header.version != SEGMENT_HEADER_VERSION_V2
+            || header.flags & SEGMENT_FLAG_FILE_DIGEST == 0
+        {
+            return None;
+        }
+        let at =
+            self.file_size()
+                .checked_sub(SEGMENT_FILE_DIGEST_SIZE + SEGMENT_TRAILER_SIZE)? as usize;
+        Some(u64::from_ne_bytes(
+            self.data[at..at + 8].try_into().unwrap(),
+        ))
+    }
+
+    // Whether the segment was written in the footer layout, see SegmentWriter
+    pub fn is_footer_layout(&self) -> bool {
+        self.footer.is_some()
+    }
+
+    // File offset of the segment header, 0 for the header layout
+    fn header_offset(&self) -> usize {
+        self.footer
+            .map_or(0, |footer| footer.header_offset as usize)
     }
 
+    // The stream header table, empty if it does not fit in the file (see stream_headers).
+    // Only for callers that an empty table does no harm (logging, lookups that then
+    // miss), anything that rewrites or accounts segments must use stream_headers
     pub fn get_stream_headers(&self) -> &[SegmentStreamHeader] {
+        self.stream_headers().unwrap_or_else(|e| {
+            log::error!("{}", e);
+            &[]
+        })
+    }
+
+    // The stream header table, an error if the header claims more than the file holds
+    pub fn stream_headers(&self) -> Result<&[SegmentStreamHeader]> {
+        if let Some(headers) = &self.legacy_stream_headers {
+            return Ok(headers);
+        }
         let header = self.get_segment_header();
-        unsafe {
+        let in_bounds = header
+            .stream_headers_count
+            .checked_mul(SEGMENT_STREAM_HEADER_SIZE)
+            .and_then(|size| size.checked_add(header.stream_headers_offset))
+            .is_some_and(|end| end <= self.file_size());
+        let aligned = header.stream_headers_offset as usize
+            % std::mem::align_of::<SegmentStreamHeader>()
+            == 0;
+        if !in_bounds || !aligned {
+            return Err(errors::new_corrupt_segment(
+                &self.filename,
+                format!(
+                    "stream header table ({} headers at offset {}) exceeds file size {}",
+                    header.stream_headers_count,
+                    header.stream_headers_offset,
+                    self.file_size()
+                ),
+            ));
+        }
+        Ok(unsafe {
             std::slice::from_raw_parts(
                 self.data().add(header.stream_headers_offset as usize)
                     as *const SegmentStreamHeader,
                 header.stream_headers_count as usize,
             )
-        }
+        })
+    }
+
+    // Return the ids of the streams that have data from entries in [lo, hi].
+    // V1 segments do not track per-stream entry ids, so all their streams are returned
+    pub fn streams_in_entry_range(&self, lo: u64, hi: u64) -> Vec<StreamId> {
+        let legacy = self.legacy_stream_headers.is_some();
+        self.get_stream_headers()
+            .iter()
+            .filter(|header| legacy || (header.first_entry <= hi && lo <= header.last_entry))
+            .map(|header| header.stream_id)
+            .collect()
     }
 
     pub fn get_stream_range(&self, stream_id: StreamId) -> Option<(u64, u64)> {
+        self.stream_logical_range(stream_id)
+    }
+
+    // The metadata blob the segment was generated with, None if it has none.
+    // Only its bounds are verified on open: the metadata checksum covers the
+    // segment header and stream header table, not the blob, so a corrupt blob is
+    // returned as is. Producers that need to detect that checksum it themselves
+    pub fn metadata(&self) -> Option<&[u8]> {
+        let header = self.get_segment_header();
+        if header.metadata_size == 0 {
+            return None;
+        }
+        let start = header.metadata_offset as usize;
+        self.data
+            .get(start..start.checked_add(header.metadata_size as usize)?)
+    }
+
+    // Stream offsets [offset, offset + size) of the stream's data in this segment.
+    // The data does not start at 0 once earlier data of the stream was compacted
+    // away, reads below `offset` fail with Error::OffsetBelowRetention
+    pub fn stream_logical_range(&self, stream_id: StreamId) -> Option<(u64, u64)> {
         let stream_header = self.find_stream_header(stream_id)?;
-        let offset = stream_header.offset;
-        let size = stream_header.size;
-        Some((offset, offset + size))
+        Some((
+            stream_header.offset,
+            stream_header.offset + stream_header.size,
+        ))
     }
 
+    // Binary search of the stream header table, through the block index of
+    // segments with many streams so that only one block of headers is touched
     pub fn find_stream_header(&self, stream_id: StreamId) -> Option<SegmentStreamHeader> {
-        self.get_stream_headers()
-            .binary_search_by(|b| {
-                if b.stream_id < stream_id {
-                    std::cmp::Ordering::Less
-                } else if b.stream_id > stream_id {
-                    std::cmp::Ordering::Greater
-                } else {
-                    std::cmp::Ordering::Equal
-                }
-            })
+        let headers = self.get_stream_headers();
+        let (begin, end) = match self.stream_header_blocks() {
+            Some((blocks, block_len)) => {
+                let block = blocks
+                    .partition_point(|first| *first <= stream_id)
+                    .checked_sub(1)?;
+                let begin = block * block_len;
+                (begin, (begin + block_len).min(headers.len()))
+            }
+            None => (0, headers.len()),
+        };
+        let block = headers.get(begin..end)?;
+        block
+            .binary_search_by_key(&stream_id, |header| header.stream_id)
             .ok()
-            .map(|index| self.get_stream_headers()[index].clone())
+            .map(|index| block[index].clone())
+    }
+
+    // The block index and the number of stream headers per block, None if the
+    // segment has no block index or it does not fit in the file
+    fn stream_header_blocks(&self) -> Option<(&[StreamId], usize)> {
+        let header = self.get_segment_header();
+        if header.stream_header_block_len == 0 || self.legacy_stream_headers.is_some() {
+            return None;
+        }
+        let count = header
+            .stream_headers_count
+            .div_ceil(header.stream_header_block_len);
+        let in_bounds = count
+            .checked_mul(8)
+            .and_then(|size| size.checked_add(header.stream_header_blocks_offset))
+            .is_some_and(|end| end <= self.file_size());
+        if !in_bounds || header.stream_header_blocks_offset % 8 != 0 {
+            log::error!(
+                "stream header block index of {} exceeds file size {}",
+                self.filename.display(),
+                self.file_size()
+            );
+            return None;
+        }
+        let blocks = unsafe {
+            std::slice::from_raw_parts(
+                self.data().add(header.stream_header_blocks_offset as usize) as *const StreamId,
+                count as usize,
+            )
+        };
+        Some((blocks, header.stream_header_block_len as usize))
     }
 
     fn data(&self) -> *const u8 {
-        self.data.as_ref().unwrap().as_ptr()
+        self.data.as_ptr()
     }
 
     pub fn read_stream(
@@ -190,49 +1301,452 @@ impl Segment {
         offset: u64,
         buf: &mut [u8],
     ) -> io::Result<usize> {
-        let stream_header = self.find_stream_header(stream_id);
-        return match stream_header {
-            Some(stream_header) => {
-                if stream_header.offset <= offset
-                    && offset < stream_header.size + stream_header.offset
-                {
-                    let stream_data = unsafe {
-                        std::slice::from_raw_parts(
-                            self.data().add(stream_header.file_offset as usize) as *const u8,
-                            stream_header.size as usize,
-                        )
-                    };
-
-                    let start = (offset - stream_header.offset) as usize;
-                    let end = (start + buf.len()).min(stream_data.len());
-
-                    let data_to_copy = &stream_data[start..end];
-                    let bytes_to_copy = data_to_copy.len();
-                    if bytes_to_copy == 0 {
-                        return Ok(0); // No data to copy
-                    }
-                    buf[..bytes_to_copy].copy_from_slice(data_to_copy);
-                    Ok(bytes_to_copy)
-                } else {
-                    Ok(0)
-                }
-            }
+        match self.find_stream_header(stream_id) {
+            Some(stream_header) => self.read_stream_with_header(&stream_header, offset, buf),
             None => Err(io::Error::new(
                 io::ErrorKind::NotFound,
                 format!("Stream ID {} not found", stream_id),
             )),
+        }
+    }
+
+    // Same as read_stream, with the stream header already looked up (e.g. cached by the store).
+    // An offset below the stream's base fails with an InvalidInput error wrapping
+    // Error::OffsetBelowRetention
+    pub fn read_stream_with_header(
+        &self,
+        stream_header: &SegmentStreamHeader,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        if offset < stream_header.offset {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                errors::Error::OffsetBelowRetention {
+                    stream_id: stream_header.stream_id,
+                    offset,
+                    base: stream_header.offset,
+                },
+            ));
+        }
+        if offset < stream_header.size + stream_header.offset {
+            let stream_data = self
+                .stream_header_data(stream_header)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+            let start = (offset - stream_header.offset) as usize;
+            let end = (start + buf.len()).min(stream_data.len());
+
+            let data_to_copy = &stream_data[start..end];
+            let bytes_to_copy = data_to_copy.len();
+            if bytes_to_copy == 0 {
+                return Ok(0); // No data to copy
+            }
+            buf[..bytes_to_copy].copy_from_slice(data_to_copy);
+            Ok(bytes_to_copy)
+        } else {
+            Ok(0)
+        }
+    }
+
+    // Copy the stream's data into `w` in bounded chunks, returning the number of bytes written
+    pub fn write_stream_to<W: Write>(&self, stream_id: StreamId, w: &mut W) -> Result<u64> {
+        let data = self
+            .stream_data(stream_id)?
+            .ok_or_else(|| errors::new_stream_not_found(stream_id))?;
+
+        let mut written = 0;
+        for chunk in data.chunks(SEGMENT_WRITE_CHUNK_SIZE) {
+            w.write_all(chunk).map_err(errors::new_io_error)?;
+            written += chunk.len() as u64;
+        }
+        Ok(written)
+    }
+
+    // Return the `size` bytes at stream offset `offset`, None if the stream is not
+    // in this segment. Errors if the range is not fully held by the segment
+    pub fn read_stream_range(
+        &self,
+        stream_id: StreamId,
+        offset: u64,
+        size: u64,
+    ) -> Result<Option<&[u8]>> {
+        let Some(stream_header) = self.find_stream_header(stream_id) else {
+            return Ok(None);
         };
+        let data = self.stream_header_data(&stream_header)?;
+        Ok(Some(stream_slice(&stream_header, data, offset, size)?))
     }
 
-    pub fn stream_data(&self, stream_id: StreamId) -> Option<&[u8]> {
-        let stream_header = self.find_stream_header(stream_id)?;
-        let offset = stream_header.file_offset;
-        let size = stream_header.size;
+    // Same as read_stream_range for each (offset, size) in `ranges`, the stream is
+    // looked up once. Errors if any of the ranges is not fully held by the segment
+    pub fn read_stream_ranges(
+        &self,
+        stream_id: StreamId,
+        ranges: &[(u64, u64)],
+    ) -> Result<Option<Vec<&[u8]>>> {
+        let Some(stream_header) = self.find_stream_header(stream_id) else {
+            return Ok(None);
+        };
+        let data = self.stream_header_data(&stream_header)?;
+        ranges
+            .iter()
+            .map(|&(offset, size)| stream_slice(&stream_header, data, offset, size))
+            .collect::<Result<Vec<_>>>()
+            .map(Some)
+    }
+
+    // File position of the data at stream offset `logical_offset`, found through
+    // the stream offset index (O(log n) in the number of index entries)
+    #[cfg(feature = "stream-index")]
+    pub fn seek_stream(&self, stream_id: StreamId, logical_offset: u64) -> Result<usize> {
+        let headers = self.stream_headers()?;
+        let index = headers
+            .binary_search_by_key(&stream_id, |header| header.stream_id)
+            .map_err(|_| errors::new_stream_not_found(stream_id))?;
+        let header = &headers[index];
+        if logical_offset < header.offset || logical_offset >= header.offset + header.size {
+            return Err(errors::new_stream_offset_invalid(stream_id, logical_offset));
+        }
+
+        let entries = self.stream_index(index, headers.len())?;
+        // segments written without the index hold each stream contiguously
+        let Some(entry) = entries
+            .partition_point(|entry| entry.offset <= logical_offset)
+            .checked_sub(1)
+            .map(|position| entries[position])
+        else {
+            return Ok((header.file_offset + (logical_offset - header.offset)) as usize);
+        };
+        Ok((entry.file_offset + (logical_offset - entry.offset)) as usize)
+    }
+
+    // Index entries of the stream at position `index` of the stream header table
+    #[cfg(feature = "stream-index")]
+    fn stream_index(&self, index: usize, stream_count: usize) -> Result<&[StreamIndexEntry]> {
+        let segment_header = self.get_segment_header();
+        if segment_header.stream_index_offset == 0 {
+            return Ok(&[]);
+        }
+        let corrupt = |reason: String| errors::new_corrupt_segment(&self.filename, reason);
+        let data = &self.data[..];
+        let read_u64 = |position: u64| -> Result<u64> {
+            data.get(position as usize..position as usize + 8)
+                .map(|bytes| u64::from_ne_bytes(bytes.try_into().unwrap()))
+                .ok_or_else(|| corrupt(format!("stream index position {} exceeds file", position)))
+        };
+
+        let starts_offset = segment_header.stream_index_offset;
+        let entries_offset = starts_offset + 8 * (stream_count as u64 + 1);
+        let start = read_u64(starts_offset + 8 * index as u64)?;
+        let end = read_u64(starts_offset + 8 * (index as u64 + 1))?;
+        let range = start
+            .checked_mul(STREAM_INDEX_ENTRY_SIZE)
+            .zip(end.checked_mul(STREAM_INDEX_ENTRY_SIZE))
+            .filter(|(begin, end)| begin <= end)
+            .map(|(begin, end)| (entries_offset + begin, entries_offset + end))
+            .filter(|(_, end)| *end <= self.file_size());
+        let Some((begin, end)) = range else {
+            return Err(corrupt(format!(
+                "stream index entries [{}, {}) exceed file size {}",
+                start,
+                end,
+                self.file_size()
+            )));
+        };
+        // the index region is 8-byte aligned and so are the entries
+        Ok(unsafe {
+            std::slice::from_raw_parts(
+                data.as_ptr().add(begin as usize) as *const StreamIndexEntry,
+                ((end - begin) / STREAM_INDEX_ENTRY_SIZE) as usize,
+            )
+        })
+    }
+
+    // Owned, zero-copy view of the stream's data that keeps the mapping alive,
+    // so it can outlive the segment borrow (e.g. in async response bodies)
+    pub fn stream_bytes(&self, stream_id: StreamId) -> Result<Option<bytes::Bytes>> {
+        let Some(stream_header) = self.find_stream_header(stream_id) else {
+            return Ok(None);
+        };
+        let (start, end) = self.data_range(&stream_header)?;
+        let mmap = SharedMmap(self.data.clone());
+        Ok(Some(bytes::Bytes::from_owner(mmap).slice(start..end)))
+    }
+
+    // The stream's data, None if the stream is not in this segment. Errors if the
+    // stream header points past the end of the file (e.g. a truncated segment).
+    // Stream data is stored as is, so this borrows the mapped file and never
+    // allocates. A stream stored encoded (e.g. compressed) would be decoded into
+    // Cow::Owned, allocating its full size on every call: callers needing the data
+    // more than once should keep it, and callers able to consume it in pieces
+    // should prefer write_stream_to or read_stream
+    pub fn stream_data(&self, stream_id: StreamId) -> Result<Option<Cow<'_, [u8]>>> {
+        match self.find_stream_header(stream_id) {
+            Some(stream_header) => self
+                .stream_header_data(&stream_header)
+                .map(|data| Some(Cow::Borrowed(data))),
+            None => Ok(None),
+        }
+    }
+
+    // Every stream of the segment with its data, in stream id order. Streams whose
+    // data does not fit in the file (only possible after Segment::open_unchecked)
+    // are logged and skipped
+    pub fn iter_streams(&self) -> SegmentStreams<'_> {
+        SegmentStreams {
+            reader: self,
+            headers: self.get_stream_headers().iter(),
+        }
+    }
+
+    fn stream_header_data(&self, stream_header: &SegmentStreamHeader) -> Result<&[u8]> {
+        let (start, end) = self.data_range(stream_header)?;
+        Ok(&self.data[start..end])
+    }
+
+    // File range of the stream's data, checked against the mapped file
+    fn data_range(&self, stream_header: &SegmentStreamHeader) -> Result<(usize, usize)> {
+        match stream_header.file_offset.checked_add(stream_header.size) {
+            Some(end) if end <= self.file_size() => {
+                Ok((stream_header.file_offset as usize, end as usize))
+            }
+            _ => Err(errors::new_corrupt_segment(
+                &self.filename,
+                format!(
+                    "stream {} data [{}, +{}) exceeds file size {}",
+                    stream_header.stream_id,
+                    stream_header.file_offset,
+                    stream_header.size,
+                    self.file_size()
+                ),
+            )),
+        }
+    }
+}
+
+// Iterator of SegmentReader::iter_streams, yields (stream id, data)
+pub struct SegmentStreams<'a> {
+    reader: &'a SegmentReader,
+    headers: std::slice::Iter<'a, SegmentStreamHeader>,
+}
+
+impl<'a> Iterator for SegmentStreams<'a> {
+    type Item = (StreamId, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for header in self.headers.by_ref() {
+            match self.reader.stream_header_data(header) {
+                Ok(data) => return Some((header.stream_id, data)),
+                Err(e) => log::error!("{}", e),
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, self.headers.size_hint().1)
+    }
+}
+
+impl<'a> IntoIterator for &'a SegmentReader {
+    type Item = (StreamId, &'a [u8]);
+    type IntoIter = SegmentStreams<'a>;
+
+    fn into_iter(self) -> SegmentStreams<'a> {
+        self.iter_streams()
+    }
+}
+
+impl<'a> IntoIterator for &'a Segment {
+    type Item = (StreamId, &'a [u8]);
+    type IntoIter = SegmentStreams<'a>;
+
+    fn into_iter(self) -> SegmentStreams<'a> {
+        self.iter_streams()
+    }
+}
+
+// Read-ahead of a sequential stream reader: once the reader gets within half a
+// window of the end of the prefetched data, the next window of the segment is
+// prefetched, overlapping page faults on cold segments with processing
+#[derive(Debug, Clone, Copy)]
+pub struct ReadAhead {
+    window: u64,
+    // stream offset the data is prefetched up to
+    until: u64,
+}
+
+impl ReadAhead {
+    // Prefetch `window` bytes ahead of the reader, 0 disables read-ahead
+    pub fn new(window: u64) -> Self {
+        ReadAhead { window, until: 0 }
+    }
+
+    pub fn window(&self) -> u64 {
+        self.window
+    }
+
+    // Forget the prefetched range, e.g. after a seek
+    pub fn reset(&mut self) {
+        self.until = 0;
+    }
+
+    // Called before reading the stream at `offset` from `segment`. Failures to
+    // prefetch are only logged, the read faults the pages in anyway
+    pub fn advance(&mut self, segment: &Segment, stream_header: &SegmentStreamHeader, offset: u64) {
+        if self.window == 0 || offset + self.window / 2 < self.until {
+            return;
+        }
+        let start = offset.max(self.until);
+        let end = (offset + self.window).min(stream_header.offset + stream_header.size);
+        if start >= end {
+            return;
+        }
+        if let Err(e) = segment.prefetch_stream(stream_header, start, end - start) {
+            log::debug!(
+                "Read-ahead of stream {} in segment {} failed: {:?}",
+                stream_header.stream_id,
+                segment.filename().display(),
+                e
+            );
+        }
+        self.until = end;
+    }
+}
+
+// Reads one stream across a set of segments (e.g. before they are compacted)
+// in stream offset order, optionally continuing into a memtable holding the tail
+// of the stream. Segments that do not hold the stream are skipped
+pub struct SegmentStreamReader {
+    stream_id: StreamId,
+    // segments holding the stream with its header, sorted by stream offset
+    parts: Vec<(Arc<Segment>, SegmentStreamHeader)>,
+    mem_table: Option<MemTableArc>,
+    offset: u64,
+    read_ahead: ReadAhead,
+}
+
+impl SegmentStreamReader {
+    // Reader positioned at the first offset of the stream held by `segments`.
+    // Errors if the segments hold overlapping or non-adjacent parts of the stream
+    pub fn new(segments: &[Arc<Segment>], stream_id: StreamId) -> Result<Self> {
+        let mut parts = segments
+            .iter()
+            .filter_map(|segment| {
+                segment
+                    .find_stream_header(stream_id)
+                    .map(|header| (segment.clone(), header))
+            })
+            .collect::<Vec<_>>();
+        parts.sort_by_key(|(_, header)| header.offset);
+        for pair in parts.windows(2) {
+            let (_, prev) = &pair[0];
+            let (_, next) = &pair[1];
+            if next.offset != prev.offset + prev.size {
+                return Err(errors::new_stream_offset_invalid(stream_id, next.offset));
+            }
+        }
+
+        let offset = parts.first().map(|(_, header)| header.offset).unwrap_or(0);
+        Ok(SegmentStreamReader {
+            stream_id,
+            parts,
+            mem_table: None,
+            offset,
+            read_ahead: ReadAhead::new(DEFAULT_READ_AHEAD),
+        })
+    }
+
+    // Continue reading from `mem_table` after the segments' data. When no segment
+    // holds the stream, reading starts at the start of the stream in the memtable
+    pub fn with_mem_table(mut self, mem_table: MemTableArc) -> Self {
+        if self.parts.is_empty() {
+            if let Some((begin, _)) = mem_table.get_stream_range(self.stream_id) {
+                self.offset = begin;
+            }
+        }
+        self.mem_table = Some(mem_table);
+        self
+    }
 
-        let data = unsafe {
-            std::slice::from_raw_parts(self.data().add(offset as usize) as *const u8, size as usize)
+    // Current stream offset, the next read starts here
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn seek_to(&mut self, offset: u64) {
+        self.offset = offset;
+        self.read_ahead.reset();
+    }
+
+    // Prefetch `window` bytes of segment data ahead of the reader (1 MiB by
+    // default), 0 disables read-ahead
+    pub fn with_read_ahead(mut self, window: u64) -> Self {
+        self.read_ahead = ReadAhead::new(window);
+        self
+    }
+}
+
+impl io::Read for SegmentStreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let index = self
+            .parts
+            .partition_point(|(_, header)| header.offset + header.size <= self.offset);
+        let size = match self.parts.get(index) {
+            Some((segment, header)) if header.offset <= self.offset => {
+                self.read_ahead.advance(segment, header, self.offset);
+                segment.read_stream_with_header(header, self.offset, buf)?
+            }
+            Some(_) => 0,
+            None => match &self.mem_table {
+                Some(mem_table) => match mem_table.get_stream_range(self.stream_id) {
+                    Some((begin, end)) if begin <= self.offset && self.offset < end => {
+                        mem_table.read_stream(self.stream_id, self.offset, buf)?
+                    }
+                    _ => 0,
+                },
+                None => 0,
+            },
         };
-        Some(data)
+        self.offset += size as u64;
+        Ok(size)
+    }
+}
+
+// The `size` bytes at stream offset `offset` of the stream's data
+fn stream_slice<'a>(
+    stream_header: &SegmentStreamHeader,
+    data: &'a [u8],
+    offset: u64,
+    size: u64,
+) -> Result<&'a [u8]> {
+    let stream_id = stream_header.stream_id;
+    let end = offset
+        .checked_add(size)
+        .ok_or_else(|| errors::new_stream_offset_invalid(stream_id, offset))?;
+    if offset < stream_header.offset {
+        return Err(errors::new_offset_below_retention(
+            stream_id,
+            offset,
+            stream_header.offset,
+        ));
+    }
+    if end > stream_header.offset + stream_header.size {
+        return Err(errors::new_stream_offset_invalid(stream_id, offset));
+    }
+    let start = (offset - stream_header.offset) as usize;
+    Ok(&data[start..start + size as usize])
+}
+
+struct SharedMmap(Arc<memmap2::Mmap>);
+
+impl AsRef<[u8]> for SharedMmap {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
     }
 }
 
@@ -241,8 +1755,9 @@ impl Drop for Segment {
         if self.drop_delete.load(atomic::Ordering::Relaxed) {
             log::debug!("Deleting segment file: {}", self.filename.display());
 
-            // Ensure the file and data are properly released
-            self.data.take();
+            // Ensure the file and data are properly released,
+            // readers and bytes from stream_bytes keep the mapping alive until they are dropped
+            self.reader.take();
             self.file.take();
 
             match std::fs::remove_file(&self.filename) {
@@ -264,14 +1779,114 @@ impl Drop for Segment {
 unsafe impl Send for Segment {}
 unsafe impl Sync for Segment {}
 
+fn segment_header_bytes(segment_header: &SegmentHeader) -> &[u8] {
+    unsafe {
+        std::slice::from_raw_parts(
+            segment_header as *const SegmentHeader as *const u8,
+            SEGMENT_HEADER_SIZE as usize,
+        )
+    }
+}
+
+#[cfg(test)]
 pub(crate) fn generate_segment(
     segment_file_path: &path::PathBuf,
     table: &MemTable,
+) -> Result<Segment> {
+    generate_segment_with_alignment(segment_file_path, table, 1, false)
+}
+
+// Same as generate_segment, with every stream's data starting at a multiple of
+// `data_alignment` (a power of two, e.g. 4096 for O_DIRECT reads).
+//
+// With `lock` the temp file is held with an exclusive advisory lock until it
+// is renamed, so a second writer of the same segment (e.g. another process sharing
+// the data directory) fails with Error::SegmentLocked instead of interleaving its
+// writes, and the new segment is opened with Segment::open_locked
+pub(crate) fn generate_segment_with_alignment(
+    segment_file_path: &path::PathBuf,
+    table: &MemTable,
+    data_alignment: u64,
+    lock: bool,
+) -> Result<Segment> {
+    generate_segment_with_metadata(segment_file_path, table, data_alignment, &[], lock)
+}
+
+// Same as generate_segment_with_alignment, storing `metadata` (e.g. a small JSON
+// description of the batch that produced the segment) for Segment::metadata.
+// An empty blob writes no metadata region, the blob is not checksummed. A table
+// without streams fails with Error::EmptyMemtable before any file is created
+pub(crate) fn generate_segment_with_metadata(
+    segment_file_path: &path::PathBuf,
+    table: &MemTable,
+    data_alignment: u64,
+    metadata: &[u8],
+    lock: bool,
+) -> Result<Segment> {
+    generate_segment_with_mode(
+        segment_file_path,
+        table,
+        data_alignment,
+        metadata,
+        lock,
+        SegmentWriteMode::default(),
+    )
+}
+
+// How generated segments are written to their file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SegmentWriteMode {
+    // buffered write and write_vectored calls
+    Syscall,
+    // the file is sized up front, mapped and filled through the map: one copy of
+    // the stream data and no syscall per chunk, which pays off on large memtables.
+    // Falls back to Syscall if the file cannot be sized or mapped
+    Mmap,
+}
+
+impl Default for SegmentWriteMode {
+    fn default() -> Self {
+        if cfg!(feature = "mmap-write") {
+            SegmentWriteMode::Mmap
+        } else {
+            SegmentWriteMode::Syscall
+        }
+    }
+}
+
+fn generate_segment_with_mode(
+    segment_file_path: &path::PathBuf,
+    table: &MemTable,
+    data_alignment: u64,
+    metadata: &[u8],
+    lock: bool,
+    mode: SegmentWriteMode,
 ) -> Result<Segment> {
     assert!(align_of::<SegmentHeader>() <= 8);
+    assert!(
+        data_alignment.is_power_of_two(),
+        "data alignment must be a power of two"
+    );
+    if table.stream_count() == 0 {
+        return Err(errors::new_empty_memtable());
+    }
 
     let temp_file_path = segment_file_path.with_extension("tmp");
-    let mut file = File::create(&temp_file_path).map_err(errors::new_io_error)?;
+    let write_error =
+        |e| errors::new_segment_io_error(SegmentOp::WriteSegment, &temp_file_path, e);
+    // readable too, the mmap write path maps the file
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(!lock)
+        .open(&temp_file_path)
+        .map_err(write_error)?;
+    if lock {
+        // truncate only once the lock is held, the file may be another writer's
+        lock_file(&file, &temp_file_path, true)?;
+        file.set_len(0).map_err(write_error)?;
+    }
 
     let mut segment_stream_headers = Vec::new();
 
@@ -291,7 +1906,10 @@ pub(crate) fn generate_segment(
         .get_stream_tables()
         .iter()
         .for_each(|(_, stream_table)| {
+            let (first_entry, last_entry) = stream_table.entry_range();
             let stream_header = SegmentStreamHeader {
+                first_entry,
+                last_entry,
                 size: stream_table.size(),
                 crc64: stream_table.crc64(),
                 offset: stream_table.offset(),
@@ -302,13 +1920,22 @@ pub(crate) fn generate_segment(
         });
     segment_stream_headers.sort_by(|a, b| a.stream_id.cmp(&b.stream_id));
 
-    let segment_header = SegmentHeader {
+    let mut segment_header = SegmentHeader {
         first_entry: table.get_first_entry(),
         last_entry: table.get_last_entry(),
         stream_headers_count: segment_stream_headers.len() as u64,
+        data_alignment,
+        flags: SEGMENT_FLAG_FILE_DIGEST,
         ..Default::default()
     };
 
+    let metadata_end = plan_stream_header_blocks(&mut segment_header);
+    let metadata_end = plan_metadata(&mut segment_header, metadata_end, metadata);
+    let metadata_end =
+        assign_file_offsets(&mut segment_stream_headers, data_alignment, metadata_end);
+    #[cfg(feature = "stream-index")]
+    plan_stream_index(&mut segment_header, &segment_stream_headers, metadata_end);
+
     log::debug!(
         "Segment {} Header: first_entry: {}, last_entry: {}, stream_headers_count: {}",
         segment_file_path.display(),
@@ -317,179 +1944,799 @@ pub(crate) fn generate_segment(
         segment_header.stream_headers_count
     );
 
-    // Write the segment stream headers to the file
-    file.write_all(unsafe {
-        std::slice::from_raw_parts(
-            &segment_header as *const SegmentHeader as *const u8,
-            SEGMENT_HEADER_SIZE as usize,
+    let write = |file: &mut dyn Write| {
+        write_table_segment(
+            file,
+            table,
+            &segment_header,
+            &segment_stream_headers,
+            metadata,
+            metadata_end,
         )
-    })
-    .map_err(errors::new_io_error)?;
+    };
+    let mapped = match mode {
+        SegmentWriteMode::Mmap => {
+            let file_size =
+                planned_file_size(&segment_header, &segment_stream_headers, metadata_end);
+            match MmapWriter::map(&file, file_size) {
+                Ok(mut writer) => {
+                    write(&mut writer).map_err(write_error)?;
+                    writer.finish().map_err(write_error)?;
+                    true
+                }
+                Err(e) => {
+                    log::warn!(
+                        "Failed to map segment {} for writing, writing it with syscalls: {}",
+                        temp_file_path.display(),
+                        e
+                    );
+                    file.set_len(0).map_err(write_error)?;
+                    false
+                }
+            }
+        }
+        SegmentWriteMode::Syscall => false,
+    };
+    if !mapped {
+        write(&mut file).map_err(write_error)?;
+    }
 
-    let mut offset = SEGMENT_HEADER_SIZE as u64;
-    offset += SEGMENT_STREAM_HEADER_SIZE as u64 * segment_stream_headers.len() as u64;
+    // flush the file to disk
+    file.flush().map_err(write_error)?;
+    file.sync_all().map_err(write_error)?;
 
-    // update the file offset
-    for stream_header in segment_stream_headers.iter_mut() {
-        stream_header.file_offset = offset;
-        offset += stream_header.size;
+    // rename the file, still holding the lock, then close it
+    std::fs::rename(&temp_file_path, segment_file_path).map_err(|e| {
+        errors::new_segment_io_error(SegmentOp::RenameSegment, segment_file_path, e)
+    })?;
+    drop(file);
+
+    let segment = if lock {
+        Segment::open_locked(segment_file_path)?
+    } else {
+        Segment::open(segment_file_path)?
+    };
+    // the table is no longer appended to, a size out of sync with its streams is a
+    // bug in the append path
+    if cfg!(debug_assertions)
+        && let Err(e) = table.audit()
+    {
+        panic!("memtable flushed to {}: {}", segment_file_path.display(), e);
     }
+    table.notify_flushed();
+    Ok(segment)
+}
 
-    let stream_header = segment_stream_headers.as_ptr() as *const SegmentStreamHeader;
+// Write a segment of `table` planned by generate_segment_with_mode to `file`,
+// from the segment header to the trailer. `metadata_end` is the end of the
+// metadata, where the stream data is laid out from
+fn write_table_segment(
+    file: &mut dyn Write,
+    table: &MemTable,
+    segment_header: &SegmentHeader,
+    stream_headers: &[SegmentStreamHeader],
+    metadata: &[u8],
+    metadata_end: u64,
+) -> io::Result<()> {
+    let mut written = metadata_end;
+    let mut writer = DigestWriter::new(file);
+    writer.write_all(segment_header_bytes(segment_header))?;
+
+    let stream_header = stream_headers.as_ptr() as *const SegmentStreamHeader;
     let data = unsafe {
         std::slice::from_raw_parts(
             stream_header as *const SegmentStreamHeader as *const u8,
-            SEGMENT_STREAM_HEADER_SIZE as usize * segment_stream_headers.len() as usize,
+            SEGMENT_STREAM_HEADER_SIZE as usize * stream_headers.len() as usize,
         )
     };
-    file.write_all(data).map_err(errors::new_io_error)?;
+    writer.write_all(data)?;
+    write_stream_header_blocks(&mut writer, segment_header, stream_headers)?;
+    writer.write_all(metadata)?;
+    let trailer = SegmentTrailer::new(segment_header_bytes(segment_header), data);
 
     // Verify that the segment stream headers are written correctly
     {
         let temp_stream_headers = unsafe {
             std::slice::from_raw_parts(
                 data.as_ptr() as *const SegmentStreamHeader,
-                segment_stream_headers.len(),
+                stream_headers.len(),
             )
         };
-        assert!(temp_stream_headers.len() == segment_stream_headers.len());
+        assert!(temp_stream_headers.len() == stream_headers.len());
         for (i, stream_header) in temp_stream_headers.iter().enumerate() {
-            assert!(stream_header.stream_id == segment_stream_headers[i].stream_id);
-            assert!(stream_header.file_offset == segment_stream_headers[i].file_offset);
-            assert!(stream_header.size == segment_stream_headers[i].size);
-            assert!(stream_header.offset == segment_stream_headers[i].offset);
-            assert!(stream_header.version == segment_stream_headers[i].version);
-            assert!(stream_header.crc64 == segment_stream_headers[i].crc64);
+            assert!(stream_header.stream_id == stream_headers[i].stream_id);
+            assert!(stream_header.file_offset == stream_headers[i].file_offset);
+            assert!(stream_header.size == stream_headers[i].size);
+            assert!(stream_header.offset == stream_headers[i].offset);
+            assert!(stream_header.version == stream_headers[i].version);
+            assert!(stream_header.crc64 == stream_headers[i].crc64);
+            assert!(stream_header.first_entry == stream_headers[i].first_entry);
+            assert!(stream_header.last_entry == stream_headers[i].last_entry);
         }
     }
 
-    // Write the stream data to the file
-    for (_, stream_table) in table.get_stream_tables().iter() {
-        for stream_data in stream_table.stream_datas() {
-            file.write_all(unsafe {
-                std::slice::from_raw_parts(
-                    stream_data.data().as_ptr() as *const u8,
-                    stream_data.size() as usize,
-                )
+    // Write the stream data to the file, in the same order as the stream headers
+    let stream_tables = table.get_stream_tables();
+    for stream_header in stream_headers.iter() {
+        write_padding(&mut writer, &mut written, stream_header.file_offset)?;
+        written += stream_header.size;
+        let stream_table = &stream_tables[&stream_header.stream_id];
+        let mut slices = stream_table
+            .stream_datas()
+            .filter(|stream_data| stream_data.size() > 0)
+            .map(|stream_data| {
+                IoSlice::new(unsafe {
+                    std::slice::from_raw_parts(
+                        stream_data.data().as_ptr() as *const u8,
+                        stream_data.size() as usize,
+                    )
+                })
             })
-            .map_err(errors::new_io_error)?;
-        }
+            .collect::<Vec<_>>();
+        write_all_vectored(&mut writer, &mut slices)?;
     }
+    drop(stream_tables);
+    #[cfg(feature = "stream-index")]
+    write_stream_index(&mut writer, &mut written, segment_header, stream_headers)?;
+    writer.finish(&trailer)?;
+    Ok(())
+}
 
-    // flush the file to disk
-    file.flush().map_err(errors::new_io_error)?;
-    file.sync_all().map_err(errors::new_io_error)?;
-
-    // close the file
-    drop(file);
-
-    // rename the file
-    std::fs::rename(temp_file_path, segment_file_path).map_err(errors::new_io_error)?;
-
-    Ok(Segment::open(segment_file_path)?)
+// Size of the file write_table_segment writes: the stream data or, when the
+// segment has one, the stream index ends it, followed by the digest and trailer
+#[cfg_attr(not(feature = "stream-index"), allow(unused_variables))]
+fn planned_file_size(
+    segment_header: &SegmentHeader,
+    stream_headers: &[SegmentStreamHeader],
+    metadata_end: u64,
+) -> u64 {
+    let end = stream_headers
+        .iter()
+        .map(|header| header.file_offset + header.size)
+        .max()
+        .unwrap_or(metadata_end);
+    #[cfg(feature = "stream-index")]
+    let end = if segment_header.stream_index_offset == 0 {
+        end
+    } else {
+        let entries = stream_headers
+            .iter()
+            .map(|header| header.size.div_ceil(segment_header.stream_index_interval))
+            .sum::<u64>();
+        segment_header.stream_index_offset
+            + 8 * (stream_headers.len() as u64 + 1)
+            + STREAM_INDEX_ENTRY_SIZE * entries
+    };
+    end + SEGMENT_FILE_DIGEST_SIZE + SEGMENT_TRAILER_SIZE
 }
 
-pub(crate) fn merge_segments(
-    segment_file_path: &path::PathBuf,
-    segments: &[SegmentArc],
-) -> Result<Segment> {
-    assert!(!segments.is_empty(), "No segments to merge");
+// Writes a segment file through a writable map of it, sized up front
+struct MmapWriter {
+    map: memmap2::MmapMut,
+    pos: usize,
+}
 
-    assert!(align_of::<SegmentHeader>() <= 8);
+impl MmapWriter {
+    fn map(file: &File, size: u64) -> io::Result<MmapWriter> {
+        file.set_len(size)?;
+        let map = unsafe { memmap2::MmapMut::map_mut(file) }?;
+        Ok(MmapWriter { map, pos: 0 })
+    }
 
-    let begin = std::time::Instant::now();
-    let temp_file_path = segment_file_path.with_extension("tmp");
+    // Flush the map to the file, every byte of it must have been written
+    fn finish(self) -> io::Result<()> {
+        if self.pos != self.map.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "segment size mismatch: {} bytes written, {} planned",
+                    self.pos,
+                    self.map.len()
+                ),
+            ));
+        }
+        self.map.flush()
+    }
+}
 
-    let mut file = File::create(&temp_file_path).map_err(errors::new_io_error)?;
+impl Write for MmapWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.map.len() - self.pos);
+        self.map[self.pos..self.pos + len].copy_from_slice(&buf[..len]);
+        self.pos += len;
+        Ok(len)
+    }
 
-    // delete temp file if errors happen
-    let temp_filename_clone = temp_file_path.clone();
-    defer::defer!({
-        // check if the file exists
-        if std::fs::metadata(&temp_filename_clone).is_ok() {
-            // delete the file
-            if std::fs::remove_file(&temp_filename_clone).is_err() {
-                log::warn!("Failed to delete temp file: {:?}", &temp_filename_clone);
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let mut written = 0;
+        for buf in bufs {
+            let len = self.write(buf)?;
+            written += len;
+            if len < buf.len() {
+                break;
             }
         }
-    });
+        Ok(written)
+    }
 
-    // Calculate the CRC64 checksum for the segment data
-    // Use the Redis CRC64 algorithm
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
 
-    let mut segment_data_map = HashMap::new();
+/// Writer of segments in the footer layout, for producers that build a segment in
+/// a single pass: the data of each stream is written as soon as it is added, in
+/// any stream order, and finish appends the sorted stream header table, the
+/// segment header and a footer pointing back at the header. Segment::open reads
+/// both layouts.
+///
+/// The segment is written to a temp file next to its path and renamed by finish,
+/// the temp file of an unfinished writer is removed when it is dropped.
+pub struct SegmentWriter {
+    path: path::PathBuf,
+    temp_file_path: path::PathBuf,
+    file: File,
+    written: u64,
+    level: u32,
+    stream_headers: Vec<SegmentStreamHeader>,
+    stream_ids: HashSet<StreamId>,
+}
 
-    let mut header_map = HashMap::new();
+impl SegmentWriter {
+    pub fn create(path: &path::Path) -> Result<SegmentWriter> {
+        let temp_file_path = path.with_extension("tmp");
+        let file = File::create(&temp_file_path).map_err(|e| {
+            errors::new_segment_io_error(SegmentOp::WriteSegment, &temp_file_path, e)
+        })?;
+        Ok(SegmentWriter {
+            path: path.to_path_buf(),
+            temp_file_path,
+            file,
+            written: 0,
+            level: 0,
+            stream_headers: Vec::new(),
+            stream_ids: HashSet::new(),
+        })
+    }
 
-    for segment in segments.iter() {
-        for header in segment.get_stream_headers() {
-            header_map
-                .entry(&header.stream_id)
-                .or_insert_with(|| SegmentStreamHeader {
-                    version: SEGMENT_STREAM_HEADER_VERSION_V1,
-                    offset: header.offset,
-                    stream_id: header.stream_id,
-                    ..Default::default()
-                })
-                .size += header.size;
+    // Level recorded in the segment header, 0 by default
+    pub fn set_level(&mut self, level: u32) {
+        self.level = level;
+    }
 
-            let entry = segment_data_map
-                .entry(&header.stream_id)
-                .or_insert_with(|| Vec::new());
-            let data = segment.stream_data(header.stream_id).unwrap();
-            entry.push(data);
+    // Append the data of a stream, starting at stream offset `offset` and holding
+    // data of the entries in `entry_range`. Every stream can be added once, a
+    // second add fails with Error::AlreadyExists
+    pub fn add_stream(
+        &mut self,
+        stream_id: StreamId,
+        offset: u64,
+        entry_range: (u64, u64),
+        data: &[u8],
+    ) -> Result<()> {
+        if self.stream_ids.contains(&stream_id) {
+            return Err(errors::new_already_exists());
         }
-    }
+        self.file.write_all(data).map_err(|e| {
+            errors::new_segment_io_error(SegmentOp::WriteSegment, &self.temp_file_path, e)
+        })?;
 
-    for (stream_id, datas) in segment_data_map.iter() {
         let crc64 = Crc::<u64>::new(&crc::CRC_64_REDIS);
         let mut hash = crc64.digest();
-        for data in datas.iter() {
-            hash.update(data);
-        }
-        header_map.get_mut(stream_id).unwrap().crc64 = hash.finalize();
+        hash.update(data);
+        self.stream_headers.push(SegmentStreamHeader {
+            stream_id,
+            offset,
+            file_offset: self.written,
+            size: data.len() as u64,
+            crc64: hash.finalize(),
+            first_entry: entry_range.0,
+            last_entry: entry_range.1,
+            ..Default::default()
+        });
+        self.stream_ids.insert(stream_id);
+        self.written += data.len() as u64;
+        Ok(())
     }
 
-    let mut segment_stream_headers = header_map
-        .into_iter()
-        .map(|(_, header)| header)
-        .collect::<Vec<SegmentStreamHeader>>();
+    // Append the metadata, rename the file into place and open the segment
+    pub fn finish(mut self) -> Result<Segment> {
+        let temp_file_path = self.temp_file_path.clone();
+        let write_error =
+            |e| errors::new_segment_io_error(SegmentOp::WriteSegment, &temp_file_path, e);
 
-    segment_stream_headers.sort_by(|a, b| a.stream_id.cmp(&b.stream_id));
+        self.stream_headers.sort_by_key(|header| header.stream_id);
+        let mut segment_header = SegmentHeader {
+            level: self.level,
+            first_entry: self
+                .stream_headers
+                .iter()
+                .fold(0, |first, header| min_entry(first, header.first_entry)),
+            last_entry: self
+                .stream_headers
+                .iter()
+                .map(|header| header.last_entry)
+                .max()
+                .unwrap_or(0),
+            // stream headers are read in place, the table must be 8 byte aligned
+            stream_headers_offset: self.written.next_multiple_of(8),
+            stream_headers_count: self.stream_headers.len() as u64,
+            ..Default::default()
+        };
+        // the table and block index are a multiple of 8 bytes, so is the header offset
+        let header_offset = plan_stream_header_blocks(&mut segment_header);
 
-    let segment_header = SegmentHeader {
-        level: segments[0].get_segment_header().level + 1,
-        first_entry: segments[0].get_segment_header().first_entry,
-        last_entry: segments.last().unwrap().get_segment_header().last_entry,
-        stream_headers_count: segment_stream_headers.len() as u64,
-        ..Default::default()
-    };
+        write_padding(
+            &mut self.file,
+            &mut self.written,
+            segment_header.stream_headers_offset,
+        )
+        .map_err(write_error)?;
+        let table = unsafe {
+            std::slice::from_raw_parts(
+                self.stream_headers.as_ptr() as *const u8,
+                SEGMENT_STREAM_HEADER_SIZE as usize * self.stream_headers.len(),
+            )
+        };
+        self.file.write_all(table).map_err(write_error)?;
+        write_stream_header_blocks(&mut self.file, &segment_header, &self.stream_headers)
+            .map_err(write_error)?;
+        let header = segment_header_bytes(&segment_header);
+        self.file.write_all(header).map_err(write_error)?;
+        let footer = SegmentFooter {
+            header_offset,
+            metadata_crc64: SegmentTrailer::new(header, table).metadata_crc64,
+            magic: SEGMENT_FOOTER_MAGIC,
+        };
+        self.file
+            .write_all(footer.as_bytes())
+            .map_err(write_error)?;
 
-    log::debug!(
-        "Segment {} Header: first_entry: {}, last_entry: {}, stream_headers_count: {}",
-        segment_file_path.display(),
-        segment_header.first_entry,
-        segment_header.last_entry,
-        segment_header.stream_headers_count
-    );
+        self.file.flush().map_err(write_error)?;
+        self.file.sync_all().map_err(write_error)?;
+        std::fs::rename(&self.temp_file_path, &self.path)
+            .map_err(|e| errors::new_segment_io_error(SegmentOp::RenameSegment, &self.path, e))?;
+        Segment::open(&self.path)
+    }
+}
 
-    // Write the segment stream headers to the file
-    file.write_all(unsafe {
-        std::slice::from_raw_parts(
-            &segment_header as *const SegmentHeader as *const u8,
-            SEGMENT_HEADER_SIZE as usize,
-        )
-    })
-    .map_err(errors::new_io_error)?;
+impl Drop for SegmentWriter {
+    fn drop(&mut self) {
+        // finish renamed the temp file, if it is still there the segment is unfinished
+        if std::fs::metadata(&self.temp_file_path).is_ok()
+            && std::fs::remove_file(&self.temp_file_path).is_err()
+        {
+            log::warn!("Failed to delete temp file: {:?}", &self.temp_file_path);
+        }
+    }
+}
 
-    let mut offset = SEGMENT_HEADER_SIZE as u64;
-    offset += SEGMENT_STREAM_HEADER_SIZE as u64 * segment_stream_headers.len() as u64;
+// Take an advisory lock on the file without waiting, exclusive for writers and
+// shared for readers. It is released when the file is closed
+fn lock_file(file: &File, path: &path::Path, exclusive: bool) -> Result<()> {
+    // fully qualified, std::fs::File has inherent methods of the same names
+    let locked = if exclusive {
+        fs2::FileExt::try_lock_exclusive(file)
+    } else {
+        fs2::FileExt::try_lock_shared(file)
+    };
+    locked.map_err(|e| {
+        if e.kind() == fs2::lock_contended_error().kind() {
+            errors::new_segment_locked(path)
+        } else {
+            errors::new_segment_io_error(SegmentOp::OpenSegment, path, e)
+        }
+    })
+}
 
-    // update the file offset
-    for stream_header in segment_stream_headers.iter_mut() {
+// Lay out the stream data after the header table, each stream starting at a
+// multiple of `data_alignment`. Returns the end of the header table
+fn assign_file_offsets(
+    stream_headers: &mut [SegmentStreamHeader],
+    data_alignment: u64,
+    metadata_end: u64,
+) -> u64 {
+    let mut offset = metadata_end;
+    for stream_header in stream_headers.iter_mut() {
+        offset = offset.next_multiple_of(data_alignment);
         stream_header.file_offset = offset;
         offset += stream_header.size;
     }
+    metadata_end
+}
+
+// Place the stream header block index after the stream header table if the
+// segment has enough streams to need one, returns the end of the metadata
+fn plan_stream_header_blocks(segment_header: &mut SegmentHeader) -> u64 {
+    let table_end = segment_header.stream_headers_offset
+        + SEGMENT_STREAM_HEADER_SIZE * segment_header.stream_headers_count;
+    if segment_header.stream_headers_count <= STREAM_HEADER_BLOCK_THRESHOLD {
+        return table_end;
+    }
+    segment_header.stream_header_block_len = STREAM_HEADER_BLOCK_LEN;
+    segment_header.stream_header_blocks_offset = table_end;
+    table_end
+        + 8 * segment_header
+            .stream_headers_count
+            .div_ceil(STREAM_HEADER_BLOCK_LEN)
+}
+
+// Place the metadata blob at `metadata_end`, returns the new end of the metadata
+fn plan_metadata(segment_header: &mut SegmentHeader, metadata_end: u64, metadata: &[u8]) -> u64 {
+    if metadata.is_empty() {
+        return metadata_end;
+    }
+    segment_header.metadata_offset = metadata_end;
+    segment_header.metadata_size = metadata.len() as u64;
+    metadata_end + metadata.len() as u64
+}
+
+// Write the block index planned by plan_stream_header_blocks, right after the
+// stream header table
+fn write_stream_header_blocks<W: Write>(
+    file: &mut W,
+    segment_header: &SegmentHeader,
+    stream_headers: &[SegmentStreamHeader],
+) -> io::Result<()> {
+    if segment_header.stream_header_block_len == 0 {
+        return Ok(());
+    }
+    let blocks = stream_headers
+        .iter()
+        .step_by(segment_header.stream_header_block_len as usize)
+        .flat_map(|header| header.stream_id.to_ne_bytes())
+        .collect::<Vec<_>>();
+    file.write_all(&blocks)
+}
+
+// The smaller of two entry ids, where 0 means no entry
+fn min_entry(a: u64, b: u64) -> u64 {
+    match (a, b) {
+        (0, b) => b,
+        (a, 0) => a,
+        (a, b) => a.min(b),
+    }
+}
+
+// Reserve the stream offset index region after the stream data of `stream_headers`
+#[cfg(feature = "stream-index")]
+fn plan_stream_index(
+    segment_header: &mut SegmentHeader,
+    stream_headers: &[SegmentStreamHeader],
+    metadata_end: u64,
+) {
+    let data_end = stream_headers
+        .iter()
+        .map(|header| header.file_offset + header.size)
+        .max()
+        .unwrap_or(metadata_end);
+    segment_header.stream_index_offset = data_end.next_multiple_of(8);
+    segment_header.stream_index_interval = STREAM_INDEX_INTERVAL;
+}
+
+// Write the stream offset index planned by plan_stream_index: an entry every
+// stream_index_interval bytes of each stream
+#[cfg(feature = "stream-index")]
+fn write_stream_index<W: Write>(
+    file: &mut W,
+    written: &mut u64,
+    segment_header: &SegmentHeader,
+    stream_headers: &[SegmentStreamHeader],
+) -> io::Result<()> {
+    let interval = segment_header.stream_index_interval;
+    write_padding(file, written, segment_header.stream_index_offset)?;
+
+    let mut start = 0u64;
+    let mut starts = Vec::with_capacity((stream_headers.len() + 1) * 8);
+    starts.extend_from_slice(&start.to_ne_bytes());
+    for header in stream_headers {
+        start += header.size.div_ceil(interval);
+        starts.extend_from_slice(&start.to_ne_bytes());
+    }
+    file.write_all(&starts)?;
+    *written += starts.len() as u64;
+
+    let mut entries = Vec::new();
+    for header in stream_headers {
+        for position in (0..header.size).step_by(interval as usize) {
+            // stream data is stored uncompressed, so the file position follows the offset
+            let entry = StreamIndexEntry {
+                offset: header.offset + position,
+                file_offset: header.file_offset + position,
+            };
+            entries.extend_from_slice(&entry.offset.to_ne_bytes());
+            entries.extend_from_slice(&entry.file_offset.to_ne_bytes());
+        }
+    }
+    file.write_all(&entries)?;
+    *written += entries.len() as u64;
+    Ok(())
+}
+
+// Write all slices with as few syscalls as possible, at most IOV_MAX slices per
+// write_vectored call, handling partial writes
+fn write_all_vectored<W: Write>(writer: &mut W, mut slices: &mut [IoSlice<'_>]) -> io::Result<()> {
+    IoSlice::advance_slices(&mut slices, 0);
+    while !slices.is_empty() {
+        let batch = slices.len().min(WRITE_VECTORED_MAX_SLICES);
+        match writer.write_vectored(&slices[..batch]) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write whole buffer",
+                ));
+            }
+            Ok(n) => IoSlice::advance_slices(&mut slices, n),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// Write zeros from `written` up to `file_offset`
+fn write_padding<W: Write>(file: &mut W, written: &mut u64, file_offset: u64) -> io::Result<()> {
+    const ZEROS: [u8; 4096] = [0; 4096];
+    while *written < file_offset {
+        let len = (file_offset - *written).min(ZEROS.len() as u64);
+        file.write_all(&ZEROS[..len as usize])?;
+        *written += len;
+    }
+    Ok(())
+}
+
+// Writer of segment files that hashes everything written through it into the
+// file digest, see SegmentReader::file_digest
+struct DigestWriter<W: Write> {
+    file: W,
+    digest: crc::Digest<'static, u64>,
+}
+
+impl<W: Write> DigestWriter<W> {
+    fn new(file: W) -> Self {
+        DigestWriter {
+            file,
+            digest: FILE_DIGEST_CRC.digest(),
+        }
+    }
+
+    // Write the file digest and the trailer that follows it, the digest covers
+    // every byte of the file but its own
+    fn finish(mut self, trailer: &SegmentTrailer) -> io::Result<u64> {
+        self.digest.update(trailer.as_bytes());
+        let digest = self.digest.finalize();
+        self.file.write_all(&digest.to_ne_bytes())?;
+        self.file.write_all(trailer.as_bytes())?;
+        Ok(digest)
+    }
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.file.write(buf)?;
+        self.digest.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let n = self.file.write_vectored(bufs)?;
+        let mut left = n;
+        for buf in bufs {
+            let len = buf.len().min(left);
+            self.digest.update(&buf[..len]);
+            left -= len;
+            if left == 0 {
+                break;
+            }
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+// Merge all `segments` into one, one level above the first. `lock` holds the
+// files as generate_segment_with_alignment does
+pub(crate) fn merge_segments(
+    segment_file_path: &path::PathBuf,
+    segments: &[SegmentArc],
+    lock: bool,
+) -> Result<Segment> {
+    assert!(!segments.is_empty(), "No segments to merge");
+    let level = segments[0].get_segment_header().level + 1;
+    // the inputs' metadata describe the batches that produced them, the merged
+    // segment has none of its own
+    write_merged_segment(segment_file_path, segments, level, &[], &|_| Some(0), lock)
+}
+
+// Merge all `segments` into one, keeping of each stream only what `retention`
+// says: None drops the stream, Some(offset) drops its data below offset. The
+// merged segment takes the highest level of the inputs
+pub(crate) fn maintain_segments(
+    segment_file_path: &path::PathBuf,
+    segments: &[SegmentArc],
+    retention: &dyn Fn(StreamId) -> Option<u64>,
+    lock: bool,
+) -> Result<Segment> {
+    assert!(!segments.is_empty(), "No segments to maintain");
+    let level = segments
+        .iter()
+        .map(|segment| segment.level())
+        .max()
+        .unwrap();
+    write_merged_segment(segment_file_path, segments, level, &[], retention, lock)
+}
+
+// Rewrite a segment whose stream header table is not sorted by stream id (e.g.
+// written by an older or third-party writer) into `output`, with a sorted table.
+// Headers of the same stream are joined like a merge joins them, the segment
+// keeps its level, entry range and metadata
+pub fn repair_segment(input: &path::PathBuf, output: &path::PathBuf) -> Result<Segment> {
+    let segment = Arc::new(Segment::open_with(input, true)?);
+    let level = segment.level();
+    let metadata = segment.metadata().unwrap_or_default().to_vec();
+    // a standalone tool, outside of any store sharing the directory
+    write_merged_segment(output, &[segment], level, &metadata, &|_| Some(0), false)
+}
+
+fn write_merged_segment(
+    segment_file_path: &path::PathBuf,
+    segments: &[SegmentArc],
+    level: u32,
+    metadata: &[u8],
+    retention: &dyn Fn(StreamId) -> Option<u64>,
+    lock: bool,
+) -> Result<Segment> {
+    assert!(align_of::<SegmentHeader>() <= 8);
+
+    let begin = std::time::Instant::now();
+    let temp_file_path = segment_file_path.with_extension("tmp");
+
+    let write_error =
+        |e| errors::new_segment_io_error(SegmentOp::WriteSegment, &temp_file_path, e);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(!lock)
+        .open(&temp_file_path)
+        .map_err(write_error)?;
+    if lock {
+        // truncate only once the lock is held, the file may be another writer's
+        lock_file(&file, &temp_file_path, true)?;
+        file.set_len(0).map_err(write_error)?;
+    }
+
+    // delete temp file if errors happen
+    let temp_filename_clone = temp_file_path.clone();
+    defer::defer!({
+        // check if the file exists
+        if std::fs::metadata(&temp_filename_clone).is_ok() {
+            // delete the file
+            if std::fs::remove_file(&temp_filename_clone).is_err() {
+                log::warn!("Failed to delete temp file: {:?}", &temp_filename_clone);
+            }
+        }
+    });
+
+    // The data of every stream, in stream offset order, less what retention drops.
+    // Parts left without data only record where the stream ends
+    let mut stream_parts: HashMap<StreamId, Vec<(SegmentStreamHeader, &[u8])>> = HashMap::new();
+    let mut trimmed_ends: HashMap<StreamId, u64> = HashMap::new();
+    for segment in segments.iter() {
+        for header in segment.stream_headers()? {
+            let Some(retain_from) = retention(header.stream_id) else {
+                continue;
+            };
+            let skip = retain_from.saturating_sub(header.offset).min(header.size);
+            if skip == header.size {
+                let end = trimmed_ends.entry(header.stream_id).or_default();
+                *end = (*end).max(header.offset + header.size);
+                continue;
+            }
+            let data = &segment.stream_header_data(header)?[skip as usize..];
+            let header = SegmentStreamHeader {
+                offset: header.offset + skip,
+                size: header.size - skip,
+                ..header.clone()
+            };
+            stream_parts
+                .entry(header.stream_id)
+                .or_default()
+                .push((header, data));
+        }
+    }
+    // a retained stream trimmed of all its data keeps an empty header at its end
+    // offset, so that a reload resumes the stream there instead of at 0
+    for (stream_id, end) in trimmed_ends {
+        stream_parts.entry(stream_id).or_insert_with(|| {
+            let header = SegmentStreamHeader {
+                stream_id,
+                offset: end,
+                ..Default::default()
+            };
+            vec![(header, &[][..])]
+        });
+    }
+
+    // A merged stream must be the concatenation of its parts: each part starts
+    // where the previous one ends, any overlap or gap fails the merge
+    let mut segment_stream_headers = Vec::with_capacity(stream_parts.len());
+    for (stream_id, parts) in stream_parts.iter_mut() {
+        parts.sort_by_key(|(header, _)| header.offset);
+
+        let crc64 = Crc::<u64>::new(&crc::CRC_64_REDIS);
+        let mut hash = crc64.digest();
+        let mut merged = SegmentStreamHeader {
+            stream_id: *stream_id,
+            offset: parts[0].0.offset,
+            ..Default::default()
+        };
+        for (header, data) in parts.iter() {
+            let expected = merged.offset + merged.size;
+            if header.offset != expected {
+                return Err(errors::new_compaction_overlap(
+                    *stream_id,
+                    expected,
+                    header.offset,
+                ));
+            }
+            merged.size += header.size;
+            merged.first_entry = min_entry(merged.first_entry, header.first_entry);
+            merged.last_entry = merged.last_entry.max(header.last_entry);
+            hash.update(data);
+        }
+        merged.crc64 = hash.finalize();
+        segment_stream_headers.push(merged);
+    }
+
+    segment_stream_headers.sort_by(|a, b| a.stream_id.cmp(&b.stream_id));
+
+    // keep the strictest alignment of the merged segments
+    let data_alignment = segments
+        .iter()
+        .map(|segment| segment.data_alignment())
+        .max()
+        .unwrap();
+    // the merged entry range is the union of the inputs' ranges
+    let (first_entry, last_entry) = segments.iter().fold((0, 0), |(first, last), segment| {
+        let header = segment.get_segment_header();
+        (
+            min_entry(first, header.first_entry),
+            last.max(header.last_entry),
+        )
+    });
+    let mut segment_header = SegmentHeader {
+        level,
+        first_entry,
+        last_entry,
+        stream_headers_count: segment_stream_headers.len() as u64,
+        data_alignment,
+        flags: SEGMENT_FLAG_FILE_DIGEST,
+        ..Default::default()
+    };
+
+    let metadata_end = plan_stream_header_blocks(&mut segment_header);
+    let metadata_end = plan_metadata(&mut segment_header, metadata_end, metadata);
+    let mut written =
+        assign_file_offsets(&mut segment_stream_headers, data_alignment, metadata_end);
+    #[cfg(feature = "stream-index")]
+    plan_stream_index(&mut segment_header, &segment_stream_headers, written);
+
+    log::debug!(
+        "Segment {} Header: first_entry: {}, last_entry: {}, stream_headers_count: {}",
+        segment_file_path.display(),
+        segment_header.first_entry,
+        segment_header.last_entry,
+        segment_header.stream_headers_count
+    );
+
+    // Write the segment stream headers to the file
+    let mut writer = DigestWriter::new(&mut file);
+    writer
+        .write_all(segment_header_bytes(&segment_header))
+        .map_err(write_error)?;
 
     let stream_header = segment_stream_headers.as_ptr() as *const SegmentStreamHeader;
     let data = unsafe {
@@ -498,7 +2745,11 @@ pub(crate) fn merge_segments(
             SEGMENT_STREAM_HEADER_SIZE as usize * segment_stream_headers.len() as usize,
         )
     };
-    file.write_all(data).map_err(errors::new_io_error)?;
+    writer.write_all(data).map_err(write_error)?;
+    write_stream_header_blocks(&mut writer, &segment_header, &segment_stream_headers)
+        .map_err(write_error)?;
+    writer.write_all(metadata).map_err(write_error)?;
+    let trailer = SegmentTrailer::new(segment_header_bytes(&segment_header), data);
 
     // Verify that the segment stream headers are written correctly
     {
@@ -516,34 +2767,52 @@ pub(crate) fn merge_segments(
             assert!(stream_header.offset == segment_stream_headers[i].offset);
             assert!(stream_header.version == segment_stream_headers[i].version);
             assert!(stream_header.crc64 == segment_stream_headers[i].crc64);
+            assert!(stream_header.first_entry == segment_stream_headers[i].first_entry);
+            assert!(stream_header.last_entry == segment_stream_headers[i].last_entry);
         }
     }
 
     for header in segment_stream_headers.iter() {
-        for segment in segments.iter() {
-            if let Some(stream_data) = segment.stream_data(header.stream_id) {
-                file.write_all(stream_data).map_err(errors::new_io_error)?;
-            }
-        }
+        write_padding(&mut writer, &mut written, header.file_offset).map_err(write_error)?;
+        written += header.size;
+        let mut slices = stream_parts[&header.stream_id]
+            .iter()
+            .filter(|(_, stream_data)| !stream_data.is_empty())
+            .map(|(_, stream_data)| IoSlice::new(stream_data))
+            .collect::<Vec<_>>();
+        write_all_vectored(&mut writer, &mut slices).map_err(write_error)?;
     }
+    #[cfg(feature = "stream-index")]
+    write_stream_index(
+        &mut writer,
+        &mut written,
+        &segment_header,
+        &segment_stream_headers,
+    )
+    .map_err(write_error)?;
+    writer.finish(&trailer).map_err(write_error)?;
 
     // flush the file to disk
-    file.flush().map_err(errors::new_io_error)?;
-    file.sync_all().map_err(errors::new_io_error)?;
+    file.flush().map_err(write_error)?;
+    file.sync_all().map_err(write_error)?;
 
-    // close the file
+    // rename the file, still holding the lock, then close it
+    std::fs::rename(&temp_file_path, segment_file_path).map_err(|e| {
+        errors::new_segment_io_error(SegmentOp::RenameSegment, segment_file_path, e)
+    })?;
     drop(file);
 
-    // rename the file
-    std::fs::rename(temp_file_path, &segment_file_path).map_err(errors::new_io_error)?;
-
     log::debug!(
         "Segment {} merged in {} ms",
         segment_file_path.display(),
         begin.elapsed().as_millis()
     );
 
-    Ok(Segment::open(&segment_file_path)?)
+    if lock {
+        Segment::open_locked(segment_file_path)
+    } else {
+        Segment::open(segment_file_path)
+    }
 }
 
 #[test]
@@ -570,7 +2839,7 @@ fn test_segment_header_size() {
                     version: 1,
                     id: entry_id,
                     stream_id: stream_id,
-                    data: data,
+                    data: data.into(),
                     callback: None,
                 })
                 .unwrap();
@@ -581,17 +2850,24 @@ fn test_segment_header_size() {
     let segment = generate_segment(&segment_file_path, &memtable).unwrap();
 
     let seg_header = segment.get_segment_header();
-    assert!(seg_header.version == SEGMENT_HEADER_VERSION_V1);
+    assert!(seg_header.version == SEGMENT_HEADER_VERSION_V2);
     assert!(seg_header.first_entry == 1);
     assert!(seg_header.last_entry == entry_id);
     assert!(seg_header.stream_headers_offset == SEGMENT_HEADER_SIZE);
     assert!(seg_header.stream_headers_count == 10);
 
-    let mut file_offset =
-        SEGMENT_HEADER_SIZE + SEGMENT_STREAM_HEADER_SIZE * seg_header.stream_headers_count;
+    // the data follows the stream header table and its block index
+    assert_eq!(seg_header.stream_header_block_len, STREAM_HEADER_BLOCK_LEN);
+    let mut file_offset = SEGMENT_HEADER_SIZE
+        + SEGMENT_STREAM_HEADER_SIZE * seg_header.stream_headers_count
+        + 8 * seg_header
+            .stream_headers_count
+            .div_ceil(STREAM_HEADER_BLOCK_LEN);
     for (index, header) in segment.get_stream_headers().iter().enumerate() {
-        assert!(header.version == SEGMENT_STREAM_HEADER_VERSION_V1);
+        assert!(header.version == SEGMENT_STREAM_HEADER_VERSION_V2);
         assert!(header.stream_id == index as StreamId + 1);
+        assert!(header.first_entry == index as u64 * 1000 + 1);
+        assert!(header.last_entry == (index as u64 + 1) * 1000);
         assert!(header.offset == 0);
         assert!(
             header.file_offset == file_offset,
@@ -610,3 +2886,1297 @@ fn test_segment_header_size() {
         file_offset += header.size;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub(crate) fn new_test_memtable(streams: &[(StreamId, &[u8])]) -> MemTable {
+        let memtable = MemTable::new(Box::new(|_stream_id| Ok(0)));
+        for (index, (stream_id, data)) in streams.iter().enumerate() {
+            memtable
+                .append(&crate::entry::Entry {
+                    version: 1,
+                    id: index as u64 + 1,
+                    stream_id: *stream_id,
+                    data: data.to_vec().into(),
+                    callback: None,
+                })
+                .unwrap();
+        }
+        memtable
+    }
+
+    // A memtable holding `data` for each stream, starting at the given stream offset
+    // and with entry ids counting from `first_id`
+    fn new_memtable_at(first_id: u64, streams: &[(StreamId, u64, &[u8])]) -> MemTable {
+        let offsets = streams
+            .iter()
+            .map(|(stream_id, offset, _)| (*stream_id, *offset))
+            .collect::<HashMap<_, _>>();
+        let memtable = MemTable::new(Box::new(move |stream_id| Ok(offsets[&stream_id])));
+        for (index, (stream_id, _, data)) in streams.iter().enumerate() {
+            memtable
+                .append(&crate::entry::Entry {
+                    version: 1,
+                    id: first_id + index as u64,
+                    stream_id: *stream_id,
+                    data: data.to_vec().into(),
+                    callback: None,
+                })
+                .unwrap();
+        }
+        memtable
+    }
+
+    #[test]
+    fn test_merge_segments_stream_order() {
+        let first_path = path::PathBuf::from("test_merge_order_first.seg");
+        let first = generate_segment(
+            &first_path,
+            &new_memtable_at(1, &[(1, 0, b"hello"), (2, 0, b"only here")]),
+        )
+        .unwrap();
+        first.set_drop_delete(true);
+        let second_path = path::PathBuf::from("test_merge_order_second.seg");
+        let second =
+            generate_segment(&second_path, &new_memtable_at(10, &[(1, 5, b" world")])).unwrap();
+        second.set_drop_delete(true);
+
+        // the inputs are ordered by stream offset, whatever order they are given in
+        let merged_path = path::PathBuf::from("test_merge_order_merged.seg");
+        let merged =
+            merge_segments(&merged_path, &[Arc::new(second), Arc::new(first)], false).unwrap();
+        merged.set_drop_delete(true);
+
+        assert_eq!(merged.entry_index(), (1, 10));
+        let split = merged.find_stream_header(1).unwrap();
+        assert_eq!((split.offset, split.size), (0, 11));
+        assert_eq!((split.first_entry, split.last_entry), (1, 10));
+        assert_eq!(&*merged.stream_data(1).unwrap().unwrap(), b"hello world");
+
+        let single = merged.find_stream_header(2).unwrap();
+        assert_eq!((single.offset, single.size), (0, 9));
+        assert_eq!((single.first_entry, single.last_entry), (2, 2));
+        assert_eq!(&*merged.stream_data(2).unwrap().unwrap(), b"only here");
+        assert!(merged.check_crc().unwrap());
+    }
+
+    #[test]
+    fn test_merge_segments_overlap() {
+        let first_path = path::PathBuf::from("test_merge_overlap_first.seg");
+        let first =
+            generate_segment(&first_path, &new_memtable_at(1, &[(1, 0, b"hello")])).unwrap();
+        first.set_drop_delete(true);
+        let overlap_path = path::PathBuf::from("test_merge_overlap_second.seg");
+        let overlap =
+            generate_segment(&overlap_path, &new_memtable_at(2, &[(1, 3, b"lo!")])).unwrap();
+        overlap.set_drop_delete(true);
+        let gap_path = path::PathBuf::from("test_merge_overlap_gap.seg");
+        let gap = generate_segment(&gap_path, &new_memtable_at(3, &[(1, 8, b"!")])).unwrap();
+        gap.set_drop_delete(true);
+
+        let first = Arc::new(first);
+        let merged_path = path::PathBuf::from("test_merge_overlap_merged.seg");
+        for (other, expected, offset) in [(overlap, 5, 3), (gap, 5, 8)] {
+            let Err(err) = merge_segments(&merged_path, &[first.clone(), Arc::new(other)], false)
+            else {
+                panic!("overlapping segments must not merge");
+            };
+            assert!(matches!(
+                err.downcast_ref::<errors::Error>(),
+                Some(errors::Error::CompactionOverlap { stream_id: 1, expected: e, offset: o })
+                    if *e == expected && *o == offset
+            ));
+        }
+        assert!(!merged_path.exists());
+        assert!(!merged_path.with_extension("tmp").exists());
+    }
+
+    #[test]
+    fn test_generate_segment_on_flush() {
+        let memtable = new_test_memtable(&[(1, b"hello"), (2, b"world!"), (1, b"again")]);
+        let flushed = Arc::new(std::sync::Mutex::new(None));
+        let flushed_clone = flushed.clone();
+        memtable.set_on_flush(move |end_offsets| {
+            *flushed_clone.lock().unwrap() = Some(end_offsets.clone());
+        });
+
+        let segment_file_path = path::PathBuf::from("test_generate_segment_on_flush.seg");
+        let segment = generate_segment(&segment_file_path, &memtable).unwrap();
+        segment.set_drop_delete(true);
+
+        assert_eq!(
+            flushed.lock().unwrap().take(),
+            Some(HashMap::from([(1, 10), (2, 6)]))
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let memtable = new_test_memtable(&[(1, b"hello"), (2, b"world"), (3, b"again")]);
+        let segment_file_path = path::PathBuf::from("test_validate.seg");
+        let segment = generate_segment(&segment_file_path, &memtable).unwrap();
+        segment.validate().unwrap();
+
+        // flip a data byte of stream 2 and 3, the metadata stays valid
+        let mut content = std::fs::read(&segment_file_path).unwrap();
+        for stream_id in [2, 3] {
+            content[segment.find_stream_header(stream_id).unwrap().file_offset as usize] ^= 0xff;
+        }
+        drop(segment);
+        std::fs::write(&segment_file_path, content).unwrap();
+
+        let segment = Segment::open(&segment_file_path).unwrap();
+        segment.set_drop_delete(true);
+        assert!(!segment.check_crc().unwrap());
+        let err = segment.validate().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<errors::Error>(),
+            Some(errors::Error::CorruptSegment { .. })
+        ));
+        assert!(err.to_string().contains("stream 2 crc mismatch"), "{}", err);
+    }
+
+    #[test]
+    fn test_backfill_crcs() {
+        let memtable = new_test_memtable(&[(1, b"hello"), (2, b"world"), (3, b"again")]);
+        let segment_file_path = path::PathBuf::from("test_backfill_crcs.seg");
+        let mut segment = generate_segment(&segment_file_path, &memtable).unwrap();
+        let err = segment.backfill_crcs().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<errors::Error>(),
+            Some(errors::Error::CrcAlreadySet { stream_id: 1, .. })
+        ));
+
+        // clear the crcs, as a writer without checksums would leave them, and fix
+        // up the trailer
+        let header = segment.get_segment_header();
+        let mut content = std::fs::read(&segment_file_path).unwrap();
+        let table_start = header.stream_headers_offset as usize;
+        let table_end = table_start + 3 * SEGMENT_STREAM_HEADER_SIZE as usize;
+        for index in 0..3 {
+            let at = table_start
+                + index * SEGMENT_STREAM_HEADER_SIZE as usize
+                + std::mem::offset_of!(SegmentStreamHeader, crc64);
+            content[at..at + 8].fill(0);
+        }
+        let trailer = SegmentTrailer::new(
+            &content[..SEGMENT_HEADER_SIZE as usize],
+            &content[table_start..table_end],
+        );
+        let trailer_offset = content.len() - SEGMENT_TRAILER_SIZE as usize;
+        content[trailer_offset..].copy_from_slice(trailer.as_bytes());
+        drop(segment);
+        std::fs::write(&segment_file_path, content).unwrap();
+
+        let mut segment = Segment::open(&segment_file_path).unwrap();
+        segment.set_drop_delete(true);
+        assert!(!segment.check_crc().unwrap());
+        assert!(!Segment::verify_file_digest(&segment_file_path).unwrap());
+        assert_eq!(segment.backfill_crcs().unwrap(), 3);
+        segment.validate().unwrap();
+        assert_eq!(&*segment.stream_data(2).unwrap().unwrap(), b"world");
+
+        // the file on disk is updated, metadata checksum and file digest included
+        let reopened = Segment::open(&segment_file_path).unwrap();
+        reopened.validate().unwrap();
+        assert!(Segment::verify_file_digest(&segment_file_path).unwrap());
+        assert!(segment.backfill_crcs().is_err());
+    }
+
+    #[test]
+    fn test_file_digest() {
+        let memtable = new_test_memtable(&[(1, b"hello"), (2, b"world")]);
+        let segment_file_path = path::PathBuf::from("test_file_digest.seg");
+        let segment = generate_segment(&segment_file_path, &memtable).unwrap();
+        segment.set_drop_delete(true);
+
+        let content = std::fs::read(&segment_file_path).unwrap();
+        let digest_at = content.len() - (SEGMENT_FILE_DIGEST_SIZE + SEGMENT_TRAILER_SIZE) as usize;
+        let mut expected = FILE_DIGEST_CRC.digest();
+        expected.update(&content[..digest_at]);
+        expected.update(&content[digest_at + SEGMENT_FILE_DIGEST_SIZE as usize..]);
+        assert_eq!(segment.file_digest(), Some(expected.finalize()));
+        assert!(Segment::verify_file_digest(&segment_file_path).unwrap());
+
+        // a copy with a flipped bit in the stream data no longer matches
+        let copy_path = path::PathBuf::from("test_file_digest_copy.seg");
+        let mut copy = content.clone();
+        let header = segment.find_stream_header(2).unwrap();
+        copy[header.file_offset as usize] ^= 1;
+        std::fs::write(&copy_path, &copy).unwrap();
+        assert!(!Segment::verify_file_digest(&copy_path).unwrap());
+
+        // files written before the digest have none
+        let mut header = segment.get_segment_header();
+        header.flags = 0;
+        copy.copy_from_slice(&content);
+        copy[..SEGMENT_HEADER_SIZE as usize].copy_from_slice(segment_header_bytes(&header));
+        std::fs::write(&copy_path, &copy).unwrap();
+        let err = Segment::verify_file_digest(&copy_path).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<errors::Error>(),
+            Some(errors::Error::NoFileDigest { .. })
+        ));
+        std::fs::remove_file(&copy_path).unwrap();
+    }
+
+    #[test]
+    fn test_generate_segment_empty_memtable() {
+        let memtable = MemTable::new(Box::new(|_stream_id| Ok(0)));
+        let segment_file_path = path::PathBuf::from("test_generate_segment_empty.seg");
+        let err = generate_segment(&segment_file_path, &memtable)
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<errors::Error>(),
+            Some(errors::Error::EmptyMemtable)
+        ));
+        assert!(!segment_file_path.exists());
+        assert!(!segment_file_path.with_extension("tmp").exists());
+    }
+
+    #[test]
+    fn test_generate_segment_with_alignment() {
+        let memtable = new_test_memtable(&[(1, b"hello"), (2, b"world!"), (3, b"x")]);
+        let segment_file_path = path::PathBuf::from("test_generate_segment_aligned.seg");
+        let segment =
+            generate_segment_with_alignment(&segment_file_path, &memtable, 4096, false).unwrap();
+        segment.set_drop_delete(true);
+
+        assert_eq!(segment.data_alignment(), 4096);
+        for header in segment.get_stream_headers() {
+            assert_eq!(header.file_offset % 4096, 0);
+        }
+        assert_eq!(&*segment.stream_data(2).unwrap().unwrap(), b"world!");
+        assert!(segment.check_crc().unwrap());
+
+        // merging keeps the alignment
+        let other_path = path::PathBuf::from("test_generate_segment_unaligned.seg");
+        let other_table = MemTable::new(Box::new(|_stream_id| Ok(6)));
+        other_table
+            .append(&crate::entry::Entry {
+                version: 1,
+                id: 4,
+                stream_id: 2,
+                data: b"more".to_vec().into(),
+                callback: None,
+            })
+            .unwrap();
+        let other = generate_segment(&other_path, &other_table).unwrap();
+        other.set_drop_delete(true);
+        assert_eq!(other.data_alignment(), 1);
+        let merged_path = path::PathBuf::from("test_generate_segment_aligned_merged.seg");
+        let merged =
+            merge_segments(&merged_path, &[Arc::new(segment), Arc::new(other)], false).unwrap();
+        merged.set_drop_delete(true);
+        assert_eq!(merged.data_alignment(), 4096);
+        for header in merged.get_stream_headers() {
+            assert_eq!(header.file_offset % 4096, 0);
+        }
+        assert_eq!(&*merged.stream_data(2).unwrap().unwrap(), b"world!more");
+    }
+
+    #[test]
+    fn test_write_all_vectored() {
+        // accepts at most 7 bytes per call, like a writev cut short by a signal
+        struct ShortWriter {
+            data: Vec<u8>,
+            calls: usize,
+        }
+        impl Write for ShortWriter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.write_vectored(&[IoSlice::new(buf)])
+            }
+            fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+                assert!(bufs.len() <= WRITE_VECTORED_MAX_SLICES);
+                self.calls += 1;
+                let mut written = 0;
+                for buf in bufs {
+                    let len = buf.len().min(7 - written);
+                    self.data.extend_from_slice(&buf[..len]);
+                    written += len;
+                    if written == 7 {
+                        break;
+                    }
+                }
+                Ok(written)
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let chunks = (0..WRITE_VECTORED_MAX_SLICES * 3 + 1)
+            .map(|i| format!("{}|", i).into_bytes())
+            .collect::<Vec<_>>();
+        let mut slices = chunks.iter().map(|c| IoSlice::new(c)).collect::<Vec<_>>();
+        let mut writer = ShortWriter {
+            data: Vec::new(),
+            calls: 0,
+        };
+        write_all_vectored(&mut writer, &mut slices).unwrap();
+        assert_eq!(writer.data, chunks.concat());
+        assert_eq!(writer.calls, writer.data.len().div_ceil(7));
+    }
+
+    #[test]
+    fn test_generate_segment_multiple_chunks() {
+        // larger than a few stream data chunks
+        let data = (0..(400u32 << 10)).map(|i| i as u8).collect::<Vec<_>>();
+        let memtable = new_test_memtable(&[(1, b"hello"), (2, &data), (3, b"world")]);
+        let segment_file_path = path::PathBuf::from("test_generate_segment_multiple_chunks.seg");
+        let segment = generate_segment(&segment_file_path, &memtable).unwrap();
+        segment.set_drop_delete(true);
+        segment.validate().unwrap();
+        assert_eq!(&*segment.stream_data(1).unwrap().unwrap(), b"hello");
+        assert_eq!(&*segment.stream_data(2).unwrap().unwrap(), &data[..]);
+        assert_eq!(&*segment.stream_data(3).unwrap().unwrap(), b"world");
+    }
+
+    #[test]
+    fn test_generate_segment_mmap() {
+        // 64 streams of 256KB, a few chunks each
+        let datas = (0..64u32)
+            .map(|stream| {
+                (0..(256u32 << 10))
+                    .map(|i| (i + stream) as u8)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let streams = datas
+            .iter()
+            .enumerate()
+            .map(|(i, data)| (i as StreamId + 1, &data[..]))
+            .collect::<Vec<_>>();
+        let memtable = new_test_memtable(&streams);
+
+        let mut segments = Vec::new();
+        for (mode, name) in [
+            (
+                SegmentWriteMode::Syscall,
+                "test_generate_segment_syscall.seg",
+            ),
+            (SegmentWriteMode::Mmap, "test_generate_segment_mmap.seg"),
+        ] {
+            let segment_file_path = path::PathBuf::from(name);
+            let segment =
+                generate_segment_with_mode(&segment_file_path, &memtable, 1, &[], false, mode)
+                    .unwrap();
+            segment.set_drop_delete(true);
+            segment.validate().unwrap();
+            assert!(Segment::verify_file_digest(&segment_file_path).unwrap());
+            segments.push((segment, segment_file_path));
+        }
+
+        let (syscall, mmap) = (&segments[0], &segments[1]);
+        assert_eq!(syscall.0.file_digest(), mmap.0.file_digest());
+        assert_eq!(
+            std::fs::read(&syscall.1).unwrap(),
+            std::fs::read(&mmap.1).unwrap()
+        );
+        for (stream_id, data) in &streams {
+            assert_eq!(&*mmap.0.stream_data(*stream_id).unwrap().unwrap(), *data);
+        }
+    }
+
+    #[test]
+    fn test_write_stream_to() {
+        let large = vec![0x42u8; SEGMENT_WRITE_CHUNK_SIZE * 2 + 100];
+        let memtable = new_test_memtable(&[(1, b"hello"), (2, &large)]);
+        let segment_file_path = path::PathBuf::from("test_write_stream_to.seg");
+        let segment = generate_segment(&segment_file_path, &memtable).unwrap();
+
+        let mut out = Vec::new();
+        assert_eq!(segment.write_stream_to(1, &mut out).unwrap(), 5);
+        assert_eq!(out, b"hello");
+
+        let mut out = Vec::new();
+        assert_eq!(
+            segment.write_stream_to(2, &mut out).unwrap(),
+            large.len() as u64
+        );
+        assert_eq!(out, large);
+
+        assert!(segment.write_stream_to(3, &mut Vec::new()).is_err());
+
+        segment.set_drop_delete(true);
+    }
+
+    #[test]
+    fn test_read_stream_range() {
+        let memtable = MemTable::new(Box::new(|_stream_id| Ok(100)));
+        memtable
+            .append(&crate::entry::Entry {
+                version: 1,
+                id: 1,
+                stream_id: 1,
+                data: b"hello world".to_vec().into(),
+                callback: None,
+            })
+            .unwrap();
+        let segment_file_path = path::PathBuf::from("test_read_stream_range.seg");
+        let segment = generate_segment(&segment_file_path, &memtable).unwrap();
+        segment.set_drop_delete(true);
+
+        assert_eq!(
+            segment.read_stream_range(1, 106, 5).unwrap(),
+            Some(&b"world"[..])
+        );
+        assert_eq!(
+            segment.read_stream_range(1, 100, 0).unwrap(),
+            Some(&b""[..])
+        );
+        assert_eq!(segment.read_stream_range(2, 0, 1).unwrap(), None);
+
+        for (offset, size) in [(106, 6), (111, 1), (u64::MAX, 1)] {
+            let err = segment.read_stream_range(1, offset, size).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<errors::Error>(),
+                Some(errors::Error::StreamOffsetInvalid { stream_id: 1, .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_stream_logical_range() {
+        // the stream's data starts at 100, as after earlier data was compacted away
+        let memtable = new_memtable_at(1, &[(1, 100, b"hello world")]);
+        let segment_file_path = path::PathBuf::from("test_stream_logical_range.seg");
+        let segment = generate_segment(&segment_file_path, &memtable).unwrap();
+        segment.set_drop_delete(true);
+
+        assert_eq!(segment.stream_logical_range(1), Some((100, 111)));
+        assert_eq!(segment.stream_logical_range(2), None);
+
+        let err = segment.read_stream_range(1, 99, 2).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<errors::Error>(),
+            Some(errors::Error::OffsetBelowRetention {
+                stream_id: 1,
+                offset: 99,
+                base: 100
+            })
+        ));
+
+        let mut buf = [0u8; 4];
+        let err = segment.read_stream(1, 0, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(matches!(
+            err.get_ref()
+                .and_then(|e| e.downcast_ref::<errors::Error>()),
+            Some(errors::Error::OffsetBelowRetention { base: 100, .. })
+        ));
+        assert_eq!(segment.read_stream(1, 106, &mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"worl");
+    }
+
+    #[test]
+    fn test_read_stream_ranges() {
+        let memtable = new_test_memtable(&[(1, b"hello world"), (2, b"other")]);
+        let segment_file_path = path::PathBuf::from("test_read_stream_ranges.seg");
+        let segment = generate_segment(&segment_file_path, &memtable).unwrap();
+        segment.set_drop_delete(true);
+
+        assert_eq!(
+            segment
+                .read_stream_ranges(1, &[(6, 5), (0, 5), (4, 0), (10, 1)])
+                .unwrap(),
+            Some(vec![&b"world"[..], &b"hello"[..], &b""[..], &b"d"[..]])
+        );
+        assert_eq!(segment.read_stream_ranges(1, &[]).unwrap(), Some(vec![]));
+        assert_eq!(segment.read_stream_ranges(3, &[(0, 1)]).unwrap(), None);
+
+        // a single bad range fails the whole read
+        let err = segment
+            .read_stream_ranges(1, &[(0, 5), (6, 6)])
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<errors::Error>(),
+            Some(errors::Error::StreamOffsetInvalid {
+                stream_id: 1,
+                offset: 6
+            })
+        ));
+    }
+
+    #[cfg(feature = "stream-index")]
+    #[test]
+    fn test_seek_stream() {
+        let large = (0..200 << 10).map(|i| i as u8).collect::<Vec<u8>>();
+        let memtable = new_memtable_at(1, &[(1, 0, b"small"), (2, 1000, &large)]);
+        let segment_file_path = path::PathBuf::from("test_seek_stream.seg");
+        let segment = generate_segment(&segment_file_path, &memtable).unwrap();
+        segment.set_drop_delete(true);
+
+        let segment_header = segment.get_segment_header();
+        assert_ne!(segment_header.stream_index_offset, 0);
+        assert_eq!(segment_header.stream_index_interval, STREAM_INDEX_INTERVAL);
+        assert_eq!(segment.stream_index(0, 2).unwrap().len(), 1);
+        assert_eq!(segment.stream_index(1, 2).unwrap().len(), 4);
+
+        let data = &segment.data[..];
+        for offset in [
+            1000,
+            1001,
+            1000 + STREAM_INDEX_INTERVAL,
+            1000 + (200 << 10) - 1,
+        ] {
+            let position = segment.seek_stream(2, offset).unwrap();
+            assert_eq!(data[position], large[(offset - 1000) as usize]);
+        }
+        assert_eq!(data[segment.seek_stream(1, 4).unwrap()], b'l');
+
+        let err = segment.seek_stream(2, 999).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<errors::Error>(),
+            Some(errors::Error::StreamOffsetInvalid { stream_id: 2, .. })
+        ));
+        let err = segment.seek_stream(3, 0).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<errors::Error>(),
+            Some(errors::Error::StreamNotFound { stream_id: 3 })
+        ));
+
+        // merged segments carry their own index
+        let other_path = path::PathBuf::from("test_seek_stream_other.seg");
+        let other = generate_segment(
+            &other_path,
+            &new_memtable_at(10, &[(2, 1000 + (200 << 10), b"tail")]),
+        )
+        .unwrap();
+        other.set_drop_delete(true);
+        let merged_path = path::PathBuf::from("test_seek_stream_merged.seg");
+        let merged =
+            merge_segments(&merged_path, &[Arc::new(segment), Arc::new(other)], false).unwrap();
+        merged.set_drop_delete(true);
+        let data = &merged.data[..];
+        let position = merged.seek_stream(2, 1000 + (200 << 10) + 1).unwrap();
+        assert_eq!(data[position], b'a');
+        assert!(merged.check_crc().unwrap());
+    }
+
+    #[test]
+    fn test_segment_stream_reader() {
+        use std::io::Read;
+
+        let first_path = path::PathBuf::from("test_stream_reader_first.seg");
+        let first = generate_segment(
+            &first_path,
+            &new_memtable_at(1, &[(1, 0, b"hello"), (2, 0, b"other")]),
+        )
+        .unwrap();
+        first.set_drop_delete(true);
+        let unrelated_path = path::PathBuf::from("test_stream_reader_unrelated.seg");
+        let unrelated =
+            generate_segment(&unrelated_path, &new_memtable_at(5, &[(3, 0, b"skip")])).unwrap();
+        unrelated.set_drop_delete(true);
+        let second_path = path::PathBuf::from("test_stream_reader_second.seg");
+        let second =
+            generate_segment(&second_path, &new_memtable_at(10, &[(1, 5, b" world")])).unwrap();
+        second.set_drop_delete(true);
+        let segments = [Arc::new(second), Arc::new(unrelated), Arc::new(first)];
+
+        let tail = Arc::new(new_memtable_at(20, &[(1, 11, b"!")]));
+        let mut reader = SegmentStreamReader::new(&segments, 1)
+            .unwrap()
+            .with_mem_table(tail.clone());
+        let mut data = Vec::new();
+        // a tiny buffer makes the reads cross the segment boundaries
+        let mut buf = [0u8; 3];
+        loop {
+            let n = reader.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            data.extend_from_slice(&buf[..n]);
+        }
+        assert_eq!(data, b"hello world!");
+        assert_eq!(reader.offset(), 12);
+
+        reader.seek_to(6);
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "world!");
+
+        // streams only in the memtable, or nowhere
+        let mut reader = SegmentStreamReader::new(&segments, 4)
+            .unwrap()
+            .with_mem_table(tail);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        let mut reader = SegmentStreamReader::new(&segments, 2).unwrap();
+        let mut other = Vec::new();
+        reader.read_to_end(&mut other).unwrap();
+        assert_eq!(other, b"other");
+
+        // a reader starts at the first offset the segments hold
+        let reader = SegmentStreamReader::new(&[segments[0].clone()], 1).unwrap();
+        assert_eq!(reader.offset(), 5);
+        // a missing part of the stream is an error
+        let gap_path = path::PathBuf::from("test_stream_reader_gap.seg");
+        let gap = generate_segment(&gap_path, &new_memtable_at(30, &[(1, 20, b"gap")])).unwrap();
+        gap.set_drop_delete(true);
+        assert!(SegmentStreamReader::new(&[segments[2].clone(), Arc::new(gap)], 1).is_err());
+    }
+
+    #[test]
+    fn test_segment_reader() {
+        let memtable = new_test_memtable(&[(1, b"hello"), (2, b"world")]);
+        let segment_file_path = path::PathBuf::from("test_segment_reader.seg");
+        let segment = generate_segment(&segment_file_path, &memtable).unwrap();
+        segment.set_drop_delete(true);
+        let reader = segment.reader();
+
+        // the reader keeps working after the segment is dropped and its file deleted
+        drop(segment);
+        assert!(!segment_file_path.exists());
+        let handle = std::thread::spawn(move || {
+            let mut buf = [0u8; 5];
+            assert_eq!(reader.read_stream(2, 0, &mut buf).unwrap(), 5);
+            assert_eq!(&buf, b"world");
+            assert_eq!(&*reader.stream_data(1).unwrap().unwrap(), b"hello");
+            assert_eq!(reader.get_stream_range(2), Some((0, 5)));
+        });
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_read_metadata() {
+        let memtable = new_memtable_at(3, &[(2, 0, b"world"), (1, 0, b"hello")]);
+        let segment_file_path = path::PathBuf::from("test_read_metadata.seg");
+        let segment = generate_segment(&segment_file_path, &memtable).unwrap();
+        segment.set_drop_delete(true);
+
+        let metadata = Segment::read_metadata(&segment_file_path).unwrap();
+        let header = segment.get_segment_header();
+        assert_eq!(metadata.version, header.version);
+        assert_eq!((metadata.first_entry, metadata.last_entry), (3, 4));
+        assert_eq!(metadata.stream_headers_count, 2);
+        assert_eq!(metadata.stream_ids, vec![1, 2]);
+
+        // a damaged stream header table is caught by the trailer checksum
+        let corrupt_path = path::PathBuf::from("test_read_metadata_corrupt.seg");
+        let mut data = std::fs::read(&segment_file_path).unwrap();
+        data[header.stream_headers_offset as usize + 8] ^= 0xff;
+        std::fs::write(&corrupt_path, &data).unwrap();
+        let err = Segment::read_metadata(&corrupt_path).unwrap_err();
+        std::fs::remove_file(&corrupt_path).unwrap();
+        assert!(err.to_string().contains("checksum"), "{}", err);
+    }
+
+    #[test]
+    fn test_stream_header_blocks() {
+        let streams = (1..=9)
+            .map(|i| (i * 10, format!("stream-{}", i).into_bytes()))
+            .collect::<Vec<_>>();
+        let streams = streams
+            .iter()
+            .map(|(stream_id, data)| (*stream_id, data.as_slice()))
+            .collect::<Vec<_>>();
+        let memtable = new_test_memtable(&streams);
+        let segment_file_path = path::PathBuf::from("test_stream_header_blocks.seg");
+        let segment = generate_segment(&segment_file_path, &memtable).unwrap();
+        segment.set_drop_delete(true);
+
+        let header = segment.get_segment_header();
+        assert_eq!(header.stream_header_block_len, STREAM_HEADER_BLOCK_LEN);
+        let (blocks, _) = segment.stream_header_blocks().unwrap();
+        assert_eq!(blocks, &[10, 30, 50, 70, 90]);
+        for (stream_id, data) in streams.iter() {
+            assert_eq!(
+                segment.find_stream_header(*stream_id).unwrap().stream_id,
+                *stream_id
+            );
+            assert_eq!(&*segment.stream_data(*stream_id).unwrap().unwrap(), *data);
+        }
+        for missing in [1, 15, 45, 91] {
+            assert!(segment.find_stream_header(missing).is_none());
+        }
+
+        // a segment with few streams keeps the flat table
+        let small = new_test_memtable(&streams[..3]);
+        let small_path = path::PathBuf::from("test_stream_header_blocks_small.seg");
+        let small = generate_segment(&small_path, &small).unwrap();
+        small.set_drop_delete(true);
+        assert_eq!(small.get_segment_header().stream_header_block_len, 0);
+        assert!(small.stream_header_blocks().is_none());
+        assert_eq!(small.find_stream_header(30).unwrap().stream_id, 30);
+
+        // a block index that does not match the table is caught on open
+        let corrupt_path = path::PathBuf::from("test_stream_header_blocks_corrupt.seg");
+        let mut data = std::fs::read(&segment_file_path).unwrap();
+        let position = header.stream_header_blocks_offset as usize + 8;
+        data[position..position + 8].copy_from_slice(&31i64.to_ne_bytes());
+        std::fs::write(&corrupt_path, &data).unwrap();
+        let result = Segment::open(&corrupt_path);
+        std::fs::remove_file(&corrupt_path).unwrap();
+        let Err(err) = result else {
+            panic!("corrupt block index was not detected");
+        };
+        assert!(err.to_string().contains("block index"), "{}", err);
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let memtable = new_test_memtable(&[(1, b"hello"), (2, b"world")]);
+        let segment_file_path = path::PathBuf::from("test_prefetch.seg");
+        let segment = generate_segment(&segment_file_path, &memtable).unwrap();
+        segment.set_drop_delete(true);
+
+        segment.prefetch_metadata().unwrap();
+        segment.prefetch().unwrap();
+        assert_eq!(&*segment.stream_data(2).unwrap().unwrap(), b"world");
+    }
+
+    #[test]
+    fn test_read_ahead() {
+        let data = vec![3u8; 10_000];
+        let memtable = new_test_memtable(&[(1, b"hello"), (2, &data[..])]);
+        let segment_file_path = path::PathBuf::from("test_read_ahead.seg");
+        let segment = Arc::new(generate_segment(&segment_file_path, &memtable).unwrap());
+        segment.set_drop_delete(true);
+        let header = segment.find_stream_header(2).unwrap();
+
+        // clamped to the stream's data
+        segment.prefetch_stream(&header, 9_000, 1 << 20).unwrap();
+        segment.prefetch_stream(&header, 20_000, 100).unwrap();
+
+        let mut read_ahead = ReadAhead::new(4_000);
+        read_ahead.advance(&segment, &header, 0);
+        assert_eq!(read_ahead.until, 4_000);
+        // within the prefetched window, nothing to do
+        read_ahead.advance(&segment, &header, 1_000);
+        assert_eq!(read_ahead.until, 4_000);
+        // past half of it, the next window is prefetched up to the end of the stream
+        read_ahead.advance(&segment, &header, 2_000);
+        assert_eq!(read_ahead.until, 6_000);
+        read_ahead.advance(&segment, &header, 8_000);
+        assert_eq!(read_ahead.until, 10_000);
+        read_ahead.reset();
+        assert_eq!(read_ahead.until, 0);
+
+        let mut disabled = ReadAhead::new(0);
+        disabled.advance(&segment, &header, 0);
+        assert_eq!(disabled.until, 0);
+
+        let mut reader = SegmentStreamReader::new(std::slice::from_ref(&segment), 2)
+            .unwrap()
+            .with_read_ahead(4_000);
+        let mut buf = Vec::new();
+        io::Read::read_to_end(&mut reader, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        assert_eq!(reader.read_ahead.until, 10_000);
+    }
+
+    #[test]
+    fn test_from_file() {
+        let memtable = new_test_memtable(&[(1, b"hello"), (2, b"world")]);
+        let segment_file_path = path::PathBuf::from("test_from_file.seg");
+        drop(generate_segment(&segment_file_path, &memtable).unwrap());
+
+        let file = File::open(&segment_file_path).unwrap();
+        let segment = Segment::from_file(file, "test_from_file.seg".to_string()).unwrap();
+        segment.set_drop_delete(true);
+
+        assert_eq!(segment.filename(), segment_file_path);
+        assert_eq!(&*segment.stream_data(1).unwrap().unwrap(), b"hello");
+        assert_eq!(&*segment.stream_data(2).unwrap().unwrap(), b"world");
+    }
+
+    #[test]
+    fn test_stream_bytes() {
+        let memtable = new_test_memtable(&[(1, b"hello"), (2, b"world")]);
+        let segment_file_path = path::PathBuf::from("test_stream_bytes.seg");
+        let segment = generate_segment(&segment_file_path, &memtable).unwrap();
+        segment.set_drop_delete(true);
+
+        let bytes = segment.stream_bytes(2).unwrap().unwrap();
+        assert!(segment.stream_bytes(3).unwrap().is_none());
+        let data = segment.stream_data(2).unwrap().unwrap();
+        // stream data is borrowed from the mapped file, as are the bytes
+        assert!(matches!(data, Cow::Borrowed(_)));
+        assert_eq!(bytes.as_ptr(), data.as_ptr());
+        drop(data);
+
+        // the bytes stay valid after the segment is dropped
+        drop(segment);
+        assert_eq!(&bytes[..], b"world");
+    }
+
+    #[test]
+    fn test_compaction_stats() {
+        let memtable = new_test_memtable(&[(1, b"hello world"), (2, b"dropped"), (3, b"kept")]);
+        let segment_file_path = path::PathBuf::from("test_compaction_stats.seg");
+        let segment = generate_segment(&segment_file_path, &memtable).unwrap();
+        segment.set_drop_delete(true);
+
+        let stats = segment.compaction_stats(&|_| Some(0)).unwrap();
+        assert_eq!(stats.live_bytes, 22);
+        assert_eq!(stats.dead_bytes, 0);
+        assert_eq!(stats.stream_count, 3);
+        assert_eq!(stats.dead_ratio(), 0.0);
+
+        let stats = segment
+            .compaction_stats(&|stream_id| match stream_id {
+                1 => Some(6),
+                2 => None,
+                _ => Some(100),
+            })
+            .unwrap();
+        assert_eq!(
+            stats,
+            CompactionStats {
+                live_bytes: 5,
+                dead_bytes: 17,
+                tombstone_bytes: 7,
+                stream_count: 3,
+                tombstone_count: 1,
+            }
+        );
+        assert_eq!(stats.dead_ratio(), 17.0 / 22.0);
+        assert_eq!(CompactionStats::default().dead_ratio(), 0.0);
+    }
+
+    #[test]
+    fn test_describe() {
+        let memtable = new_test_memtable(&[(1, b"hello"), (2, b"world!")]);
+        let segment_file_path = path::PathBuf::from("test_describe.seg");
+        let segment = generate_segment(&segment_file_path, &memtable).unwrap();
+        segment.set_drop_delete(true);
+
+        let description = segment.describe();
+        assert_eq!(description.version, SEGMENT_HEADER_VERSION_V2);
+        assert_eq!(description.first_entry, 1);
+        assert_eq!(description.last_entry, 2);
+        assert_eq!(description.stream_headers_count, 2);
+        assert_eq!(description.streams.len(), 2);
+        assert_eq!(description.streams[1].stream_id, 2);
+        assert_eq!(description.streams[1].size, 6);
+        assert!(description.streams.iter().all(|s| s.crc_ok));
+        assert!(description.streams.iter().all(|s| s.data_preview.is_none()));
+
+        let json = description.to_json_string().unwrap();
+        assert!(json.contains("\"crc_ok\": true"));
+        assert!(!json.contains("data_preview"));
+
+        let description = segment.describe_with_preview(2);
+        assert_eq!(description.streams[0].data_preview.as_deref(), Some("6865"));
+    }
+
+    #[test]
+    fn test_open_corrupt_metadata() {
+        let memtable = new_test_memtable(&[(1, b"hello"), (2, b"world")]);
+        let segment_file_path = path::PathBuf::from("test_open_corrupt_metadata.seg");
+        let segment = generate_segment(&segment_file_path, &memtable).unwrap();
+        assert!(segment.check_crc().unwrap());
+        drop(segment);
+
+        let is_corrupt = |e: anyhow::Error| {
+            matches!(
+                e.downcast_ref::<errors::Error>(),
+                Some(errors::Error::CorruptSegmentMetadata { .. })
+            )
+        };
+        let content = std::fs::read(&segment_file_path).unwrap();
+        let corrupt = |offset: usize, mutate: &dyn Fn(&mut [u8])| {
+            let mut content = content.clone();
+            mutate(&mut content[offset..]);
+            std::fs::write(&segment_file_path, content).unwrap();
+        };
+
+        // flipped stream_headers_count
+        let count_offset = std::mem::offset_of!(SegmentHeader, stream_headers_count);
+        corrupt(count_offset, &|b| b[0] = 0xff);
+        assert!(is_corrupt(Segment::open(&segment_file_path).err().unwrap()));
+
+        // flipped stream crc, only the metadata checksum catches it
+        let crc_offset =
+            SEGMENT_HEADER_SIZE as usize + std::mem::offset_of!(SegmentStreamHeader, crc64);
+        corrupt(crc_offset, &|b| b[0] ^= 0xff);
+        assert!(is_corrupt(Segment::open(&segment_file_path).err().unwrap()));
+
+        // the data is still readable through the unchecked open
+        let segment = unsafe { Segment::open_unchecked(&segment_file_path) }.unwrap();
+        assert_eq!(&*segment.stream_data(1).unwrap().unwrap(), b"hello");
+        assert!(!segment.check_crc().unwrap());
+
+        // missing trailer
+        drop(segment);
+        std::fs::write(&segment_file_path, &content[..content.len() - 1]).unwrap();
+        assert!(is_corrupt(Segment::open(&segment_file_path).err().unwrap()));
+
+        std::fs::remove_file(&segment_file_path).unwrap();
+    }
+
+    #[test]
+    fn test_truncated_segment_reads() {
+        let memtable = new_test_memtable(&[(1, b"hello"), (2, b"world")]);
+        let segment_file_path = path::PathBuf::from("test_truncated_segment_reads.seg");
+        drop(generate_segment(&segment_file_path, &memtable).unwrap());
+
+        // cut the file in the middle of stream 2's data, as a crash during the write would
+        let content = std::fs::read(&segment_file_path).unwrap();
+        let segment = Segment::open(&segment_file_path).unwrap();
+        let cut = segment.find_stream_header(2).unwrap().file_offset as usize + 2;
+        drop(segment);
+        std::fs::write(&segment_file_path, &content[..cut]).unwrap();
+
+        assert!(Segment::open(&segment_file_path).is_err());
+        let segment = unsafe { Segment::open_unchecked(&segment_file_path) }.unwrap();
+        segment.set_drop_delete(true);
+
+        let is_corrupt = |e: anyhow::Error| {
+            matches!(
+                e.downcast_ref::<errors::Error>(),
+                Some(errors::Error::CorruptSegment { .. })
+            )
+        };
+        assert_eq!(&*segment.stream_data(1).unwrap().unwrap(), b"hello");
+        assert!(is_corrupt(segment.stream_data(2).unwrap_err()));
+        assert!(is_corrupt(segment.stream_bytes(2).unwrap_err()));
+        assert!(is_corrupt(segment.read_stream_range(2, 0, 1).unwrap_err()));
+        let mut buf = [0u8; 8];
+        assert_eq!(
+            segment.read_stream(2, 0, &mut buf).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert!(!segment.check_crc().unwrap());
+
+        // cut the stream header table, the segments built from it must not see an
+        // empty segment
+        std::fs::write(
+            &segment_file_path,
+            &content[..SEGMENT_HEADER_SIZE as usize + 8],
+        )
+        .unwrap();
+        let segment = Arc::new(unsafe { Segment::open_unchecked(&segment_file_path) }.unwrap());
+        assert!(segment.get_stream_headers().is_empty());
+        assert!(is_corrupt(segment.stream_headers().unwrap_err()));
+        assert!(is_corrupt(
+            segment.compaction_stats(&|_| Some(0)).unwrap_err()
+        ));
+        assert!(is_corrupt(segment.check_crc().unwrap_err()));
+        assert!(is_corrupt(segment.validate().unwrap_err()));
+        let merged_path = path::PathBuf::from("test_truncated_segment_merged.seg");
+        assert!(is_corrupt(
+            merge_segments(&merged_path, std::slice::from_ref(&segment), false)
+                .err()
+                .unwrap()
+        ));
+        assert!(!merged_path.exists());
+
+        // a file too short for the segment header is rejected even when unchecked
+        std::fs::write(&segment_file_path, &content[..8]).unwrap();
+        assert!(is_corrupt(
+            unsafe { Segment::open_unchecked(&segment_file_path) }
+                .err()
+                .unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_streams_in_entry_range() {
+        // entry ids 1..=4
+        let memtable = new_test_memtable(&[(1, b"a"), (2, b"b"), (1, b"c"), (3, b"d")]);
+        let segment_file_path = path::PathBuf::from("test_streams_in_entry_range.seg");
+        let segment = generate_segment(&segment_file_path, &memtable).unwrap();
+        segment.set_drop_delete(true);
+
+        assert_eq!(segment.find_stream_header(1).unwrap().first_entry, 1);
+        assert_eq!(segment.find_stream_header(1).unwrap().last_entry, 3);
+        assert_eq!(segment.streams_in_entry_range(1, 4), vec![1, 2, 3]);
+        assert_eq!(segment.streams_in_entry_range(2, 2), vec![1, 2]);
+        assert_eq!(segment.streams_in_entry_range(4, 10), vec![3]);
+        assert!(segment.streams_in_entry_range(5, 10).is_empty());
+    }
+
+    #[test]
+    fn test_streams_in_entry_range_v1_segment() {
+        let segment_file_path = path::PathBuf::from("test_streams_in_entry_range_v1.seg");
+        let segment_header = SegmentHeader {
+            version: SEGMENT_HEADER_VERSION_V1,
+            first_entry: 1,
+            last_entry: 2,
+            stream_headers_count: 2,
+            ..Default::default()
+        };
+        let data_offset =
+            SEGMENT_HEADER_SIZE + 2 * std::mem::size_of::<SegmentStreamHeaderV1>() as u64;
+        let stream_headers = [
+            SegmentStreamHeaderV1 {
+                version: SEGMENT_STREAM_HEADER_VERSION_V1,
+                stream_id: 1,
+                offset: 0,
+                file_offset: data_offset,
+                size: 1,
+                crc64: 0,
+            },
+            SegmentStreamHeaderV1 {
+                version: SEGMENT_STREAM_HEADER_VERSION_V1,
+                stream_id: 2,
+                offset: 0,
+                file_offset: data_offset + 1,
+                size: 1,
+                crc64: 0,
+            },
+        ];
+        let mut content = unsafe {
+            std::slice::from_raw_parts(
+                &segment_header as *const SegmentHeader as *const u8,
+                SEGMENT_HEADER_SIZE as usize,
+            )
+        }
+        .to_vec();
+        content.extend_from_slice(unsafe {
+            std::slice::from_raw_parts(
+                stream_headers.as_ptr() as *const u8,
+                std::mem::size_of_val(&stream_headers),
+            )
+        });
+        content.extend_from_slice(b"ab");
+        std::fs::write(&segment_file_path, content).unwrap();
+
+        let segment = Segment::open(&segment_file_path).unwrap();
+        segment.set_drop_delete(true);
+
+        assert_eq!(&*segment.stream_data(2).unwrap().unwrap(), b"b");
+        assert_eq!(segment.streams_in_entry_range(100, 200), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_repair_segment() {
+        let segment_file_path = path::PathBuf::from("test_repair_sorted.seg");
+        let segment = generate_segment(
+            &segment_file_path,
+            &new_test_memtable(&[(1, b"first"), (2, b"second"), (3, b"third")]),
+        )
+        .unwrap();
+        segment.set_drop_delete(true);
+
+        // swap the first and last stream headers, as a buggy writer would leave them,
+        // and fix up the trailer so only the order is wrong
+        let header = segment.get_segment_header();
+        let mut content = std::fs::read(&segment_file_path).unwrap();
+        let table_start = header.stream_headers_offset as usize;
+        let stream_header_size = SEGMENT_STREAM_HEADER_SIZE as usize;
+        let table_end = table_start + 3 * stream_header_size;
+        let mut table = content[table_start..table_end].to_vec();
+        let (first, rest) = table.split_at_mut(stream_header_size);
+        first.swap_with_slice(&mut rest[stream_header_size..]);
+        content[table_start..table_end].copy_from_slice(&table);
+        let trailer = SegmentTrailer::new(&content[..SEGMENT_HEADER_SIZE as usize], &table);
+        let trailer_start = content.len() - SEGMENT_TRAILER_SIZE as usize;
+        content[trailer_start..].copy_from_slice(trailer.as_bytes());
+        let unsorted_path = path::PathBuf::from("test_repair_unsorted.seg");
+        std::fs::write(&unsorted_path, content).unwrap();
+
+        let err = Segment::open(&unsorted_path).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<errors::Error>(),
+            Some(errors::Error::UnsortedStreamHeaders {
+                previous: 3,
+                stream_id: 2,
+                ..
+            })
+        ));
+        let unsorted = unsafe { Segment::open_unchecked(&unsorted_path) }.unwrap();
+        unsorted.set_drop_delete(true);
+        assert!(unsorted.validate().is_err());
+
+        let repaired_path = path::PathBuf::from("test_repair_repaired.seg");
+        let repaired = repair_segment(&unsorted_path, &repaired_path).unwrap();
+        repaired.set_drop_delete(true);
+        repaired.validate().unwrap();
+        assert_eq!(repaired.level(), segment.level());
+        assert_eq!(repaired.entry_index(), segment.entry_index());
+        for (stream_id, data) in [(1, &b"first"[..]), (2, b"second"), (3, b"third")] {
+            assert_eq!(&*repaired.stream_data(stream_id).unwrap().unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_segment_metadata_blob() {
+        let memtable = new_test_memtable(&[(1, b"hello"), (2, b"world")]);
+
+        let plain_path = path::PathBuf::from("test_metadata_none.seg");
+        let plain = generate_segment(&plain_path, &memtable).unwrap();
+        plain.set_drop_delete(true);
+        assert_eq!(plain.metadata(), None);
+
+        let metadata = br#"{"producer":"ingest","batch":42}"#;
+        let segment_file_path = path::PathBuf::from("test_metadata_blob.seg");
+        let segment =
+            generate_segment_with_metadata(&segment_file_path, &memtable, 1, metadata, false)
+                .unwrap();
+        segment.set_drop_delete(true);
+        assert_eq!(segment.metadata(), Some(&metadata[..]));
+        assert_eq!(&*segment.stream_data(1).unwrap().unwrap(), b"hello");
+        assert_eq!(&*segment.stream_data(2).unwrap().unwrap(), b"world");
+
+        let reopened = Segment::open(&segment_file_path).unwrap();
+        reopened.validate().unwrap();
+        assert_eq!(reopened.metadata(), Some(&metadata[..]));
+        assert_eq!(
+            Segment::read_metadata(&segment_file_path)
+                .unwrap()
+                .metadata_size,
+            metadata.len() as u64
+        );
+
+        let repaired_path = path::PathBuf::from("test_metadata_repaired.seg");
+        let repaired = repair_segment(&segment_file_path, &repaired_path).unwrap();
+        repaired.set_drop_delete(true);
+        assert_eq!(repaired.metadata(), Some(&metadata[..]));
+    }
+
+    #[test]
+    fn test_segment_file_lock() {
+        let memtable = new_test_memtable(&[(1, b"hello")]);
+
+        // another writer holds the temp file of the segment
+        let segment_file_path = path::PathBuf::from("test_lock_busy.seg");
+        let temp_file_path = segment_file_path.with_extension("tmp");
+        let writer = File::create(&temp_file_path).unwrap();
+        fs2::FileExt::try_lock_exclusive(&writer).unwrap();
+        for err in [
+            generate_segment_with_alignment(&segment_file_path, &memtable, 1, true).err(),
+            Segment::open_locked(&temp_file_path).err(),
+        ] {
+            assert!(matches!(
+                err.unwrap().downcast_ref::<errors::Error>(),
+                Some(errors::Error::SegmentLocked { .. })
+            ));
+        }
+        // the other writer's file is left alone
+        assert!(temp_file_path.exists());
+
+        drop(writer);
+        let segment =
+            generate_segment_with_alignment(&segment_file_path, &memtable, 1, true).unwrap();
+        segment.set_drop_delete(true);
+        assert!(!temp_file_path.exists());
+        assert_eq!(&*segment.stream_data(1).unwrap().unwrap(), b"hello");
+
+        // readers share the lock
+        let reader = Segment::open_locked(&segment_file_path).unwrap();
+        assert_eq!(&*reader.stream_data(1).unwrap().unwrap(), b"hello");
+
+        // merged segments are held the same way
+        let merged_path = path::PathBuf::from("test_lock_merged.seg");
+        let merged = merge_segments(&merged_path, &[Arc::new(segment)], true).unwrap();
+        merged.set_drop_delete(true);
+        let file = File::open(&merged_path).unwrap();
+        assert!(fs2::FileExt::try_lock_exclusive(&file).is_err());
+        assert_eq!(&*merged.stream_data(1).unwrap().unwrap(), b"hello");
+        drop(merged);
+        drop(reader);
+    }
+
+    #[test]
+    fn test_segment_into_iter() {
+        let segment_file_path = path::PathBuf::from("test_segment_into_iter.seg");
+        let segment = generate_segment(
+            &segment_file_path,
+            &new_test_memtable(&[(3, b"third"), (1, b"first"), (2, b"second")]),
+        )
+        .unwrap();
+        segment.set_drop_delete(true);
+
+        let mut streams = Vec::new();
+        for (stream_id, data) in &segment {
+            streams.push((stream_id, data.to_vec()));
+        }
+        assert_eq!(
+            streams,
+            vec![
+                (1, b"first".to_vec()),
+                (2, b"second".to_vec()),
+                (3, b"third".to_vec())
+            ]
+        );
+
+        let sizes: Vec<_> = (&segment)
+            .into_iter()
+            .filter(|(stream_id, _)| stream_id % 2 == 1)
+            .map(|(stream_id, data)| (stream_id, data.len()))
+            .collect();
+        assert_eq!(sizes, vec![(1, 5), (3, 5)]);
+        assert_eq!(segment.reader().iter_streams().count(), 3);
+    }
+
+    #[test]
+    fn test_segment_writer_footer_layout() {
+        let segment_file_path = path::PathBuf::from("test_segment_writer_footer.seg");
+        let mut writer = SegmentWriter::create(&segment_file_path).unwrap();
+        writer.set_level(1);
+        // streams out of order and of odd sizes, more than the block index threshold
+        let streams: Vec<(StreamId, Vec<u8>)> = [7, 3, 5, 1, 9, 2]
+            .iter()
+            .map(|id| (*id, vec![*id as u8; *id as usize * 3]))
+            .collect();
+        for (stream_id, data) in &streams {
+            let entry = *stream_id as u64 + 10;
+            writer
+                .add_stream(*stream_id, 100, (entry, entry), data)
+                .unwrap();
+        }
+        let err = writer.add_stream(3, 0, (1, 1), b"again").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<errors::Error>(),
+            Some(errors::Error::AlreadyExists)
+        ));
+        let segment = writer.finish().unwrap();
+        segment.set_drop_delete(true);
+        assert!(!segment_file_path.with_extension("tmp").exists());
+
+        assert!(segment.is_footer_layout());
+        assert_eq!(segment.file_digest(), None);
+        assert!(Segment::verify_file_digest(&segment_file_path).is_err());
+        assert_eq!(segment.level(), 1);
+        assert_eq!(segment.entry_index(), (11, 19));
+        assert_ne!(segment.get_segment_header().stream_header_block_len, 0);
+        segment.validate().unwrap();
+        for (stream_id, data) in &streams {
+            assert_eq!(
+                &*segment.stream_data(*stream_id).unwrap().unwrap(),
+                &data[..]
+            );
+            assert_eq!(
+                segment.get_stream_range(*stream_id),
+                Some((100, 100 + data.len() as u64))
+            );
+        }
+        assert!(segment.find_stream_header(4).is_none());
+        segment.prefetch_metadata().unwrap();
+
+        let metadata = Segment::read_metadata(&segment_file_path).unwrap();
+        assert_eq!(metadata.level, 1);
+        assert_eq!(metadata.stream_ids, vec![1, 2, 3, 5, 7, 9]);
+
+        // a corrupt stream header table fails the footer checksum
+        let mut content = std::fs::read(&segment_file_path).unwrap();
+        let table_offset = segment.get_segment_header().stream_headers_offset as usize;
+        content[table_offset + std::mem::offset_of!(SegmentStreamHeader, size)] ^= 1;
+        let corrupt_path = path::PathBuf::from("test_segment_writer_footer_corrupt.seg");
+        std::fs::write(&corrupt_path, &content).unwrap();
+        for err in [
+            Segment::open(&corrupt_path).err(),
+            Segment::read_metadata(&corrupt_path).err(),
+        ] {
+            assert!(matches!(
+                err.unwrap().downcast_ref::<errors::Error>(),
+                Some(errors::Error::CorruptSegmentMetadata { .. })
+            ));
+        }
+        std::fs::remove_file(&corrupt_path).unwrap();
+
+        // an unfinished writer leaves nothing behind
+        let unfinished_path = path::PathBuf::from("test_segment_writer_unfinished.seg");
+        let mut writer = SegmentWriter::create(&unfinished_path).unwrap();
+        writer.add_stream(1, 0, (1, 1), b"hello").unwrap();
+        drop(writer);
+        assert!(!unfinished_path.exists());
+        assert!(!unfinished_path.with_extension("tmp").exists());
+    }
+}
@@ -1,5 +1,5 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     ops::ControlFlow,
     path,
     rc::Rc,
@@ -15,21 +15,74 @@ use arc_swap::ArcSwap;
 
 use crate::{
     StreamId,
+    cursors::Cursors,
     entry::{AppendEntryResultFn, DataType, Entry},
     errors::{self, new_stream_not_found},
     futures::AppendFuture,
+    manifest::{self, Manifest},
     mem_table::{GetStreamOffset, MemTable, MemTableArc},
     metrics::{self},
     options::Options,
-    reader::StreamReader,
+    reader::{StreamHandle, StreamReader},
     reload::{self, reload_segments},
-    segments::{Segment, generate_segment, merge_segments},
+    segments::{
+        Segment, SegmentStreamHeader, generate_segment_with_alignment,
+        generate_segment_with_metadata, maintain_segments, merge_segments,
+    },
+    stream_cache::StreamHeaderCache,
+    table::StreamDataPool,
     wal::{Wal, WalInner},
 };
 
+pub use crate::stream_cache::StreamHeaderCacheStats;
+pub use crate::table::StreamDataPoolStats;
+
 pub(crate) type SegmentArc = Arc<Segment>;
 pub(crate) type SegmentWeak = Weak<Segment>;
 
+// A rotated memtable waiting to be written as a segment, explicit flushes wait
+// on `reply` for the segment
+struct SegmentTask {
+    file_name: path::PathBuf,
+    table: MemTableArc,
+    // metadata blob of the segment, see Store::flush_with_metadata
+    metadata: Vec<u8>,
+    reply: Option<SyncSender<Result<SegmentArc>>>,
+}
+
+// What Store::maintain discards while it compacts the segments
+#[derive(Debug, Clone, Default)]
+pub struct MaintenancePolicy {
+    // streams whose data is dropped entirely
+    pub tombstones: HashSet<StreamId>,
+    // per stream, the offset below which its data is dropped
+    pub retention: HashMap<StreamId, u64>,
+}
+
+impl MaintenancePolicy {
+    // None if the stream is tombstoned, else the offset below which its data is
+    // dropped, the retention of maintain_segments and Segment::compaction_stats
+    pub fn retain_from(&self, stream_id: StreamId) -> Option<u64> {
+        (!self.tombstones.contains(&stream_id))
+            .then(|| self.retention.get(&stream_id).copied().unwrap_or(0))
+    }
+}
+
+// Outcome of Store::maintain
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MaintenanceReport {
+    // segments compacted into the new one
+    pub segments_merged: usize,
+    // tombstoned streams that had data in the segments
+    pub streams_dropped: usize,
+    // stream data of the tombstoned streams
+    pub bytes_dropped: u64,
+    // stream data below the retention offsets
+    pub bytes_trimmed: u64,
+    // size of the old segment files less the size of the new one
+    pub bytes_reclaimed: u64,
+}
+
 pub struct StreamStoreInner {
     // segment files
     wal_inner: Arc<WalInner>,
@@ -42,12 +95,27 @@ pub struct StreamStoreInner {
     pub(crate) segment_files: RwLock<VecDeque<SegmentArc>>,
     pub(crate) offsets: Arc<Mutex<HashMap<StreamId, u64>>>,
     pub(crate) is_readonly: Arc<atomic::AtomicBool>,
+    stream_header_cache: Option<StreamHeaderCache>,
+    stream_data_pool: Option<Arc<StreamDataPool>>,
+    // sequence number the next segment file is named after
+    next_segment_id: AtomicU64,
+    // held while appending to or rotating the current memtable, so a flush never
+    // rotates a table an entry is being appended to
+    rotate_lock: Mutex<()>,
+    // memtable size that triggers a flush, starts at Options::max_table_size
+    target_segment_size: AtomicU64,
+    // consumer cursors, loaded on first use
+    cursors: Mutex<Option<Cursors>>,
+    // held while segments are rewritten, so the merger and maintain never both
+    // take the same segments
+    merge_lock: Mutex<()>,
 }
 
 #[derive(Clone)]
 pub struct Store {
     inner: Arc<StreamStoreInner>,
     wal: Wal,
+    segment_sender: SyncSender<SegmentTask>,
 }
 
 impl std::ops::Deref for Store {
@@ -120,18 +188,156 @@ impl StreamStoreInner {
         }
     }
 
+    // Persist the current segment list, must be called with the segment list write lock held
+    pub(crate) fn save_manifest(&self, segment_files: &VecDeque<SegmentArc>) -> Result<()> {
+        Manifest::from_segments(segment_files, self.next_segment_id())
+            .store(path::Path::new(&self.config.segment_path))
+    }
+
+    // Sequence number the next segment file is named after, segment file names
+    // sort in creation order
+    pub fn next_segment_id(&self) -> u64 {
+        self.next_segment_id.load(atomic::Ordering::SeqCst)
+    }
+
+    fn new_segment_file_name(&self) -> path::PathBuf {
+        let sequence = self.next_segment_id.fetch_add(1, atomic::Ordering::SeqCst);
+        path::Path::new(&self.config.segment_path).join(manifest::segment_file_name(sequence))
+    }
+
+    pub(crate) fn segment_read_ahead(&self) -> u64 {
+        self.config.segment_read_ahead
+    }
+
     pub(crate) fn find_segment(&self, stream_id: StreamId, offset: u64) -> Option<SegmentArc> {
         self.segment_files
             .read()
             .unwrap()
             .iter()
-            .find(|segment| match segment.get_stream_range(stream_id) {
-                Some((begin, end)) => begin <= offset && offset < end,
-                None => false,
+            .find(|segment| {
+                self.find_stream_header(segment, stream_id)
+                    .is_some_and(|header| {
+                        header.offset <= offset && offset < header.offset + header.size
+                    })
             })
             .cloned()
     }
 
+    // Look up the stream header of a segment through the stream header cache, if enabled
+    pub(crate) fn find_stream_header(
+        &self,
+        segment: &Segment,
+        stream_id: StreamId,
+    ) -> Option<SegmentStreamHeader> {
+        match &self.stream_header_cache {
+            Some(cache) => cache.get_or_resolve(segment.id(), stream_id, || {
+                segment.find_stream_header(stream_id)
+            }),
+            None => segment.find_stream_header(stream_id),
+        }
+    }
+
+    // Hit/miss counts of the stream header cache, None if it is disabled
+    pub fn stream_header_cache_stats(&self) -> Option<StreamHeaderCacheStats> {
+        self.stream_header_cache.as_ref().map(|cache| cache.stats())
+    }
+
+    // Allocation counts of the memtable stream data pool, None if it is disabled
+    pub fn stream_data_pool_stats(&self) -> Option<StreamDataPoolStats> {
+        self.stream_data_pool.as_ref().map(|pool| pool.stats())
+    }
+
+    // Memtable size at which it is flushed to a segment
+    pub fn target_segment_size(&self) -> u64 {
+        self.target_segment_size.load(atomic::Ordering::Relaxed)
+    }
+
+    // Change the flush size at runtime, e.g. small for quick durability of a
+    // latency sensitive tenant, large for archival. A memtable already past the
+    // new size is flushed on the next append
+    pub fn set_target_segment_size(&self, bytes: u64) {
+        self.target_segment_size.store(bytes, atomic::Ordering::Relaxed);
+    }
+
+    // Bytes in the current memtable, a flush follows once it exceeds
+    // target_segment_size
+    pub fn mem_table_size(&self) -> u64 {
+        self.table.load().get_size()
+    }
+
+    // Load the segments another store added to the segment directory since this
+    // store loaded it (e.g. the primary of a read replica sharing the directory),
+    // and release the loaded ones whose files were deleted, e.g. merged away by
+    // the writer. Returns the number of segments added.
+    //
+    // The new segments are opened before the segment list is locked, reads in
+    // progress keep the segments they hold. A replica must neither append nor
+    // merge (max_segment_merge_level 0), it would write into the writer's directory
+    pub fn refresh(&self) -> Result<usize> {
+        let loaded = self
+            .segment_files
+            .read()
+            .unwrap()
+            .iter()
+            .map(|segment| segment.filename())
+            .collect::<HashSet<_>>();
+        let mut opened = Vec::new();
+        for filename in reload::live_segment_files(&self.config.segment_path)? {
+            if !loaded.contains(&filename) {
+                opened.push(Arc::new(reload::open_segment(
+                    &filename,
+                    self.config.reload_check_crc,
+                    self.config.segment_file_lock,
+                )?));
+            }
+        }
+
+        let mut segment_files = self.segment_files.write().unwrap();
+        let count = segment_files.len();
+        segment_files.retain(|segment| segment.filename().exists());
+        let released = count - segment_files.len();
+        let mut added = 0;
+        for segment in opened {
+            // registered meanwhile by a flush or merge of this store
+            if segment_files
+                .iter()
+                .any(|s| s.filename() == segment.filename())
+            {
+                continue;
+            }
+            let mut offsets = self.offsets.lock().unwrap();
+            for stream_header in segment.stream_headers()? {
+                let end = stream_header.offset + stream_header.size;
+                let offset = offsets.entry(stream_header.stream_id).or_default();
+                *offset = (*offset).max(end);
+            }
+            drop(offsets);
+            segment_files.push_back(segment);
+            added += 1;
+        }
+        segment_files
+            .make_contiguous()
+            .sort_by_key(|s| s.entry_index().1);
+
+        if added > 0 || released > 0 {
+            log::info!(
+                "Refreshed segments of {}: {} added, {} released",
+                self.config.segment_path,
+                added,
+                released
+            );
+        }
+        Ok(added)
+    }
+
+    fn new_mem_table(&self, get_stream_offset: GetStreamOffset) -> MemTable {
+        let mem_table = MemTable::new(get_stream_offset);
+        match &self.stream_data_pool {
+            Some(pool) => mem_table.with_stream_data_pool(pool.clone()),
+            None => mem_table,
+        }
+    }
+
     pub fn get_stream_begin(&self, stream_id: StreamId) -> Result<u64> {
         let mut begin = self
             .segment_files
@@ -197,23 +403,45 @@ impl StreamStoreInner {
         return Ok(end.unwrap());
     }
 
-    fn memtable_writer(
+    fn get_stream_offset(&self) -> GetStreamOffset {
+        let offsets = self.offsets.clone();
+        Box::new(
+            move |stream_id| match offsets.lock().unwrap().get(&stream_id) {
+                Some(offset) => {
+                    log::debug!("Get stream offset for stream_id {}: {}", stream_id, offset);
+                    Ok(*offset)
+                }
+                None => Ok(0), // Default to 0 if not found
+            },
+        )
+    }
+
+    // Replace the current memtable with an empty one and queue `table` for the
+    // segment generator, must be called with the rotate lock held
+    fn rotate_mem_table(
         &self,
-        write_segment_sender: SyncSender<(path::PathBuf, MemTableArc)>,
-    ) -> () {
-        let get_stream_offset = || -> _ {
-            let offsets = self.offsets.clone();
-            Box::new(
-                move |stream_id| match offsets.lock().unwrap().get(&stream_id) {
-                    Some(offset) => {
-                        log::debug!("Get stream offset for stream_id {}: {}", stream_id, offset);
-                        Ok(*offset)
-                    }
-                    None => Ok(0), // Default to 0 if not found
-                },
-            )
-        };
+        table: MemTableArc,
+        metadata: Vec<u8>,
+        reply: Option<SyncSender<Result<SegmentArc>>>,
+        write_segment_sender: &SyncSender<SegmentTask>,
+    ) -> Result<()> {
+        self.mem_tables.write().unwrap().push_back(table.clone());
+        self.table
+            .store(Arc::new(self.new_mem_table(self.get_stream_offset())));
+
+        let file_name = self.new_segment_file_name();
+        // notify to create a new segment
+        write_segment_sender
+            .send(SegmentTask {
+                file_name,
+                table,
+                metadata,
+                reply,
+            })
+            .map_err(|_| errors::new_store_is_read_only())
+    }
 
+    fn memtable_writer(&self, write_segment_sender: SyncSender<SegmentTask>) -> () {
         loop {
             let entries = match self.entry_receiver.lock().unwrap().recv() {
                 Ok(entries) => entries,
@@ -225,7 +453,8 @@ impl StreamStoreInner {
             };
 
             for entry in entries {
-                let table = self.table.load();
+                let _rotate_guard = self.rotate_lock.lock().unwrap();
+                let table = self.table.load_full();
                 // Append the memory table
                 match table.append(&entry) {
                     Ok(offset) => {
@@ -244,21 +473,13 @@ impl StreamStoreInner {
                 }
 
                 // Check if the table size is greater than the max size
-                if table.get_size() > self.config.max_table_size {
-                    self.mem_tables.write().unwrap().push_back(table.clone());
-                    self.table
-                        .store(Arc::new(MemTable::new(get_stream_offset())));
-
-                    let filename = std::path::Path::new(&self.config.segment_path).join(format!(
-                        "{}-{}.seg",
-                        table.get_first_entry(),
-                        table.get_last_entry()
-                    ));
-                    // notify to create a new segment
-                    write_segment_sender
-                        .send((filename, table.clone()))
-                        .unwrap();
-                    // Reset the memory table
+                if table.get_size() > self.target_segment_size() {
+                    if let Err(e) =
+                        self.rotate_mem_table(table, Vec::new(), None, &write_segment_sender)
+                    {
+                        log::error!("Failed to rotate memtable: {:?}", e);
+                        return;
+                    }
                 }
             }
         }
@@ -266,7 +487,7 @@ impl StreamStoreInner {
 
     fn run_segment_generater(
         &self,
-        receiver: Receiver<(path::PathBuf, MemTableArc)>,
+        receiver: Receiver<SegmentTask>,
         cond: Arc<(Mutex<u64>, Condvar)>,
     ) -> Result<()> {
         loop {
@@ -274,58 +495,109 @@ impl StreamStoreInner {
                 log::info!("Stop segment generator");
                 break;
             }
-            let (file_name, table) = match receiver.recv() {
-                Ok((file_name, table)) => (file_name, table),
+            let SegmentTask {
+                file_name,
+                table,
+                metadata,
+                reply,
+            } = match receiver.recv() {
+                Ok(task) => task,
                 Err(_) => {
                     log::info!("Segment generator receiver closed, exiting segment generator");
                     return Ok(());
                 }
             };
-            match generate_segment(&file_name, &table) {
-                Ok(_) => {
-                    log::info!("Segment generated: {}", file_name.display());
-                    match self.wal_inner.gc(table.get_last_entry()) {
-                        Ok(_) => {
-                            log::info!(
-                                "WAL garbage collection completed for segment: {}",
-                                file_name.display()
-                            );
-                        }
-                        Err(e) => {
-                            log::error!("Failed to garbage collect WAL: {:?}", e);
-                            // If WAL garbage collection fails, set the store to readonly
-                            self.is_readonly.store(true, atomic::Ordering::SeqCst);
-                            return Err(e);
-                        }
-                    }
-                }
+            if table.stream_count() == 0 {
+                // nothing to write, flush never rotates an empty table
+                log::debug!(
+                    "Skipping segment {} of an empty memtable",
+                    file_name.display()
+                );
+                continue;
+            }
+            let segment = match self.write_segment(&file_name, &table, &metadata) {
+                Ok(segment) => segment,
                 Err(e) => {
-                    // If segment generation fails, set the store to readonly
+                    // If the segment cannot be written, set the store to readonly
                     self.is_readonly.store(true, atomic::Ordering::SeqCst);
-                    log::error!(
-                        "Failed to generate segment {}: {:?}",
-                        file_name.display(),
-                        e
-                    );
+                    if let Some(reply) = reply {
+                        let _ = reply.send(Err(errors::new_flush_failed(
+                            &file_name,
+                            format!("{:#}", e),
+                        )));
+                    }
                     return Err(e);
                 }
-            }
-            // update segment list
-            let segment = Arc::new(Segment::open(&file_name).unwrap());
-
-            let mut segment_files_guard = self.segment_files.write().unwrap();
-            segment_files_guard.push_back(segment.clone());
+            };
 
             let mut memtables = self.mem_tables.write().unwrap();
             if memtables.len() > self.config.max_tables_count as usize {
                 memtables.pop_front();
             }
+            drop(memtables);
+            if let Some(reply) = reply {
+                let _ = reply.send(Ok(segment));
+            }
             // notify the segment merger
             cond.1.notify_one();
         }
         Ok(())
     }
 
+    // Generate the segment of a rotated memtable, list it in the manifest and
+    // remove the WAL files it covers
+    fn write_segment(
+        &self,
+        file_name: &path::PathBuf,
+        table: &MemTableArc,
+        metadata: &[u8],
+    ) -> Result<SegmentArc> {
+        let segment = match generate_segment_with_metadata(
+            file_name,
+            table,
+            self.config.segment_data_alignment,
+            metadata,
+            self.config.segment_file_lock,
+        ) {
+            Ok(segment) => {
+                log::info!("Segment generated: {}", file_name.display());
+                Arc::new(segment)
+            }
+            Err(e) => {
+                log::error!(
+                    "Failed to generate segment {}: {:?}",
+                    file_name.display(),
+                    e
+                );
+                return Err(e);
+            }
+        };
+
+        // update segment list
+        let mut segment_files_guard = self.segment_files.write().unwrap();
+        segment_files_guard.push_back(segment.clone());
+
+        // the manifest must list the segment before the WAL covering it is removed
+        if let Err(e) = self.save_manifest(&segment_files_guard) {
+            log::error!("Failed to save manifest: {:?}", e);
+            return Err(e);
+        }
+        drop(segment_files_guard);
+        match self.wal_inner.gc(table.get_last_entry()) {
+            Ok(_) => {
+                log::info!(
+                    "WAL garbage collection completed for segment: {}",
+                    file_name.display()
+                );
+            }
+            Err(e) => {
+                log::error!("Failed to garbage collect WAL: {:?}", e);
+                return Err(e);
+            }
+        }
+        Ok(segment)
+    }
+
     pub fn merge_segments(&self) -> Result<()> {
         for level in 0..self.config.max_segment_merge_level {
             loop {
@@ -348,6 +620,7 @@ impl StreamStoreInner {
     }
 
     pub fn merge_segments_with_level(&self, level: u32) -> Result<bool> {
+        let _merge_guard = self.merge_lock.lock().unwrap();
         let to_merges = match self.segment_files.read().unwrap().iter().try_fold(
             Vec::new(),
             |mut acc, segment| {
@@ -371,13 +644,9 @@ impl StreamStoreInner {
         let begin_ts = std::time::Instant::now();
 
         // Generate the new segment file name
-        let file_name = std::path::Path::new(&self.config.segment_path).join(format!(
-            "{}-{}.seg",
-            to_merges.first().unwrap().get_segment_header().first_entry,
-            to_merges.last().unwrap().get_segment_header().last_entry,
-        ));
+        let file_name = self.new_segment_file_name();
 
-        let segment = match merge_segments(&file_name, &to_merges) {
+        let segment = match merge_segments(&file_name, &to_merges, self.config.segment_file_lock) {
             Ok(segment) => {
                 log::info!(
                     "Merged {:?} segments into new segment: {}",
@@ -403,15 +672,20 @@ impl StreamStoreInner {
         segment_files_guard.push_back(Arc::new(segment));
 
         // Remove the merged segments from the list
-        for segment in to_merges {
+        for segment in to_merges.iter() {
             segment_files_guard.retain(|s| s.filename() != segment.filename());
-            segment.set_drop_delete(true);
         }
 
         segment_files_guard
             .make_contiguous()
             .sort_by_key(|s| s.entry_index().1);
 
+        // only delete the merged segments once the manifest no longer references them
+        self.save_manifest(&segment_files_guard)?;
+        for segment in to_merges {
+            segment.set_drop_delete(true);
+        }
+
         log::info!(
             "Segment merge completed, new segment file: {} took {} ms",
             file_name.display(),
@@ -422,6 +696,97 @@ impl StreamStoreInner {
         return Ok(true);
     }
 
+    // Compact all segments into one in a single pass, leaving out what `policy`
+    // discards: every byte of the tombstoned streams and, per stream, the data
+    // below its retention offset. The new segment replaces the old ones in one
+    // update of the segment list and manifest, reads in progress keep the segments
+    // they hold. Flushed memtables are released, unflushed data is not touched:
+    // data of a tombstoned stream still in a memtable reaches a segment with its
+    // flush. A stream trimmed of all its data keeps its end offset in the new
+    // segment, appends after a reload continue from there
+    pub fn maintain(&self, policy: MaintenancePolicy) -> Result<MaintenanceReport> {
+        let _merge_guard = self.merge_lock.lock().unwrap();
+        let segments = self
+            .segment_files
+            .read()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut report = MaintenanceReport {
+            segments_merged: segments.len(),
+            ..Default::default()
+        };
+        if segments.is_empty() {
+            return Ok(report);
+        }
+
+        let retention = |stream_id: StreamId| policy.retain_from(stream_id);
+        for segment in &segments {
+            let stats = segment.compaction_stats(&retention)?;
+            report.bytes_dropped += stats.tombstone_bytes;
+            report.bytes_trimmed += stats.dead_bytes - stats.tombstone_bytes;
+        }
+        // a tombstoned stream is counted once however many segments hold its data
+        report.streams_dropped = policy
+            .tombstones
+            .iter()
+            .filter(|stream_id| {
+                segments
+                    .iter()
+                    .any(|segment| segment.find_stream_header(**stream_id).is_some())
+            })
+            .count();
+
+        // the new segment is written even if no data is left, it keeps the entry
+        // range of the old ones for the next reload
+        let begin_ts = std::time::Instant::now();
+        let file_name = self.new_segment_file_name();
+        let segment = Arc::new(maintain_segments(
+            &file_name,
+            &segments,
+            &retention,
+            self.config.segment_file_lock,
+        )?);
+        report.bytes_reclaimed = segments
+            .iter()
+            .map(|segment| segment.file_size())
+            .sum::<u64>()
+            .saturating_sub(segment.file_size());
+
+        let mut segment_files_guard = self.segment_files.write().unwrap();
+        segment_files_guard.retain(|s| !segments.iter().any(|m| m.filename() == s.filename()));
+        segment_files_guard.push_back(segment);
+        segment_files_guard
+            .make_contiguous()
+            .sort_by_key(|s| s.entry_index().1);
+        // only delete the old segments once the manifest no longer references them
+        self.save_manifest(&segment_files_guard)?;
+        // flushed memtables kept for reads still hold the dropped data, the segments
+        // have everything they hold
+        let last_entry = segments
+            .iter()
+            .map(|segment| segment.entry_index().1)
+            .max()
+            .unwrap_or(0);
+        self.mem_tables
+            .write()
+            .unwrap()
+            .retain(|table| table.get_last_entry() > last_entry);
+        drop(segment_files_guard);
+        for segment in segments {
+            segment.set_drop_delete(true);
+        }
+
+        log::info!(
+            "Segment maintenance completed, new segment file: {} took {} ms: {:?}",
+            file_name.display(),
+            begin_ts.elapsed().as_millis(),
+            report
+        );
+        Ok(report)
+    }
+
     // Start the segment generator thread
     fn run_segment_merger(&self, signal: Arc<(Mutex<u64>, Condvar)>) -> Result<()> {
         loop {
@@ -444,6 +809,100 @@ impl StreamStoreInner {
         }
     }
 
+    // Capture the segments and memtables holding data at one point in time.
+    // segment_files is locked before mem_tables, the order the segment generator
+    // takes them in, so a flush is either fully before or fully after the snapshot
+    fn read_snapshot(&self) -> (Vec<SegmentArc>, Vec<MemTableArc>) {
+        let segment_files = self.segment_files.read().unwrap();
+        let mem_tables = self.mem_tables.read().unwrap();
+        let mut tables = mem_tables.iter().cloned().collect::<Vec<_>>();
+        // the active table can already be in mem_tables while it is being swapped out
+        let table = self.table.load_full();
+        if !tables.iter().any(|t| Arc::ptr_eq(t, &table)) {
+            tables.push(table);
+        }
+        (segment_files.iter().cloned().collect(), tables)
+    }
+
+    // Read `size` bytes of the stream starting at `offset` from a single snapshot
+    // of the store. Data moving from a memtable into a segment during the read is
+    // neither read twice nor skipped; appends after the snapshot are not visible.
+    // The result is shorter than `size` when the snapshot ends before offset + size
+    pub fn read_consistent(&self, stream_id: StreamId, offset: u64, size: u64) -> Result<Vec<u8>> {
+        let (segments, tables) = self.read_snapshot();
+
+        let end = offset
+            .checked_add(size)
+            .ok_or_else(|| errors::new_stream_offset_invalid(stream_id, offset))?;
+        // allocate only what the snapshot holds, `size` may be far past the stream end
+        let snapshot_end = tables
+            .iter()
+            .filter_map(|table| table.get_stream_range(stream_id))
+            .chain(
+                segments
+                    .iter()
+                    .filter_map(|segment| segment.get_stream_range(stream_id)),
+            )
+            .map(|(_, end)| end)
+            .max()
+            .unwrap_or(offset);
+        let end = end.min(snapshot_end.max(offset));
+        let mut data = vec![0u8; (end - offset) as usize];
+        let mut pos = offset;
+        while pos < end {
+            let buf = &mut data[(pos - offset) as usize..];
+            let in_range = |range: Option<(u64, u64)>| {
+                range.is_some_and(|(begin, end)| begin <= pos && pos < end)
+            };
+            // memtables first, newest (the live table) to oldest: a table rotated
+            // out stays listed until after its segment is, so recent data is
+            // always found in one of the two
+            let read = if let Some(table) = tables
+                .iter()
+                .rev()
+                .find(|table| in_range(table.get_stream_range(stream_id)))
+            {
+                table.read_stream(stream_id, pos, buf)
+            } else if let Some(segment) = segments
+                .iter()
+                .find(|segment| in_range(segment.get_stream_range(stream_id)))
+            {
+                segment.read_stream(stream_id, pos, buf)
+            } else {
+                break;
+            }
+            .map_err(errors::new_io_error)?;
+            if read == 0 {
+                break;
+            }
+            pos += read as u64;
+        }
+
+        if pos == offset && size > 0 {
+            // nothing at offset, tell an offset past the end from an unknown stream
+            // or from data that was compacted away
+            let base = segments
+                .iter()
+                .filter_map(|segment| segment.stream_logical_range(stream_id))
+                .chain(
+                    tables
+                        .iter()
+                        .filter_map(|table| table.get_stream_range(stream_id)),
+                )
+                .map(|(begin, _)| begin)
+                .min();
+            match base {
+                None => return Err(new_stream_not_found(stream_id)),
+                Some(base) if offset < base => {
+                    return Err(errors::new_offset_below_retention(stream_id, offset, base));
+                }
+                Some(_) => {}
+            }
+        }
+        data.truncate((pos - offset) as usize);
+        Ok(data)
+    }
+
     pub fn get_stream_range(&self, stream_id: StreamId) -> Result<(u64, u64)> {
         let res = self.get_stream_begin(stream_id);
         match res {
@@ -457,10 +916,11 @@ impl StreamStoreInner {
 }
 
 impl Store {
+    // Append `data` to the stream, Bytes (and Vec) data is stored without a copy
     pub fn append(
         &self,
         stream_id: StreamId,
-        data: DataType,
+        data: impl Into<DataType>,
         callback: Option<AppendEntryResultFn>,
     ) -> Result<()> {
         // Check if the store is read-only
@@ -472,15 +932,12 @@ impl Store {
             .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
 
         self.wal.write(Entry {
-            version: 1,
-            id: id,
-            stream_id,
-            data,
-            callback: callback,
+            callback,
+            ..Entry::new(id, stream_id, data)
         })
     }
 
-    pub async fn append_async(&self, stream_id: StreamId, data: DataType) -> Result<u64> {
+    pub async fn append_async(&self, stream_id: StreamId, data: impl Into<DataType>) -> Result<u64> {
         // Check if the store is read-only
         if self.is_readonly.load(atomic::Ordering::SeqCst) {
             return Err(errors::new_store_is_read_only());
@@ -492,16 +949,13 @@ impl Store {
         let f = AppendFuture::new();
 
         let result = self.wal.write(Entry {
-            version: 1,
-            id: id,
-            stream_id,
-            data,
             callback: Some(Box::new({
                 let f = f.clone();
                 move |result| {
                     f.set_result(result);
                 }
             })),
+            ..Entry::new(id, stream_id, data)
         });
         if result.is_err() {
             return Err(result.err().unwrap());
@@ -510,6 +964,39 @@ impl Store {
         f.await
     }
 
+    /// Write the current memtable as a segment and wait until the manifest lists it.
+    ///
+    /// The segment holds every entry whose append has completed, it is queued behind
+    /// memtables already rotated by size so segments stay in entry order. Returns
+    /// None without doing anything if the memtable is empty, so flushing an idle
+    /// store twice is harmless.
+    pub fn flush(&self) -> Result<Option<Arc<Segment>>> {
+        self.flush_with_metadata(&[])
+    }
+
+    /// Same as flush, storing `metadata` (e.g. a small JSON description of the
+    /// batch) in the segment for Segment::metadata. The metadata is dropped along
+    /// with the flush if the memtable is empty, and memtables rotated by size get
+    /// none. It is not covered by the segment checksums, see Segment::metadata.
+    pub fn flush_with_metadata(&self, metadata: &[u8]) -> Result<Option<Arc<Segment>>> {
+        if self.is_readonly.load(atomic::Ordering::SeqCst) {
+            return Err(errors::new_store_is_read_only());
+        }
+        let (reply, result) = sync_channel(1);
+        {
+            let _rotate_guard = self.rotate_lock.lock().unwrap();
+            let table = self.table.load_full();
+            if table.stream_count() == 0 {
+                return Ok(None);
+            }
+            self.rotate_mem_table(table, metadata.to_vec(), Some(reply), &self.segment_sender)?;
+        }
+        result
+            .recv()
+            .map_err(|_| errors::new_store_is_read_only())?
+            .map(Some)
+    }
+
     pub fn new_stream_reader(&self, stream_id: StreamId) -> Result<StreamReader> {
         self.offsets.lock().unwrap().get(&stream_id).map_or_else(
             || Err(new_stream_not_found(stream_id)),
@@ -517,6 +1004,12 @@ impl Store {
         )
     }
 
+    // Open the stream as a Read + Seek handle over its whole logical range, see
+    // StreamHandle. Fails with Error::StreamNotFound if the stream has no data
+    pub fn open_stream(&self, stream_id: StreamId) -> Result<StreamHandle> {
+        StreamHandle::new(self.inner.clone(), stream_id)
+    }
+
     pub fn get_stream_end(&self, stream_id: StreamId) -> Result<u64> {
         self.inner.get_stream_end(stream_id)
     }
@@ -529,6 +1022,43 @@ impl Store {
         self.inner.get_stream_range(stream_id)
     }
 
+    pub fn read_consistent(&self, stream_id: StreamId, offset: u64, size: u64) -> Result<Vec<u8>> {
+        self.inner.read_consistent(stream_id, offset, size)
+    }
+
+    /// Read `size` bytes of the stream at `offset`, see read_consistent.
+    ///
+    /// Reads see every append of this store whose callback has run (or whose
+    /// append_async has returned), also while its memtable is being flushed: the
+    /// rotated table keeps serving reads until its segment is registered.
+    pub fn read(&self, stream_id: StreamId, offset: u64, size: u64) -> Result<Vec<u8>> {
+        self.inner.read_consistent(stream_id, offset, size)
+    }
+
+    /// Durably record that consumer `consumer_id` has read stream `stream_id` up to
+    /// `offset`, so it can resume there after a restart, see load_cursor.
+    ///
+    /// Cursors are kept in a small sidecar file next to the manifest, written on
+    /// every save, independent of appends and flushes. Consumer ids are free-form
+    /// names, e.g. the name of a service, on a single line.
+    pub fn save_cursor(&self, consumer_id: &str, stream_id: StreamId, offset: u64) -> Result<()> {
+        self.with_cursors(|cursors| cursors.save(consumer_id, stream_id, offset))
+    }
+
+    /// The offset last saved by save_cursor for the consumer and stream, None if the
+    /// consumer never saved one
+    pub fn load_cursor(&self, consumer_id: &str, stream_id: StreamId) -> Result<Option<u64>> {
+        self.with_cursors(|cursors| Ok(cursors.get(consumer_id, stream_id)))
+    }
+
+    fn with_cursors<R>(&self, f: impl FnOnce(&mut Cursors) -> Result<R>) -> Result<R> {
+        let mut cursors = self.cursors.lock().unwrap();
+        if cursors.is_none() {
+            *cursors = Some(Cursors::load(path::Path::new(&self.config.segment_path))?);
+        }
+        f(cursors.as_mut().unwrap())
+    }
+
     #[allow(dead_code)]
     fn get_last_segment_entry_index(&self) -> Result<u64> {
         let segment_files = self.segment_files.read().unwrap();
@@ -543,9 +1073,14 @@ impl Store {
         let mut offset_map = HashMap::new();
 
         let (entries_sender, entries_receiver) = sync_channel::<Vec<Entry>>(100);
+        let (segment_sender, segment_receiver) = sync_channel::<SegmentTask>(10);
 
         let mut last_segment_entry_index = 0;
-        let mut segment_files = reload_segments(&options.segment_path, options.reload_check_crc)?;
+        let mut segment_files = reload_segments(
+            &options.segment_path,
+            options.reload_check_crc,
+            options.segment_file_lock,
+        )?;
         if !segment_files.is_empty() {
             last_segment_entry_index = segment_files.back().unwrap().entry_index().1;
         }
@@ -553,7 +1088,7 @@ impl Store {
         // reload the offsets from the segment files
         log::info!("Reloading offsets from segment files");
         for segment in segment_files.iter() {
-            for stream_header in segment.get_stream_headers() {
+            for stream_header in segment.stream_headers()? {
                 let stream_id = stream_header.stream_id;
                 let offset = stream_header.offset + stream_header.size;
                 offset_map.insert(stream_id, offset);
@@ -571,7 +1106,7 @@ impl Store {
         log::info!("Reloading offsets from memtables");
         for mem_tables in mem_tables.iter() {
             let stream_tables = mem_tables.get_stream_tables();
-            for (stream_id, stream_table) in &*stream_tables {
+            for (stream_id, stream_table) in stream_tables.iter() {
                 match stream_table.get_stream_range() {
                     Some((_begin, end)) => {
                         offset_map.insert(stream_id.clone(), end);
@@ -590,14 +1125,21 @@ impl Store {
 
         let mem_table = mem_tables.pop_back().unwrap();
         // generate the segment files from the memtable
-        for table in mem_tables {
-            let filename = std::path::Path::new(&options.segment_path).join(format!(
-                "{}-{}.seg",
-                table.get_first_entry(),
-                table.get_last_entry()
-            ));
-            segment_files.push_back(Arc::new(generate_segment(&filename, &table)?));
+        let mut next_segment_id =
+            manifest::next_segment_sequence(path::Path::new(&options.segment_path))?;
+        for table in mem_tables.iter().filter(|table| table.stream_count() > 0) {
+            let filename = path::Path::new(&options.segment_path)
+                .join(manifest::segment_file_name(next_segment_id));
+            next_segment_id += 1;
+            segment_files.push_back(Arc::new(generate_segment_with_alignment(
+                &filename,
+                table,
+                options.segment_data_alignment,
+                options.segment_file_lock,
+            )?));
         }
+        Manifest::from_segments(&segment_files, next_segment_id)
+            .store(path::Path::new(&options.segment_path))?;
 
         let is_readonly = Arc::new(atomic::AtomicBool::new(false));
         let last_log_entry = mem_table.get_last_entry();
@@ -622,14 +1164,27 @@ impl Store {
 
         log::info!("last log entry: {}", last_log_entry);
 
+        let stream_data_pool = (options.stream_data_pool_capacity > 0)
+            .then(|| Arc::new(StreamDataPool::new(options.stream_data_pool_capacity)));
         // unwrap the Rc to get the inner memtable
-        let memtable = Rc::into_inner(mem_table).unwrap();
+        let mut memtable = Rc::into_inner(mem_table).unwrap();
+        if let Some(pool) = &stream_data_pool {
+            memtable = memtable.with_stream_data_pool(pool.clone());
+        }
 
         let inner = StreamStoreInner {
             wal_inner: wal.clone_inner(),
             config: options.clone(),
             offsets: Arc::new(Mutex::new(offset_map)),
             is_readonly: is_readonly.clone(),
+            stream_header_cache: (options.stream_header_cache_capacity > 0)
+                .then(|| StreamHeaderCache::new(options.stream_header_cache_capacity)),
+            stream_data_pool,
+            next_segment_id: AtomicU64::new(next_segment_id),
+            rotate_lock: Mutex::new(()),
+            target_segment_size: AtomicU64::new(options.max_table_size),
+            cursors: Mutex::new(None),
+            merge_lock: Mutex::new(()),
             entry_index: AtomicU64::new(last_log_entry + 1),
             table: ArcSwap::new(Arc::new(memtable)),
             mem_tables: RwLock::new(VecDeque::new()),
@@ -640,10 +1195,11 @@ impl Store {
         let store = Store {
             inner: Arc::new(inner),
             wal: wal,
+            segment_sender,
         };
 
         // start background thread
-        store.start();
+        store.start(segment_receiver);
 
         Ok(store)
     }
@@ -656,10 +1212,10 @@ impl Store {
         metrics::encode_metrics()
     }
 
-    fn start(&self) -> () {
+    fn start(&self, receiver: Receiver<SegmentTask>) -> () {
         self.wal.start();
 
-        let (sender, receiver) = sync_channel::<(path::PathBuf, MemTableArc)>(10);
+        let sender = self.segment_sender.clone();
         let cond = Arc::new((Mutex::new(0 as u64), Condvar::new()));
 
         let _ = std::thread::Builder::new()
@@ -701,3 +1257,540 @@ impl Drop for Store {
         // ref count of Arc
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_read_consistent_during_flush() {
+        let dir = std::env::temp_dir().join(format!(
+            "streamstore_read_consistent_{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut options = Options::new_with_data_path(dir.to_str().unwrap());
+        // small tables, so the reader keeps racing flushes
+        options.max_table_size(1024).max_wal_size(4096);
+        let store = options.open_store().unwrap();
+
+        const STREAM_ID: StreamId = 7;
+        const RECORDS: u64 = 20000;
+        // each record is its own index, so every prefix of the stream is predictable
+        let expected = (0..RECORDS)
+            .flat_map(|i| i.to_le_bytes())
+            .collect::<Vec<_>>();
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let writer = std::thread::spawn({
+            let store = store.clone();
+            move || {
+                for i in 0..RECORDS {
+                    let sender = sender.clone();
+                    store
+                        .append(
+                            STREAM_ID,
+                            i.to_le_bytes().to_vec(),
+                            Some(Box::new(move |result| {
+                                sender.send(result.is_ok()).unwrap();
+                            })),
+                        )
+                        .unwrap();
+                }
+            }
+        });
+
+        let mut acked = 0;
+        let mut reads = 0;
+        while acked < RECORDS {
+            while let Ok(ok) = receiver.try_recv() {
+                assert!(ok);
+                acked += 1;
+            }
+            let Ok(end) = store.get_stream_end(STREAM_ID) else {
+                continue;
+            };
+            let data = store.read_consistent(STREAM_ID, 0, end).unwrap();
+            assert_eq!(data.len() as u64, end);
+            assert!(
+                data == expected[..data.len()],
+                "inconsistent read of {} bytes",
+                end
+            );
+
+            // a read starting in the middle of a record
+            if end > 12 {
+                let data = store.read_consistent(STREAM_ID, 3, end - 3).unwrap();
+                assert!(data == expected[3..end as usize]);
+            }
+            reads += 1;
+        }
+        writer.join().unwrap();
+        assert!(reads > 0);
+
+        let data = store
+            .read_consistent(STREAM_ID, 0, expected.len() as u64 + 100)
+            .unwrap();
+        assert!(data == expected);
+        // a huge size is a short read, not an allocation of that size
+        let data = store.read_consistent(STREAM_ID, 0, u64::MAX >> 1).unwrap();
+        assert!(data == expected);
+        let data = store.read(STREAM_ID, 8, u64::MAX - 8).unwrap();
+        assert!(data == expected[8..]);
+        assert!(store.read_consistent(STREAM_ID + 1, 0, 8).is_err());
+
+        drop(store);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_segment_file_names() {
+        let dir =
+            std::env::temp_dir().join(format!("streamstore_segment_names_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut options = Options::new_with_data_path(dir.to_str().unwrap());
+        options.max_table_size(256).max_wal_size(4096);
+        let store = options.open_store().unwrap();
+        assert_eq!(store.next_segment_id(), 0);
+
+        for i in 0..200u64 {
+            store.append(1, i.to_le_bytes().to_vec(), None).unwrap();
+        }
+        let begin = std::time::Instant::now();
+        while store.segment_files.read().unwrap().len() < 2 {
+            assert!(begin.elapsed() < std::time::Duration::from_secs(10));
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+
+        let segment_files = store.segment_files.read().unwrap().clone();
+        let names = segment_files
+            .iter()
+            .map(|segment| {
+                let name = segment.filename();
+                let name = name.file_name().unwrap().to_str().unwrap();
+                manifest::parse_segment_sequence(name).unwrap()
+            })
+            .collect::<BTreeSet<_>>();
+        // flushed and merged segments never share a name
+        assert_eq!(names.len(), segment_files.len());
+        assert!(store.next_segment_id() > *names.last().unwrap());
+
+        drop(store);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_flush() {
+        let dir = std::env::temp_dir().join(format!("streamstore_flush_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let options = Options::new_with_data_path(dir.to_str().unwrap());
+        let store = options.open_store().unwrap();
+        assert!(store.flush().unwrap().is_none());
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        for i in 0..10u64 {
+            let sender = sender.clone();
+            store
+                .append(
+                    (i % 2 + 1) as StreamId,
+                    i.to_le_bytes().to_vec(),
+                    Some(Box::new(move |result| {
+                        sender.send(result.is_ok()).unwrap();
+                    })),
+                )
+                .unwrap();
+        }
+        for _ in 0..10 {
+            assert!(receiver.recv().unwrap());
+        }
+
+        let segment = store.flush().unwrap().unwrap();
+        assert_eq!(segment.stream_headers().unwrap().len(), 2);
+        let expected = [1u64, 3, 5, 7, 9]
+            .iter()
+            .flat_map(|i| i.to_le_bytes())
+            .collect::<Vec<_>>();
+        assert_eq!(&*segment.stream_data(2).unwrap().unwrap(), &expected[..]);
+        assert!(
+            store
+                .segment_files
+                .read()
+                .unwrap()
+                .iter()
+                .any(|s| Arc::ptr_eq(s, &segment))
+        );
+        let manifest = Manifest::load(&dir.join("segment")).unwrap().unwrap();
+        let name = segment.filename();
+        let name = name.file_name().unwrap().to_str().unwrap();
+        assert!(manifest.segments.iter().any(|s| s == name));
+
+        // the memtable is empty again, flushing is a no-op
+        assert!(store.flush().unwrap().is_none());
+        assert_eq!(
+            store.get_stream_range(2).unwrap(),
+            (0, expected.len() as u64)
+        );
+        assert!(segment.metadata().is_none());
+
+        store
+            .append(
+                1,
+                b"batch".to_vec(),
+                Some(Box::new(move |result| {
+                    sender.send(result.is_ok()).unwrap();
+                })),
+            )
+            .unwrap();
+        assert!(receiver.recv().unwrap());
+        let segment = store
+            .flush_with_metadata(br#"{"batch":1}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(segment.metadata(), Some(&br#"{"batch":1}"#[..]));
+        let name = segment.filename();
+        drop(segment);
+        drop(store);
+
+        // the metadata is read back from the segment file after a reload
+        let store = options.open_store().unwrap();
+        let segment = store
+            .segment_files
+            .read()
+            .unwrap()
+            .iter()
+            .find(|s| s.filename() == name)
+            .cloned()
+            .unwrap();
+        assert_eq!(segment.metadata(), Some(&br#"{"batch":1}"#[..]));
+
+        drop(segment);
+        drop(store);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_read_your_writes() {
+        let dir = std::env::temp_dir().join(format!(
+            "streamstore_read_your_writes_{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut options = Options::new_with_data_path(dir.to_str().unwrap());
+        // tables rotate every few records, reads keep crossing flushes
+        options.max_table_size(64).max_wal_size(4096);
+        let store = options.open_store().unwrap();
+
+        const STREAM_ID: StreamId = 3;
+        let (sender, receiver) = std::sync::mpsc::channel();
+        for i in 0..1000u64 {
+            let sender = sender.clone();
+            store
+                .append(
+                    STREAM_ID,
+                    i.to_le_bytes().to_vec(),
+                    Some(Box::new(move |result| {
+                        sender.send(result.unwrap()).unwrap();
+                    })),
+                )
+                .unwrap();
+            let end = receiver.recv().unwrap();
+            assert_eq!(end, (i + 1) * 8);
+            assert_eq!(store.read(STREAM_ID, i * 8, 8).unwrap(), i.to_le_bytes());
+            if i % 100 == 99 {
+                store.flush().unwrap();
+                assert_eq!(store.read(STREAM_ID, i * 8, 8).unwrap(), i.to_le_bytes());
+            }
+        }
+
+        let expected = (0..1000u64)
+            .flat_map(|i| i.to_le_bytes())
+            .collect::<Vec<_>>();
+        assert!(store.read(STREAM_ID, 0, expected.len() as u64).unwrap() == expected);
+
+        drop(store);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_target_segment_size() {
+        let dir = std::env::temp_dir().join(format!(
+            "streamstore_target_segment_size_{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = Options::new_with_data_path(dir.to_str().unwrap())
+            .open_store()
+            .unwrap();
+        assert_eq!(store.target_segment_size(), 128 * 1024 * 1024);
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let append = |i: u64| {
+            let sender = sender.clone();
+            store
+                .append(
+                    1,
+                    i.to_le_bytes().to_vec(),
+                    Some(Box::new(move |result| {
+                        sender.send(result.unwrap()).unwrap();
+                    })),
+                )
+                .unwrap();
+            receiver.recv().unwrap()
+        };
+        for i in 0..10 {
+            append(i);
+        }
+        assert_eq!(store.mem_table_size(), 80);
+
+        store.set_target_segment_size(100);
+        assert_eq!(store.target_segment_size(), 100);
+        for i in 10..13 {
+            append(i);
+        }
+        // 104 bytes rotated the memtable, flush waits for the rotation and finds
+        // nothing left to write
+        assert!(store.flush().unwrap().is_none());
+        assert_eq!(store.mem_table_size(), 0);
+        assert_eq!(store.read(1, 0, 104).unwrap().len(), 104);
+
+        drop(store);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_cursors() {
+        let dir = std::env::temp_dir().join(format!("streamstore_cursors_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let options = Options::new_with_data_path(dir.to_str().unwrap());
+        let store = options.open_store().unwrap();
+        assert_eq!(store.load_cursor("indexer", 1).unwrap(), None);
+        store.save_cursor("indexer", 1, 42).unwrap();
+        store.save_cursor("exporter", 1, 7).unwrap();
+        assert_eq!(store.load_cursor("indexer", 1).unwrap(), Some(42));
+        drop(store);
+
+        let store = options.open_store().unwrap();
+        assert_eq!(store.load_cursor("indexer", 1).unwrap(), Some(42));
+        assert_eq!(store.load_cursor("exporter", 1).unwrap(), Some(7));
+        assert_eq!(store.load_cursor("exporter", 2).unwrap(), None);
+        drop(store);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_refresh() {
+        let dir = std::env::temp_dir().join(format!("streamstore_refresh_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let segment_path = dir.join("segment");
+        let segment_path = segment_path.to_str().unwrap();
+        let primary = Options::new_with_data_path(dir.to_str().unwrap())
+            .open_store()
+            .unwrap();
+        let append = |stream_id: StreamId, data: &[u8]| {
+            let (sender, receiver) = std::sync::mpsc::channel();
+            primary
+                .append(
+                    stream_id,
+                    data.to_vec(),
+                    Some(Box::new(move |result| sender.send(result.is_ok()).unwrap())),
+                )
+                .unwrap();
+            assert!(receiver.recv().unwrap());
+        };
+        append(1, b"hello");
+        primary.flush().unwrap().unwrap();
+
+        let replica = Options::new_with_data_path(dir.to_str().unwrap())
+            .wal_path(dir.join("replica_wal").to_str().unwrap())
+            .segment_path(segment_path)
+            .max_segment_merge_level(0)
+            .open_store()
+            .unwrap();
+        assert_eq!(replica.refresh().unwrap(), 0);
+        assert_eq!(replica.read(1, 0, 5).unwrap(), b"hello");
+
+        append(1, b" world");
+        append(2, b"new stream");
+        primary.flush().unwrap().unwrap();
+        assert!(replica.read(2, 0, 10).is_err());
+
+        assert_eq!(replica.refresh().unwrap(), 1);
+        assert_eq!(replica.read(1, 0, 11).unwrap(), b"hello world");
+        assert_eq!(replica.read(2, 0, 10).unwrap(), b"new stream");
+        assert!(replica.new_stream_reader(2).is_ok());
+        assert_eq!(replica.refresh().unwrap(), 0);
+
+        // segments the writer deleted are released
+        let first = replica.segment_files.read().unwrap()[0].filename();
+        std::fs::remove_file(&first).unwrap();
+        assert_eq!(replica.refresh().unwrap(), 0);
+        assert_eq!(replica.segment_files.read().unwrap().len(), 1);
+
+        drop(replica);
+        drop(primary);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_maintain() {
+        let dir = std::env::temp_dir().join(format!("streamstore_maintain_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut options = Options::new_with_data_path(dir.to_str().unwrap());
+        options.max_segment_merge_level(0);
+        let store = options.open_store().unwrap();
+        assert_eq!(
+            store.maintain(MaintenancePolicy::default()).unwrap(),
+            MaintenanceReport::default()
+        );
+
+        let append = |stream_id: StreamId, data: &[u8]| {
+            let (sender, receiver) = std::sync::mpsc::channel();
+            store
+                .append(
+                    stream_id,
+                    data.to_vec(),
+                    Some(Box::new(move |result| sender.send(result.is_ok()).unwrap())),
+                )
+                .unwrap();
+            assert!(receiver.recv().unwrap());
+        };
+        append(1, b"0123456789");
+        append(2, b"tombstoned");
+        store.flush().unwrap().unwrap();
+        append(1, b"abcdef");
+        append(2, b"gone");
+        append(3, b"kept");
+        store.flush().unwrap().unwrap();
+
+        let policy = MaintenancePolicy {
+            tombstones: HashSet::from([2]),
+            retention: HashMap::from([(1, 12)]),
+        };
+        let report = store.maintain(policy).unwrap();
+        assert_eq!(report.segments_merged, 2);
+        assert_eq!(report.streams_dropped, 1);
+        assert_eq!(report.bytes_dropped, 14);
+        assert_eq!(report.bytes_trimmed, 12);
+        assert!(report.bytes_reclaimed > 0);
+
+        let check = |store: &Store| {
+            assert_eq!(store.segment_files.read().unwrap().len(), 1);
+            assert_eq!(store.get_stream_range(1).unwrap(), (12, 16));
+            assert_eq!(store.read(1, 12, 4).unwrap(), b"cdef");
+            assert!(store.read(1, 0, 4).is_err());
+            assert!(store.get_stream_range(2).is_err());
+            assert_eq!(store.read(3, 0, 4).unwrap(), b"kept");
+        };
+        check(&store);
+        drop(store);
+
+        let store = options.open_store().unwrap();
+        check(&store);
+        drop(store);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_maintain_trimmed_stream_keeps_offset() {
+        let dir = std::env::temp_dir().join(format!(
+            "streamstore_maintain_trimmed_{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        let options = Options::new_with_data_path(dir.to_str().unwrap());
+        let append = |store: &Store, data: &[u8]| {
+            let (sender, receiver) = std::sync::mpsc::channel();
+            store
+                .append(
+                    1,
+                    data.to_vec(),
+                    Some(Box::new(move |result| sender.send(result.is_ok()).unwrap())),
+                )
+                .unwrap();
+            assert!(receiver.recv().unwrap());
+        };
+
+        let store = options.open_store().unwrap();
+        append(&store, b"0123456789");
+        store.flush().unwrap().unwrap();
+        // retention past the end of the stream trims all of its data
+        let policy = MaintenancePolicy {
+            retention: HashMap::from([(1, 100)]),
+            ..Default::default()
+        };
+        let report = store.maintain(policy).unwrap();
+        assert_eq!(report.bytes_trimmed, 10);
+        assert_eq!(store.get_stream_range(1).unwrap(), (10, 10));
+        drop(store);
+
+        let store = options.open_store().unwrap();
+        assert_eq!(store.get_stream_range(1).unwrap(), (10, 10));
+        append(&store, b"abc");
+        assert_eq!(store.get_stream_range(1).unwrap(), (10, 13));
+        assert_eq!(store.read(1, 10, 3).unwrap(), b"abc");
+        assert!(store.read(1, 0, 3).is_err());
+        drop(store);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_open_stream() {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let dir =
+            std::env::temp_dir().join(format!("streamstore_open_stream_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = Options::new_with_data_path(dir.to_str().unwrap())
+            .open_store()
+            .unwrap();
+        let append = |data: &[u8]| {
+            let (sender, receiver) = std::sync::mpsc::channel();
+            store
+                .append(
+                    1,
+                    data.to_vec(),
+                    Some(Box::new(move |result| sender.send(result.is_ok()).unwrap())),
+                )
+                .unwrap();
+            assert!(receiver.recv().unwrap());
+        };
+        assert!(store.open_stream(1).is_err());
+
+        // the data spans a segment and the memtable
+        append(b"hello ");
+        store.flush().unwrap().unwrap();
+        append(b"world");
+        let mut handle = store.open_stream(1).unwrap();
+        assert_eq!(handle.len().unwrap(), 11);
+        assert!(!handle.is_empty().unwrap());
+        let mut data = Vec::new();
+        handle.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"hello world");
+        assert_eq!(handle.position(), 11);
+
+        assert_eq!(handle.seek(SeekFrom::End(-5)).unwrap(), 6);
+        let mut buf = [0u8; 5];
+        handle.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"world");
+        assert_eq!(handle.seek(SeekFrom::Start(2)).unwrap(), 2);
+        handle.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"llo w");
+
+        // out of bounds seeks fail and keep the position
+        assert!(handle.seek(SeekFrom::Start(12)).is_err());
+        assert!(handle.seek(SeekFrom::Current(-10)).is_err());
+        assert_eq!(handle.position(), 7);
+
+        // appends extend the stream
+        append(b"!");
+        assert_eq!(handle.len().unwrap(), 12);
+        handle.seek(SeekFrom::End(-1)).unwrap();
+        handle.read_exact(&mut buf[..1]).unwrap();
+        assert_eq!(buf[0], b'!');
+
+        drop(handle);
+        drop(store);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
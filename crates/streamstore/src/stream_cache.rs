@@ -0,0 +1,181 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use crate::{StreamId, metrics, segments::SegmentStreamHeader};
+
+type CacheKey = (u64, StreamId);
+
+// Hit/miss counts of the stream header cache
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamHeaderCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub len: usize,
+}
+
+// LRU cache of stream headers resolved by (segment id, stream id). Only the
+// header (offsets and size) is kept, never the stream data. Streams missing from a
+// segment are not cached: a lookup scans the segments and misses most of them, so
+// caching the misses would evict the hot headers. A miss is a binary search of
+// the segment's stream header table, cheap enough to repeat
+pub(crate) struct StreamHeaderCache {
+    capacity: usize,
+    state: Mutex<LruState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct LruState {
+    tick: u64,
+    entries: HashMap<CacheKey, (SegmentStreamHeader, u64)>,
+    // last use tick -> key, the first entry is the least recently used
+    order: BTreeMap<u64, CacheKey>,
+}
+
+impl StreamHeaderCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        assert!(
+            capacity > 0,
+            "stream header cache capacity must be greater than zero"
+        );
+        Self {
+            capacity,
+            state: Mutex::new(LruState::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    // Return the cached header of the stream in the segment, resolving it on a miss
+    // and caching it if the segment has the stream
+    pub(crate) fn get_or_resolve(
+        &self,
+        segment_id: u64,
+        stream_id: StreamId,
+        resolve: impl FnOnce() -> Option<SegmentStreamHeader>,
+    ) -> Option<SegmentStreamHeader> {
+        let key = (segment_id, stream_id);
+        {
+            let mut guard = self.state.lock().unwrap();
+            let state = &mut *guard;
+            state.tick += 1;
+            let tick = state.tick;
+            if let Some((header, last_used)) = state.entries.get_mut(&key) {
+                let header = header.clone();
+                let old = std::mem::replace(last_used, tick);
+                state.order.remove(&old);
+                state.order.insert(tick, key);
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                metrics::stream_header_cache_hit_count.inc();
+                return Some(header);
+            }
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        metrics::stream_header_cache_miss_count.inc();
+        // resolve without holding the lock, a concurrent miss on the same key just resolves twice
+        let header = resolve()?;
+
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        state.tick += 1;
+        let tick = state.tick;
+        if let Some((_, old)) = state.entries.insert(key, (header.clone(), tick)) {
+            state.order.remove(&old);
+        }
+        state.order.insert(tick, key);
+        while state.entries.len() > self.capacity {
+            let (_, evicted) = state.order.pop_first().unwrap();
+            state.entries.remove(&evicted);
+        }
+        Some(header)
+    }
+
+    pub(crate) fn stats(&self) -> StreamHeaderCacheStats {
+        StreamHeaderCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            len: self.state.lock().unwrap().entries.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_header(stream_id: StreamId, offset: u64) -> SegmentStreamHeader {
+        SegmentStreamHeader {
+            version: 2,
+            stream_id,
+            offset,
+            file_offset: 0,
+            size: 10,
+            crc64: 0,
+            first_entry: 1,
+            last_entry: 1,
+        }
+    }
+
+    #[test]
+    fn test_stream_header_cache_hit_miss() {
+        let cache = StreamHeaderCache::new(4);
+
+        let header = cache.get_or_resolve(1, 10, || Some(new_header(10, 100)));
+        assert_eq!(header.unwrap().offset, 100);
+        let header = cache.get_or_resolve(1, 10, || panic!("should be cached"));
+        assert_eq!(header.unwrap().offset, 100);
+
+        // missing streams are resolved again every time
+        assert!(cache.get_or_resolve(1, 20, || None).is_none());
+        assert!(cache.get_or_resolve(1, 20, || None).is_none());
+
+        assert_eq!(
+            cache.stats(),
+            StreamHeaderCacheStats {
+                hits: 1,
+                misses: 3,
+                len: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_stream_header_cache_misses_keep_hot_entries() {
+        let cache = StreamHeaderCache::new(2);
+        cache.get_or_resolve(1, 1, || Some(new_header(1, 0)));
+        cache.get_or_resolve(2, 1, || Some(new_header(1, 100)));
+
+        // a scan missing the stream in many segments
+        for segment_id in 3..100 {
+            assert!(cache.get_or_resolve(segment_id, 1, || None).is_none());
+        }
+
+        assert_eq!(cache.stats().len, 2);
+        let header = cache.get_or_resolve(1, 1, || panic!("should be cached"));
+        assert_eq!(header.unwrap().offset, 0);
+        let header = cache.get_or_resolve(2, 1, || panic!("should be cached"));
+        assert_eq!(header.unwrap().offset, 100);
+    }
+
+    #[test]
+    fn test_stream_header_cache_evicts_lru() {
+        let cache = StreamHeaderCache::new(2);
+        cache.get_or_resolve(1, 1, || Some(new_header(1, 0)));
+        cache.get_or_resolve(1, 2, || Some(new_header(2, 0)));
+        // touch stream 1 so stream 2 is the least recently used
+        cache.get_or_resolve(1, 1, || None);
+        cache.get_or_resolve(1, 3, || Some(new_header(3, 0)));
+
+        assert_eq!(cache.stats().len, 2);
+        assert!(cache.get_or_resolve(1, 1, || None).is_some());
+        assert!(cache.get_or_resolve(1, 3, || None).is_some());
+        assert!(cache.get_or_resolve(1, 2, || None).is_none());
+    }
+}
@@ -1,15 +1,104 @@
-use std::{io, slice::Iter};
+use std::{
+    io,
+    slice::Iter,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
 
 use anyhow::Result;
+use bytes::Bytes;
 
 use crate::StreamId;
 
-const STREAM_DATA_BUFFER_CAP: u64 = 128 << 10; // 128KB
+pub(crate) const STREAM_DATA_BUFFER_CAP: u64 = 128 << 10; // 128KB
+const STREAM_DATA_COMPACT_CAP: u64 = 4 << 20; // 4MB
+// compact the stream table when it has more chunks than this
+const STREAM_DATA_COMPACT_THRESHOLD: usize = 64;
+// data appended as Bytes is referenced instead of copied from this size on,
+// a chunk per small entry would cost more than the copy
+const STREAM_DATA_SHARE_MIN: usize = 64 << 10; // 64KB
+
+// Allocation counts of a StreamDataPool
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamDataPoolStats {
+    // buffers allocated because the pool was empty
+    pub allocations: u64,
+    // buffers handed out again after being returned
+    pub reuses: u64,
+    // buffers currently waiting in the pool
+    pub free: usize,
+}
+
+// Pool of stream data chunk buffers shared by memtables. Chunks return their
+// buffer when they are dropped (the memtable is flushed and released, reset or
+// compacted), so a steady ingest reuses the same buffers instead of allocating
+pub struct StreamDataPool {
+    // max number of free buffers kept, the rest are released
+    capacity: usize,
+    free: Mutex<Vec<Vec<u8>>>,
+    allocations: AtomicU64,
+    reuses: AtomicU64,
+}
+
+impl StreamDataPool {
+    pub fn new(capacity: usize) -> Self {
+        StreamDataPool {
+            capacity,
+            free: Mutex::new(Vec::new()),
+            allocations: AtomicU64::new(0),
+            reuses: AtomicU64::new(0),
+        }
+    }
+
+    fn take(&self) -> Vec<u8> {
+        match self.free.lock().unwrap().pop() {
+            Some(buffer) => {
+                self.reuses.fetch_add(1, Ordering::Relaxed);
+                buffer
+            }
+            None => {
+                self.allocations.fetch_add(1, Ordering::Relaxed);
+                Vec::with_capacity(STREAM_DATA_BUFFER_CAP as usize)
+            }
+        }
+    }
+
+    fn give(&self, mut buffer: Vec<u8>) {
+        // merged chunks have other capacities, they are not worth keeping
+        if buffer.capacity() != STREAM_DATA_BUFFER_CAP as usize {
+            return;
+        }
+        let mut free = self.free.lock().unwrap();
+        if free.len() < self.capacity {
+            buffer.clear();
+            free.push(buffer);
+        }
+    }
+
+    pub fn stats(&self) -> StreamDataPoolStats {
+        StreamDataPoolStats {
+            allocations: self.allocations.load(Ordering::Relaxed),
+            reuses: self.reuses.load(Ordering::Relaxed),
+            free: self.free.lock().unwrap().len(),
+        }
+    }
+}
 
 pub struct StreamData {
     stream_id: StreamId,
     offset: u64,
-    data: Vec<u8>,
+    data: ChunkData,
+    // the pool `data` goes back to on drop
+    pool: Option<Arc<StreamDataPool>>,
+}
+
+// Bytes of a chunk: a buffer appends are copied into, or the data of a single
+// append referenced without a copy (see StreamTable::append_bytes)
+enum ChunkData {
+    Owned(Vec<u8>),
+    Shared(Bytes),
 }
 
 impl StreamData {
@@ -17,7 +106,28 @@ impl StreamData {
         StreamData {
             stream_id,
             offset,
-            data: Vec::with_capacity(buffer_cap as usize),
+            data: ChunkData::Owned(Vec::with_capacity(buffer_cap as usize)),
+            pool: None,
+        }
+    }
+
+    // A full chunk referencing `data`
+    fn shared(stream_id: StreamId, offset: u64, data: Bytes) -> Self {
+        StreamData {
+            stream_id,
+            offset,
+            data: ChunkData::Shared(data),
+            pool: None,
+        }
+    }
+
+    // A STREAM_DATA_BUFFER_CAP chunk whose buffer is taken from and returned to `pool`
+    fn from_pool(stream_id: StreamId, offset: u64, pool: &Arc<StreamDataPool>) -> Self {
+        StreamData {
+            stream_id,
+            offset,
+            data: ChunkData::Owned(pool.take()),
+            pool: Some(pool.clone()),
         }
     }
 
@@ -26,7 +136,9 @@ impl StreamData {
     // If the buffer is not full, return None
     pub fn fill<'a>(&mut self, data: &'a [u8]) -> Result<(usize, Option<&'a [u8]>)> {
         let available = self.cap_remaining().min(data.len());
-        self.data.extend_from_slice(&data[..available as usize]);
+        if let ChunkData::Owned(buffer) = &mut self.data {
+            buffer.extend_from_slice(&data[..available as usize]);
+        }
 
         let remaining_data = if available < data.len() {
             Some(&data[available as usize..])
@@ -38,32 +150,90 @@ impl StreamData {
     }
 
     pub fn get_stream_range(&self) -> Option<(u64, u64)> {
-        if self.data.is_empty() {
+        if self.data().is_empty() {
             return None;
         }
         let start = self.offset;
-        let end = self.offset + self.data.len() as u64;
+        let end = self.offset + self.size();
         Some((start, end))
     }
 
     pub fn size(&self) -> u64 {
-        self.data.len() as u64
+        self.data().len() as u64
     }
 
     pub fn data(&self) -> &[u8] {
-        &self.data
+        match &self.data {
+            ChunkData::Owned(buffer) => buffer,
+            ChunkData::Shared(data) => data,
+        }
     }
 
+    // Shared chunks are always full
     pub fn cap_remaining(&self) -> usize {
-        self.data.capacity() - self.data.len()
+        match &self.data {
+            ChunkData::Owned(buffer) => buffer.capacity() - buffer.len(),
+            ChunkData::Shared(_) => 0,
+        }
+    }
+
+    fn is_shared(&self) -> bool {
+        matches!(self.data, ChunkData::Shared(_))
+    }
+
+    // Concatenate adjacent chunks into a single chunk
+    fn merge(mut stream_datas: Vec<StreamData>) -> StreamData {
+        if stream_datas.len() == 1 {
+            return stream_datas.pop().unwrap();
+        }
+        let size = stream_datas
+            .iter()
+            .map(|stream_data| stream_data.size())
+            .sum::<u64>();
+        let mut merged = StreamData::new(stream_datas[0].stream_id, stream_datas[0].offset, size);
+        for stream_data in stream_datas.iter() {
+            merged.fill(stream_data.data()).unwrap();
+        }
+        merged
+    }
+
+    // Drop the data after the first `size` bytes, the capacity is kept
+    pub fn truncate(&mut self, size: u64) {
+        match &mut self.data {
+            ChunkData::Owned(buffer) => buffer.truncate(size as usize),
+            ChunkData::Shared(data) => data.truncate(size as usize),
+        }
     }
 }
 
+impl Drop for StreamData {
+    fn drop(&mut self) {
+        if let (Some(pool), ChunkData::Owned(buffer)) = (self.pool.take(), &mut self.data) {
+            pool.give(std::mem::take(buffer));
+        }
+    }
+}
+
+// State of a StreamTable that rollback can return to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamTableCheckpoint {
+    size: u64,
+    first_entry: u64,
+    last_entry: u64,
+}
+
 pub struct StreamTable {
     stream_id: StreamId,
     offset: u64,
     size: u64,
+    // min/max id of the entries appended to this stream, 0 if none
+    first_entry: u64,
+    last_entry: u64,
     stream_datas: Vec<StreamData>,
+    // chunk buffers are taken from this pool when set
+    pool: Option<Arc<StreamDataPool>>,
+    // capacity of the chunks appends allocate
+    chunk_cap: u64,
 }
 
 impl StreamTable {
@@ -72,10 +242,30 @@ impl StreamTable {
             stream_id,
             offset: offset,
             size: 0,
+            first_entry: 0,
+            last_entry: 0,
             stream_datas: Vec::new(),
+            pool: None,
+            chunk_cap: STREAM_DATA_BUFFER_CAP,
         }
     }
 
+    pub fn with_pool(stream_id: StreamId, offset: u64, pool: Option<Arc<StreamDataPool>>) -> Self {
+        StreamTable {
+            pool,
+            ..StreamTable::new(stream_id, offset)
+        }
+    }
+
+    // Allocate chunks of `chunk_cap` bytes instead of 128KB, so a high-volume
+    // stream fills fewer, larger chunks. The pool only holds 128KB buffers, chunks
+    // of another capacity are allocated without it
+    pub fn with_chunk_capacity(mut self, chunk_cap: u64) -> Self {
+        assert!(chunk_cap > 0, "chunk capacity must not be zero");
+        self.chunk_cap = chunk_cap;
+        self
+    }
+
     pub fn stream_id(&self) -> StreamId {
         self.stream_id
     }
@@ -85,24 +275,112 @@ impl StreamTable {
     pub fn size(&self) -> u64 {
         self.size
     }
+    pub fn entry_range(&self) -> (u64, u64) {
+        (self.first_entry, self.last_entry)
+    }
+
+    // Record that the entry `entry_id` contributed data to this stream
+    pub fn record_entry(&mut self, entry_id: u64) {
+        if self.first_entry == 0 || entry_id < self.first_entry {
+            self.first_entry = entry_id;
+        }
+        self.last_entry = self.last_entry.max(entry_id);
+    }
+
+    pub fn checkpoint(&self) -> StreamTableCheckpoint {
+        StreamTableCheckpoint {
+            size: self.size,
+            first_entry: self.first_entry,
+            last_entry: self.last_entry,
+        }
+    }
+
+    // Discard everything appended since `checkpoint` was taken
+    pub fn rollback(&mut self, checkpoint: StreamTableCheckpoint) {
+        assert!(checkpoint.size <= self.size);
+        let mut remaining = checkpoint.size;
+        let mut keep = 0;
+        for stream_data in self.stream_datas.iter_mut() {
+            if remaining == 0 {
+                break;
+            }
+            let size = stream_data.size().min(remaining);
+            stream_data.truncate(size);
+            remaining -= size;
+            keep += 1;
+        }
+        self.stream_datas.truncate(keep);
+        self.size = checkpoint.size;
+        self.first_entry = checkpoint.first_entry;
+        self.last_entry = checkpoint.last_entry;
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.stream_datas.len()
+    }
+
+    // All of the stream's data as one slice, None when it spans several chunks
+    pub fn contiguous_data(&self) -> Option<&[u8]> {
+        match self.stream_datas.as_slice() {
+            [] => Some(&[]),
+            [stream_data] => Some(stream_data.data()),
+            _ => None,
+        }
+    }
+
+    // Coalesce adjacent chunks into buffers of up to STREAM_DATA_COMPACT_CAP bytes.
+    // The last chunk is left alone so appends keep filling it, and so are shared
+    // chunks, they are large and copying them is what append_bytes avoids
+    pub fn compact(&mut self) {
+        if self.stream_datas.len() <= 2 {
+            return;
+        }
+        let mut stream_datas = std::mem::take(&mut self.stream_datas);
+        let last = stream_datas.pop().unwrap();
+
+        let mut group = Vec::new();
+        let mut group_size = 0;
+        for stream_data in stream_datas {
+            if stream_data.is_shared() {
+                if !group.is_empty() {
+                    self.stream_datas
+                        .push(StreamData::merge(std::mem::take(&mut group)));
+                    group_size = 0;
+                }
+                self.stream_datas.push(stream_data);
+                continue;
+            }
+            if !group.is_empty() && group_size + stream_data.size() > STREAM_DATA_COMPACT_CAP {
+                self.stream_datas
+                    .push(StreamData::merge(std::mem::take(&mut group)));
+                group_size = 0;
+            }
+            group_size += stream_data.size();
+            group.push(stream_data);
+        }
+        if !group.is_empty() {
+            self.stream_datas.push(StreamData::merge(group));
+        }
+        self.stream_datas.push(last);
+    }
+
     pub fn stream_datas(&self) -> Iter<StreamData> {
         self.stream_datas.iter()
     }
 
     pub fn append(&mut self, data: &[u8]) -> Result<u64> {
         if self.stream_datas.is_empty() || self.stream_datas.last().unwrap().cap_remaining() == 0 {
-            if !self.stream_datas.is_empty() {
-                assert_eq!(
-                    self.stream_datas.last().unwrap().size(),
-                    STREAM_DATA_BUFFER_CAP
-                );
+            if self.stream_datas.len() >= STREAM_DATA_COMPACT_THRESHOLD {
+                self.compact();
             }
 
-            self.stream_datas.push(StreamData::new(
-                self.stream_id,
-                self.offset + self.size,
-                STREAM_DATA_BUFFER_CAP,
-            ));
+            let offset = self.offset + self.size;
+            self.stream_datas.push(match &self.pool {
+                Some(pool) if self.chunk_cap == STREAM_DATA_BUFFER_CAP => {
+                    StreamData::from_pool(self.stream_id, offset, pool)
+                }
+                _ => StreamData::new(self.stream_id, offset, self.chunk_cap),
+            });
         }
 
         let stream_data = self.stream_datas.last_mut().unwrap();
@@ -117,6 +395,23 @@ impl StreamTable {
         Ok(self.offset + self.size)
     }
 
+    // Same as append, referencing `data` instead of copying it when it is at least
+    // STREAM_DATA_SHARE_MIN bytes, e.g. a network buffer handed over as is. The
+    // chunk keeps the whole buffer `data` is a slice of alive
+    pub fn append_bytes(&mut self, data: &Bytes) -> Result<u64> {
+        if data.len() < STREAM_DATA_SHARE_MIN {
+            return self.append(data);
+        }
+        if self.stream_datas.len() >= STREAM_DATA_COMPACT_THRESHOLD {
+            self.compact();
+        }
+        let offset = self.offset + self.size;
+        self.stream_datas
+            .push(StreamData::shared(self.stream_id, offset, data.clone()));
+        self.size += data.len() as u64;
+        Ok(self.offset + self.size)
+    }
+
     pub fn get_stream_range(&self) -> Option<(u64, u64)> {
         if self.stream_datas.is_empty() {
             return None;
@@ -128,7 +423,10 @@ impl StreamTable {
         for (i, stream_data) in self.stream_datas.iter().enumerate() {
             assert!(stream_data.stream_id == self.stream_id);
             if i != self.stream_datas.len() - 1 {
-                assert_eq!(stream_data.size(), STREAM_DATA_BUFFER_CAP);
+                assert_eq!(
+                    stream_data.offset + stream_data.size(),
+                    self.stream_datas[i + 1].offset
+                );
             }
         }
     }
@@ -137,7 +435,7 @@ impl StreamTable {
         let crc64 = crc::Crc::<u64>::new(&crc::CRC_64_REDIS);
         let mut digest = crc64.digest();
         for stream_data in &self.stream_datas {
-            digest.update(&stream_data.data);
+            digest.update(stream_data.data());
         }
         digest.finalize()
     }
@@ -184,7 +482,7 @@ impl StreamTable {
             let end = (start + size as usize).min(stream_data_size as usize);
 
             // copy the data to the buffer
-            let data_to_copy = &stream_data.data[start..end];
+            let data_to_copy = &stream_data.data()[start..end];
             let bytes_to_copy = data_to_copy.len();
             buf[copied_size as usize..(copied_size as u64 + bytes_to_copy as u64) as usize]
                 .copy_from_slice(data_to_copy);
@@ -209,7 +507,9 @@ mod tests {
         let stream_data = StreamData::new(123, 1000, buffer_cap);
         assert_eq!(stream_data.stream_id, 123);
         assert_eq!(stream_data.offset, 1000);
-        assert_eq!(stream_data.data.capacity(), buffer_cap as usize);
+        assert!(
+            matches!(&stream_data.data, ChunkData::Owned(buffer) if buffer.capacity() == buffer_cap as usize)
+        );
         assert_eq!(stream_data.size(), 0);
         assert_eq!(stream_data.cap_remaining(), buffer_cap as usize);
         assert_eq!(stream_data.get_stream_range(), None);
@@ -273,6 +573,163 @@ mod tests {
         assert_eq!(table.get_stream_range(), None);
     }
 
+    #[test]
+    fn test_stream_table_record_entry() {
+        let mut table = StreamTable::new(1, 0);
+        assert_eq!(table.entry_range(), (0, 0));
+        table.record_entry(5);
+        table.record_entry(9);
+        table.record_entry(7);
+        assert_eq!(table.entry_range(), (5, 9));
+    }
+
+    #[test]
+    fn test_stream_table_rollback() {
+        let mut table = StreamTable::new(1, 100);
+        table.append(b"hello").unwrap();
+        table.record_entry(1);
+        let checkpoint = table.checkpoint();
+
+        let large = vec![7u8; STREAM_DATA_BUFFER_CAP as usize + 10];
+        table.append(&large).unwrap();
+        table.record_entry(2);
+        assert_eq!(table.stream_datas().count(), 2);
+
+        table.rollback(checkpoint);
+        assert_eq!(table.size(), 5);
+        assert_eq!(table.entry_range(), (1, 1));
+        assert_eq!(table.stream_datas().count(), 1);
+        assert_eq!(table.get_stream_range(), Some((100, 105)));
+
+        // appending after a rollback continues from the checkpoint
+        assert_eq!(table.append(b" world").unwrap(), 111);
+        let mut buf = vec![0u8; 11];
+        assert_eq!(table.read_stream(100, &mut buf).unwrap(), 11);
+        assert_eq!(&buf, b"hello world");
+    }
+
+    #[test]
+    fn test_stream_table_compact() {
+        let mut table = StreamTable::new(1, 10);
+        let data = (0..STREAM_DATA_BUFFER_CAP * 5 + 100)
+            .map(|i| i as u8)
+            .collect::<Vec<u8>>();
+        for chunk in data.chunks(1000) {
+            table.append(chunk).unwrap();
+        }
+        assert_eq!(table.chunk_count(), 6);
+        let crc64 = table.crc64();
+
+        table.compact();
+        assert_eq!(table.chunk_count(), 2);
+        assert_eq!(table.crc64(), crc64);
+        assert_eq!(table.get_stream_range(), Some((10, 10 + data.len() as u64)));
+
+        // reads across the compacted chunk boundary
+        let mut buf = vec![0u8; 2000];
+        let offset = 10 + STREAM_DATA_BUFFER_CAP * 5 - 1000;
+        assert_eq!(table.read_stream(offset, &mut buf).unwrap(), 1100);
+        assert_eq!(&buf[..1100], &data[data.len() - 1100..]);
+
+        // appends continue after compaction
+        table
+            .append(&vec![1u8; STREAM_DATA_BUFFER_CAP as usize])
+            .unwrap();
+        assert_eq!(table.chunk_count(), 3);
+        assert_eq!(table.size(), data.len() as u64 + STREAM_DATA_BUFFER_CAP);
+    }
+
+    #[test]
+    fn test_stream_table_compact_on_append() {
+        let mut table = StreamTable::new(1, 0);
+        let chunk = vec![7u8; STREAM_DATA_BUFFER_CAP as usize];
+        for _ in 0..STREAM_DATA_COMPACT_THRESHOLD + 1 {
+            table.append(&chunk).unwrap();
+        }
+        table.append(b"tail").unwrap();
+
+        // when the 65th chunk was needed, the first 63 were coalesced into two
+        // 4MB buffers, followed by the 64th, the 65th and the tail chunk
+        assert_eq!(table.chunk_count(), 5);
+        assert_eq!(
+            table.size(),
+            STREAM_DATA_BUFFER_CAP * (STREAM_DATA_COMPACT_THRESHOLD as u64 + 1) + 4
+        );
+        let mut buf = [0u8; 4];
+        assert_eq!(table.read_stream(table.size() - 4, &mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"tail");
+    }
+
+    #[test]
+    fn test_stream_table_append_bytes() {
+        let mut table = StreamTable::new(1, 0);
+        table.append_bytes(&Bytes::from_static(b"head")).unwrap();
+        let large = Bytes::from(vec![9u8; STREAM_DATA_SHARE_MIN]);
+        assert_eq!(
+            table.append_bytes(&large).unwrap(),
+            4 + STREAM_DATA_SHARE_MIN as u64
+        );
+        table.append_bytes(&Bytes::from_static(b"tail")).unwrap();
+
+        // the small appends are copied, the large one is referenced
+        assert_eq!(table.chunk_count(), 3);
+        let chunks = table.stream_datas().collect::<Vec<_>>();
+        assert_eq!(chunks[0].data(), b"head");
+        assert_eq!(chunks[1].data().as_ptr(), large.as_ptr());
+        assert_eq!(chunks[2].data(), b"tail");
+        assert_eq!(chunks[2].offset, 4 + STREAM_DATA_SHARE_MIN as u64);
+
+        let mut buf = vec![0u8; 8];
+        let offset = STREAM_DATA_SHARE_MIN as u64;
+        assert_eq!(table.read_stream(offset, &mut buf).unwrap(), 8);
+        assert_eq!(&buf, b"\x09\x09\x09\x09tail");
+
+        // compaction copies the owned chunks around the shared one only
+        for _ in 0..3 {
+            table
+                .append(&vec![1u8; STREAM_DATA_BUFFER_CAP as usize])
+                .unwrap();
+        }
+        let crc64 = table.crc64();
+        table.compact();
+        assert_eq!(table.crc64(), crc64);
+        let chunks = table.stream_datas().collect::<Vec<_>>();
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(chunks[1].data().as_ptr(), large.as_ptr());
+
+        // a rollback into the shared chunk keeps referencing it
+        table.rollback(StreamTableCheckpoint {
+            size: 10,
+            first_entry: 0,
+            last_entry: 0,
+        });
+        assert_eq!(table.size(), 10);
+        assert_eq!(table.chunk_count(), 2);
+        assert_eq!(
+            table.crc64(),
+            crc::Crc::<u64>::new(&crc::CRC_64_REDIS).checksum(b"head\x09\x09\x09\x09\x09\x09")
+        );
+    }
+
+    #[test]
+    fn test_stream_table_chunk_capacity() {
+        let pool = Arc::new(StreamDataPool::new(4));
+        let mut table = StreamTable::with_pool(1, 0, Some(pool.clone())).with_chunk_capacity(1024);
+        table.append(&[7u8; 1500]).unwrap();
+        assert_eq!(table.chunk_count(), 2);
+        let caps = table
+            .stream_datas()
+            .map(|stream_data| stream_data.size() + stream_data.cap_remaining() as u64)
+            .collect::<Vec<_>>();
+        assert_eq!(caps, vec![1024, 1024]);
+        // the pool's buffers have the default capacity, it is not used
+        assert_eq!(pool.stats().allocations, 0);
+
+        let mut buf = vec![0u8; 1500];
+        assert_eq!(table.read_stream(0, &mut buf).unwrap(), 1500);
+        assert!(buf.iter().all(|b| *b == 7));
+    }
+
     #[test]
     fn test_stream_table_append_single() {
         let mut table = StreamTable::new(1, 0);
@@ -472,4 +929,71 @@ mod tests {
         let bytes_read = table.read_stream(0, &mut buf).unwrap();
         assert_eq!(bytes_read, 0);
     }
+
+    #[test]
+    fn test_stream_data_pool_reuse() {
+        let pool = Arc::new(StreamDataPool::new(8));
+        let chunk = vec![3u8; STREAM_DATA_BUFFER_CAP as usize];
+
+        let mut table = StreamTable::with_pool(1, 0, Some(pool.clone()));
+        for _ in 0..4 {
+            table.append(&chunk).unwrap();
+        }
+        assert_eq!(
+            pool.stats(),
+            StreamDataPoolStats {
+                allocations: 4,
+                reuses: 0,
+                free: 0
+            }
+        );
+
+        // dropping the table (e.g. after a flush) returns its buffers
+        drop(table);
+        assert_eq!(pool.stats().free, 4);
+
+        let mut table = StreamTable::with_pool(2, 0, Some(pool.clone()));
+        for _ in 0..4 {
+            table.append(&chunk).unwrap();
+        }
+        let mut buf = vec![0u8; chunk.len()];
+        assert_eq!(table.read_stream(0, &mut buf).unwrap(), chunk.len());
+        assert_eq!(buf, chunk);
+        assert_eq!(
+            pool.stats(),
+            StreamDataPoolStats {
+                allocations: 4,
+                reuses: 4,
+                free: 0
+            }
+        );
+
+        // rolled back chunks go back to the pool as well
+        let checkpoint = table.checkpoint();
+        table.append(&chunk).unwrap();
+        table.rollback(checkpoint);
+        assert_eq!(pool.stats().allocations, 5);
+        assert_eq!(pool.stats().free, 1);
+    }
+
+    #[test]
+    fn test_stream_data_pool_capacity() {
+        let pool = Arc::new(StreamDataPool::new(2));
+        let mut table = StreamTable::with_pool(1, 0, Some(pool.clone()));
+        table
+            .append(&vec![1u8; STREAM_DATA_BUFFER_CAP as usize * 4])
+            .unwrap();
+        drop(table);
+        // only `capacity` free buffers are kept
+        assert_eq!(pool.stats().free, 2);
+
+        // merged chunks are not pooled
+        let mut table = StreamTable::with_pool(1, 0, Some(pool.clone()));
+        table
+            .append(&vec![1u8; STREAM_DATA_BUFFER_CAP as usize * 4])
+            .unwrap();
+        table.compact();
+        assert_eq!(table.chunk_count(), 2);
+        assert_eq!(pool.stats().free, 2);
+    }
 }
@@ -18,6 +18,18 @@ use std::{
     thread,
 };
 
+// When an append to a MemTable with a WAL (see MemTable::recover) is durable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalSyncPolicy {
+    // sync the WAL file before every append returns
+    EveryWrite,
+    // sync the WAL file once this many appends were written since the last sync,
+    // the appends in between are only written to the OS
+    Batched(usize),
+    // never sync, the OS writes the WAL file back when it sees fit
+    None,
+}
+
 pub struct WalInner {
     file: Mutex<(File, PathBuf)>,
     dir: String,
@@ -31,7 +43,7 @@ pub struct WalInner {
 }
 
 impl WalInner {
-    pub fn batch_write(&self, items: &[Entry]) -> Result<()> {
+    pub(crate) fn batch_write(&self, items: &[Entry]) -> Result<()> {
         assert!(!items.is_empty(), "Items cannot be empty");
         assert!(
             items[0].id == self.last_entry.load(atomic::Ordering::Relaxed) + 1,
@@ -69,8 +81,20 @@ impl WalInner {
         Ok(())
     }
 
+    // Sync the file data written so far to disk
+    pub(crate) fn sync(&self) -> Result<()> {
+        let file_guard = self.file.lock().unwrap();
+        file_guard.0.sync_data().map_err(errors::new_io_error)?;
+        Ok(())
+    }
+
+    // Id of the last entry written
+    pub(crate) fn last_entry(&self) -> u64 {
+        self.last_entry.load(atomic::Ordering::SeqCst)
+    }
+
     // Rotate the WAL file
-    pub fn try_to_rotate(&self) -> Result<()> {
+    pub(crate) fn try_to_rotate(&self) -> Result<()> {
         let file_size = self.file_size.load(atomic::Ordering::Relaxed);
         if file_size < self.max_size {
             return Ok(());
@@ -105,7 +129,7 @@ impl WalInner {
         Ok(())
     }
 
-    pub fn gc(&self, last_entry_id: u64) -> Result<()> {
+    pub(crate) fn gc(&self, last_entry_id: u64) -> Result<()> {
         log::info!(
             "Performing garbage collection on WAL files, last_entry_id: {}",
             last_entry_id
@@ -131,11 +155,11 @@ impl WalInner {
         Ok(())
     }
 
-    pub fn drop_next_sender(&self) {
+    pub(crate) fn drop_next_sender(&self) {
         self.next.borrow_mut().take();
     }
 
-    pub fn run(&self) -> Result<()> {
+    pub(crate) fn run(&self) -> Result<()> {
         // Start the WAL thread
 
         let receiver = self.receiver.lock().unwrap();
@@ -210,7 +234,7 @@ impl std::ops::Deref for Wal {
 }
 
 impl Wal {
-    pub fn new(
+    pub(crate) fn new(
         file: (File, PathBuf),
         dir: String,
         max_size: u64,
@@ -241,17 +265,17 @@ impl Wal {
         }
     }
 
-    pub fn clone_inner(&self) -> Arc<WalInner> {
+    pub(crate) fn clone_inner(&self) -> Arc<WalInner> {
         self.inner.clone()
     }
 
-    pub fn write(&self, item: Entry) -> Result<()> {
+    pub(crate) fn write(&self, item: Entry) -> Result<()> {
         // Append data to the stream
         self.sender.send(item).context("wal sender error")?;
         Ok(())
     }
 
-    pub fn start(&self) -> () {
+    pub(crate) fn start(&self) -> () {
         // Start the WAL with the given sender
         let _ = thread::Builder::new()
             .name("wals write thread".into())
@@ -267,7 +291,7 @@ impl Wal {
             });
     }
 
-    pub fn set_err_handler(
+    pub(crate) fn set_err_handler(
         &self,
         err_handler: Box<dyn Fn(Error) + Send + Sync>,
     ) -> Result<(), Error> {